@@ -0,0 +1,105 @@
+//! Pacing the rate new WebSocket connections are accepted
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Paces accepting connections to `rate` per second, allowing bursts of up
+/// to `burst`, so a reconnect storm (e.g. after a network blip) is ramped up
+/// smoothly instead of spiking the node's CPU all at once.
+///
+/// Connections over the rate are delayed for their turn, or rejected when
+/// their turn is more than `max_wait` away.
+pub struct AcceptRateLimiter {
+    /// The interval between accepts (`None` disables pacing)
+    interval: Option<Duration>,
+    /// How far accepts may run ahead of the rate (the burst)
+    tolerance: Duration,
+    max_wait: Duration,
+    /// When the next accept is due at the steady rate
+    next: Mutex<Option<Instant>>,
+}
+
+impl AcceptRateLimiter {
+    pub fn new(rate: f64, burst: u32, max_wait: Duration) -> Self {
+        let interval = (rate > 0.0)
+            .then(|| Duration::try_from_secs_f64(1.0 / rate).ok())
+            .flatten();
+        Self {
+            interval,
+            tolerance: interval.unwrap_or_default() * burst.saturating_sub(1),
+            max_wait,
+            next: Default::default(),
+        }
+    }
+
+    /// Reserve a turn to accept a connection.
+    ///
+    /// Returns how long to wait for the turn, or `None` when it's further off
+    /// than `max_wait` (and the connection should be rejected)
+    pub fn reserve(&self) -> Option<Duration> {
+        self.reserve_at(Instant::now())
+    }
+
+    fn reserve_at(&self, now: Instant) -> Option<Duration> {
+        let Some(interval) = self.interval else {
+            return Some(Duration::ZERO);
+        };
+        let mut next = self.next.lock().expect("AcceptRateLimiter poisoned");
+        let due = next.map_or(now, |next| next.max(now));
+        let wait = due.saturating_duration_since(now + self.tolerance);
+        if wait > self.max_wait {
+            return None;
+        }
+        *next = Some(due + interval);
+        Some(wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::AcceptRateLimiter;
+
+    #[test]
+    fn burst_smoothed() {
+        let limiter = AcceptRateLimiter::new(10.0, 5, Duration::from_secs(1));
+        let now = Instant::now();
+        let waits: Vec<_> = (0..30).map(|_| limiter.reserve_at(now)).collect();
+        // The burst's accepted immediately
+        assert!(waits[..5].iter().all(|wait| *wait == Some(Duration::ZERO)));
+        // Then paced at the rate, up to the max wait
+        for (i, wait) in waits[5..15].iter().enumerate() {
+            let expected = Duration::from_millis(100) * (i as u32 + 1);
+            let wait = wait.unwrap();
+            assert!(
+                wait.abs_diff(expected) < Duration::from_millis(1),
+                "{i}: {wait:?}"
+            );
+        }
+        // The rest are rejected
+        assert!(waits[15..].iter().all(Option::is_none));
+
+        // Turns free up as time passes
+        let later = now + Duration::from_secs(2);
+        assert_eq!(limiter.reserve_at(later), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn steady_rate_unaffected() {
+        let limiter = AcceptRateLimiter::new(10.0, 1, Duration::ZERO);
+        let start = Instant::now();
+        for i in 0..50 {
+            let now = start + Duration::from_millis(100 * i);
+            assert_eq!(limiter.reserve_at(now), Some(Duration::ZERO));
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let limiter = AcceptRateLimiter::new(0.0, 0, Duration::ZERO);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(limiter.reserve_at(now), Some(Duration::ZERO));
+        }
+    }
+}
@@ -18,6 +18,12 @@ use autopush_common::errors::{ApcErrorKind, Result};
 
 use crate::protocol::BroadcastValue;
 
+/// The `errors` entry counting the missing broadcasts omitted from a
+/// response (see `Broadcast::missing_into_errors`). Megaphone broadcast ids
+/// are of the form `<broadcaster>/<bchannel>` (of alphanumerics, `-` and
+/// `_`), so it can't collide with one
+pub const MISSING_BROADCASTS_OVERFLOW: &str = "*truncated*";
+
 /// A Broadcast entry Key in a BroadcastRegistry
 /// This is the way that both the client and server identify a given Broadcast.
 type BroadcastKey = u32;
@@ -33,14 +39,16 @@ pub struct BroadcastSubs {
 #[derive(Debug)]
 struct BroadcastRegistry {
     lookup: HashMap<String, BroadcastKey>, // mapping of broadcast string identifiers to internal BroadcastKeys
-    table: Vec<String>,                    // List of known broadcast string identifiers
+    table: HashMap<BroadcastKey, String>, // mapping of BroadcastKeys back to their string identifiers
+    next_key: BroadcastKey, // the key of the next added broadcast (keys aren't reused)
 }
 
 impl BroadcastRegistry {
     fn new() -> BroadcastRegistry {
         BroadcastRegistry {
             lookup: HashMap::new(),
-            table: Vec::new(),
+            table: HashMap::new(),
+            next_key: 0,
         }
     }
 
@@ -50,14 +58,23 @@ impl BroadcastRegistry {
         if let Some(v) = self.lookup.get(&broadcast_id) {
             return *v;
         }
-        let i = self.table.len() as u32;
-        self.table.push(broadcast_id.clone());
+        let i = self.next_key;
+        self.next_key += 1;
+        self.table.insert(i, broadcast_id.clone());
         self.lookup.insert(broadcast_id, i);
         i
     }
 
+    /// Removes a broadcast from the lookup table, returning its id. Its key
+    /// is never reused (clients may still be subscribed to it).
+    fn remove_broadcast(&mut self, key: BroadcastKey) -> Option<String> {
+        let broadcast_id = self.table.remove(&key)?;
+        self.lookup.remove(&broadcast_id);
+        Some(broadcast_id)
+    }
+
     fn lookup_id(&self, key: BroadcastKey) -> Option<String> {
-        self.table.get(key as usize).cloned()
+        self.table.get(&key).cloned()
     }
 
     fn lookup_key(&self, broadcast_id: &str) -> Option<BroadcastKey> {
@@ -114,6 +131,27 @@ impl Broadcast {
     pub fn vec_into_hashmap(broadcasts: Vec<Broadcast>) -> HashMap<String, BroadcastValue> {
         broadcasts.into_iter().map(|v| v.into()).collect()
     }
+
+    /// Convert missing broadcasts (from
+    /// `BroadcastChangeTracker::missing_broadcasts`) into a response's
+    /// `errors` value, reporting at most `max_reported` (0 for all) of them.
+    /// The number of any further ones is reported under
+    /// `MISSING_BROADCASTS_OVERFLOW`
+    pub fn missing_into_errors(mut missing: Vec<Broadcast>, max_reported: usize) -> BroadcastValue {
+        let overflow = if max_reported > 0 && missing.len() > max_reported {
+            missing.split_off(max_reported).len()
+        } else {
+            0
+        };
+        let mut errors = Self::vec_into_hashmap(missing);
+        if overflow > 0 {
+            errors.insert(
+                MISSING_BROADCASTS_OVERFLOW.to_owned(),
+                BroadcastValue::Value(overflow.to_string()),
+            );
+        }
+        BroadcastValue::Nested(errors)
+    }
 }
 
 /// Return to `Server::broadcast_init` the result of the first delta call for a client
@@ -134,6 +172,7 @@ pub struct BroadcastChangeTracker {
     broadcast_registry: BroadcastRegistry,
     broadcast_versions: HashMap<BroadcastKey, String>,
     change_count: u32,
+    max_tracked_broadcasts: Option<usize>,
 }
 
 impl BroadcastChangeTracker {
@@ -144,6 +183,7 @@ impl BroadcastChangeTracker {
             broadcast_registry: BroadcastRegistry::new(),
             broadcast_versions: HashMap::new(),
             change_count: 0,
+            max_tracked_broadcasts: None,
         };
         for srv in broadcasts {
             let key = tracker.broadcast_registry.add_broadcast(srv.broadcast_id);
@@ -152,6 +192,18 @@ impl BroadcastChangeTracker {
         tracker
     }
 
+    /// Limit the number of tracked broadcasts to `max`: adding new
+    /// broadcasts beyond it drops the least recently changed
+    pub fn with_max_tracked_broadcasts(mut self, max: Option<usize>) -> Self {
+        self.max_tracked_broadcasts = max;
+        self
+    }
+
+    /// The number of broadcasts currently tracked
+    pub fn tracked_count(&self) -> usize {
+        self.broadcast_versions.len()
+    }
+
     /// Add a `Vec` of `Broadcast`s via `self.add_broadcast`
     ///
     /// Returning the latest change_count (or `None` for an empty `Vec`)
@@ -170,6 +222,11 @@ impl BroadcastChangeTracker {
             trace!("📢 returning change count {}", &change_count);
             return change_count;
         }
+        if let Some(max) = self.max_tracked_broadcasts {
+            while max > 0 && self.tracked_count() >= max {
+                self.drop_oldest_broadcast();
+            }
+        }
         self.change_count += 1;
         let key = self
             .broadcast_registry
@@ -182,6 +239,30 @@ impl BroadcastChangeTracker {
         self.change_count
     }
 
+    /// Stop tracking the least recently changed broadcast: those unchanged
+    /// since initialization (the oldest first), otherwise the broadcast with
+    /// the oldest revision
+    fn drop_oldest_broadcast(&mut self) {
+        let unchanged = self
+            .broadcast_versions
+            .keys()
+            .filter(|key| !self.broadcast_list.iter().any(|b| b.broadcast == **key))
+            .min()
+            .copied();
+        let Some(key) = unchanged.or_else(|| self.broadcast_list.first().map(|b| b.broadcast))
+        else {
+            return;
+        };
+        self.broadcast_list.retain(|b| b.broadcast != key);
+        self.broadcast_versions.remove(&key);
+        if let Some(broadcast_id) = self.broadcast_registry.remove_broadcast(key) {
+            warn!(
+                "📢 Tracking too many broadcasts (max: {:?}), dropping {}",
+                self.max_tracked_broadcasts, broadcast_id
+            );
+        }
+    }
+
     /// Update a `broadcast` to a new revision, triggering a change_count increase.
     ///
     /// Returns an error if the `broadcast` was never initialized/added.
@@ -390,4 +471,69 @@ mod tests {
         assert_eq!(broadcast_subs.change_count, 1);
         assert_eq!(tracker.broadcast_list.len(), 1);
     }
+
+    #[test]
+    fn test_missing_into_errors_capped() {
+        let tracker = BroadcastChangeTracker::new(make_broadcast_base());
+        let requested: Vec<Broadcast> = (0..1000)
+            .map(|i| (format!("junk{i}"), "rev1".to_owned()).into())
+            .collect();
+        let missing = tracker.missing_broadcasts(&requested);
+        assert_eq!(missing.len(), 1000);
+
+        let BroadcastValue::Nested(errors) = Broadcast::missing_into_errors(missing.clone(), 10)
+        else {
+            panic!("Expected nested errors");
+        };
+        assert_eq!(errors.len(), 11);
+        assert_eq!(
+            errors.get(MISSING_BROADCASTS_OVERFLOW),
+            Some(&BroadcastValue::Value("990".to_owned()))
+        );
+        assert_eq!(
+            errors.get("junk0"),
+            Some(&BroadcastValue::Value("Broadcast not found".to_owned()))
+        );
+        assert!(!errors.contains_key("junk10"));
+
+        // Uncapped
+        let BroadcastValue::Nested(errors) = Broadcast::missing_into_errors(missing, 0) else {
+            panic!("Expected nested errors");
+        };
+        assert_eq!(errors.len(), 1000);
+        assert!(!errors.contains_key(MISSING_BROADCASTS_OVERFLOW));
+    }
+
+    #[test]
+    fn test_max_tracked_broadcasts() {
+        let mut tracker =
+            BroadcastChangeTracker::new(make_broadcast_base()).with_max_tracked_broadcasts(Some(3));
+        tracker.add_broadcast(("bcastc".to_owned(), "rev1".to_owned()).into());
+        assert_eq!(tracker.tracked_count(), 3);
+        // bcastb changed most recently, bcasta's unchanged since initialization
+        tracker.add_broadcast(("bcastb".to_owned(), "rev2".to_owned()).into());
+
+        for i in 0..10 {
+            tracker.add_broadcast((format!("bcast{i}"), "rev1".to_owned()).into());
+            assert_eq!(tracker.tracked_count(), 3);
+        }
+        // Dropped broadcasts don't linger in the registry
+        assert_eq!(tracker.broadcast_registry.table.len(), 3);
+        assert_eq!(tracker.broadcast_registry.lookup.len(), 3);
+        assert_eq!(tracker.broadcast_list.len(), 3);
+        let missing = tracker.missing_broadcasts(&[
+            ("bcasta".to_owned(), "rev1".to_owned()).into(),
+            ("bcastb".to_owned(), "rev2".to_owned()).into(),
+            ("bcast9".to_owned(), "rev1".to_owned()).into(),
+        ]);
+        assert_eq!(missing.len(), 2);
+        assert!(missing.iter().all(|b| b.broadcast_id != "bcast9"));
+
+        // A dropped broadcast can be tracked again
+        tracker.add_broadcast(("bcasta".to_owned(), "rev2".to_owned()).into());
+        assert_eq!(tracker.tracked_count(), 3);
+        let BroadcastSubsInit(_, delta) =
+            tracker.broadcast_delta(&[("bcasta".to_owned(), "rev1".to_owned()).into()]);
+        assert_eq!(delta[0].version, "rev2");
+    }
 }
@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate slog_scope;
 
+pub mod accept;
 pub mod broadcast;
 pub mod megaphone;
 pub mod protocol;
@@ -1,7 +1,7 @@
 use std::{collections::HashMap, error::Error, io, sync::Arc, time::Duration};
 
 use actix_web::rt;
-use cadence::{CountedExt, StatsdClient};
+use cadence::{CountedExt, Gauged, StatsdClient};
 use serde_derive::Deserialize;
 use tokio::sync::RwLock;
 
@@ -41,6 +41,10 @@ pub async fn init_and_spawn_megaphone_updater(
             } else {
                 metrics.incr_with_tags("megaphone.updater.ok").send();
             }
+            let tracked = broadcaster.read().await.tracked_count();
+            metrics
+                .gauge_with_tags("megaphone.broadcasts.tracked", tracked as u64)
+                .send();
         }
     });
 
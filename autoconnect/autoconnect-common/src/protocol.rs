@@ -6,31 +6,85 @@
 //! are used to generate the ability to serialize these structures to JSON,
 //! using the `serde` crate. More docs for serde can be found at
 //! <https://serde.rs>
+//!
+//! The `derive(JsonSchema)` annotations generate a JSON Schema of the
+//! messages from the same serde attributes, see [protocol_schema].
 use std::collections::HashMap;
+use std::fmt;
 use std::str::FromStr;
 
+use schemars::{schema_for, JsonSchema};
 use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use autopush_common::notification::Notification;
 
-#[derive(Debug, Eq, PartialEq, Serialize)]
+#[derive(Debug, Eq, PartialEq, Serialize, JsonSchema)]
 #[serde(untagged)]
 pub enum BroadcastValue {
     Value(String),
     Nested(HashMap<String, BroadcastValue>),
 }
 
+/// The WebPush protocol version, negotiated via the WebSocket subprotocol
+/// (`Sec-WebSocket-Protocol`)
+///
+/// Allows gating future changes of the messages' shapes by version.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ProtocolVersion {
+    /// The original protocol, also used when the Client requests no (or no
+    /// supported) subprotocol
+    #[default]
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// The WebSocket subprotocol name of this version
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            Self::V1 => "push-notification",
+            Self::V2 => "push-notification-v2",
+        }
+    }
+
+    /// Select the first supported subprotocol of those offered (a comma
+    /// separated `Sec-WebSocket-Protocol` header value)
+    pub fn negotiate(offered: &str) -> Option<Self> {
+        offered.split(',').map(str::trim).find_map(|subprotocol| {
+            [Self::V1, Self::V2]
+                .into_iter()
+                .find(|version| version.subprotocol() == subprotocol)
+        })
+    }
+}
+
+/// Requests delivery of stored notifications in
+/// `ServerMessage::NotificationBatch` frames, when included in a Hello's
+/// `features`
+pub const FEATURE_NOTIFICATION_BATCH: &str = "notification_batch";
+
+/// Requests `ServerMessage::AppPing`s in place of WebSocket Pings (when the
+/// server's `app_level_ping` allows it), when included in a Hello's
+/// `features`
+pub const FEATURE_APP_PING: &str = "app_ping";
+
 #[derive(Debug, Default)]
 // Used for the server to flag a webpush client to deliver a Notification or Check storage
 pub enum ServerNotification {
     CheckStorage,
     Notification(Notification),
+    /// A new connection for the same UAID has arrived: finish flushing
+    /// in-flight notifications then disconnect
+    GracefulDisconnect,
+    /// Another connection for the same UAID (with
+    /// `allow_multiple_connections`) Ack'd these notifications
+    Acked(Vec<ClientAck>),
     #[default]
     Disconnect,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(tag = "messageType", rename_all = "snake_case")]
 pub enum ClientMessage {
     Hello {
@@ -39,12 +93,39 @@ pub enum ClientMessage {
         _channel_ids: Option<Vec<Uuid>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         broadcasts: Option<HashMap<String, String>>,
+        /// Optional protocol features requested by the Client (e.g.
+        /// [FEATURE_NOTIFICATION_BATCH])
+        #[serde(skip_serializing_if = "Option::is_none")]
+        features: Option<Vec<String>>,
+    },
+
+    /// Reconnect with the `resume_token` of a previous Hello response in
+    /// place of a Hello. Handled as a Hello when the token's no longer valid
+    Resume {
+        token: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        broadcasts: Option<HashMap<String, String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        features: Option<Vec<String>>,
     },
 
     Register {
         #[serde(rename = "channelID")]
         channel_id: String,
         key: Option<String>,
+        /// Only store the channel's notifications, for the Client to collect
+        /// when it checks storage, never delivering them directly. They
+        /// never trigger a storage check themselves: a connected Client only
+        /// receives them on reconnecting or at its next check (prompted by
+        /// other channels' stored notifications, once it's acked
+        /// everything outstanding). Re-registering without it clears the
+        /// mark
+        #[serde(
+            default,
+            rename = "storeOnly",
+            skip_serializing_if = "std::ops::Not::not"
+        )]
+        store_only: bool,
     },
 
     Unregister {
@@ -67,23 +148,87 @@ pub enum ClientMessage {
     },
 
     Ping,
+
+    /// Response to a `ServerMessage::AppPing`
+    AppPong,
 }
 
+/// The `messageType`s of [ClientMessage]
+const CLIENT_MESSAGE_TYPES: &[&str] = &[
+    "hello",
+    "resume",
+    "register",
+    "unregister",
+    "broadcast_subscribe",
+    "ack",
+    "nack",
+    "ping",
+    "app_pong",
+];
+
 impl FromStr for ClientMessage {
-    type Err = serde_json::error::Error;
+    type Err = InvalidClientMessage;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         // parse empty object "{}" as a Ping
-        serde_json::from_str::<HashMap<(), ()>>(s)
-            .map(|_| ClientMessage::Ping)
-            .or_else(|_| serde_json::from_str(s))
+        if serde_json::from_str::<HashMap<(), ()>>(s).is_ok() {
+            return Ok(ClientMessage::Ping);
+        }
+        let value: serde_json::Value =
+            serde_json::from_str(s).map_err(InvalidClientMessage::MalformedJson)?;
+        let message_type = value.get("messageType").and_then(|v| v.as_str());
+        match message_type {
+            Some(message_type) if CLIENT_MESSAGE_TYPES.contains(&message_type) => {
+                serde_json::from_value(value).map_err(InvalidClientMessage::MalformedJson)
+            }
+            _ => Err(InvalidClientMessage::UnknownType(
+                message_type.unwrap_or_default().to_owned(),
+            )),
+        }
+    }
+}
+
+/// A Text frame that couldn't be parsed as a [ClientMessage]
+#[derive(Debug)]
+pub enum InvalidClientMessage {
+    /// Invalid JSON or fields for a known `messageType`
+    MalformedJson(serde_json::Error),
+    /// A missing or unrecognized `messageType`
+    UnknownType(String),
+}
+
+impl InvalidClientMessage {
+    /// A short name for this error (for metrics and the Client)
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::MalformedJson(_) => "malformed_json",
+            Self::UnknownType(_) => "unknown_type",
+        }
+    }
+}
+
+impl fmt::Display for InvalidClientMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedJson(e) => write!(f, "Malformed message JSON: {e}"),
+            Self::UnknownType(message_type) => write!(f, "Unknown messageType: {message_type:?}"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidClientMessage {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::MalformedJson(e) => Some(e),
+            Self::UnknownType(_) => None,
+        }
     }
 }
 
 /// Returned ACKnowledgement of the received message by the User Agent.
 /// This is the payload for the `messageType:ack` packet.
 ///
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct ClientAck {
     // The channel_id which received messages
     #[serde(rename = "channelID")]
@@ -92,7 +237,20 @@ pub struct ClientAck {
     pub version: String,
 }
 
-#[derive(Debug, Serialize)]
+/// Server imposed limits advertised to the Client in the Hello response
+#[derive(Debug, Default, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct ServerLimits {
+    /// The max size of a notification's data in bytes
+    pub max_data_bytes: usize,
+    /// The max number of channels the Client may register: further
+    /// registrations fail with a 409 status
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_channels: Option<usize>,
+    /// The interval (in seconds) the server pings the Client at
+    pub ping_interval: u64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
 #[serde(tag = "messageType", rename_all = "snake_case")]
 pub enum ServerMessage {
     Hello {
@@ -101,6 +259,14 @@ pub enum ServerMessage {
         // This is required for output, but will always be "true"
         use_webpush: bool,
         broadcasts: HashMap<String, BroadcastValue>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limits: Option<ServerLimits>,
+        /// Presented in a `ClientMessage::Resume` to reconnect
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+        /// The subset of the Client's requested features that were enabled
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        features: Vec<String>,
     },
 
     Register {
@@ -123,7 +289,32 @@ pub enum ServerMessage {
 
     Notification(Notification),
 
+    /// Multiple stored notifications in a single frame, sent in place of
+    /// individual `Notification`s when [FEATURE_NOTIFICATION_BATCH] was
+    /// negotiated. Each notification's Ack'd individually
+    NotificationBatch {
+        notifications: Vec<Notification>,
+    },
+
     Ping,
+
+    /// An application level Ping (sent when `app_level_ping` is enabled in
+    /// place of WebSocket Ping frames), answered with a
+    /// `ClientMessage::AppPong`
+    AppPing,
+
+    /// Advise the Client to wait before reconnecting, sent prior to closing
+    /// the connection when the node is overloaded
+    Reconnect {
+        reconnect_after_secs: u64,
+    },
+
+    /// Describe why the Client's message was rejected, sent prior to closing
+    /// the connection
+    Error {
+        reason: String,
+        description: String,
+    },
 }
 
 impl ServerMessage {
@@ -136,3 +327,150 @@ impl ServerMessage {
         }
     }
 }
+
+/// Return a JSON Schema describing the `ClientMessage`s accepted from and the
+/// `ServerMessage`s sent to the Client
+///
+/// Note that both sides may also send the empty object `{}` as a Ping.
+pub fn protocol_schema() -> serde_json::Value {
+    serde_json::json!({
+        "ClientMessage": schema_for!(ClientMessage),
+        "ServerMessage": schema_for!(ServerMessage),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use schemars::schema::RootSchema;
+
+    use super::{
+        protocol_schema, ClientMessage, InvalidClientMessage, ProtocolVersion, ServerMessage,
+        CLIENT_MESSAGE_TYPES,
+    };
+
+    /// Collect every `messageType` value declared in the schema
+    fn message_types(value: &serde_json::Value, types: &mut HashSet<String>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                if let Some(values) = map
+                    .get("properties")
+                    .and_then(|props| props.get("messageType"))
+                    .and_then(|message_type| message_type.get("enum"))
+                    .and_then(|values| values.as_array())
+                {
+                    types.extend(values.iter().filter_map(|v| v.as_str().map(str::to_owned)));
+                }
+                map.values().for_each(|v| message_types(v, types));
+            }
+            serde_json::Value::Array(values) => values.iter().for_each(|v| message_types(v, types)),
+            _ => (),
+        }
+    }
+
+    #[test]
+    fn schema_contains_message_types() {
+        let schema = protocol_schema();
+        for (name, expected) in [
+            (
+                "ClientMessage",
+                vec![
+                    "hello",
+                    "resume",
+                    "register",
+                    "unregister",
+                    "broadcast_subscribe",
+                    "ack",
+                    "nack",
+                    "ping",
+                    "app_pong",
+                ],
+            ),
+            (
+                "ServerMessage",
+                vec![
+                    "hello",
+                    "register",
+                    "unregister",
+                    "broadcast",
+                    "notification",
+                    "notification_batch",
+                    "ping",
+                    "app_ping",
+                    "reconnect",
+                    "error",
+                ],
+            ),
+        ] {
+            let json = serde_json::to_string(&schema[name]).unwrap();
+            let root: RootSchema = serde_json::from_str(&json).unwrap();
+            assert!(root.schema.subschemas.is_some());
+
+            let mut types = HashSet::new();
+            message_types(&schema[name], &mut types);
+            for message_type in expected {
+                assert!(
+                    types.contains(message_type),
+                    "{name} missing {message_type}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn client_message_types() {
+        let mut types = HashSet::new();
+        message_types(&protocol_schema()["ClientMessage"], &mut types);
+        let expected: HashSet<String> =
+            CLIENT_MESSAGE_TYPES.iter().map(|t| t.to_string()).collect();
+        assert_eq!(types, expected);
+    }
+
+    #[test]
+    fn invalid_client_message() {
+        for (msg, reason) in [
+            (r#"{"messageType": "hello""#, "malformed_json"),
+            (r#"{"messageType": "register"}"#, "malformed_json"),
+            (r#"{"messageType": "gimme"}"#, "unknown_type"),
+            (r#"{"channelID": "foo"}"#, "unknown_type"),
+        ] {
+            let err = msg.parse::<ClientMessage>().unwrap_err();
+            assert_eq!(err.reason(), reason, "{msg}");
+        }
+        assert!(matches!(
+            r#"{"messageType": "gimme"}"#.parse::<ClientMessage>(),
+            Err(InvalidClientMessage::UnknownType(t)) if t == "gimme"
+        ));
+        assert!(matches!(
+            "{}".parse::<ClientMessage>(),
+            Ok(ClientMessage::Ping)
+        ));
+    }
+
+    #[test]
+    fn error_message_json() {
+        let smsg = ServerMessage::Error {
+            reason: "unknown_type".to_owned(),
+            description: "Unknown messageType".to_owned(),
+        };
+        assert_eq!(
+            smsg.to_json().unwrap(),
+            r#"{"messageType":"error","reason":"unknown_type","description":"Unknown messageType"}"#
+        );
+    }
+
+    #[test]
+    fn negotiate_protocol_version() {
+        assert_eq!(
+            ProtocolVersion::negotiate("push-notification-v2"),
+            Some(ProtocolVersion::V2)
+        );
+        assert_eq!(
+            ProtocolVersion::negotiate("chat, push-notification, push-notification-v2"),
+            Some(ProtocolVersion::V1)
+        );
+        assert_eq!(ProtocolVersion::negotiate("push-notification-v9"), None);
+        assert_eq!(ProtocolVersion::negotiate(""), None);
+    }
+}
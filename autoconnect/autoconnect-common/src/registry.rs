@@ -1,13 +1,17 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
+use actix_web::rt;
 use futures::channel::mpsc;
 use futures_locks::RwLock;
 use uuid::Uuid;
 
 use autopush_common::errors::{ApcErrorKind, Result};
 use autopush_common::notification::Notification;
+use autopush_common::otel;
 
-use crate::protocol::ServerNotification;
+use crate::protocol::{ClientAck, ServerNotification};
 
 /// A connected Websocket client.
 #[derive(Debug)]
@@ -20,13 +24,41 @@ struct RegisteredClient {
     pub tx: mpsc::UnboundedSender<ServerNotification>,
 }
 
-/// Contains a mapping of UAID to the associated RegisteredClient.
+/// Contains a mapping of UAID to the associated RegisteredClient(s).
 #[derive(Default)]
 pub struct ClientRegistry {
-    clients: RwLock<HashMap<Uuid, RegisteredClient>>,
+    /// Connected clients by UAID. Holds a single client unless
+    /// `allow_multiple_connections` is enabled
+    clients: RwLock<HashMap<Uuid, Vec<RegisteredClient>>>,
+    /// The total number of `clients`, maintained on connect and disconnect
+    /// so it's read without locking (or scanning) them
+    connected: AtomicUsize,
+    /// Previous connections for a UAID that have been replaced by a new
+    /// connection but are still within their `disconnect_grace` period
+    draining: RwLock<HashMap<Uuid, RegisteredClient>>,
+    /// How long a replaced connection may continue flushing its in-flight
+    /// notifications before being forcibly disconnected. Zero disconnects
+    /// immediately.
+    disconnect_grace: Duration,
+    /// Whether multiple simultaneous connections (distinguished by their
+    /// `uid`) are allowed for a UAID instead of replacing the previous one
+    allow_multiple_connections: bool,
 }
 
 impl ClientRegistry {
+    pub fn new(disconnect_grace: Duration, allow_multiple_connections: bool) -> Self {
+        Self {
+            disconnect_grace,
+            allow_multiple_connections,
+            ..Default::default()
+        }
+    }
+
+    /// The number of currently connected clients
+    pub fn count(&self) -> usize {
+        self.connected.load(Ordering::Relaxed)
+    }
+
     /// Informs this server that a new `client` has connected
     ///
     /// For now just registers internal state by keeping track of the `client`,
@@ -40,56 +72,335 @@ impl ClientRegistry {
         let (tx, snotif_stream) = mpsc::unbounded();
         let client = RegisteredClient { uaid, uid, tx };
         let mut clients = self.clients.write().await;
-        if let Some(client) = clients.insert(client.uaid, client) {
-            // Drop existing connection
+        if self.allow_multiple_connections {
+            clients.entry(uaid).or_default().push(client);
+            self.connected.fetch_add(1, Ordering::Relaxed);
+            return snotif_stream;
+        }
+        let previous = clients.insert(uaid, vec![client]);
+        // Replacing a connection leaves the count unchanged
+        if previous.is_none() {
+            self.connected.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(previous) = previous {
+            for client in previous {
+                self.ghost(client).await;
+            }
+        }
+        snotif_stream
+    }
+
+    /// Drop an existing connection replaced by a new one for the same UAID
+    ///
+    /// When a `disconnect_grace` is configured the old connection is first
+    /// sent a `GracefulDisconnect`, allowing it to finish flushing its current
+    /// batch of notifications. A `Disconnect` is forced once the grace period
+    /// elapses.
+    async fn ghost(&self, client: RegisteredClient) {
+        let mut draining = self.draining.write().await;
+        if let Some(previous) = draining.remove(&client.uaid) {
+            // A still draining connection is superseded: drop it now
+            let _ = previous.tx.unbounded_send(ServerNotification::Disconnect);
+        }
+        if self.disconnect_grace.is_zero() {
             let result = client.tx.unbounded_send(ServerNotification::Disconnect);
             if result.is_ok() {
                 debug!("ClientRegistry::connect Ghosting client, new one wants to connect");
             }
+            return;
         }
-        snotif_stream
+        if client
+            .tx
+            .unbounded_send(ServerNotification::GracefulDisconnect)
+            .is_err()
+        {
+            // Already gone
+            return;
+        }
+        debug!(
+            "ClientRegistry::connect Ghosting client after {:?}, new one wants to connect",
+            self.disconnect_grace
+        );
+        let tx = client.tx.clone();
+        let grace = self.disconnect_grace;
+        rt::spawn(async move {
+            rt::time::sleep(grace).await;
+            // Fails harmlessly if the client's already finished flushing
+            if tx.unbounded_send(ServerNotification::Disconnect).is_ok() {
+                debug!("ClientRegistry::connect Grace period elapsed, forcing disconnect");
+            }
+        });
+        draining.insert(client.uaid, client);
     }
 
     /// A notification has come for the uaid
+    ///
+    /// Delivered to every connection for the uaid, succeeding if at least one
+    /// of them accepted it.
     pub async fn notify(&self, uaid: Uuid, notif: Notification) -> Result<()> {
         trace!("ClientRegistry::notify");
+        let message_id = notif.version.clone();
+        otel::in_span(
+            otel::REGISTRY_NOTIFY,
+            &uaid,
+            Some(&message_id),
+            self.notify_clients(uaid, notif),
+        )
+        .await
+    }
+
+    async fn notify_clients(&self, uaid: Uuid, notif: Notification) -> Result<()> {
         let clients = self.clients.read().await;
-        if let Some(client) = clients.get(&uaid) {
+        let mut delivered = false;
+        for client in clients.get(&uaid).into_iter().flatten() {
             debug!("ClientRegistry::notify Found a client to deliver a notification to");
             let result = client
                 .tx
-                .unbounded_send(ServerNotification::Notification(notif));
+                .unbounded_send(ServerNotification::Notification(notif.clone()));
             if result.is_ok() {
                 debug!("ClientRegistry::notify Dropped notification in queue");
-                return Ok(());
+                delivered = true;
             }
         }
+        if delivered {
+            return Ok(());
+        }
         Err(ApcErrorKind::GeneralError("User not connected".into()).into())
     }
 
     /// A check for notification command has come for the uaid
+    ///
+    /// Sent to every connection for the uaid, succeeding if at least one of
+    /// them accepted it.
     pub async fn check_storage(&self, uaid: Uuid) -> Result<()> {
         trace!("ClientRegistry::check_storage");
         let clients = self.clients.read().await;
-        if let Some(client) = clients.get(&uaid) {
+        let mut delivered = false;
+        for client in clients.get(&uaid).into_iter().flatten() {
             let result = client.tx.unbounded_send(ServerNotification::CheckStorage);
             if result.is_ok() {
                 debug!("ClientRegistry::check_storage Told client to check storage");
-                return Ok(());
+                delivered = true;
             }
         }
+        if delivered {
+            return Ok(());
+        }
         Err(ApcErrorKind::GeneralError("User not connected".into()).into())
     }
 
-    /// The client specified by `uaid` has disconnected.
+    /// The client specified by `uaid` and `uid` Ack'd `updates`
+    ///
+    /// With `allow_multiple_connections` notifications are fanned out to
+    /// every connection for the uaid, so the Ack's relayed to the others for
+    /// them to resolve the same notifications.
+    pub async fn acked(&self, uaid: &Uuid, uid: &Uuid, updates: &[ClientAck]) {
+        trace!("ClientRegistry::acked");
+        if !self.allow_multiple_connections || updates.is_empty() {
+            return;
+        }
+        let clients = self.clients.read().await;
+        for client in clients
+            .get(uaid)
+            .into_iter()
+            .flatten()
+            .filter(|client| client.uid != *uid)
+        {
+            let _ = client
+                .tx
+                .unbounded_send(ServerNotification::Acked(updates.to_vec()));
+        }
+    }
+
+    /// The client specified by `uaid` and `uid` has disconnected.
+    ///
+    /// Other connections for the same `uaid` are left untouched.
     pub async fn disconnect(&self, uaid: &Uuid, uid: &Uuid) -> Result<()> {
         trace!("ClientRegistry::disconnect");
         let mut clients = self.clients.write().await;
-        let client_exists = clients.get(uaid).map_or(false, |client| client.uid == *uid);
-        if client_exists {
-            clients.remove(uaid).expect("Couldn't remove client?");
+        if let Some(connected) = clients.get_mut(uaid) {
+            if let Some(pos) = connected.iter().position(|client| client.uid == *uid) {
+                connected.remove(pos);
+                self.connected.fetch_sub(1, Ordering::Relaxed);
+                if connected.is_empty() {
+                    clients.remove(uaid);
+                }
+                return Ok(());
+            }
+        }
+        let mut draining = self.draining.write().await;
+        let draining_exists = draining
+            .get(uaid)
+            .map_or(false, |client| client.uid == *uid);
+        if draining_exists {
+            draining
+                .remove(uaid)
+                .expect("Couldn't remove draining client?");
             return Ok(());
         }
         Err(ApcErrorKind::GeneralError("User not connected".into()).into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use uuid::Uuid;
+
+    use autopush_common::notification::Notification;
+
+    use super::ClientRegistry;
+    use crate::protocol::{ClientAck, ServerNotification};
+
+    #[actix_rt::test]
+    async fn reconnect_immediate_disconnect() {
+        let registry = ClientRegistry::default();
+        let uaid = Uuid::new_v4();
+        let mut old = registry.connect(uaid, Uuid::new_v4()).await;
+        let _new = registry.connect(uaid, Uuid::new_v4()).await;
+        assert!(matches!(
+            old.next().await,
+            Some(ServerNotification::Disconnect)
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn reconnect_grace_flushes_before_disconnect() {
+        let registry = ClientRegistry::new(Duration::from_millis(50), false);
+        let uaid = Uuid::new_v4();
+        let old_uid = Uuid::new_v4();
+        let mut old = registry.connect(uaid, old_uid).await;
+        registry
+            .notify(uaid, Notification::default())
+            .await
+            .unwrap();
+
+        // Rapid reconnect
+        let mut new = registry.connect(uaid, Uuid::new_v4()).await;
+        // The in-flight notification is flushed to the old connection first
+        assert!(matches!(
+            old.next().await,
+            Some(ServerNotification::Notification(_))
+        ));
+        assert!(matches!(
+            old.next().await,
+            Some(ServerNotification::GracefulDisconnect)
+        ));
+        // New notifications go to the new connection
+        registry
+            .notify(uaid, Notification::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            new.next().await,
+            Some(ServerNotification::Notification(_))
+        ));
+        // Forced once the grace period elapses
+        assert!(matches!(
+            old.next().await,
+            Some(ServerNotification::Disconnect)
+        ));
+        assert!(registry.disconnect(&uaid, &old_uid).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn single_connection_disconnect() {
+        let registry = ClientRegistry::default();
+        let uaid = Uuid::new_v4();
+        let old_uid = Uuid::new_v4();
+        let new_uid = Uuid::new_v4();
+        let _old = registry.connect(uaid, old_uid).await;
+        let mut new = registry.connect(uaid, new_uid).await;
+        assert_eq!(registry.count(), 1);
+
+        // The ghosted connection's disconnect leaves the new one in place
+        assert!(registry.disconnect(&uaid, &old_uid).await.is_err());
+        registry.check_storage(uaid).await.unwrap();
+        assert!(matches!(
+            new.next().await,
+            Some(ServerNotification::CheckStorage)
+        ));
+        registry.disconnect(&uaid, &new_uid).await.unwrap();
+        assert_eq!(registry.count(), 0);
+        assert!(registry.check_storage(uaid).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn multiple_connections_fan_out() {
+        let registry = ClientRegistry::new(Duration::ZERO, true);
+        let uaid = Uuid::new_v4();
+        let first_uid = Uuid::new_v4();
+        let second_uid = Uuid::new_v4();
+        let mut first = registry.connect(uaid, first_uid).await;
+        let mut second = registry.connect(uaid, second_uid).await;
+        assert_eq!(registry.count(), 2);
+
+        registry
+            .notify(uaid, Notification::default())
+            .await
+            .unwrap();
+        registry.check_storage(uaid).await.unwrap();
+        for rx in [&mut first, &mut second] {
+            assert!(matches!(
+                rx.next().await,
+                Some(ServerNotification::Notification(_))
+            ));
+            assert!(matches!(
+                rx.next().await,
+                Some(ServerNotification::CheckStorage)
+            ));
+        }
+
+        // Only the matching uid is removed
+        registry.disconnect(&uaid, &first_uid).await.unwrap();
+        assert_eq!(registry.count(), 1);
+        assert!(registry.disconnect(&uaid, &first_uid).await.is_err());
+        registry.check_storage(uaid).await.unwrap();
+        assert!(matches!(
+            second.next().await,
+            Some(ServerNotification::CheckStorage)
+        ));
+        registry.disconnect(&uaid, &second_uid).await.unwrap();
+        assert!(registry
+            .notify(uaid, Notification::default())
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn multiple_connections_acked() {
+        let ack = || ClientAck {
+            channel_id: Uuid::new_v4(),
+            version: "version".to_owned(),
+        };
+        let registry = ClientRegistry::new(Duration::ZERO, true);
+        let uaid = Uuid::new_v4();
+        let first_uid = Uuid::new_v4();
+        let mut first = registry.connect(uaid, first_uid).await;
+        let mut second = registry.connect(uaid, Uuid::new_v4()).await;
+
+        // Relayed to the other connections only
+        registry.acked(&uaid, &first_uid, &[ack()]).await;
+        assert!(matches!(
+            second.next().await,
+            Some(ServerNotification::Acked(updates)) if updates.len() == 1
+        ));
+        registry.check_storage(uaid).await.unwrap();
+        assert!(matches!(
+            first.next().await,
+            Some(ServerNotification::CheckStorage)
+        ));
+
+        // A no-op without allow_multiple_connections
+        let registry = ClientRegistry::default();
+        let mut only = registry.connect(uaid, first_uid).await;
+        registry.acked(&uaid, &first_uid, &[ack()]).await;
+        registry.check_storage(uaid).await.unwrap();
+        assert!(matches!(
+            only.next().await,
+            Some(ServerNotification::CheckStorage)
+        ));
+    }
+}
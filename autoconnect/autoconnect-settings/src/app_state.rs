@@ -1,4 +1,10 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 #[cfg(feature = "bigtable")]
 use autopush_common::db::bigtable::BigTableClientImpl;
@@ -8,8 +14,8 @@ use fernet::{Fernet, MultiFernet};
 use tokio::sync::RwLock;
 
 use autoconnect_common::{
-    broadcast::BroadcastChangeTracker, megaphone::init_and_spawn_megaphone_updater,
-    registry::ClientRegistry,
+    accept::AcceptRateLimiter, broadcast::BroadcastChangeTracker,
+    megaphone::init_and_spawn_megaphone_updater, registry::ClientRegistry,
 };
 use autopush_common::db::{client::DbClient, DbSettings, StorageType};
 
@@ -24,10 +30,19 @@ pub struct AppState {
 
     /// Encryption object for the endpoint URL
     pub fernet: MultiFernet,
+    /// Encryption object for resume tokens (when `resume_token_key` is set)
+    pub resume_fernet: Option<MultiFernet>,
     /// The connected WebSocket clients
     pub clients: Arc<ClientRegistry>,
     /// The Megaphone Broadcast change tracker
     pub broadcaster: Arc<RwLock<BroadcastChangeTracker>>,
+    /// Paces accepting new WebSocket connections (shared by all workers)
+    pub accept_limiter: Arc<AcceptRateLimiter>,
+    /// The WebSocket `Origin`s allowed to connect, parsed from
+    /// `allowed_origins` (empty allows all)
+    pub allowed_origins: Arc<Vec<String>>,
+    /// Whether the node's draining (see [AppState::drain])
+    pub draining: Arc<AtomicBool>,
 
     pub settings: Settings,
     pub router_url: String,
@@ -36,26 +51,18 @@ pub struct AppState {
 
 impl AppState {
     pub fn from_settings(settings: Settings) -> Result<Self, ConfigError> {
-        let crypto_key = &settings.crypto_key;
-        if !(crypto_key.starts_with('[') && crypto_key.ends_with(']')) {
-            return Err(ConfigError::Message(format!(
-                "Invalid {ENV_PREFIX}_CRYPTO_KEY"
-            )));
-        }
-        let crypto_key = &crypto_key[1..crypto_key.len() - 1];
-        debug!("🔐 Fernet keys: {:?}", &crypto_key);
-        let fernets: Vec<Fernet> = crypto_key
-            .split(',')
-            .map(|s| s.trim().to_string())
-            .map(|key| {
-                Fernet::new(&key).unwrap_or_else(|| panic!("Invalid {ENV_PREFIX}_CRYPTO_KEY"))
-            })
-            .collect();
-        let fernet = MultiFernet::new(fernets);
+        let fernet = multi_fernet(&settings.crypto_key, "CRYPTO_KEY")?;
+        let resume_fernet = settings
+            .resume_token_key
+            .as_deref()
+            .map(|key| multi_fernet(key, "RESUME_TOKEN_KEY"))
+            .transpose()?;
         let metrics = autopush_common::metrics::builder(
             &settings.statsd_label,
             &settings.statsd_host,
             settings.statsd_port,
+            &settings.statsd_cluster,
+            settings.metrics_required,
         )
         .map_err(|e| ConfigError::Message(e.to_string()))?
         // Temporary tag to distinguish from the legacy autopush(connect)
@@ -88,7 +95,10 @@ impl AppState {
             .timeout(Duration::from_secs(1))
             .build()
             .unwrap_or_else(|e| panic!("Error while building reqwest::Client: {}", e));
-        let broadcaster = Arc::new(RwLock::new(BroadcastChangeTracker::new(Vec::new())));
+        let broadcaster = Arc::new(RwLock::new(
+            BroadcastChangeTracker::new(Vec::new())
+                .with_max_tracked_broadcasts(settings.max_tracked_broadcasts),
+        ));
 
         let router_url = settings.router_url();
         let endpoint_url = settings.endpoint_url();
@@ -98,14 +108,43 @@ impl AppState {
             metrics,
             http,
             fernet,
-            clients: Arc::new(ClientRegistry::default()),
+            resume_fernet,
+            clients: Arc::new(ClientRegistry::new(
+                settings.disconnect_grace_period,
+                settings.allow_multiple_connections,
+            )),
             broadcaster,
+            accept_limiter: Arc::new(AcceptRateLimiter::new(
+                settings.max_accept_rate,
+                settings.max_accept_burst,
+                settings.max_accept_wait,
+            )),
+            allowed_origins: Arc::new(settings.allowed_origins()),
+            draining: Default::default(),
             settings,
             router_url,
             endpoint_url,
         })
     }
 
+    /// Whether this node has reached its `overload_client_limit`
+    pub fn is_overloaded(&self) -> bool {
+        match self.settings.overload_client_limit {
+            Some(limit) => self.clients.count() >= limit,
+            None => false,
+        }
+    }
+
+    /// Drain the node: it reports unhealthy to the load balancer and refuses
+    /// new Hellos while continuing to serve its existing clients
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
     /// Initialize the `BroadcastChangeTracker`
     ///
     /// Via `autoconnect_common::megaphone::init_and_spawn_megaphone_updater`
@@ -132,6 +171,21 @@ impl AppState {
     }
 }
 
+/// Parse a `[key1,key2,...]` list of Fernet keys (the `name`d setting)
+fn multi_fernet(keys: &str, name: &str) -> Result<MultiFernet, ConfigError> {
+    if !(keys.starts_with('[') && keys.ends_with(']')) {
+        return Err(ConfigError::Message(format!("Invalid {ENV_PREFIX}_{name}")));
+    }
+    let keys = &keys[1..keys.len() - 1];
+    debug!("🔐 Fernet keys: {:?}", &keys);
+    let fernets: Vec<Fernet> = keys
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .map(|key| Fernet::new(&key).unwrap_or_else(|| panic!("Invalid {ENV_PREFIX}_{name}")))
+        .collect();
+    Ok(MultiFernet::new(fernets))
+}
+
 /// For tests
 #[cfg(debug_assertions)]
 impl Default for AppState {
@@ -8,6 +8,7 @@ use actix_web::{
 use serde_json::json;
 
 use autoconnect_settings::AppState;
+use autopush_common::db::error::DbResult;
 
 use crate::error::ApiError;
 
@@ -25,11 +26,19 @@ pub fn config(config: &mut web::ServiceConfig) {
         .service(web::resource("/__version__").route(web::get().to(version_route)));
 }
 
+/// Check the database's health, verifying its write path when
+/// `deep_health_check` is enabled
+async fn db_health_check(state: &AppState) -> DbResult<bool> {
+    if state.settings.deep_health_check {
+        state.db.write_health_check().await
+    } else {
+        state.db.health_check().await
+    }
+}
+
 /// Handle the `/health` and `/__heartbeat__` routes
 pub async fn health_route(state: Data<AppState>) -> Json<serde_json::Value> {
-    let healthy = state
-        .db
-        .health_check()
+    let healthy = db_health_check(&state)
         .await
         .map_err(|e| {
             error!("Autoconnect Health Error: {:?}", e);
@@ -46,7 +55,7 @@ pub async fn health_route(state: Data<AppState>) -> Json<serde_json::Value> {
 pub async fn status_route(state: Data<AppState>) -> Json<serde_json::Value> {
     let mut status: std::collections::HashMap<&str, String> = std::collections::HashMap::new();
     status.insert("version", env!("CARGO_PKG_VERSION").to_owned());
-    let check = state.db.health_check().await;
+    let check = db_health_check(&state).await;
     if check.is_ok() {
         status.insert("status", "OK".to_owned());
     } else {
@@ -60,8 +69,12 @@ pub async fn status_route(state: Data<AppState>) -> Json<serde_json::Value> {
 }
 
 /// Handle the `/__lbheartbeat__` route
-pub async fn lb_heartbeat_route() -> HttpResponse {
-    // Used by the load balancers, just return OK.
+pub async fn lb_heartbeat_route(state: Data<AppState>) -> HttpResponse {
+    // Used by the load balancers: a draining node reports unavailable so it
+    // stops receiving new connections
+    if state.is_draining() {
+        return HttpResponse::ServiceUnavailable().json(json!({"status": "DRAINING"}));
+    }
     HttpResponse::Ok().finish()
 }
 
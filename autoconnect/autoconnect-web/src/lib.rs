@@ -43,5 +43,8 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 pub fn config_router(cfg: &mut web::ServiceConfig) {
     cfg.service(web::resource("/push/{uaid}").route(web::put().to(routes::push_route)))
         .service(web::resource("/notif/{uaid}").route(web::put().to(routes::check_storage_route)))
+        .service(web::resource("/debug/user/{uaid}").route(web::get().to(routes::debug_user_route)))
+        .service(web::resource("/__drain__").route(web::post().to(routes::drain_route)))
+        .service(web::resource("/__settings__").route(web::get().to(routes::debug_settings_route)))
         .service(web::scope("").configure(dockerflow::config));
 }
@@ -1,7 +1,9 @@
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{error::ErrorInternalServerError, web, HttpMessage, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
 use uuid::Uuid;
 
-use autoconnect_settings::AppState;
+use autoconnect_settings::{AppState, Settings};
 use autopush_common::notification::Notification;
 
 use crate::error::ApiError;
@@ -16,20 +18,28 @@ pub async fn ws_route(
 }
 
 /// Deliver a Push notification directly to a connected client
+///
+/// The notification's JSON or, with a `Content-Type` of
+/// `application/msgpack` (see autoendpoint's `internal_encoding`),
+/// MessagePack encoded. Bodies sent with a `gzip` or `zstd`
+/// `Content-Encoding` (see autoendpoint's `internal_compression`) are
+/// decompressed by the `Bytes` extractor.
 pub async fn push_route(
+    req: HttpRequest,
     uaid: web::Path<Uuid>,
-    notif: web::Json<Notification>,
+    body: web::Bytes,
     app_state: web::Data<AppState>,
 ) -> HttpResponse {
+    let notif = match decode_notification(&req, &body) {
+        Ok(notif) => notif,
+        Err(response) => return response,
+    };
     trace!(
         "⏩ push_route, uaid: {} channel_id: {}",
         uaid,
         notif.channel_id
     );
-    let result = app_state
-        .clients
-        .notify(uaid.into_inner(), notif.into_inner())
-        .await;
+    let result = app_state.clients.notify(uaid.into_inner(), notif).await;
     if result.is_ok() {
         HttpResponse::Ok().finish()
     } else {
@@ -37,6 +47,22 @@ pub async fn push_route(
     }
 }
 
+/// Decode a notification sent to `push_route` per its `Content-Type`
+fn decode_notification(req: &HttpRequest, body: &[u8]) -> Result<Notification, HttpResponse> {
+    match req.content_type() {
+        "application/json" => serde_json::from_slice(body)
+            .map_err(|e| HttpResponse::BadRequest().body(format!("Invalid JSON: {e}"))),
+        "application/msgpack" | "application/x-msgpack" => {
+            // The sender encodes UUIDs as strings
+            let mut deserializer =
+                rmp_serde::Deserializer::from_read_ref(body).with_human_readable();
+            Notification::deserialize(&mut deserializer)
+                .map_err(|e| HttpResponse::BadRequest().body(format!("Invalid MessagePack: {e}")))
+        }
+        _ => Err(HttpResponse::UnsupportedMediaType().finish()),
+    }
+}
+
 /// Notify a connected client to check storage for new notifications
 pub async fn check_storage_route(
     uaid: web::Path<Uuid>,
@@ -50,3 +76,104 @@ pub async fn check_storage_route(
         HttpResponse::NotFound().body("Client not available")
     }
 }
+
+/// Drain the node ahead of its removal: it reports unavailable to the load
+/// balancer (via `/__lbheartbeat__`) and refuses new Hellos, while its
+/// existing clients continue to be served
+pub async fn drain_route(app_state: web::Data<AppState>) -> HttpResponse {
+    info!("🚰 Draining node");
+    app_state.drain();
+    HttpResponse::Ok().finish()
+}
+
+/// Ensure the debug routes are enabled and that the request bears the
+/// `debug_endpoints_token`. Returns the response to reject the request with
+/// otherwise
+fn check_debug_access(req: &HttpRequest, settings: &Settings) -> Result<(), HttpResponse> {
+    if !settings.debug_endpoints_enabled {
+        return Err(HttpResponse::NotFound().finish());
+    }
+    // Settings::validate requires a token of enabled debug routes: refuse
+    // any request regardless
+    let Some(token) = &settings.debug_endpoints_token else {
+        return Err(HttpResponse::Unauthorized().finish());
+    };
+    let authorized = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|v| {
+            v.len() == token.len() && openssl::memcmp::eq(v.as_bytes(), token.as_bytes())
+        });
+    if !authorized {
+        return Err(HttpResponse::Unauthorized().finish());
+    }
+    Ok(())
+}
+
+/// Dump a user's router record, channels and pending message count, for
+/// support debugging
+///
+/// Only available when `debug_endpoints_enabled`, to requests bearing the
+/// `debug_endpoints_token`. The `router_data` (which may include bridge
+/// tokens) is redacted.
+pub async fn debug_user_route(
+    req: HttpRequest,
+    uaid: web::Path<Uuid>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    if let Err(response) = check_debug_access(&req, &app_state.settings) {
+        return Ok(response);
+    }
+
+    let uaid = uaid.into_inner();
+    trace!("⏩ debug_user_route, uaid: {}", uaid);
+    let db = &app_state.db;
+    let Some((user, channels)) = db
+        .get_user_with_channels(&uaid)
+        .await
+        .map_err(ErrorInternalServerError)?
+    else {
+        return Ok(HttpResponse::NotFound().body("User not found"));
+    };
+    let mut channels: Vec<_> = channels
+        .into_iter()
+        .map(|chid| chid.as_hyphenated().to_string())
+        .collect();
+    channels.sort();
+    let pending_messages = db
+        .pending_message_count(&uaid)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "uaid": user.uaid.as_simple().to_string(),
+        "connected_at": user.connected_at,
+        "router_type": user.router_type,
+        "router_data": user.router_data.map(|_| "[redacted]"),
+        "node_id": user.node_id,
+        "record_version": user.record_version,
+        "current_timestamp": user.current_timestamp,
+        "last_disconnect_reason": user.last_disconnect_reason,
+        "last_disconnect_at": user.last_disconnect_at,
+        "version": user.version,
+        "channels": channels,
+        "pending_messages": pending_messages,
+    })))
+}
+
+/// Dump the node's effective settings, for fleet auditing
+///
+/// Only available when `debug_endpoints_enabled`, to requests bearing the
+/// `debug_endpoints_token`. Secrets are redacted.
+pub async fn debug_settings_route(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(response) = check_debug_access(&req, &app_state.settings) {
+        return response;
+    }
+    trace!("⏩ debug_settings_route");
+    HttpResponse::Ok().json(app_state.settings.redacted())
+}
@@ -1,16 +1,22 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use actix_http::ws::{self, Codec};
 use actix_test::TestServer;
+use cadence::{SpyMetricSink, StatsdClient};
 use futures_util::{SinkExt, StreamExt};
 use serde_json::json;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use autoconnect_common::test_support::{hello_again_db, hello_db, DUMMY_UAID, HELLO, HELLO_AGAIN};
+use autoconnect_common::test_support::{
+    hello_again_db, hello_db, DUMMY_CHID, DUMMY_UAID, HELLO, HELLO_AGAIN,
+};
 use autoconnect_settings::{AppState, Settings};
-use autopush_common::notification::Notification;
+use autopush_common::{
+    db::{mock::MockDbClient, User},
+    notification::Notification,
+};
 
-use crate::{build_app, config};
+use crate::{build_app, config, config_router};
 
 #[ctor::ctor]
 fn init_test_logging() {
@@ -51,7 +57,8 @@ pub async fn hello_new_user() {
     assert_eq!(msg["use_webpush"], true);
     assert!(msg["uaid"].is_string());
     assert!(msg["broadcasts"].is_object());
-    assert_eq!(msg.as_object().map_or(0, |o| o.len()), 5);
+    assert!(msg["limits"].is_object());
+    assert_eq!(msg.as_object().map_or(0, |o| o.len()), 6);
 }
 
 #[actix_rt::test]
@@ -172,6 +179,169 @@ pub async fn direct_notif() {
     assert_eq!(msg["data"], "foo");
 }
 
+#[actix_rt::test]
+pub async fn drain() {
+    let app_state = AppState {
+        db: hello_again_db(DUMMY_UAID).into_boxed_arc(),
+        ..Default::default()
+    };
+    let mut srv = test_server(app_state.clone());
+    let router_srv = actix_test::start({
+        let app_state = app_state.clone();
+        move || build_app!(app_state, config_router)
+    });
+
+    let mut framed = srv.ws().await.unwrap();
+    framed
+        .send(ws::Message::Text(HELLO_AGAIN.into()))
+        .await
+        .unwrap();
+    let msg = json_msg(&mut framed).await;
+    assert_eq!(msg["messageType"], "hello");
+
+    let response = srv.get("/__lbheartbeat__").send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::OK);
+
+    let response = router_srv.post("/__drain__").send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::OK);
+
+    let mut response = srv.get("/__lbheartbeat__").send().await.unwrap();
+    assert_eq!(
+        response.status(),
+        actix_http::StatusCode::SERVICE_UNAVAILABLE
+    );
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "DRAINING");
+
+    // The existing client's still delivered to
+    let notif = Notification {
+        channel_id: DUMMY_CHID,
+        version: "foo".to_owned(),
+        data: Some("bar".to_owned()),
+        ..Notification::default()
+    };
+    let response = router_srv
+        .put(format!("/push/{}", DUMMY_UAID))
+        .send_json(&notif)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::OK);
+    let msg = json_msg(&mut framed).await;
+    assert_eq!(msg["messageType"], "notification");
+    assert_eq!(msg["data"], "bar");
+}
+
+#[actix_rt::test]
+pub async fn compressed_push() {
+    let app_state = AppState {
+        db: hello_again_db(DUMMY_UAID).into_boxed_arc(),
+        ..Default::default()
+    };
+    let mut srv = test_server(app_state.clone());
+    let router_srv = actix_test::start({
+        let app_state = app_state.clone();
+        move || build_app!(app_state, config_router)
+    });
+
+    let mut framed = srv.ws().await.unwrap();
+    framed
+        .send(ws::Message::Text(HELLO_AGAIN.into()))
+        .await
+        .unwrap();
+    let msg = json_msg(&mut framed).await;
+    assert_eq!(msg["messageType"], "hello");
+
+    let notif = Notification {
+        channel_id: DUMMY_CHID,
+        version: "foo".to_owned(),
+        data: Some("bar".to_owned()),
+        ..Notification::default()
+    };
+    let body = zstd::encode_all(
+        serde_json::to_vec(&notif).unwrap().as_slice(),
+        zstd::DEFAULT_COMPRESSION_LEVEL,
+    )
+    .unwrap();
+    let response = router_srv
+        .put(format!("/push/{}", DUMMY_UAID))
+        .insert_header(("Content-Type", "application/json"))
+        .insert_header(("Content-Encoding", "zstd"))
+        .send_body(body)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::OK);
+
+    let msg = json_msg(&mut framed).await;
+    assert_eq!(msg["messageType"], "notification");
+    let mut expected = serde_json::to_value(&notif).unwrap();
+    expected["messageType"] = "notification".into();
+    assert_eq!(msg, expected);
+}
+
+/// Notifications are delivered whether forwarded as JSON or MessagePack
+#[actix_rt::test]
+pub async fn push_encodings() {
+    let app_state = AppState {
+        db: hello_again_db(DUMMY_UAID).into_boxed_arc(),
+        ..Default::default()
+    };
+    let mut srv = test_server(app_state.clone());
+    let router_srv = actix_test::start({
+        let app_state = app_state.clone();
+        move || build_app!(app_state, config_router)
+    });
+
+    let mut framed = srv.ws().await.unwrap();
+    framed
+        .send(ws::Message::Text(HELLO_AGAIN.into()))
+        .await
+        .unwrap();
+    let msg = json_msg(&mut framed).await;
+    assert_eq!(msg["messageType"], "hello");
+
+    let notif = Notification {
+        channel_id: DUMMY_CHID,
+        version: "foo".to_owned(),
+        data: Some("bar".to_owned()),
+        headers: Some([("encoding".to_owned(), "aes128gcm".to_owned())].into()),
+        ..Notification::default()
+    };
+    // Encoded as autoendpoint does: from its JSON representation
+    let value = serde_json::to_value(&notif).unwrap();
+    for (content_type, body) in [
+        ("application/json", serde_json::to_vec(&value).unwrap()),
+        ("application/msgpack", rmp_serde::to_vec(&value).unwrap()),
+    ] {
+        let response = router_srv
+            .put(format!("/push/{}", DUMMY_UAID))
+            .insert_header(("Content-Type", content_type))
+            .send_body(body)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            actix_http::StatusCode::OK,
+            "{content_type}"
+        );
+
+        let msg = json_msg(&mut framed).await;
+        let mut expected = value.clone();
+        expected["messageType"] = "notification".into();
+        assert_eq!(msg, expected, "{content_type}");
+    }
+
+    let response = router_srv
+        .put(format!("/push/{}", DUMMY_UAID))
+        .insert_header(("Content-Type", "text/plain"))
+        .send_body("foo")
+        .await
+        .unwrap();
+    assert_eq!(
+        response.status(),
+        actix_http::StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+}
+
 #[actix_rt::test]
 pub async fn broadcast_after_ping() {
     let settings = Settings {
@@ -229,3 +399,269 @@ pub async fn broadcast_after_ping() {
         .expect("!broadcasts.is_object()");
     assert_eq!(broadcasts["foo/bar"].as_str(), Some("v2"));
 }
+
+#[actix_rt::test]
+pub async fn max_connections_rejected() {
+    let (rx, sink) = SpyMetricSink::new();
+    let mut srv = test_server(AppState {
+        metrics: Arc::new(StatsdClient::builder("", sink).build()),
+        ..AppState::from_settings(Settings {
+            max_worker_connections: Some(1),
+            ..Settings::test_settings()
+        })
+        .unwrap()
+    });
+
+    let _framed = srv.ws().await.unwrap();
+    let response = srv.get("/").send().await.unwrap();
+    assert_eq!(
+        response.status(),
+        actix_http::StatusCode::SERVICE_UNAVAILABLE
+    );
+    let rejected: Vec<_> = rx
+        .try_iter()
+        .map(|m| String::from_utf8(m).unwrap())
+        .filter(|m| m.starts_with("ua.connection.rejected"))
+        .collect();
+    assert_eq!(
+        rejected,
+        vec!["ua.connection.rejected:1|c|#reason:max_connections"]
+    );
+}
+
+#[actix_rt::test]
+pub async fn disallowed_origin_forbidden() {
+    let mut srv = test_server(
+        AppState::from_settings(Settings {
+            allowed_origins: "[https://example.com]".to_owned(),
+            ..Settings::test_settings()
+        })
+        .unwrap(),
+    );
+
+    let response = srv
+        .get("/")
+        .insert_header(("Origin", "https://example.org"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::FORBIDDEN);
+    // An allowed origin proceeds to the WebSocket handshake
+    let response = srv
+        .get("/")
+        .insert_header(("Origin", "https://example.com"))
+        .send()
+        .await
+        .unwrap();
+    assert_ne!(response.status(), actix_http::StatusCode::FORBIDDEN);
+}
+
+/// Send a WebSocket handshake request, with only the given `User-Agent`
+async fn handshake_status(srv: &TestServer, user_agent: Option<&str>) -> actix_http::StatusCode {
+    let mut req = srv
+        .get("/")
+        .no_default_headers()
+        .insert_header(("Connection", "upgrade"))
+        .insert_header(("Upgrade", "websocket"))
+        .insert_header(("Sec-WebSocket-Version", "13"))
+        .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="));
+    if let Some(user_agent) = user_agent {
+        req = req.insert_header(("User-Agent", user_agent));
+    }
+    req.send().await.unwrap().status()
+}
+
+#[actix_rt::test]
+pub async fn missing_user_agent() {
+    // Allowed by default
+    let srv = test_server(AppState::from_settings(Settings::test_settings()).unwrap());
+    assert_eq!(
+        handshake_status(&srv, None).await,
+        actix_http::StatusCode::SWITCHING_PROTOCOLS
+    );
+    assert_eq!(
+        handshake_status(&srv, Some("Mozilla/5.0")).await,
+        actix_http::StatusCode::SWITCHING_PROTOCOLS
+    );
+
+    let (rx, sink) = SpyMetricSink::new();
+    let srv = test_server(AppState {
+        metrics: Arc::new(StatsdClient::builder("", sink).build()),
+        ..AppState::from_settings(Settings {
+            require_user_agent: true,
+            ..Settings::test_settings()
+        })
+        .unwrap()
+    });
+    assert_eq!(
+        handshake_status(&srv, None).await,
+        actix_http::StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        handshake_status(&srv, Some("Mozilla/5.0")).await,
+        actix_http::StatusCode::SWITCHING_PROTOCOLS
+    );
+    let rejected: Vec<_> = rx
+        .try_iter()
+        .map(|m| String::from_utf8(m).unwrap())
+        .filter(|m| m.starts_with("ua.connection.rejected"))
+        .collect();
+    assert_eq!(
+        rejected,
+        vec!["ua.connection.rejected:1|c|#reason:no_user_agent"]
+    );
+}
+
+#[actix_rt::test]
+pub async fn debug_user() {
+    let mut db = MockDbClient::new();
+    db.expect_get_user_with_channels()
+        .times(1)
+        .return_once(|_| {
+            Ok(Some((
+                User {
+                    uaid: DUMMY_UAID,
+                    router_type: "fcm".to_owned(),
+                    router_data: Some([("token".to_owned(), json!("secret-fcm-token"))].into()),
+                    ..Default::default()
+                },
+                [DUMMY_CHID].into(),
+            )))
+        });
+    db.expect_pending_message_count()
+        .times(1)
+        .return_once(|_| Ok(3));
+    let app_state = AppState {
+        db: db.into_boxed_arc(),
+        ..AppState::from_settings(Settings {
+            debug_endpoints_enabled: true,
+            debug_endpoints_token: Some("sekrit".to_owned()),
+            ..Settings::test_settings()
+        })
+        .unwrap()
+    };
+    let srv = actix_test::start(move || build_app!(app_state, config_router));
+    let path = format!("/debug/user/{}", DUMMY_UAID);
+
+    let response = srv.get(&path).send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::UNAUTHORIZED);
+    let response = srv
+        .get(&path)
+        .insert_header(("Authorization", "Bearer sekrit-but-wrong"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::UNAUTHORIZED);
+
+    let mut response = srv
+        .get(&path)
+        .insert_header(("Authorization", "Bearer sekrit"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::OK);
+    let body = response.body().await.unwrap();
+    assert!(!String::from_utf8(body.to_vec())
+        .unwrap()
+        .contains("secret-fcm-token"));
+    let user: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(user["uaid"], DUMMY_UAID.as_simple().to_string());
+    assert_eq!(
+        user["channels"],
+        json!([DUMMY_CHID.as_hyphenated().to_string()])
+    );
+    assert_eq!(user["pending_messages"], 3);
+    assert!(user["connected_at"].is_u64());
+    assert_eq!(user["router_type"], "fcm");
+    assert_eq!(user["router_data"], "[redacted]");
+}
+
+#[actix_rt::test]
+pub async fn debug_user_disabled() {
+    let app_state = AppState::from_settings(Settings::test_settings()).unwrap();
+    let srv = actix_test::start(move || build_app!(app_state, config_router));
+
+    let response = srv
+        .get(format!("/debug/user/{}", DUMMY_UAID))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+pub async fn debug_settings() {
+    let app_state = AppState::from_settings(Settings {
+        debug_endpoints_enabled: true,
+        debug_endpoints_token: Some("sekrit".to_owned()),
+        megaphone_api_token: Some("megasekrit".to_owned()),
+        ..Settings::test_settings()
+    })
+    .unwrap();
+    let crypto_key = app_state.settings.crypto_key.clone();
+    let srv = actix_test::start(move || build_app!(app_state, config_router));
+
+    let response = srv.get("/__settings__").send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::UNAUTHORIZED);
+    let response = srv
+        .get("/__settings__")
+        .insert_header(("Authorization", "Bearer sekri"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::UNAUTHORIZED);
+
+    let mut response = srv
+        .get("/__settings__")
+        .insert_header(("Authorization", "Bearer sekrit"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::OK);
+    let body = response.body().await.unwrap();
+    let settings: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(settings["debug_endpoints_enabled"].as_bool().unwrap());
+    for secret in [
+        "crypto_key",
+        "debug_endpoints_token",
+        "megaphone_api_token",
+        "db_dsn",
+    ] {
+        assert_eq!(settings[secret], "[redacted]", "{secret} not redacted");
+    }
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    for secret in [crypto_key.as_str(), "sekrit", "megasekrit", "grpc://"] {
+        assert!(!body.contains(secret), "{secret} leaked");
+    }
+    // Durations are readable
+    assert_eq!(settings["auto_ping_timeout"], "4s");
+}
+
+#[actix_rt::test]
+pub async fn debug_settings_disabled() {
+    let app_state = AppState::from_settings(Settings::test_settings()).unwrap();
+    let srv = actix_test::start(move || build_app!(app_state, config_router));
+
+    let response = srv.get("/__settings__").send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+pub async fn debug_settings_without_token() {
+    // Rejected by Settings::validate but refused by the route regardless
+    let app_state = AppState::from_settings(Settings {
+        debug_endpoints_enabled: true,
+        ..Settings::test_settings()
+    })
+    .unwrap();
+    let srv = actix_test::start(move || build_app!(app_state, config_router));
+
+    for auth in [None, Some("Bearer "), Some("Bearer None")] {
+        let mut req = srv.get("/__settings__");
+        if let Some(auth) = auth {
+            req = req.insert_header(("Authorization", auth));
+        }
+        let response = req.send().await.unwrap();
+        assert_eq!(response.status(), actix_http::StatusCode::UNAUTHORIZED);
+    }
+}
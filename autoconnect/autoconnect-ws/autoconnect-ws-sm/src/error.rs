@@ -1,8 +1,9 @@
-use std::{error::Error, fmt};
+use std::{error::Error, fmt, time::Duration};
 
 use actix_ws::CloseCode;
 use backtrace::Backtrace;
 
+use autoconnect_common::protocol::ServerMessage;
 use autopush_common::{db::error::DbError, errors::ApcError, errors::ReportableError};
 
 /// WebSocket state machine errors
@@ -41,10 +42,24 @@ impl SMError {
     pub fn close_code(&self) -> actix_ws::CloseCode {
         match self.kind {
             SMErrorKind::UaidReset => CloseCode::Normal,
+            SMErrorKind::Overloaded(_) | SMErrorKind::Draining(_) => CloseCode::Again,
             _ => CloseCode::Error,
         }
     }
 
+    /// Return a `ServerMessage` advising the Client when to reconnect, to be
+    /// sent before closing the connection
+    pub fn reconnect_message(&self) -> Option<ServerMessage> {
+        match self.kind {
+            SMErrorKind::Overloaded(delay) | SMErrorKind::Draining(delay) => {
+                Some(ServerMessage::Reconnect {
+                    reconnect_after_secs: delay.as_secs(),
+                })
+            }
+            _ => None,
+        }
+    }
+
     pub fn invalid_message(description: String) -> Self {
         SMErrorKind::InvalidMessage(description).into()
     }
@@ -102,8 +117,17 @@ pub enum SMErrorKind {
     #[error("Failed to generate endpoint: {0}")]
     MakeEndpoint(#[source] ApcError),
 
+    #[error("Client has registered the max number of channels: {0}")]
+    TooManyChannels(usize),
+
     #[error("Client sent too many pings too often")]
     ExcessivePing,
+
+    #[error("Node is overloaded, reconnect after {0:?}")]
+    Overloaded(Duration),
+
+    #[error("Node is draining, reconnect after {0:?}")]
+    Draining(Duration),
 }
 
 impl SMErrorKind {
@@ -1,13 +1,14 @@
-use std::{fmt, mem, sync::Arc};
+use std::{collections::VecDeque, fmt, mem, sync::Arc};
 
 use actix_web::rt;
-use cadence::Timed;
+use cadence::{StatsdClient, Timed};
 use futures::channel::mpsc;
+use tokio::sync::Semaphore;
 use uuid::Uuid;
 
 use autoconnect_common::{
     broadcast::{Broadcast, BroadcastSubs},
-    protocol::{ServerMessage, ServerNotification},
+    protocol::{ProtocolVersion, ServerMessage, ServerNotification},
 };
 
 use autoconnect_settings::{AppState, Settings};
@@ -39,6 +40,8 @@ pub struct WebPushClient {
     pub uid: Uuid,
     /// The User Agent information block derived from the User-Agent header
     pub ua_info: UserAgentInfo,
+    /// The negotiated WebPush protocol version
+    pub protocol_version: ProtocolVersion,
 
     /// Broadcast Subscriptions this Client is subscribed to
     broadcast_subs: BroadcastSubs,
@@ -65,6 +68,12 @@ pub struct WebPushClient {
     /// The last notification timestamp.
     // TODO: RENAME THIS TO `last_notification_timestamp`
     current_timestamp: Option<u64>,
+    /// The User record's `version` read on Hello, guarding
+    /// `increment_storage` writes
+    version: Option<Uuid>,
+    /// Bounds this Client's storage fetches in flight to
+    /// `settings.max_concurrent_fetches` (`None` when unbounded)
+    fetch_permits: Option<Arc<Semaphore>>,
 
     app_state: Arc<AppState>,
 }
@@ -75,6 +84,7 @@ impl fmt::Debug for WebPushClient {
             .field("uaid", &self.uaid)
             .field("uid", &self.uid)
             .field("ua_info", &self.ua_info)
+            .field("protocol_version", &self.protocol_version)
             .field("broadcast_subs", &self.broadcast_subs)
             .field("flags", &self.flags)
             .field("ack_state", &self.ack_state)
@@ -96,6 +106,7 @@ impl WebPushClient {
         flags: ClientFlags,
         connected_at: u64,
         current_timestamp: Option<u64>,
+        version: Option<Uuid>,
         deferred_add_user: Option<User>,
         app_state: Arc<AppState>,
     ) -> Result<(Self, Vec<ServerMessage>), SMError> {
@@ -108,15 +119,19 @@ impl WebPushClient {
             uaid,
             uid: Uuid::new_v4(),
             ua_info: UserAgentInfo::from(ua.as_str()),
+            protocol_version: Default::default(),
             broadcast_subs,
             flags,
             ack_state: Default::default(),
             sent_from_storage: Default::default(),
             connected_at,
             current_timestamp,
+            version,
             deferred_add_user,
             last_ping: Default::default(),
             stats,
+            fetch_permits: (app_state.settings.max_concurrent_fetches > 0)
+                .then(|| Arc::new(Semaphore::new(app_state.settings.max_concurrent_fetches))),
             app_state,
         };
 
@@ -138,6 +153,17 @@ impl WebPushClient {
         &self.app_state.settings
     }
 
+    /// Whether this Client negotiated `ServerMessage::AppPing`s in place of
+    /// WebSocket Pings
+    pub fn app_ping(&self) -> bool {
+        self.flags.app_ping
+    }
+
+    /// Return a reference to `AppState`'s metrics client
+    pub fn app_metrics(&self) -> &StatsdClient {
+        &self.app_state.metrics
+    }
+
     /// Connect this `WebPushClient` to the `ClientRegistry`
     ///
     /// Returning a `Stream` of `ServerNotification`s from the `ClientRegistry`
@@ -204,12 +230,38 @@ impl WebPushClient {
         );
     }
 
+    /// Record why this session ended on the user record (when
+    /// `track_disconnect_reason` is enabled)
+    pub fn record_disconnect(&self, reason: &'static str) {
+        // Deferred users were never written to the db
+        if !self.app_state.settings.track_disconnect_reason || self.deferred_add_user.is_some() {
+            return;
+        }
+        let app_state = Arc::clone(&self.app_state);
+        let uaid = self.uaid;
+        rt::spawn(async move {
+            if let Err(e) = app_state
+                .db
+                .set_last_disconnect(&uaid, reason, ms_since_epoch())
+                .await
+            {
+                warn!("👁‍🗨WebPushClient::record_disconnect failed: {}", e);
+            }
+        });
+    }
+
     /// Save any Direct unAck'd messages to the db (on shutdown)
     ///
     /// Direct messages are solely stored in memory until Ack'd by the Client,
     /// so on shutdown, any not Ack'd are stored in the db to not be lost
     fn save_and_notify_unacked_direct_notifs(&mut self) {
         let mut notifs = mem::take(&mut self.ack_state.unacked_direct_notifs);
+        // Paused notifications were never delivered
+        notifs.extend(
+            mem::take(&mut self.ack_state.paused_direct_notifs)
+                .into_iter()
+                .filter(|notif| notif.ttl != 0),
+        );
         trace!(
             "👁‍🗨WebPushClient::save_and_notify_unacked_direct_notifs len: {}",
             notifs.len()
@@ -291,6 +343,15 @@ pub struct ClientFlags {
     pub old_record_version: bool,
     /// First time a user has connected "today"
     pub emit_channel_metrics: bool,
+    /// A new connection for this UAID has arrived: disconnect once all
+    /// in-flight notifications are Ack'd
+    pub ghost_pending: bool,
+    /// Deliver stored notifications in `ServerMessage::NotificationBatch`
+    /// frames (negotiated via the Hello's `features`)
+    pub notification_batch: bool,
+    /// Ping via `ServerMessage::AppPing` rather than WebSocket Pings
+    /// (negotiated via the Hello's `features`)
+    pub app_ping: bool,
 }
 
 impl Default for ClientFlags {
@@ -301,6 +362,9 @@ impl Default for ClientFlags {
             check_storage: false,
             old_record_version: false,
             emit_channel_metrics: false,
+            ghost_pending: false,
+            notification_batch: false,
+            app_ping: false,
         }
     }
 }
@@ -336,6 +400,10 @@ struct AckState {
     unacked_direct_notifs: Vec<Notification>,
     /// List of unAck'd sent notifications from storage
     unacked_stored_notifs: Vec<Notification>,
+    /// Direct notifications awaiting delivery while the Client has
+    /// `settings.max_unacked` notifications outstanding (holding at most
+    /// that many, the overflow's stored)
+    paused_direct_notifs: VecDeque<Notification>,
     /// Either the `current_timestamp` value in storage (returned from
     /// `fetch_messages`) or the last unAck'd timestamp Message's
     /// `sortkey_timestamp` (returned from `fetch_timestamp_messages`).
@@ -363,26 +431,35 @@ impl AckState {
     fn unacked_notifs(&self) -> bool {
         !self.unacked_stored_notifs.is_empty() || !self.unacked_direct_notifs.is_empty()
     }
+
+    /// The number of notifications sent to the Client that it has yet to Ack
+    fn unacked_count(&self) -> usize {
+        self.unacked_stored_notifs.len() + self.unacked_direct_notifs.len()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{sync::Arc, time::Duration};
 
     use uuid::Uuid;
 
     use autoconnect_common::{
-        protocol::{ClientMessage, ServerMessage, ServerNotification},
+        broadcast::MISSING_BROADCASTS_OVERFLOW,
+        protocol::{BroadcastValue, ClientAck, ClientMessage, ServerMessage, ServerNotification},
         test_support::{DUMMY_CHID, DUMMY_UAID, UA},
     };
-    use autoconnect_settings::AppState;
+    use autoconnect_settings::{AppState, DuplicateHelloPolicy, Settings};
     use autopush_common::{
-        db::{client::FetchMessageResponse, mock::MockDbClient},
+        db::{client::FetchMessageResponse, mock::MockDbClient, User},
         notification::Notification,
         util::{ms_since_epoch, sec_since_epoch},
     };
 
     use super::WebPushClient;
+    use crate::error::SMErrorKind;
+
+    const DUMMY_VERSION: Uuid = Uuid::from_u128(0xdeadbeef);
 
     async fn wpclient(uaid: Uuid, app_state: AppState) -> (WebPushClient, Vec<ServerMessage>) {
         WebPushClient::new(
@@ -392,6 +469,7 @@ mod tests {
             Default::default(),
             ms_since_epoch(),
             None,
+            Some(DUMMY_VERSION),
             None,
             Arc::new(app_state),
         )
@@ -417,6 +495,108 @@ mod tests {
         assert!(matches!(pong.as_slice(), [ServerMessage::Ping]));
     }
 
+    fn duplicate_hello() -> ClientMessage {
+        ClientMessage::Hello {
+            uaid: Some(DUMMY_UAID.as_simple().to_string()),
+            _channel_ids: None,
+            broadcasts: None,
+            features: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn duplicate_hello_close() {
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                metrics: Arc::new(cadence::StatsdClient::from_sink("autopush", sink)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let err = client.on_client_msg(duplicate_hello()).await.unwrap_err();
+        assert!(matches!(err.kind, SMErrorKind::InvalidMessage(_)));
+        assert!(rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .any(|m| m == "autopush.ua.command.hello.duplicate:1|c|#policy:close"));
+    }
+
+    #[actix_rt::test]
+    async fn duplicate_hello_ignore() {
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                settings: Settings {
+                    duplicate_hello_policy: DuplicateHelloPolicy::Ignore,
+                    ..Default::default()
+                },
+                metrics: Arc::new(cadence::StatsdClient::from_sink("autopush", sink)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let smsgs = client.on_client_msg(duplicate_hello()).await.unwrap();
+        assert!(smsgs.is_empty());
+        assert!(rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .any(|m| m == "autopush.ua.command.hello.duplicate:1|c|#policy:ignore"));
+        // The connection remains usable
+        let pong = client.on_client_msg(ClientMessage::Ping).await.unwrap();
+        assert!(matches!(pong.as_slice(), [ServerMessage::Ping]));
+    }
+
+    #[actix_rt::test]
+    async fn check_storage_fetches_bounded() {
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .returning(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .returning(|_, _, _| Ok(Default::default()));
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                settings: Settings {
+                    max_concurrent_fetches: 2,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+        let permits = client.fetch_permits.clone().unwrap();
+
+        // Another fetch is in flight: below the bound
+        let first = permits.acquire().await.unwrap();
+        client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .expect("CheckStorage failed");
+
+        // At the bound: the fetch waits for an outstanding one to finish
+        let second = permits.acquire().await.unwrap();
+        let check_storage = client.on_server_notif(ServerNotification::CheckStorage);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), check_storage)
+                .await
+                .is_err()
+        );
+
+        drop(first);
+        client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .expect("CheckStorage failed");
+        drop(second);
+        assert_eq!(permits.available_permits(), 2);
+    }
+
     #[actix_rt::test]
     async fn expired_increments_storage() {
         let mut db = MockDbClient::new();
@@ -463,8 +643,8 @@ mod tests {
         db.expect_increment_storage()
             .times(1)
             .in_sequence(&mut seq)
-            .withf(move |_, ts| ts == &timestamp)
-            .return_once(|_, _| Ok(()));
+            .withf(move |_, ts, version| ts == &timestamp && version == &DUMMY_VERSION)
+            .return_once(|_, _, _| Ok(true));
 
         // No check_storage called here (via default ClientFlags)
         let (mut client, _) = wpclient(
@@ -482,4 +662,747 @@ mod tests {
             .expect("CheckStorage failed");
         assert!(smsgs.is_empty())
     }
+
+    #[actix_rt::test]
+    async fn filtered_expired_increments_storage() {
+        let mut db = MockDbClient::new();
+        let mut seq = mockall::Sequence::new();
+        let timestamp = sec_since_epoch();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_, _| Ok(Default::default()));
+        // Storage read past (and filtered out) expired notifs
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .withf(move |_, ts, _| ts.is_none())
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(timestamp),
+                    messages: vec![],
+                })
+            });
+        db.expect_increment_storage()
+            .times(1)
+            .in_sequence(&mut seq)
+            .withf(move |_, ts, version| ts == &timestamp && version == &DUMMY_VERSION)
+            .return_once(|_, _, _| Ok(true));
+
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .expect("CheckStorage failed");
+        assert!(smsgs.is_empty())
+    }
+
+    #[actix_rt::test]
+    async fn increment_storage_retries_own_version() {
+        let mut db = MockDbClient::new();
+        let mut seq = mockall::Sequence::new();
+        let timestamp = sec_since_epoch();
+        let connected_at = ms_since_epoch();
+        let new_version = Uuid::new_v4();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(timestamp),
+                    messages: vec![],
+                })
+            });
+        // The version's changed (e.g. via an Unregister) but the record's
+        // still owned by this connection
+        db.expect_increment_storage()
+            .times(1)
+            .in_sequence(&mut seq)
+            .withf(|_, _, version| version == &DUMMY_VERSION)
+            .return_once(|_, _, _| Ok(false));
+        db.expect_get_user()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_| {
+                Ok(Some(User {
+                    connected_at,
+                    version: Some(new_version),
+                    ..Default::default()
+                }))
+            });
+        db.expect_increment_storage()
+            .times(1)
+            .in_sequence(&mut seq)
+            .withf(move |_, ts, version| ts == &timestamp && version == &new_version)
+            .return_once(|_, _, _| Ok(true));
+
+        let (mut client, _) = WebPushClient::new(
+            DUMMY_UAID,
+            UA.to_owned(),
+            Default::default(),
+            Default::default(),
+            connected_at,
+            None,
+            Some(DUMMY_VERSION),
+            None,
+            Arc::new(AppState {
+                db: db.into_boxed_arc(),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .expect("CheckStorage failed");
+        assert!(smsgs.is_empty());
+        assert_eq!(client.version, Some(new_version));
+    }
+
+    #[actix_rt::test]
+    async fn increment_storage_non_advancing() {
+        let mut db = MockDbClient::new();
+        let mut seq = mockall::Sequence::new();
+        let timestamp = sec_since_epoch();
+        let connected_at = ms_since_epoch();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(timestamp),
+                    messages: vec![],
+                })
+            });
+        db.expect_increment_storage()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _, _| Ok(false));
+        // The stored timestamp's already past this one: no retry
+        db.expect_get_user()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_| {
+                Ok(Some(User {
+                    connected_at,
+                    current_timestamp: Some(timestamp + 1),
+                    version: Some(DUMMY_VERSION),
+                    ..Default::default()
+                }))
+            });
+
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let (mut client, _) = WebPushClient::new(
+            DUMMY_UAID,
+            UA.to_owned(),
+            Default::default(),
+            Default::default(),
+            connected_at,
+            None,
+            Some(DUMMY_VERSION),
+            None,
+            Arc::new(AppState {
+                db: db.into_boxed_arc(),
+                metrics: Arc::new(cadence::StatsdClient::from_sink("autopush", sink)),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .expect("CheckStorage failed");
+        assert!(smsgs.is_empty());
+        let metrics: Vec<_> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(metrics
+            .iter()
+            .any(|m| m.starts_with("autopush.ua.command.increment_storage.stale:1|c")));
+    }
+
+    #[actix_rt::test]
+    async fn pending_message_count_sampled() {
+        let mut db = MockDbClient::new();
+        db.expect_pending_message_count()
+            .times(1)
+            .return_once(|_| Ok(3));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(|_, _, _| Ok(Default::default()));
+
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                metrics: Arc::new(cadence::StatsdClient::from_sink("autopush", sink)),
+                settings: Settings {
+                    pending_message_count_sample_rate: 1.0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .expect("CheckStorage failed");
+        assert!(smsgs.is_empty());
+        let sent: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(sent
+            .iter()
+            .any(|m| m.starts_with("autopush.ua.message_data.pending:3|h")));
+    }
+
+    #[actix_rt::test]
+    async fn delivery_latency_emitted() {
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(|_, _, _| {
+                // Stored 30 seconds ago
+                let mut notif = new_timestamp_notif(&DUMMY_CHID, 300);
+                notif.timestamp -= 30;
+                Ok(FetchMessageResponse {
+                    timestamp: notif.sortkey_timestamp,
+                    messages: vec![notif],
+                })
+            });
+
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                metrics: Arc::new(cadence::StatsdClient::from_sink("autopush", sink)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .expect("CheckStorage failed");
+        assert!(matches!(smsgs.as_slice(), [ServerMessage::Notification(_)]));
+        let latency = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .find_map(|m| {
+                m.strip_prefix("autopush.ua.message.delivery_latency:")
+                    .map(ToOwned::to_owned)
+            })
+            .expect("No delivery_latency metric");
+        let (value, tags) = latency.split_once("|h|#").unwrap();
+        assert!((30..35).contains(&value.parse::<u64>().unwrap()));
+        assert_eq!(tags, "topic:false,os:Mac OSX,browser:Firefox");
+    }
+
+    /// Deliver a backlog of 10 stored notifications with a
+    /// `notification_batch_size` of 4
+    async fn backlog_frames(notification_batch: bool) -> Vec<ServerMessage> {
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(|_, _, _| {
+                let messages: Vec<_> = (0..10)
+                    .map(|i| Notification {
+                        version: format!("version{i}"),
+                        ..new_timestamp_notif(&DUMMY_CHID, 300)
+                    })
+                    .collect();
+                Ok(FetchMessageResponse {
+                    timestamp: messages.last().unwrap().sortkey_timestamp,
+                    messages,
+                })
+            });
+
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                settings: Settings {
+                    notification_batch_size: 4,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+        client.flags.notification_batch = notification_batch;
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .expect("CheckStorage failed");
+        // Every notification awaits its own Ack regardless
+        assert_eq!(client.ack_state.unacked_stored_notifs.len(), 10);
+        smsgs
+    }
+
+    #[actix_rt::test]
+    async fn backlog_batched() {
+        let smsgs = backlog_frames(true).await;
+        let batch_sizes: Vec<_> = smsgs
+            .iter()
+            .map(|smsg| match smsg {
+                ServerMessage::NotificationBatch { notifications } => notifications.len(),
+                _ => panic!("Expected a NotificationBatch: {smsg:?}"),
+            })
+            .collect();
+        assert_eq!(batch_sizes, vec![4, 4, 2]);
+        let ServerMessage::NotificationBatch { notifications } = &smsgs[2] else {
+            unreachable!();
+        };
+        assert_eq!(notifications[1].version, "version9");
+
+        // Not negotiated
+        let smsgs = backlog_frames(false).await;
+        assert_eq!(smsgs.len(), 10);
+        assert!(smsgs
+            .iter()
+            .all(|smsg| matches!(smsg, ServerMessage::Notification(_))));
+    }
+
+    #[actix_rt::test]
+    async fn abandoned_after_max_delivery_attempts() {
+        let zombie = Notification {
+            version: "zombie".to_owned(),
+            delivery_attempts: 3,
+            ..new_timestamp_notif(&DUMMY_CHID, 300)
+        };
+        let live = Notification {
+            version: "live".to_owned(),
+            delivery_attempts: 2,
+            ..new_timestamp_notif(&DUMMY_CHID, 300)
+        };
+        let zombie_key = zombie.chidmessageid();
+
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        let messages = vec![zombie, live];
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: messages.last().unwrap().sortkey_timestamp,
+                    messages,
+                })
+            });
+        db.expect_remove_message()
+            .times(1)
+            .withf(move |_, sort_key| sort_key == zombie_key)
+            .return_once(|_, _| Ok(()));
+        db.expect_increment_delivery_attempts()
+            .times(1)
+            .withf(|_, msg| msg.version == "live")
+            .return_once(|_, _| Ok(true));
+
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                settings: Settings {
+                    max_delivery_attempts: 3,
+                    ..Default::default()
+                },
+                metrics: Arc::new(cadence::StatsdClient::from_sink("autopush", sink)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .expect("CheckStorage failed");
+        assert!(matches!(
+            smsgs.as_slice(),
+            [ServerMessage::Notification(notif)] if notif.version == "live"
+        ));
+        assert_eq!(client.ack_state.unacked_stored_notifs.len(), 1);
+        assert!(rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .any(|m| m == "autopush.notification.message.abandoned:1|c|#topic:false"));
+    }
+
+    #[actix_rt::test]
+    async fn broadcast_subscribe_caps_missing() {
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                settings: Settings {
+                    max_reported_missing_broadcasts: 5,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let broadcasts = (0..500)
+            .map(|i| (format!("junk{i}"), "v1".to_owned()))
+            .collect();
+        let smsgs = client
+            .on_client_msg(ClientMessage::BroadcastSubscribe { broadcasts })
+            .await
+            .unwrap();
+        let [ServerMessage::Broadcast { broadcasts }] = smsgs.as_slice() else {
+            panic!("Expected a Broadcast: {smsgs:?}");
+        };
+        let Some(BroadcastValue::Nested(errors)) = broadcasts.get("errors") else {
+            panic!("Expected errors: {broadcasts:?}");
+        };
+        assert_eq!(errors.len(), 6);
+        assert_eq!(
+            errors.get(MISSING_BROADCASTS_OVERFLOW),
+            Some(&BroadcastValue::Value("495".to_owned()))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn broadcast_subscribe_reports_all_missing() {
+        // Uncapped by default
+        assert_eq!(Settings::default().max_reported_missing_broadcasts, 0);
+        let (mut client, _) = wpclient(DUMMY_UAID, Default::default()).await;
+
+        let broadcasts = (0..500)
+            .map(|i| (format!("junk{i}"), "v1".to_owned()))
+            .collect();
+        let smsgs = client
+            .on_client_msg(ClientMessage::BroadcastSubscribe { broadcasts })
+            .await
+            .unwrap();
+        let [ServerMessage::Broadcast { broadcasts }] = smsgs.as_slice() else {
+            panic!("Expected a Broadcast: {smsgs:?}");
+        };
+        let Some(BroadcastValue::Nested(errors)) = broadcasts.get("errors") else {
+            panic!("Expected errors: {broadcasts:?}");
+        };
+        assert_eq!(errors.len(), 500);
+        assert!(!errors.contains_key(MISSING_BROADCASTS_OVERFLOW));
+    }
+
+    #[actix_rt::test]
+    async fn register_store_only() {
+        let mut db = MockDbClient::new();
+        db.expect_add_channel_with_store_only()
+            .times(1)
+            .withf(|_, channel_id, store_only| channel_id == &DUMMY_CHID && *store_only)
+            .return_once(|_, _, _| Ok(()));
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let msg: ClientMessage = format!(
+            r#"{{"messageType": "register", "channelID": "{DUMMY_CHID}", "storeOnly": true}}"#
+        )
+        .parse()
+        .unwrap();
+        let smsgs = client.on_client_msg(msg).await.unwrap();
+        assert!(matches!(
+            smsgs.as_slice(),
+            [ServerMessage::Register { status: 200, .. }]
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn register_max_channels() {
+        let existing = Uuid::new_v4();
+        let mut db = MockDbClient::new();
+        db.expect_get_channels()
+            .times(2)
+            .returning(move |_| Ok([existing, DUMMY_CHID].into()));
+        // Only the re-registration of an existing channel is written
+        db.expect_add_channel_with_store_only()
+            .times(1)
+            .withf(move |_, channel_id, store_only| channel_id == &existing && !*store_only)
+            .return_once(|_, _, _| Ok(()));
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                settings: Settings {
+                    max_channels: Some(2),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let register = |channel_id: Uuid| -> ClientMessage {
+            format!(r#"{{"messageType": "register", "channelID": "{channel_id}"}}"#)
+                .parse()
+                .unwrap()
+        };
+        let smsgs = client
+            .on_client_msg(register(Uuid::new_v4()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            smsgs.as_slice(),
+            [ServerMessage::Register { status: 409, .. }]
+        ));
+        let smsgs = client.on_client_msg(register(existing)).await.unwrap();
+        assert!(matches!(
+            smsgs.as_slice(),
+            [ServerMessage::Register { status: 200, .. }]
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn max_unacked_pauses_delivery() {
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                settings: Settings {
+                    max_unacked: 2,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let notifs: Vec<_> = (0..3)
+            .map(|i| Notification {
+                version: format!("version{i}"),
+                ..new_timestamp_notif(&DUMMY_CHID, 30)
+            })
+            .collect();
+        for notif in &notifs[..2] {
+            let smsgs = client
+                .on_server_notif(ServerNotification::Notification(notif.clone()))
+                .await
+                .unwrap();
+            assert!(matches!(smsgs.as_slice(), [ServerMessage::Notification(_)]));
+        }
+        // Delivery stalls at the cap
+        let smsgs = client
+            .on_server_notif(ServerNotification::Notification(notifs[2].clone()))
+            .await
+            .unwrap();
+        assert!(smsgs.is_empty());
+
+        // And resumes after an Ack
+        let smsgs = client
+            .on_client_msg(ClientMessage::Ack {
+                updates: vec![ClientAck {
+                    channel_id: DUMMY_CHID,
+                    version: notifs[0].version.clone(),
+                }],
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            smsgs.as_slice(),
+            [ServerMessage::Notification(notif)] if notif.version == notifs[2].version
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn max_unacked_overflow_stored() {
+        let notifs: Vec<_> = (0..4)
+            .map(|i| Notification {
+                version: format!("version{i}"),
+                ttl: if i == 3 { 0 } else { 30 },
+                ..new_timestamp_notif(&DUMMY_CHID, 30)
+            })
+            .collect();
+        let stored = notifs[2].clone();
+        let mut db = MockDbClient::new();
+        db.expect_save_message()
+            .times(1)
+            .withf(|_, notif| notif.version == "version2")
+            .return_once(|_, _| Ok(()));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: vec![stored],
+                })
+            });
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                settings: Settings {
+                    max_unacked: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::Notification(notifs[0].clone()))
+            .await
+            .unwrap();
+        assert!(matches!(smsgs.as_slice(), [ServerMessage::Notification(_)]));
+        // The first is paused, the second stored and the TTL 0 one dropped
+        for notif in &notifs[1..] {
+            let smsgs = client
+                .on_server_notif(ServerNotification::Notification(notif.clone()))
+                .await
+                .unwrap();
+            assert!(smsgs.is_empty());
+        }
+
+        let ack = |version: &str| ClientMessage::Ack {
+            updates: vec![ClientAck {
+                channel_id: DUMMY_CHID,
+                version: version.to_owned(),
+            }],
+        };
+        let smsgs = client.on_client_msg(ack("version0")).await.unwrap();
+        assert!(matches!(
+            smsgs.as_slice(),
+            [ServerMessage::Notification(notif)] if notif.version == "version1"
+        ));
+        // Read back from storage once everything's Ack'd
+        let smsgs = client.on_client_msg(ack("version1")).await.unwrap();
+        assert!(matches!(
+            smsgs.as_slice(),
+            [ServerMessage::Notification(notif)] if notif.version == "version2"
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn acked_elsewhere_resolves_direct() {
+        let (mut client, _) = wpclient(DUMMY_UAID, Default::default()).await;
+        let notif = new_timestamp_notif(&DUMMY_CHID, 30);
+        let version = notif.version.clone();
+        let smsgs = client
+            .on_server_notif(ServerNotification::Notification(notif))
+            .await
+            .unwrap();
+        assert!(matches!(smsgs.as_slice(), [ServerMessage::Notification(_)]));
+
+        // Unknown Acks are ignored
+        let smsgs = client
+            .on_server_notif(ServerNotification::Acked(vec![ClientAck {
+                channel_id: DUMMY_CHID,
+                version: "other".to_owned(),
+            }]))
+            .await
+            .unwrap();
+        assert!(smsgs.is_empty());
+        assert!(client.ack_state.unacked_notifs());
+
+        // Ack'd by another connection: nothing's left to store on shutdown
+        let smsgs = client
+            .on_server_notif(ServerNotification::Acked(vec![ClientAck {
+                channel_id: DUMMY_CHID,
+                version,
+            }]))
+            .await
+            .unwrap();
+        assert!(smsgs.is_empty());
+        assert!(!client.ack_state.unacked_notifs());
+    }
+
+    #[actix_rt::test]
+    async fn graceful_disconnect_flushes_unacked() {
+        let (mut client, _) = wpclient(DUMMY_UAID, Default::default()).await;
+        let notif = new_timestamp_notif(&DUMMY_CHID, 30);
+        let version = notif.version.clone();
+        let smsgs = client
+            .on_server_notif(ServerNotification::Notification(notif))
+            .await
+            .unwrap();
+        assert!(matches!(smsgs.as_slice(), [ServerMessage::Notification(_)]));
+
+        // A new connection arrived: wait for the outstanding Ack
+        let smsgs = client
+            .on_server_notif(ServerNotification::GracefulDisconnect)
+            .await
+            .unwrap();
+        assert!(smsgs.is_empty());
+
+        let err = client
+            .on_client_msg(ClientMessage::Ack {
+                updates: vec![ClientAck {
+                    channel_id: DUMMY_CHID,
+                    version,
+                }],
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, SMErrorKind::Ghost));
+    }
+
+    #[actix_rt::test]
+    async fn graceful_disconnect_nothing_unacked() {
+        let (mut client, _) = wpclient(DUMMY_UAID, Default::default()).await;
+        let err = client
+            .on_server_notif(ServerNotification::GracefulDisconnect)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, SMErrorKind::Ghost));
+    }
+
+    #[actix_rt::test]
+    async fn zero_ttl_dropped_on_shutdown() {
+        let (mut client, _) = wpclient(DUMMY_UAID, Default::default()).await;
+        for ttl in [0, 30] {
+            let notif = new_timestamp_notif(&DUMMY_CHID, ttl);
+            client.on_server_notif_shutdown(ServerNotification::Notification(notif));
+        }
+        // Only the notif with a TTL is saved on shutdown
+        assert!(matches!(
+            client.ack_state.unacked_direct_notifs.as_slice(),
+            [Notification { ttl: 30, .. }]
+        ));
+    }
 }
@@ -7,6 +7,7 @@ use autoconnect_common::{
     broadcast::Broadcast,
     protocol::{BroadcastValue, ClientAck, ClientMessage, ServerMessage},
 };
+use autoconnect_settings::DuplicateHelloPolicy;
 use autopush_common::{endpoint::make_endpoint, util::sec_since_epoch};
 
 use super::WebPushClient;
@@ -20,12 +21,12 @@ impl WebPushClient {
         msg: ClientMessage,
     ) -> Result<Vec<ServerMessage>, SMError> {
         match msg {
-            ClientMessage::Hello { .. } => {
-                Err(SMError::invalid_message("Already Hello'd".to_owned()))
-            }
-            ClientMessage::Register { channel_id, key } => {
-                Ok(vec![self.register(channel_id, key).await?])
-            }
+            ClientMessage::Hello { .. } | ClientMessage::Resume { .. } => self.duplicate_hello(),
+            ClientMessage::Register {
+                channel_id,
+                key,
+                store_only,
+            } => Ok(vec![self.register(channel_id, key, store_only).await?]),
             ClientMessage::Unregister { channel_id, code } => {
                 Ok(vec![self.unregister(channel_id, code).await?])
             }
@@ -39,6 +40,35 @@ impl WebPushClient {
                 Ok(vec![])
             }
             ClientMessage::Ping => Ok(vec![self.ping()?]),
+            // Handled by the `webpush_ws` handler's `PingManager`
+            ClientMessage::AppPong => Ok(vec![]),
+        }
+    }
+
+    /// Handle a second Hello (or Resume) on this already identified connection
+    /// according to the `duplicate_hello_policy` setting
+    fn duplicate_hello(&mut self) -> Result<Vec<ServerMessage>, SMError> {
+        let policy = self.app_state.settings.duplicate_hello_policy;
+        self.app_state
+            .metrics
+            .incr_with_tags("ua.command.hello.duplicate")
+            .with_tag(
+                "policy",
+                match policy {
+                    DuplicateHelloPolicy::Close => "close",
+                    DuplicateHelloPolicy::Ignore => "ignore",
+                },
+            )
+            .send();
+        match policy {
+            DuplicateHelloPolicy::Close => {
+                Err(SMError::invalid_message("Already Hello'd".to_owned()))
+            }
+            DuplicateHelloPolicy::Ignore => {
+                warn!("WebPushClient::on_client_msg: Ignoring duplicate Hello";
+                      "uaid" => &self.uaid.to_string());
+                Ok(vec![])
+            }
         }
     }
 
@@ -47,11 +77,13 @@ impl WebPushClient {
         &mut self,
         channel_id_str: String,
         key: Option<String>,
+        store_only: bool,
     ) -> Result<ServerMessage, SMError> {
         trace!("WebPushClient:register";
                "uaid" => &self.uaid.to_string(),
                "channel_id" => &channel_id_str,
                "key" => &key,
+               "store_only" => store_only,
         );
         let channel_id = Uuid::try_parse(&channel_id_str).map_err(|_| {
             SMError::invalid_message(format!("Invalid channelID: {channel_id_str}"))
@@ -62,7 +94,7 @@ impl WebPushClient {
             )));
         }
 
-        let (status, push_endpoint) = match self.do_register(&channel_id, key).await {
+        let (status, push_endpoint) = match self.do_register(&channel_id, key, store_only).await {
             Ok(endpoint) => {
                 let _ = self.app_state.metrics.incr("ua.command.register");
                 self.stats.registers += 1;
@@ -72,6 +104,18 @@ impl WebPushClient {
                 error!("WebPushClient::register make_endpoint failed: {}", msg);
                 (400, "Failed to generate endpoint".to_owned())
             }
+            Err(SMErrorKind::TooManyChannels(max_channels)) => {
+                debug!(
+                    "WebPushClient::register rejected: over {} channels",
+                    max_channels
+                );
+                self.app_state
+                    .metrics
+                    .incr_with_tags("ua.command.register.rejected")
+                    .with_tag("reason", "too_many_channels")
+                    .send();
+                (409, "".to_owned())
+            }
             Err(e) => {
                 error!("WebPushClient::register failed: {}", e);
                 (500, "".to_owned())
@@ -88,6 +132,7 @@ impl WebPushClient {
         &mut self,
         channel_id: &Uuid,
         key: Option<String>,
+        store_only: bool,
     ) -> Result<String, SMErrorKind> {
         if let Some(user) = &self.deferred_add_user {
             debug!(
@@ -104,11 +149,22 @@ impl WebPushClient {
             key.as_deref(),
             &self.app_state.endpoint_url,
             &self.app_state.fernet,
+            self.app_state.settings.endpoint_ttl,
         )
         .map_err(SMErrorKind::MakeEndpoint)?;
+        if let Some(max_channels) = self.app_state.settings.max_channels {
+            // Re-registering an existing channel's always allowed. Counted
+            // from the (possibly lagging) read replica, so the limit may be
+            // briefly exceeded
+            let channels = self.app_state.db.get_channels(&self.uaid).await?;
+            if channels.len() >= max_channels && !channels.contains(channel_id) {
+                return Err(SMErrorKind::TooManyChannels(max_channels));
+            }
+        }
+        // Re-registering also sets (or clears) the store only mark
         self.app_state
             .db
-            .add_channel(&self.uaid, channel_id)
+            .add_channel_with_store_only(&self.uaid, channel_id, store_only)
             .await?;
         Ok(endpoint)
     }
@@ -167,7 +223,10 @@ impl WebPushClient {
         if !missing.is_empty() {
             response.insert(
                 "errors".to_owned(),
-                BroadcastValue::Nested(Broadcast::vec_into_hashmap(missing)),
+                Broadcast::missing_into_errors(
+                    missing,
+                    self.app_state.settings.max_reported_missing_broadcasts,
+                ),
             );
         }
 
@@ -238,14 +297,23 @@ impl WebPushClient {
                 continue;
             };
         }
+        self.app_state
+            .clients
+            .acked(&self.uaid, &self.uid, updates)
+            .await;
+        self.post_process_acks().await
+    }
 
-        if self.ack_state.unacked_notifs() {
-            // Wait for the Client to Ack all notifications before further
-            // processing
-            Ok(vec![])
-        } else {
-            self.post_process_all_acked().await
+    /// Follow up on Ack'd notifications
+    pub(super) async fn post_process_acks(&mut self) -> Result<Vec<ServerMessage>, SMError> {
+        // Acks free room for any paused notifications
+        let mut smsgs = self.resume_paused_notifs();
+        if !self.ack_state.unacked_notifs() {
+            smsgs.extend(self.post_process_all_acked().await?);
         }
+        // Otherwise wait for the Client to Ack all notifications before
+        // further processing
+        Ok(smsgs)
     }
 
     /// Negative Acknowledgement (a Client error occurred) of one or more Push
@@ -291,6 +359,13 @@ impl WebPushClient {
     /// actions such as `reset_uaid`).
     async fn post_process_all_acked(&mut self) -> Result<Vec<ServerMessage>, SMError> {
         trace!("▶️ WebPushClient:post_process_all_acked");
+        if self.flags.ghost_pending {
+            // In-flight notifications have been flushed, now disconnect
+            if self.flags.increment_storage {
+                self.increment_storage().await?;
+            }
+            return Err(SMErrorKind::Ghost.into());
+        }
         let flags = &self.flags;
         if flags.check_storage {
             if flags.increment_storage {
@@ -1,8 +1,11 @@
-use cadence::{Counted, CountedExt};
+use cadence::{Counted, CountedExt, Histogrammed};
+use futures::future::try_join_all;
+use tokio::sync::SemaphorePermit;
 
-use autoconnect_common::protocol::{ServerMessage, ServerNotification};
+use autoconnect_common::protocol::{ClientAck, ServerMessage, ServerNotification};
+use autoconnect_settings::CHECK_STORAGE_FETCH_LIMIT;
 use autopush_common::{
-    db::CheckStorageResponse, notification::Notification, util::sec_since_epoch,
+    db::CheckStorageResponse, notification::Notification, otel, util::sec_since_epoch,
 };
 
 use super::WebPushClient;
@@ -14,6 +17,9 @@ impl WebPushClient {
     /// `ServerNotification::Disconnect` is emitted by the same autoconnect
     /// node recieving it when a User has logged into that same node twice to
     /// "Ghost" (disconnect) the first user's session for its second session.
+    /// `ServerNotification::GracefulDisconnect` is its counterpart when a
+    /// `disconnect_grace_period` is configured: the session ends once the
+    /// Client has Ack'd its in-flight notifications.
     ///
     /// Other variants are emitted by autoendpoint
     pub async fn on_server_notif(
@@ -21,30 +27,129 @@ impl WebPushClient {
         snotif: ServerNotification,
     ) -> Result<Vec<ServerMessage>, SMError> {
         match snotif {
-            ServerNotification::Notification(notif) => Ok(vec![self.notif(notif)?]),
+            ServerNotification::Notification(notif) => self.notif(notif).await,
             ServerNotification::CheckStorage => self.check_storage().await,
+            ServerNotification::GracefulDisconnect => self.graceful_disconnect(),
+            ServerNotification::Acked(updates) => self.acked_elsewhere(&updates).await,
             ServerNotification::Disconnect => Err(SMErrorKind::Ghost.into()),
         }
     }
 
+    /// Resolve notifications Ack'd by another connection for this user (with
+    /// `allow_multiple_connections`), which already removed them from
+    /// storage as needed
+    async fn acked_elsewhere(
+        &mut self,
+        updates: &[ClientAck],
+    ) -> Result<Vec<ServerMessage>, SMError> {
+        trace!("WebPushClient::acked_elsewhere");
+        let acked = |notif: &Notification| {
+            updates
+                .iter()
+                .any(|ack| ack.channel_id == notif.channel_id && ack.version == notif.version)
+        };
+        let ack_state = &mut self.ack_state;
+        let unacked = ack_state.unacked_count();
+        ack_state
+            .unacked_direct_notifs
+            .retain(|notif| !acked(notif));
+        ack_state
+            .unacked_stored_notifs
+            .retain(|notif| !acked(notif));
+        ack_state.paused_direct_notifs.retain(|notif| !acked(notif));
+        if ack_state.unacked_count() == unacked {
+            return Ok(vec![]);
+        }
+        self.post_process_acks().await
+    }
+
+    /// Begin "Ghosting" this session, deferring the disconnect until the
+    /// Client has Ack'd everything it's been sent
+    fn graceful_disconnect(&mut self) -> Result<Vec<ServerMessage>, SMError> {
+        trace!("WebPushClient::graceful_disconnect");
+        if !self.ack_state.unacked_notifs() {
+            return Err(SMErrorKind::Ghost.into());
+        }
+        // The new session picks up anything remaining in storage
+        self.flags.ghost_pending = true;
+        Ok(vec![])
+    }
+
     /// After disconnecting from the `ClientRegistry`, moves any queued Direct
     /// Push Notifications to unacked_direct_notifs (to be stored in the db on
     /// `shutdown`)
+    ///
+    /// Notifications with a TTL of 0 are deliver now or never, so they're
+    /// dropped instead
     pub fn on_server_notif_shutdown(&mut self, snotif: ServerNotification) {
         trace!("WebPushClient::on_server_notif_shutdown");
         if let ServerNotification::Notification(notif) = snotif {
+            if notif.ttl == 0 {
+                trace!("WebPushClient::on_server_notif_shutdown dropping TTL 0 notif");
+                return;
+            }
             self.ack_state.unacked_direct_notifs.push(notif);
         }
     }
 
     /// Send a Direct Push Notification to this user
-    fn notif(&mut self, notif: Notification) -> Result<ServerMessage, SMError> {
+    ///
+    /// Delivery is paused (the notification is queued) while the Client has
+    /// `settings.max_unacked` notifications outstanding. At most
+    /// `settings.max_unacked` are queued: further ones are stored instead,
+    /// read back once the Client's Ack'd everything outstanding
+    async fn notif(&mut self, mut notif: Notification) -> Result<Vec<ServerMessage>, SMError> {
+        if !self.unacked_limit_reached() {
+            return Ok(vec![self.send_direct_notif(notif)]);
+        }
+        self.emit_paused_metrics("Direct");
+        if self.ack_state.paused_direct_notifs.len() < self.app_state.settings.max_unacked {
+            trace!("WebPushClient::notif Pausing a direct notif");
+            self.ack_state.paused_direct_notifs.push_back(notif);
+            return Ok(vec![]);
+        }
+        if notif.ttl == 0 {
+            trace!("WebPushClient::notif dropping an overflowing TTL 0 notif");
+            return Ok(vec![]);
+        }
+        trace!("WebPushClient::notif Storing an overflowing direct notif");
+        // As with unacked direct notifs stored on shutdown
+        notif.sortkey_timestamp = Some(0);
+        self.app_state.db.save_message(&self.uaid, notif).await?;
+        self.stats.direct_storage += 1;
+        // Read back by post_process_all_acked
+        self.flags.check_storage = true;
+        self.flags.include_topic = true;
+        Ok(vec![])
+    }
+
+    fn send_direct_notif(&mut self, notif: Notification) -> ServerMessage {
         trace!("WebPushClient::notif Sending a direct notif");
         if notif.ttl != 0 {
             self.ack_state.unacked_direct_notifs.push(notif.clone());
         }
         self.emit_send_metrics(&notif, "Direct");
-        Ok(ServerMessage::Notification(notif))
+        ServerMessage::Notification(notif)
+    }
+
+    /// Resume delivery of paused Direct Push Notifications, up to
+    /// `settings.max_unacked` outstanding
+    pub(super) fn resume_paused_notifs(&mut self) -> Vec<ServerMessage> {
+        let mut smsgs = vec![];
+        while !self.unacked_limit_reached() {
+            let Some(notif) = self.ack_state.paused_direct_notifs.pop_front() else {
+                break;
+            };
+            smsgs.push(self.send_direct_notif(notif));
+        }
+        smsgs
+    }
+
+    /// Whether the Client has `settings.max_unacked` (when enabled)
+    /// notifications outstanding
+    fn unacked_limit_reached(&self) -> bool {
+        let max_unacked = self.app_state.settings.max_unacked;
+        max_unacked > 0 && self.ack_state.unacked_count() >= max_unacked
     }
 
     /// Top level read of Push Notifications from storage
@@ -53,11 +158,47 @@ impl WebPushClient {
     /// runs `check_storage_loop`
     pub(super) async fn check_storage(&mut self) -> Result<Vec<ServerMessage>, SMError> {
         trace!("🗄️ WebPushClient::check_storage");
+        if self.flags.ghost_pending {
+            return Ok(vec![]);
+        }
         self.flags.check_storage = true;
         self.flags.include_topic = true;
+        if self.unacked_limit_reached() {
+            // Resumed by post_process_all_acked once the Client Acks its
+            // outstanding notifications
+            debug!("🗄️ WebPushClient::check_storage paused");
+            self.emit_paused_metrics("Stored");
+            return Ok(vec![]);
+        }
+        self.sample_pending_message_count().await;
         self.check_storage_loop().await
     }
 
+    /// Emit the number of Push Notifications pending in storage for a
+    /// sampling of users
+    async fn sample_pending_message_count(&self) {
+        let sample_rate = self.app_state.settings.pending_message_count_sample_rate;
+        if sample_rate <= 0.0 || rand::random::<f64>() >= sample_rate {
+            return;
+        }
+        match self.app_state.db.pending_message_count(&self.uaid).await {
+            Ok(count) => {
+                self.app_state
+                    .metrics
+                    .histogram_with_tags("ua.message_data.pending", count as u64)
+                    .with_tag("os", &self.ua_info.metrics_os)
+                    .send();
+            }
+            Err(e) => {
+                // Only informational: don't fail the check_storage
+                warn!(
+                    "🗄️ WebPushClient::sample_pending_message_count failed: {}",
+                    e
+                );
+            }
+        }
+    }
+
     /// Loop the read of Push Notifications from storage
     ///
     /// Loops until any unexpired Push Notifications are read or there's no
@@ -103,11 +244,20 @@ impl WebPushClient {
             self.ack_state.unacked_stored_highest,
             timestamp
         );
+        let prior_timestamp = self
+            .ack_state
+            .unacked_stored_highest
+            .or(self.current_timestamp);
         self.flags.include_topic = include_topic;
         self.ack_state.unacked_stored_highest = timestamp;
 
         if messages.is_empty() {
             trace!("🗄️ WebPushClient::check_storage_advance finished");
+            // Storage may have read past expired messages it filtered out:
+            // advance the timestamp messages' "pointer" past them
+            if !include_topic && timestamp.is_some() && timestamp != prior_timestamp {
+                self.flags.increment_storage = true;
+            }
             self.flags.check_storage = false;
             self.sent_from_storage = 0;
             return Ok(vec![]);
@@ -136,25 +286,24 @@ impl WebPushClient {
         }
 
         self.flags.increment_storage = !include_topic && timestamp.is_some();
+        self.drop_abandoned(&mut messages).await?;
 
         if messages.is_empty() {
-            trace!("🗄️ WebPushClient::check_storage_advance empty response (filtered expired)");
+            trace!("🗄️ WebPushClient::check_storage_advance empty response (filtered expired/abandoned)");
             return Ok(vec![]);
         }
 
         self.ack_state
             .unacked_stored_notifs
             .extend(messages.iter().cloned());
-        let smsgs: Vec<_> = messages
-            .into_iter()
-            .inspect(|msg| {
-                trace!("🗄️ WebPushClient::check_storage_advance Sending stored");
-                self.emit_send_metrics(msg, "Stored")
-            })
-            .map(ServerMessage::Notification)
-            .collect();
+        for msg in &messages {
+            trace!("🗄️ WebPushClient::check_storage_advance Sending stored");
+            self.emit_send_metrics(msg, "Stored");
+            self.emit_delivery_latency(msg, now_sec);
+        }
 
-        let count = smsgs.len() as u32;
+        let count = messages.len() as u32;
+        let smsgs = self.stored_notif_frames(messages);
         debug!(
             "🗄️ WebPushClient::check_storage_advance: sent_from_storage: {}, +{}",
             self.sent_from_storage, count
@@ -163,6 +312,86 @@ impl WebPushClient {
         Ok(smsgs)
     }
 
+    /// Drop stored notifications whose delivery has already been attempted
+    /// `settings.max_delivery_attempts` times (when enabled), recording
+    /// another attempt for the rest
+    ///
+    /// Prevents a notification the Client never Acks (e.g. one that crashes
+    /// it) from being redelivered on every connection
+    async fn drop_abandoned(&self, messages: &mut Vec<Notification>) -> Result<(), SMError> {
+        let max_attempts = self.app_state.settings.max_delivery_attempts;
+        if max_attempts == 0 {
+            return Ok(());
+        }
+        let (abandoned, deliverable): (Vec<_>, Vec<_>) = messages
+            .drain(..)
+            .partition(|msg| msg.delivery_attempts >= max_attempts);
+        // Issued concurrently: each is an independent row mutation
+        try_join_all(abandoned.iter().map(|msg| async move {
+            debug!(
+                "🗄️ WebPushClient::drop_abandoned after {} attempts: {}",
+                msg.delivery_attempts,
+                msg.chidmessageid()
+            );
+            self.app_state
+                .db
+                .remove_message(&self.uaid, &msg.chidmessageid())
+                .await?;
+            self.app_state
+                .metrics
+                .incr_with_tags("notification.message.abandoned")
+                .with_tag("topic", &msg.topic.is_some().to_string())
+                .send();
+            Ok::<_, SMError>(())
+        }))
+        .await?;
+        try_join_all(deliverable.iter().map(|msg| {
+            self.app_state
+                .db
+                .increment_delivery_attempts(&self.uaid, msg)
+        }))
+        .await?;
+        *messages = deliverable;
+        Ok(())
+    }
+
+    /// Convert stored notifications into the frames to send: individual
+    /// `Notification`s or, when negotiated, `NotificationBatch`es of up to
+    /// `settings.notification_batch_size`
+    fn stored_notif_frames(&self, messages: Vec<Notification>) -> Vec<ServerMessage> {
+        let batch_size = self.app_state.settings.notification_batch_size;
+        if !self.flags.notification_batch || batch_size < 2 {
+            return messages
+                .into_iter()
+                .map(ServerMessage::Notification)
+                .collect();
+        }
+        let mut smsgs = vec![];
+        let mut messages = messages.into_iter().peekable();
+        while messages.peek().is_some() {
+            let mut notifications: Vec<_> = messages.by_ref().take(batch_size).collect();
+            smsgs.push(if notifications.len() == 1 {
+                ServerMessage::Notification(notifications.remove(0))
+            } else {
+                ServerMessage::NotificationBatch { notifications }
+            });
+        }
+        smsgs
+    }
+
+    /// Wait for a permit to issue a storage fetch, bounding this Client's
+    /// fetches in flight to `settings.max_concurrent_fetches`
+    async fn fetch_permit(&self) -> Result<Option<SemaphorePermit<'_>>, SMError> {
+        let Some(limiter) = &self.fetch_permits else {
+            return Ok(None);
+        };
+        limiter
+            .acquire()
+            .await
+            .map(Some)
+            .map_err(|_| SMErrorKind::Internal("Fetch permits closed".to_owned()).into())
+    }
+
     /// Read a chunk (max count 10 returned) of Notifications from storage
     ///
     /// This alternates between reading Topic Notifications and Timestamp
@@ -185,11 +414,15 @@ impl WebPushClient {
         // `fetch_topic_messages()` returning a reasonable timestamp.
         let topic_resp = if self.flags.include_topic {
             trace!("🗄️ WebPushClient::do_check_storage: fetch_topic_messages");
+            let _permit = self.fetch_permit().await?;
             // Get the most recent max 11 messages.
-            self.app_state
-                .db
-                .fetch_topic_messages(&self.uaid, 11)
-                .await?
+            otel::in_span(
+                otel::CHECK_STORAGE_FETCH,
+                &self.uaid,
+                None,
+                self.app_state.db.fetch_topic_messages(&self.uaid, 11),
+            )
+            .await?
         } else {
             Default::default()
         };
@@ -226,11 +459,20 @@ impl WebPushClient {
             "🗄️ WebPushClient::do_check_storage: fetch_timestamp_messages timestamp: {:?}",
             timestamp
         );
-        let timestamp_resp = self
-            .app_state
-            .db
-            .fetch_timestamp_messages(&self.uaid, timestamp, 10)
-            .await?;
+        let timestamp_resp = {
+            let _permit = self.fetch_permit().await?;
+            otel::in_span(
+                otel::CHECK_STORAGE_FETCH,
+                &self.uaid,
+                None,
+                self.app_state.db.fetch_timestamp_messages(
+                    &self.uaid,
+                    timestamp,
+                    CHECK_STORAGE_FETCH_LIMIT,
+                ),
+            )
+            .await?
+        };
         if !timestamp_resp.messages.is_empty() {
             trace!(
                 "🗄️ WebPushClient::do_check_storage: Timestamp message returns: {:#?}",
@@ -271,11 +513,61 @@ impl WebPushClient {
             )
             .into());
         };
+        let Some(version) = self.version else {
+            return Err(SMErrorKind::Internal("increment_storage w/ no version".to_owned()).into());
+        };
         self.current_timestamp = Some(timestamp);
-        self.app_state
+        let mut updated = self
+            .app_state
             .db
-            .increment_storage(&self.uaid, timestamp)
+            .increment_storage(&self.uaid, timestamp, &version)
             .await?;
+        if !updated {
+            // Either the stored timestamp's already past this one or the
+            // record's changed since Hello
+            if let Some(user) = self.app_state.db.get_user(&self.uaid).await? {
+                if user.current_timestamp.is_some_and(|ts| ts >= timestamp) {
+                    // An out of order update: the stored timestamp's already
+                    // past it
+                    debug!(
+                        "🗄️ WebPushClient::increment_storage ignored non-advancing timestamp: {}",
+                        timestamp
+                    );
+                    self.app_state
+                        .metrics
+                        .incr_with_tags("ua.command.increment_storage.stale")
+                        .send();
+                    self.flags.increment_storage = false;
+                    return Ok(());
+                }
+                // When the record's still owned by this connection (e.g. an
+                // Unregister generated a new version) retry at its current
+                // version. A newer connection's Hello changes `connected_at`
+                if let Some(version) = user
+                    .version
+                    .filter(|_| user.connected_at == self.connected_at)
+                {
+                    self.version = Some(version);
+                    updated = self
+                        .app_state
+                        .db
+                        .increment_storage(&self.uaid, timestamp, &version)
+                        .await?;
+                }
+            }
+        }
+        if !updated {
+            // Another connection's updated the record: it owns the read
+            // position now
+            debug!(
+                "🗄️ WebPushClient::increment_storage ignored for superseded version: {}",
+                version
+            );
+            self.app_state
+                .metrics
+                .incr_with_tags("ua.command.increment_storage.superseded")
+                .send();
+        }
         self.flags.increment_storage = false;
         Ok(())
     }
@@ -305,6 +597,15 @@ impl WebPushClient {
         Ok(())
     }
 
+    /// Emit metrics for delivery to the user being paused
+    fn emit_paused_metrics(&self, source: &'static str) {
+        self.app_state
+            .metrics
+            .incr_with_tags("ua.notification.paused")
+            .with_tag("source", source)
+            .send();
+    }
+
     /// Emit metrics for a Notification to be sent to the user
     fn emit_send_metrics(&self, notif: &Notification, source: &'static str) {
         let metrics = &self.app_state.metrics;
@@ -314,6 +615,7 @@ impl WebPushClient {
             .with_tag("source", source)
             .with_tag("topic", &notif.topic.is_some().to_string())
             .with_tag("os", &ua_info.metrics_os)
+            .with_tag("browser", &ua_info.metrics_browser)
             // TODO: include `internal` if meta is set
             .send();
         metrics
@@ -323,6 +625,22 @@ impl WebPushClient {
             )
             .with_tag("source", source)
             .with_tag("os", &ua_info.metrics_os)
+            .with_tag("browser", &ua_info.metrics_browser)
+            .send();
+    }
+
+    /// Emit how long (in seconds) a stored notification waited in storage
+    /// before being delivered
+    fn emit_delivery_latency(&self, notif: &Notification, now_sec: u64) {
+        self.app_state
+            .metrics
+            .histogram_with_tags(
+                "ua.message.delivery_latency",
+                now_sec.saturating_sub(notif.timestamp),
+            )
+            .with_tag("topic", &notif.topic.is_some().to_string())
+            .with_tag("os", &self.ua_info.metrics_os)
+            .with_tag("browser", &self.ua_info.metrics_browser)
             .send();
     }
 }
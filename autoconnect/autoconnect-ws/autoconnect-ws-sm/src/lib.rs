@@ -3,9 +3,10 @@ extern crate slog_scope;
 
 mod error;
 mod identified;
+mod resume;
 mod unidentified;
 
-pub use error::SMError;
+pub use error::{SMError, SMErrorKind};
 pub use identified::WebPushClient;
 pub use unidentified::UnidentifiedClient;
 
@@ -0,0 +1,129 @@
+//! Resume tokens: issued in the Hello response, letting a Client reconnect
+//! via a `ClientMessage::Resume` instead of a full Hello
+use std::time::Duration;
+
+use fernet::MultiFernet;
+use uuid::Uuid;
+
+use autopush_common::util::{ms_utc_midnight, sec_since_epoch};
+
+/// The decrypted contents of a resume token
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct ResumeToken {
+    pub uaid: Uuid,
+    /// The User record's `version` when the token was issued. A mismatch
+    /// means the record has since changed and requires a full Hello
+    pub version: Uuid,
+    /// The User's read position (`current_timestamp`) when the token was
+    /// issued, where a resumed Client's storage check begins
+    pub current_timestamp: Option<u64>,
+    /// When (in milliseconds) the full Hello that read the User record
+    /// happened: it's carried over by resumed connections
+    pub hello_at: u64,
+    /// When the token expires (a UNIX timestamp in seconds)
+    pub expiry: u64,
+}
+
+impl ResumeToken {
+    pub fn new(
+        uaid: Uuid,
+        version: Uuid,
+        current_timestamp: Option<u64>,
+        hello_at: u64,
+        ttl: Duration,
+    ) -> Self {
+        Self {
+            uaid,
+            version,
+            current_timestamp,
+            hello_at,
+            expiry: sec_since_epoch() + ttl.as_secs(),
+        }
+    }
+
+    pub fn encrypt(&self, fernet: &MultiFernet) -> String {
+        fernet.encrypt(
+            format!(
+                "{}:{}:{}:{}:{}",
+                self.uaid.as_simple(),
+                self.version.as_simple(),
+                self.current_timestamp
+                    .map(|ts| ts.to_string())
+                    .unwrap_or_default(),
+                self.hello_at,
+                self.expiry
+            )
+            .as_bytes(),
+        )
+    }
+
+    /// Decrypt and decode a token, returning `None` when it's invalid
+    pub fn decrypt(fernet: &MultiFernet, token: &str) -> Option<Self> {
+        let decrypted = String::from_utf8(fernet.decrypt(token).ok()?).ok()?;
+        let mut segments = decrypted.split(':');
+        let token = Self {
+            uaid: Uuid::try_parse(segments.next()?).ok()?,
+            version: Uuid::try_parse(segments.next()?).ok()?,
+            current_timestamp: match segments.next()? {
+                "" => None,
+                ts => Some(ts.parse().ok()?),
+            },
+            hello_at: segments.next()?.parse().ok()?,
+            expiry: segments.next()?.parse().ok()?,
+        };
+        segments.next().is_none().then_some(token)
+    }
+
+    /// Whether the token's past its expiry, or descends from a full Hello
+    /// before today: resuming skips the full Hello's rewrite of the User's
+    /// channels (refreshing their TTLs), so it's still required daily
+    pub fn is_expired(&self) -> bool {
+        self.expiry <= sec_since_epoch() || self.hello_at < ms_utc_midnight()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fernet::{Fernet, MultiFernet};
+    use uuid::Uuid;
+
+    use autopush_common::util::{ms_since_epoch, ms_utc_midnight};
+
+    use super::ResumeToken;
+
+    #[test]
+    fn round_trip() {
+        let fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+        let now = ms_since_epoch();
+        for current_timestamp in [Some(now - 1000), None] {
+            let token = ResumeToken::new(
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+                current_timestamp,
+                now,
+                Duration::from_secs(60),
+            );
+            let decrypted = ResumeToken::decrypt(&fernet, &token.encrypt(&fernet)).unwrap();
+            assert_eq!(decrypted, token);
+            assert!(!decrypted.is_expired());
+        }
+
+        let expired = ResumeToken::new(Uuid::new_v4(), Uuid::new_v4(), None, now, Duration::ZERO);
+        assert!(ResumeToken::decrypt(&fernet, &expired.encrypt(&fernet))
+            .unwrap()
+            .is_expired());
+        let yesterdays = ResumeToken::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            None,
+            ms_utc_midnight() - 1,
+            Duration::from_secs(60),
+        );
+        assert!(yesterdays.is_expired());
+
+        assert!(ResumeToken::decrypt(&fernet, "invalid").is_none());
+        assert!(ResumeToken::decrypt(&fernet, &fernet.encrypt(b"deadbeef:0")).is_none());
+    }
+}
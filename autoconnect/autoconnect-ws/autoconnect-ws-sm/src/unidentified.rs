@@ -1,11 +1,14 @@
 use std::{collections::HashMap, fmt, sync::Arc};
 
-use cadence::{CountedExt, Histogrammed};
+use cadence::{CountedExt, Histogrammed, StatsdClient};
 use uuid::Uuid;
 
 use autoconnect_common::{
     broadcast::{Broadcast, BroadcastSubs, BroadcastSubsInit},
-    protocol::{BroadcastValue, ClientMessage, ServerMessage},
+    protocol::{
+        BroadcastValue, ClientMessage, ProtocolVersion, ServerLimits, ServerMessage,
+        FEATURE_APP_PING, FEATURE_NOTIFICATION_BATCH,
+    },
 };
 use autoconnect_settings::{AppState, Settings};
 use autopush_common::{
@@ -16,6 +19,7 @@ use autopush_common::{
 use crate::{
     error::{SMError, SMErrorKind},
     identified::{ClientFlags, WebPushClient},
+    resume::ResumeToken,
 };
 
 /// Represents a Client waiting for (or yet to process) a Hello message
@@ -23,6 +27,8 @@ use crate::{
 pub struct UnidentifiedClient {
     /// Client's User-Agent header
     ua: String,
+    /// The negotiated WebPush protocol version
+    protocol_version: ProtocolVersion,
     app_state: Arc<AppState>,
 }
 
@@ -30,13 +36,24 @@ impl fmt::Debug for UnidentifiedClient {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt.debug_struct("UnidentifiedClient")
             .field("ua", &self.ua)
+            .field("protocol_version", &self.protocol_version)
             .finish()
     }
 }
 
 impl UnidentifiedClient {
     pub fn new(ua: String, app_state: Arc<AppState>) -> Self {
-        UnidentifiedClient { ua, app_state }
+        UnidentifiedClient {
+            ua,
+            protocol_version: Default::default(),
+            app_state,
+        }
+    }
+
+    /// Speak the negotiated WebPush `protocol_version`
+    pub fn with_protocol_version(mut self, protocol_version: ProtocolVersion) -> Self {
+        self.protocol_version = protocol_version;
+        self
     }
 
     /// Return a reference to `AppState`'s `Settings`
@@ -44,56 +61,119 @@ impl UnidentifiedClient {
         &self.app_state.settings
     }
 
+    /// Return a reference to `AppState`'s metrics client
+    pub fn app_metrics(&self) -> &StatsdClient {
+        &self.app_state.metrics
+    }
+
     /// Handle a WebPush `ClientMessage` sent from the user agent over the
     /// WebSocket for this user
     ///
-    /// Anything but a Hello message is rejected as an Error
+    /// Anything but a Hello (or Resume) message is rejected as an Error
     pub async fn on_client_msg(
         self,
         msg: ClientMessage,
     ) -> Result<(WebPushClient, impl IntoIterator<Item = ServerMessage>), SMError> {
         trace!("❓UnidentifiedClient::on_client_msg");
-        let ClientMessage::Hello {
-            uaid,
-            broadcasts,
-            _channel_ids,
-        } = msg
-        else {
-            return Err(SMError::invalid_message(
-                r#"Expected messageType="hello""#.to_owned(),
-            ));
+        let (original_uaid, broadcasts, features, is_resume, resume) = match msg {
+            ClientMessage::Hello {
+                uaid,
+                broadcasts,
+                features,
+                ..
+            } => {
+                debug!(
+                    "👋UnidentifiedClient::on_client_msg Hello from uaid?: {:?}",
+                    uaid
+                );
+                // Ignore invalid uaids (treat as None) so they'll be issued a new one
+                let uaid = uaid.as_deref().and_then(|uaid| Uuid::try_parse(uaid).ok());
+                (uaid, broadcasts, features, false, None)
+            }
+            ClientMessage::Resume {
+                token,
+                broadcasts,
+                features,
+            } => {
+                let resume = self
+                    .app_state
+                    .resume_fernet
+                    .as_ref()
+                    .and_then(|fernet| ResumeToken::decrypt(fernet, &token));
+                debug!(
+                    "👋UnidentifiedClient::on_client_msg Resume from uaid?: {:?}",
+                    resume.as_ref().map(|resume| resume.uaid)
+                );
+                // An invalid token falls back to a Hello without a uaid
+                let uaid = resume.as_ref().map(|resume| resume.uaid);
+                (uaid, broadcasts, features, true, resume)
+            }
+            _ => {
+                return Err(SMError::invalid_message(
+                    r#"Expected messageType="hello""#.to_owned(),
+                ))
+            }
         };
-        debug!(
-            "👋UnidentifiedClient::on_client_msg Hello from uaid?: {:?}",
-            uaid
-        );
 
-        // Ignore invalid uaids (treat as None) so they'll be issued a new one
-        let original_uaid = uaid.as_deref().and_then(|uaid| Uuid::try_parse(uaid).ok());
+        if self.app_state.is_draining() {
+            let _ = self.app_state.metrics.incr("ua.draining");
+            return Err(SMErrorKind::Draining(self.app_settings().reconnect_advice_delay).into());
+        }
+        if self.app_state.is_overloaded() {
+            let _ = self.app_state.metrics.incr("ua.overloaded");
+            return Err(SMErrorKind::Overloaded(self.app_settings().reconnect_advice_delay).into());
+        }
 
         let GetOrCreateUser {
             user,
             existing_user,
-            flags,
-        } = self.get_or_create_user(original_uaid).await?;
+            mut flags,
+            resumed,
+            hello_at,
+        } = self
+            .get_or_create_user(original_uaid, resume.as_ref())
+            .await?;
         let uaid = user.uaid;
+        let requested_features = features.unwrap_or_default();
+        let requested = |name: &str| requested_features.iter().any(|feature| feature == name);
+        flags.notification_batch = self.app_settings().notification_batch_size > 1
+            && requested(FEATURE_NOTIFICATION_BATCH);
+        flags.app_ping = self.app_settings().app_level_ping && requested(FEATURE_APP_PING);
         debug!(
-            "💬UnidentifiedClient::on_client_msg Hello! uaid: {} existing_user: {}",
-            uaid, existing_user,
+            "💬UnidentifiedClient::on_client_msg Hello! uaid: {} existing_user: {} resumed: {}",
+            uaid, existing_user, resumed,
         );
-        self.app_state
-            .metrics
-            .incr_with_tags("ua.command.hello")
-            .with_tag("uaid", {
-                if existing_user {
-                    "existing"
-                } else if original_uaid.unwrap_or(uaid) != uaid {
-                    "reassigned"
-                } else {
-                    "new"
-                }
-            })
-            .send();
+        if is_resume {
+            self.app_state
+                .metrics
+                .incr_with_tags("ua.command.resume")
+                .with_tag(
+                    "result",
+                    match &resume {
+                        _ if resumed => "success",
+                        Some(resume) if resume.is_expired() => "expired",
+                        Some(_) => "stale",
+                        None => "invalid",
+                    },
+                )
+                .send();
+        }
+        // A Resume that fell back is counted as a full Hello
+        if !resumed {
+            self.app_state
+                .metrics
+                .incr_with_tags("ua.command.hello")
+                .with_tag("uaid", {
+                    if existing_user {
+                        "existing"
+                    } else if original_uaid.unwrap_or(uaid) != uaid {
+                        "reassigned"
+                    } else {
+                        "new"
+                    }
+                })
+                .send();
+        }
 
         // This is the first time that the user has connected "today".
         if flags.emit_channel_metrics {
@@ -110,32 +190,113 @@ impl UnidentifiedClient {
         let (broadcast_subs, broadcasts) = self
             .broadcast_init(&Broadcast::from_hashmap(broadcasts.unwrap_or_default()))
             .await;
-        let (wpclient, check_storage_smsgs) = WebPushClient::new(
+        let resume_token_ttl = self.app_settings().resume_token_ttl;
+        let resume_token = self
+            .app_state
+            .resume_fernet
+            .as_ref()
+            // A record due a migration changes on this connection
+            .filter(|_| !resume_token_ttl.is_zero() && !flags.old_record_version)
+            .zip(user.version)
+            .map(|(fernet, version)| {
+                ResumeToken::new(
+                    uaid,
+                    version,
+                    user.current_timestamp,
+                    hello_at,
+                    resume_token_ttl,
+                )
+                .encrypt(fernet)
+            });
+        let features = [
+            (flags.notification_batch, FEATURE_NOTIFICATION_BATCH),
+            (flags.app_ping, FEATURE_APP_PING),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, feature)| enabled.then(|| feature.to_owned()))
+        .collect();
+        let protocol_version = self.protocol_version;
+        let (mut wpclient, check_storage_smsgs) = WebPushClient::new(
             uaid,
             self.ua,
             broadcast_subs,
             flags,
             user.connected_at,
             user.current_timestamp,
+            user.version,
             (!existing_user).then_some(user),
             self.app_state,
         )
         .await?;
+        wpclient.protocol_version = protocol_version;
 
+        let settings = wpclient.app_settings();
         let smsg = ServerMessage::Hello {
             uaid: uaid.as_simple().to_string(),
             use_webpush: true,
             status: 200,
             broadcasts,
+            limits: Some(ServerLimits {
+                max_data_bytes: settings.max_data_bytes,
+                max_channels: settings.max_channels,
+                ping_interval: settings.auto_ping_interval.as_secs(),
+            }),
+            resume_token,
+            features,
         };
         let smsgs = std::iter::once(smsg).chain(check_storage_smsgs);
         Ok((wpclient, smsgs))
     }
 
     /// Lookup a User or return a new User record if the lookup failed
-    async fn get_or_create_user(&self, uaid: Option<Uuid>) -> Result<GetOrCreateUser, SMError> {
+    ///
+    /// The connection's resumed when a valid `resume` token matches the
+    /// User's current record `version`
+    ///
+    /// A resume skips reading the User record: the token carries what the
+    /// connection needs of it, and update_user only writes when the record's
+    /// still at the token's `version`. Otherwise it falls back to a full Hello
+    async fn get_or_create_user(
+        &self,
+        uaid: Option<Uuid>,
+        resume: Option<&ResumeToken>,
+    ) -> Result<GetOrCreateUser, SMError> {
         trace!("❓UnidentifiedClient::get_or_create_user");
         let connected_at = ms_since_epoch();
+        // Without a router autoendpoint can't reach this node: leave the
+        // node_id unset so notifications are stored instead
+        let node_id = self
+            .app_settings()
+            .router_enabled
+            .then(|| self.app_state.router_url.to_owned());
+        // The region describes the node_id: it's written (or cleared) along
+        // with it
+        let node_region = node_id
+            .as_ref()
+            .and(self.app_settings().node_region.clone());
+
+        // Only when routable: clearing a previous connection's node_id
+        // requires reading it
+        if let Some((resume, node_id)) = resume
+            .filter(|resume| !resume.is_expired())
+            .zip(node_id.clone())
+        {
+            if let Some(user) = self
+                .resume_user(resume, node_id, node_region.clone(), connected_at)
+                .await?
+            {
+                return Ok(GetOrCreateUser {
+                    user,
+                    existing_user: true,
+                    flags: ClientFlags {
+                        check_storage: true,
+                        ..Default::default()
+                    },
+                    resumed: true,
+                    hello_at: resume.hello_at,
+                });
+            }
+        }
 
         if let Some(uaid) = uaid {
             if let Some(mut user) = self.app_state.db.get_user(&uaid).await? {
@@ -147,7 +308,8 @@ impl UnidentifiedClient {
                     emit_channel_metrics: user.connected_at < ms_utc_midnight(),
                     ..Default::default()
                 };
-                user.node_id = Some(self.app_state.router_url.to_owned());
+                let previous_node_id = std::mem::replace(&mut user.node_id, node_id);
+                user.node_region = node_region;
                 if user.connected_at > connected_at {
                     let _ = self.app_state.metrics.incr("ua.already_connected");
                     return Err(SMErrorKind::AlreadyConnected.into());
@@ -157,10 +319,21 @@ impl UnidentifiedClient {
                     let _ = self.app_state.metrics.incr("ua.already_connected");
                     return Err(SMErrorKind::AlreadyConnected.into());
                 }
+                if let Some(previous_node_id) = previous_node_id.filter(|_| user.node_id.is_none())
+                {
+                    // update_user only writes a set node_id: clear the
+                    // previous connection's so it's not routed to
+                    self.app_state
+                        .db
+                        .remove_node_id(&uaid, &previous_node_id, connected_at, &user.version)
+                        .await?;
+                }
                 return Ok(GetOrCreateUser {
                     user,
                     existing_user: true,
                     flags,
+                    resumed: false,
+                    hello_at: connected_at,
                 });
             }
             // NOTE: when the client's specified a uaid but get_user returns
@@ -169,18 +342,54 @@ impl UnidentifiedClient {
             // change from the previous state machine impl)
         }
 
-        let user = User::builder()
-            .node_id(self.app_state.router_url.to_owned())
+        let mut user = User::builder()
             .connected_at(connected_at)
             .build()
             .map_err(|e| SMErrorKind::Internal(format!("User::builder error: {e}")))?;
+        user.node_id = node_id;
+        user.node_region = node_region;
         Ok(GetOrCreateUser {
             user,
             existing_user: false,
             flags: Default::default(),
+            resumed: false,
+            hello_at: connected_at,
         })
     }
 
+    /// Claim the `resume` token's User record for this connection without
+    /// reading it, returning `None` when the record's changed since the
+    /// token was issued
+    ///
+    /// Only the connection's fields are written: the rest of the record
+    /// (including its read position, which may have advanced past the
+    /// token's without changing the `version`) is left intact
+    async fn resume_user(
+        &self,
+        resume: &ResumeToken,
+        node_id: String,
+        node_region: Option<String>,
+        connected_at: u64,
+    ) -> Result<Option<User>, SMError> {
+        let mut user = User::builder()
+            .uaid(resume.uaid)
+            .connected_at(connected_at)
+            .node_id(node_id)
+            .record_version(USER_RECORD_VERSION)
+            .version(resume.version)
+            .build()
+            .map_err(|e| SMErrorKind::Internal(format!("User::builder error: {e}")))?;
+        user.node_region = node_region;
+        if !self.app_state.db.update_user(&mut user).await? {
+            return Ok(None);
+        }
+        // The storage check begins at the token's read position. Any later
+        // position only passed notifications since acknowledged (and so
+        // deleted), so there's nothing to re-deliver
+        user.current_timestamp = resume.current_timestamp;
+        Ok(Some(user))
+    }
+
     /// Initialize `Broadcast`s for a new `WebPushClient`
     async fn broadcast_init(
         &self,
@@ -194,7 +403,10 @@ impl UnidentifiedClient {
         if !missing.is_empty() {
             response.insert(
                 "errors".to_owned(),
-                BroadcastValue::Nested(Broadcast::vec_into_hashmap(missing)),
+                Broadcast::missing_into_errors(
+                    missing,
+                    self.app_state.settings.max_reported_missing_broadcasts,
+                ),
             );
         }
         (broadcast_subs, response)
@@ -206,19 +418,32 @@ struct GetOrCreateUser {
     user: User,
     existing_user: bool,
     flags: ClientFlags,
+    /// Whether a `ClientMessage::Resume` was accepted
+    resumed: bool,
+    /// When (in milliseconds) the User record was last read by a full Hello
+    hello_at: u64,
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{str::FromStr, sync::Arc};
+    use std::{str::FromStr, sync::Arc, time::Duration};
 
     use autoconnect_common::{
-        protocol::ClientMessage,
+        protocol::{
+            ClientMessage, ProtocolVersion, ServerLimits, ServerMessage, FEATURE_APP_PING,
+            FEATURE_NOTIFICATION_BATCH,
+        },
         test_support::{hello_again_db, hello_db, DUMMY_CHID, DUMMY_UAID, UA},
     };
-    use autoconnect_settings::AppState;
+    use autoconnect_settings::{AppState, Settings};
+    use autopush_common::{
+        db::{mock::MockDbClient, User},
+        util::ms_since_epoch,
+    };
+    use fernet::Fernet;
+    use uuid::Uuid;
 
-    use crate::error::SMErrorKind;
+    use crate::{error::SMErrorKind, resume::ResumeToken};
 
     use super::UnidentifiedClient;
 
@@ -246,6 +471,7 @@ mod tests {
             .on_client_msg(ClientMessage::Register {
                 channel_id: DUMMY_CHID.to_string(),
                 key: None,
+                store_only: false,
             })
             .await
             .err()
@@ -289,6 +515,18 @@ mod tests {
         client.on_client_msg(msg).await.expect("Hello failed");
     }
 
+    #[tokio::test]
+    async fn hello_protocol_version() {
+        let client = uclient(AppState {
+            db: hello_db().into_boxed_arc(),
+            ..Default::default()
+        })
+        .with_protocol_version(ProtocolVersion::V2);
+        let msg = ClientMessage::from_str(r#"{"messageType":"hello"}"#).unwrap();
+        let (client, _) = client.on_client_msg(msg).await.expect("Hello failed");
+        assert_eq!(client.protocol_version, ProtocolVersion::V2);
+    }
+
     #[tokio::test]
     async fn hello_empty_uaid() {
         let client = uclient(Default::default());
@@ -296,6 +534,7 @@ mod tests {
             uaid: Some("".to_owned()),
             _channel_ids: None,
             broadcasts: None,
+            features: None,
         };
         client.on_client_msg(msg).await.expect("Hello failed");
     }
@@ -307,10 +546,361 @@ mod tests {
             uaid: Some("invalid".to_owned()),
             _channel_ids: None,
             broadcasts: None,
+            features: None,
         };
         client.on_client_msg(msg).await.expect("Hello failed");
     }
 
     #[tokio::test]
     async fn hello_bad_user() {}
+
+    #[tokio::test]
+    async fn hello_negotiates_notification_batch() {
+        for (batch_size, features) in [(10, vec![FEATURE_NOTIFICATION_BATCH]), (0, vec![])] {
+            let mut app_state = AppState {
+                db: hello_db().into_boxed_arc(),
+                ..Default::default()
+            };
+            app_state.settings.notification_batch_size = batch_size;
+            let msg = ClientMessage::Hello {
+                uaid: None,
+                _channel_ids: None,
+                broadcasts: None,
+                features: Some(vec![
+                    FEATURE_NOTIFICATION_BATCH.to_owned(),
+                    "unknown".to_owned(),
+                ]),
+            };
+            let (_, smsgs) = uclient(app_state)
+                .on_client_msg(msg)
+                .await
+                .expect("Hello failed");
+            let smsgs: Vec<_> = smsgs.into_iter().collect();
+            let Some(ServerMessage::Hello {
+                features: enabled, ..
+            }) = smsgs.first()
+            else {
+                panic!("Expected a Hello response: {smsgs:?}");
+            };
+            assert_eq!(enabled, &features);
+        }
+    }
+
+    #[tokio::test]
+    async fn hello_negotiates_app_ping() {
+        for (app_level_ping, requested, features) in [
+            (true, vec![FEATURE_APP_PING], vec![FEATURE_APP_PING]),
+            // Only Clients opting in receive AppPings
+            (true, vec![], vec![]),
+            (false, vec![FEATURE_APP_PING], vec![]),
+        ] {
+            let mut app_state = AppState {
+                db: hello_db().into_boxed_arc(),
+                ..Default::default()
+            };
+            app_state.settings.app_level_ping = app_level_ping;
+            let msg = ClientMessage::Hello {
+                uaid: None,
+                _channel_ids: None,
+                broadcasts: None,
+                features: Some(requested.into_iter().map(str::to_owned).collect()),
+            };
+            let (client, smsgs) = uclient(app_state)
+                .on_client_msg(msg)
+                .await
+                .expect("Hello failed");
+            let smsgs: Vec<_> = smsgs.into_iter().collect();
+            let Some(ServerMessage::Hello {
+                features: enabled, ..
+            }) = smsgs.first()
+            else {
+                panic!("Expected a Hello response: {smsgs:?}");
+            };
+            assert_eq!(enabled, &features);
+            assert_eq!(client.app_ping(), !features.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn hello_router_disabled() {
+        let mut db = MockDbClient::new();
+        db.expect_get_user().times(1).return_once(move |_| {
+            let user = User::builder()
+                .uaid(DUMMY_UAID)
+                .connected_at(ms_since_epoch() - (10 * 60 * 1000))
+                .node_id("https://previous-node:8081".to_owned())
+                .build()
+                .unwrap();
+            Ok(Some(user))
+        });
+        db.expect_update_user()
+            .times(1)
+            .withf(|user| user.node_id.is_none())
+            .return_once(|_| Ok(true));
+        // The previous connection's node_id is cleared
+        db.expect_remove_node_id()
+            .times(1)
+            .withf(|_, node_id, _, _| node_id == "https://previous-node:8081")
+            .return_once(|_, _, _, _| Ok(true));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(|_, _, _| Ok(Default::default()));
+
+        let mut app_state = AppState {
+            db: db.into_boxed_arc(),
+            ..Default::default()
+        };
+        app_state.settings.router_enabled = false;
+        let msg = ClientMessage::Hello {
+            uaid: Some(DUMMY_UAID.to_string()),
+            _channel_ids: None,
+            broadcasts: None,
+            features: None,
+        };
+        uclient(app_state)
+            .on_client_msg(msg)
+            .await
+            .expect("Hello failed");
+    }
+
+    /// Reconnecting to another node replaces the previous node's region
+    #[tokio::test]
+    async fn hello_node_region() {
+        let mut db = MockDbClient::new();
+        db.expect_get_user().times(1).return_once(move |_| {
+            let user = User::builder()
+                .uaid(DUMMY_UAID)
+                .connected_at(ms_since_epoch() - (10 * 60 * 1000))
+                .node_id("https://previous-node:8081".to_owned())
+                .node_region("us-east1".to_owned())
+                .build()
+                .unwrap();
+            Ok(Some(user))
+        });
+        db.expect_update_user()
+            .times(1)
+            .withf(|user| {
+                user.node_id.as_deref() != Some("https://previous-node:8081")
+                    && user.node_region.as_deref() == Some("us-west1")
+            })
+            .return_once(|_| Ok(true));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(|_, _, _| Ok(Default::default()));
+
+        let mut app_state = AppState {
+            db: db.into_boxed_arc(),
+            ..Default::default()
+        };
+        app_state.settings.node_region = Some("us-west1".to_owned());
+        let msg = ClientMessage::Hello {
+            uaid: Some(DUMMY_UAID.to_string()),
+            _channel_ids: None,
+            broadcasts: None,
+            features: None,
+        };
+        uclient(app_state)
+            .on_client_msg(msg)
+            .await
+            .expect("Hello failed");
+    }
+
+    #[tokio::test]
+    async fn hello_overloaded() {
+        let mut app_state = AppState::default();
+        app_state.settings.overload_client_limit = Some(0);
+        app_state.settings.reconnect_advice_delay = Duration::from_secs(45);
+        let client = uclient(app_state);
+        let msg = ClientMessage::Hello {
+            uaid: None,
+            _channel_ids: None,
+            broadcasts: None,
+            features: None,
+        };
+        let err = client.on_client_msg(msg).await.err().unwrap();
+        assert!(matches!(err.kind, SMErrorKind::Overloaded(_)));
+        assert_eq!(err.close_code(), actix_ws::CloseCode::Again);
+        assert!(matches!(
+            err.reconnect_message(),
+            Some(ServerMessage::Reconnect {
+                reconnect_after_secs: 45
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn hello_draining() {
+        let app_state = AppState::default();
+        app_state.drain();
+        let client = uclient(app_state);
+        let msg = ClientMessage::Hello {
+            uaid: None,
+            _channel_ids: None,
+            broadcasts: None,
+            features: None,
+        };
+        let err = client.on_client_msg(msg).await.err().unwrap();
+        assert!(matches!(err.kind, SMErrorKind::Draining(_)));
+        assert_eq!(err.close_code(), actix_ws::CloseCode::Again);
+        assert!(err.reconnect_message().is_some());
+    }
+
+    #[tokio::test]
+    async fn hello_advertises_limits() {
+        let mut app_state = AppState {
+            db: hello_db().into_boxed_arc(),
+            ..Default::default()
+        };
+        app_state.settings.max_data_bytes = 2048;
+        app_state.settings.max_channels = Some(500);
+        app_state.settings.auto_ping_interval = Duration::from_secs(60);
+        let client = uclient(app_state);
+        let msg = ClientMessage::Hello {
+            uaid: None,
+            _channel_ids: None,
+            broadcasts: None,
+            features: None,
+        };
+        let (_, smsgs) = client.on_client_msg(msg).await.expect("Hello failed");
+        let smsgs: Vec<_> = smsgs.into_iter().collect();
+        let Some(ServerMessage::Hello { limits, .. }) = smsgs.first() else {
+            panic!("Expected a Hello response: {smsgs:?}");
+        };
+        assert_eq!(
+            limits,
+            &Some(ServerLimits {
+                max_data_bytes: 2048,
+                max_channels: Some(500),
+                ping_interval: 60,
+            })
+        );
+    }
+
+    /// The read position within the resume token
+    const TOKEN_TIMESTAMP: u64 = 1_000;
+    /// The (since advanced) read position of the stored record
+    const RECORD_TIMESTAMP: u64 = 2_000;
+
+    /// A MockDbClient responding to a Resume for an existing user with the
+    /// record `version` and a token for `token_version`
+    fn resume_db(version: Uuid, token_version: Uuid) -> MockDbClient {
+        let resumes = version == token_version;
+        let mut db = MockDbClient::new();
+        // A successful resume skips reading the record
+        db.expect_get_user()
+            .times(if resumes { 0 } else { 1 })
+            .return_once(move |_| {
+                let user = User::builder()
+                    .uaid(DUMMY_UAID)
+                    .connected_at(ms_since_epoch() - (10 * 60 * 1000))
+                    .current_timestamp(RECORD_TIMESTAMP)
+                    .version(version)
+                    .build()
+                    .unwrap();
+                Ok(Some(user))
+            });
+        // The resume's write is guarded by the token's version, falling back
+        // to the full Hello's
+        db.expect_update_user()
+            .times(if resumes { 1 } else { 2 })
+            .returning(move |user| Ok(user.version == Some(version)));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        let read_position = if resumes {
+            TOKEN_TIMESTAMP
+        } else {
+            RECORD_TIMESTAMP
+        };
+        db.expect_fetch_timestamp_messages()
+            .withf(move |_, timestamp, _| timestamp == &Some(read_position))
+            .times(1)
+            .return_once(|_, _, _| Ok(Default::default()));
+        db
+    }
+
+    /// Send a Resume with a token for the record `token_version` to a user
+    /// whose current record version is `version`, returning the emitted
+    /// metrics
+    async fn resume(version: Uuid, token_version: Uuid) -> Vec<String> {
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let mut settings = Settings::test_settings();
+        settings.resume_token_ttl = Duration::from_secs(300);
+        settings.resume_token_key = Some(format!("[{}]", Fernet::generate_key()));
+        let app_state = AppState {
+            db: resume_db(version, token_version).into_boxed_arc(),
+            metrics: Arc::new(cadence::StatsdClient::from_sink("autopush", sink)),
+            ..AppState::from_settings(settings).unwrap()
+        };
+        let resume_fernet = app_state.resume_fernet.as_ref().unwrap();
+        let token = ResumeToken::new(
+            DUMMY_UAID,
+            token_version,
+            Some(TOKEN_TIMESTAMP),
+            ms_since_epoch(),
+            Duration::from_secs(300),
+        );
+        // Tokens encrypted by the endpoint key aren't accepted
+        assert!(ResumeToken::decrypt(resume_fernet, &token.encrypt(&app_state.fernet)).is_none());
+        let token = token.encrypt(resume_fernet);
+        let client = uclient(app_state);
+        let msg = ClientMessage::Resume {
+            token,
+            broadcasts: None,
+            features: None,
+        };
+        let (_, smsgs) = client.on_client_msg(msg).await.expect("Resume failed");
+        let smsgs: Vec<_> = smsgs.into_iter().collect();
+        let Some(ServerMessage::Hello {
+            uaid, resume_token, ..
+        }) = smsgs.first()
+        else {
+            panic!("Expected a Hello response: {smsgs:?}");
+        };
+        assert_eq!(uaid, &DUMMY_UAID.as_simple().to_string());
+        assert!(resume_token.is_some());
+        rx.try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resume_success() {
+        let version = Uuid::new_v4();
+        let metrics = resume(version, version).await;
+        assert!(metrics.contains(&"autopush.ua.command.resume:1|c|#result:success".to_owned()));
+        assert!(!metrics
+            .iter()
+            .any(|m| m.starts_with("autopush.ua.command.hello:")));
+    }
+
+    #[tokio::test]
+    async fn resume_stale_version() {
+        let metrics = resume(Uuid::new_v4(), Uuid::new_v4()).await;
+        assert!(metrics.contains(&"autopush.ua.command.resume:1|c|#result:stale".to_owned()));
+        // Falls back to a full Hello for the token's uaid
+        assert!(metrics.contains(&"autopush.ua.command.hello:1|c|#uaid:existing".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn resume_invalid_token() {
+        let client = uclient(AppState {
+            db: hello_db().into_boxed_arc(),
+            ..Default::default()
+        });
+        let msg = ClientMessage::Resume {
+            token: "invalid".to_owned(),
+            broadcasts: None,
+            features: None,
+        };
+        let (client, _) = client.on_client_msg(msg).await.expect("Resume failed");
+        // Falls back to a Hello issuing a new uaid
+        assert_ne!(client.uaid, DUMMY_UAID);
+    }
 }
@@ -0,0 +1,37 @@
+use std::cell::Cell;
+
+thread_local! {
+    /// The number of WebSocket connections open on this (actix-web worker's)
+    /// thread
+    static OPEN_CONNECTIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// An open WebSocket connection counted against its worker's
+/// `max_worker_connections`, until dropped.
+///
+/// actix-web workers are single threaded and their WebSocket handler tasks
+/// are spawned onto the same thread, so the count is kept thread local.
+pub(crate) struct WorkerConnection(());
+
+impl WorkerConnection {
+    /// Count a new connection, unless this worker already has `max_connections`
+    /// open.
+    ///
+    /// Returns the number of open connections when refused.
+    pub(crate) fn open(max_connections: Option<usize>) -> Result<Self, usize> {
+        OPEN_CONNECTIONS.with(|open| {
+            let count = open.get();
+            if max_connections.is_some_and(|max| count >= max) {
+                return Err(count);
+            }
+            open.set(count + 1);
+            Ok(Self(()))
+        })
+    }
+}
+
+impl Drop for WorkerConnection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.with(|open| open.set(open.get().saturating_sub(1)));
+    }
+}
@@ -3,7 +3,8 @@ use std::{error::Error, fmt};
 use actix_ws::CloseCode;
 use backtrace::Backtrace;
 
-use autoconnect_ws_sm::{SMError, WebPushClient};
+use autoconnect_common::protocol::{InvalidClientMessage, ServerMessage};
+use autoconnect_ws_sm::{SMError, SMErrorKind, WebPushClient};
 use autopush_common::{errors::ReportableError, sentry::event_from_error};
 
 /// WebPush WebSocket Handler Errors
@@ -57,6 +58,31 @@ impl WSError {
         self.kind.as_ref()
     }
 
+    /// A short description of why this error ended the session, recorded on
+    /// the user record when `track_disconnect_reason` is enabled
+    pub fn disconnect_reason(&self) -> &'static str {
+        match &self.kind {
+            WSErrorKind::PongTimeout | WSErrorKind::AppPongTimeout => "ping_timeout",
+            WSErrorKind::SM(e) if matches!(e.kind, SMErrorKind::Ghost | SMErrorKind::UaidReset) => {
+                "forced"
+            }
+            _ => "error",
+        }
+    }
+
+    /// Return a `ServerMessage` informing the Client of this error, sent
+    /// prior to closing the connection
+    pub fn server_message(&self) -> Option<ServerMessage> {
+        match &self.kind {
+            WSErrorKind::SM(e) => e.reconnect_message(),
+            WSErrorKind::InvalidClientMessage(e) => Some(ServerMessage::Error {
+                reason: e.reason().to_owned(),
+                description: e.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
     /// Emit an event for this Error to Sentry
     pub fn capture_sentry_event(&self, client: Option<WebPushClient>) {
         if !self.is_sentry_event() {
@@ -104,6 +130,9 @@ pub enum WSErrorKind {
     #[error("Couldn't parse WebSocket message JSON: {0}")]
     Json(#[from] serde_json::Error),
 
+    #[error("Invalid WebPush message: {0}")]
+    InvalidClientMessage(#[from] InvalidClientMessage),
+
     #[error("WebSocket protocol error: {0}")]
     Protocol(#[from] actix_ws::ProtocolError),
 
@@ -122,6 +151,9 @@ pub enum WSErrorKind {
     #[error("Timeout waiting for Pong")]
     PongTimeout,
 
+    #[error("Timeout waiting for AppPong")]
+    AppPongTimeout,
+
     #[error("ClientRegistry unexpectedly disconnected")]
     RegistryDisconnected,
 }
@@ -151,7 +183,7 @@ impl WSErrorKind {
 
 #[cfg(test)]
 mod tests {
-    use autoconnect_ws_sm::__test_sm_reqwest_error;
+    use autoconnect_ws_sm::{__test_sm_reqwest_error, SMErrorKind};
     use autopush_common::{db::error::DbError, sentry::event_from_error};
 
     use super::{WSError, WSErrorKind};
@@ -188,4 +220,17 @@ mod tests {
         assert_eq!(event.exception[2].ty, "WSError");
         assert_eq!(event.extra.get("row"), Some(&"bar".into()));
     }
+
+    #[test]
+    fn disconnect_reason() {
+        for (kind, reason) in [
+            (WSErrorKind::PongTimeout, "ping_timeout"),
+            (WSErrorKind::AppPongTimeout, "ping_timeout"),
+            (WSErrorKind::SM(SMErrorKind::Ghost.into()), "forced"),
+            (WSErrorKind::SM(SMErrorKind::ExcessivePing.into()), "error"),
+            (WSErrorKind::StreamClosed, "error"),
+        ] {
+            assert_eq!(WSError::from(kind).disconnect_reason(), reason);
+        }
+    }
 }
@@ -1,14 +1,18 @@
 use std::sync::Arc;
 
 use actix_ws::{CloseReason, Message};
+use cadence::{CountedExt, StatsdClient};
 use futures::{channel::mpsc, Stream, StreamExt};
 use tokio::{select, time::timeout};
 
-use autoconnect_common::protocol::{ServerMessage, ServerNotification};
+use autoconnect_common::protocol::{
+    ClientMessage, InvalidClientMessage, ProtocolVersion, ServerMessage, ServerNotification,
+};
 use autoconnect_settings::AppState;
 use autoconnect_ws_sm::{UnidentifiedClient, WebPushClient};
 
 use crate::{
+    connections::WorkerConnection,
     error::{WSError, WSErrorKind},
     ping::PingManager,
     session::{Session, SessionImpl},
@@ -17,14 +21,20 @@ use crate::{
 type MessageStreamResult = Result<actix_ws::Message, actix_ws::ProtocolError>;
 
 /// WebPush WebSocket handler Task
-pub fn spawn_webpush_ws(
+///
+/// The `connection` remains counted against the worker until the task
+/// completes.
+pub(crate) fn spawn_webpush_ws(
     session: actix_ws::Session,
     msg_stream: actix_ws::MessageStream,
     app_state: Arc<AppState>,
     ua: String,
+    protocol_version: ProtocolVersion,
+    connection: WorkerConnection,
 ) {
     actix_rt::spawn(async move {
-        let client = UnidentifiedClient::new(ua, app_state);
+        let _connection = connection;
+        let client = UnidentifiedClient::new(ua, app_state).with_protocol_version(protocol_version);
         let mut session = SessionImpl::new(session);
         let close_reason = webpush_ws(client, &mut session, msg_stream)
             .await
@@ -63,6 +73,10 @@ pub(crate) async fn webpush_ws(
     let (mut client, smsgs) = match unidentified_ws(client, &mut msg_stream).await {
         Ok(t) => t,
         Err(e) => {
+            // e.g. Advise the Client when to reconnect before closing
+            if let Some(smsg) = e.server_message() {
+                let _ = session.text(smsg).await;
+            }
             e.capture_sentry_event(None);
             return Err(e);
         }
@@ -71,6 +85,9 @@ pub(crate) async fn webpush_ws(
     // Client now identified: add them to the registry to recieve ServerNotifications
     let mut snotif_stream = client.registry_connect().await;
     let result = identified_ws(&mut client, smsgs, session, msg_stream, &mut snotif_stream).await;
+    if let Some(smsg) = result.as_ref().err().and_then(WSError::server_message) {
+        let _ = session.text(smsg).await;
+    }
     client.registry_disconnect().await;
 
     snotif_stream.close();
@@ -78,6 +95,11 @@ pub(crate) async fn webpush_ws(
         client.on_server_notif_shutdown(snotif);
     }
     client.shutdown(result.as_ref().err().map(|e| e.to_string()));
+    client.record_disconnect(
+        result
+            .as_ref()
+            .map_or_else(WSError::disconnect_reason, |_| "clean"),
+    );
 
     if let Err(ref e) = result {
         e.capture_sentry_event(Some(client));
@@ -105,7 +127,7 @@ async fn unidentified_ws(
     trace!("❓unidentified_ws: Handshake msg: {:?}", msg);
 
     let client_msg = match msg {
-        Message::Text(ref bytestring) => bytestring.parse()?,
+        Message::Text(ref bytestring) => parse_client_msg(bytestring, client.app_metrics())?,
         _ => {
             return Err(WSErrorKind::UnsupportedMessage("Expected Text".to_owned()).into());
         }
@@ -149,7 +171,7 @@ async fn identified_ws(
         session.text(smsg).await?;
     }
 
-    let mut ping_manager = PingManager::new(client.app_settings()).await;
+    let mut ping_manager = PingManager::new(client.app_settings(), client.app_ping()).await;
     let close_reason = loop {
         select! {
             maybe_result = msg_stream.next() => {
@@ -161,7 +183,7 @@ async fn identified_ws(
                 let msg = result?;
                 trace!("identified_ws: msg: {:#?}", msg);
                 let client_msg = match msg {
-                    Message::Text(ref bytestring) => bytestring.parse()?,
+                    Message::Text(ref bytestring) => parse_client_msg(bytestring, client.app_metrics())?,
                     Message::Nop => continue,
                     Message::Close(reason) => break reason,
                     Message::Ping(bytes) => {
@@ -174,6 +196,10 @@ async fn identified_ws(
                     },
                     _ => return Err(WSErrorKind::UnsupportedMessage("Expected Text, etc.".to_owned()).into())
                 };
+                if let ClientMessage::AppPong = client_msg {
+                    ping_manager.on_app_pong(client.app_settings()).await;
+                    continue;
+                }
                 for smsg in client.on_client_msg(client_msg).await? {
                     trace!("identified_ws: msg_stream, ServerMessage -> session {:#?}", smsg);
                     session.text(smsg).await?;
@@ -193,7 +219,10 @@ async fn identified_ws(
 
             result = ping_manager.tick() => {
                 trace!("identified_ws: ping_manager tick is_ok: {}", result.is_ok());
-                // Propagate PongTimeout
+                if let Err(WSError { kind: WSErrorKind::AppPongTimeout, .. }) = result {
+                    let _ = client.app_metrics().incr("ua.app_pong_timeout");
+                }
+                // Propagate PongTimeout/AppPongTimeout
                 result?;
                 ping_manager.ws_ping_or_broadcast(client, session).await?;
             }
@@ -202,3 +231,14 @@ async fn identified_ws(
 
     Ok(close_reason)
 }
+
+/// Parse a Text frame as a `ClientMessage`, emitting a metric on failure
+fn parse_client_msg(text: &str, metrics: &StatsdClient) -> Result<ClientMessage, WSError> {
+    text.parse().map_err(|e: InvalidClientMessage| {
+        metrics
+            .incr_with_tags("ua.invalid_client_message")
+            .with_tag("reason", e.reason())
+            .send();
+        e.into()
+    })
+}
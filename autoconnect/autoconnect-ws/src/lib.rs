@@ -2,12 +2,17 @@
 extern crate slog_scope;
 
 use actix_web::{
-    http::header::{HeaderValue, USER_AGENT},
+    http::header::{HeaderValue, ORIGIN, SEC_WEBSOCKET_PROTOCOL, USER_AGENT},
     web, Error, HttpRequest, HttpResponse,
 };
+use cadence::CountedExt;
 
+use autoconnect_common::protocol::ProtocolVersion;
 use autoconnect_settings::AppState;
 
+use crate::connections::WorkerConnection;
+
+mod connections;
 mod error;
 mod handler;
 mod ping;
@@ -22,7 +27,60 @@ pub async fn ws_handler(
     app_state: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
     debug!("🔌 Got connection");
-    let (response, session, msg_stream) = actix_ws::handle(&req, body)?;
+    if !origin_allowed(&req, &app_state.allowed_origins) {
+        trace!("🔌 Rejecting connection from disallowed Origin");
+        let _ = app_state.metrics.incr("ua.origin_rejected");
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+    if app_state.settings.require_user_agent && !req.headers().contains_key(USER_AGENT) {
+        trace!("🔌 Rejecting connection without a User-Agent");
+        app_state
+            .metrics
+            .incr_with_tags("ua.connection.rejected")
+            .with_tag("reason", "no_user_agent")
+            .send();
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+    match app_state.accept_limiter.reserve() {
+        Some(wait) if wait.is_zero() => (),
+        Some(wait) => {
+            let _ = app_state.metrics.incr("ua.connection.accept_delayed");
+            actix_rt::time::sleep(wait).await;
+        }
+        None => {
+            info!("🔌 Rejecting connection: over the max_accept_rate");
+            app_state
+                .metrics
+                .incr_with_tags("ua.connection.rejected")
+                .with_tag("reason", "max_accept_rate")
+                .send();
+            return Ok(HttpResponse::ServiceUnavailable().finish());
+        }
+    }
+    let max_connections = app_state.settings.max_worker_connections;
+    let connection = match WorkerConnection::open(max_connections) {
+        Ok(connection) => connection,
+        Err(open_connections) => {
+            info!("🔌 Rejecting connection: worker at its max connections";
+                  "max_connections" => max_connections,
+                  "open_connections" => open_connections);
+            app_state
+                .metrics
+                .incr_with_tags("ua.connection.rejected")
+                .with_tag("reason", "max_connections")
+                .send();
+            return Ok(HttpResponse::ServiceUnavailable().finish());
+        }
+    };
+    let protocol_version = negotiate_protocol_version(&req);
+    let (mut response, session, msg_stream) = actix_ws::handle(&req, body)?;
+    if let Some(protocol_version) = protocol_version {
+        // Echo the selected subprotocol
+        response.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(protocol_version.subprotocol()),
+        );
+    }
     let ua = req
         .headers()
         .get(USER_AGENT)
@@ -30,6 +88,41 @@ pub async fn ws_handler(
         .to_str()
         .unwrap_or_default()
         .to_owned();
-    handler::spawn_webpush_ws(session, msg_stream, app_state.into_inner(), ua);
+    handler::spawn_webpush_ws(
+        session,
+        msg_stream,
+        app_state.into_inner(),
+        ua,
+        protocol_version.unwrap_or_default(),
+        connection,
+    );
     Ok(response)
 }
+
+/// Select the WebPush protocol version from the subprotocols offered by the
+/// request (`None` when it offers no supported subprotocol)
+fn negotiate_protocol_version(req: &HttpRequest) -> Option<ProtocolVersion> {
+    req.headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|value| value.to_str().ok())
+        .find_map(ProtocolVersion::negotiate)
+}
+
+/// Whether the request's `Origin` is allowed to connect
+///
+/// An empty `allowed_origins` allows all. Requests lacking an `Origin` header
+/// don't originate from web pages and are always allowed.
+fn origin_allowed(req: &HttpRequest, allowed_origins: &[String]) -> bool {
+    if allowed_origins.is_empty() {
+        return true;
+    }
+    let Some(origin) = req.headers().get(ORIGIN) else {
+        return true;
+    };
+    let origin = origin
+        .to_str()
+        .unwrap_or_default()
+        .trim_end_matches('/')
+        .to_lowercase();
+    allowed_origins.contains(&origin)
+}
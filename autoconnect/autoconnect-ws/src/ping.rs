@@ -1,6 +1,14 @@
-use tokio::time::{interval, Interval};
+use std::{collections::HashMap, time::Duration};
 
-use autoconnect_common::{broadcast::Broadcast, protocol::ServerMessage};
+use tokio::{
+    select,
+    time::{interval, sleep_until, Instant, Interval},
+};
+
+use autoconnect_common::{
+    broadcast::Broadcast,
+    protocol::{BroadcastValue, ServerMessage},
+};
 use autoconnect_settings::Settings;
 use autoconnect_ws_sm::WebPushClient;
 
@@ -14,6 +22,7 @@ enum Waiting {
     /// Waiting to send a WebSocket Ping (or WebPush Broadcast) to the Client
     ToPing,
     /// Waiting for the Client to respond to our WebSocket Ping with a Pong
+    /// (or our `AppPing` with an `AppPong`)
     ForPong,
 }
 
@@ -23,21 +32,37 @@ enum Waiting {
 /// pending) every `auto_ping_interval`. If the Client fails to respond to the
 /// Ping with a Pong within the `auto_ping_timeout` interval we drop their
 /// connection
+///
+/// For Clients that negotiated them (see `WebPushClient::app_ping`) WebPush
+/// `AppPing`s/`AppPong`s (JSON text frames) are used in place of the
+/// WebSocket control frames.
+///
+/// With a `broadcast_coalesce_window`, a pending Broadcast is held for that
+/// window, sending any further changes made during it in the same message.
 #[derive(Debug)]
 pub struct PingManager {
     /// Waiting to Ping or timeout recieving a Pong
     waiting: Waiting,
     ping_or_timeout: Interval,
+    app_level_ping: bool,
+    broadcast_coalesce_window: Duration,
+    /// Broadcasts held until `flush_broadcasts_at`
+    pending_broadcasts: HashMap<String, BroadcastValue>,
+    flush_broadcasts_at: Option<Instant>,
 }
 
 impl PingManager {
-    pub async fn new(settings: &Settings) -> PingManager {
+    pub async fn new(settings: &Settings, app_level_ping: bool) -> PingManager {
         // Begin by waiting to Ping
         let mut ping_or_timeout = interval(settings.auto_ping_interval);
         ping_or_timeout.tick().await;
         Self {
             waiting: Waiting::ToPing,
             ping_or_timeout,
+            app_level_ping,
+            broadcast_coalesce_window: settings.broadcast_coalesce_window,
+            pending_broadcasts: HashMap::new(),
+            flush_broadcasts_at: None,
         }
     }
 
@@ -52,11 +77,23 @@ impl PingManager {
     ///
     /// - WebSocket Ping was previously sent and the Client failed to respond
     ///   with a Pong within the `auto_ping_timeout` interval
-    ///   (`WSError::PongTimeout` Error returned)
+    ///   (`WSError::PongTimeout` Error returned, or `WSError::AppPongTimeout`
+    ///   for an `AppPing`)
+    ///
+    /// - Pending WebPush Broadcasts to be sent at the end of their
+    ///   `broadcast_coalesce_window`
     pub async fn tick(&mut self) -> Result<(), WSError> {
-        self.ping_or_timeout.tick().await;
+        if let Some(flush_at) = self.flush_broadcasts_at {
+            select! {
+                _ = self.ping_or_timeout.tick() => (),
+                _ = sleep_until(flush_at) => return Ok(()),
+            }
+        } else {
+            self.ping_or_timeout.tick().await;
+        }
         match self.waiting {
             Waiting::ToPing => Ok(()),
+            Waiting::ForPong if self.app_level_ping => Err(WSErrorKind::AppPongTimeout.into()),
             Waiting::ForPong => Err(WSErrorKind::PongTimeout.into()),
         }
     }
@@ -67,21 +104,52 @@ impl PingManager {
         client: &mut WebPushClient,
         session: &mut impl Session,
     ) -> Result<(), WSError> {
-        if let Some(broadcasts) = client.broadcast_delta().await {
-            let smsg = ServerMessage::Broadcast {
-                broadcasts: Broadcast::vec_into_hashmap(broadcasts),
-            };
-            trace!("📢PingManager::ws_ping_or_broadcast {:#?}", smsg);
-            session.text(smsg).await?;
-            // Broadcasts don't recieve a Pong but sync against the next Ping
-            // anyway
-            debug_assert!(matches!(self.waiting, Waiting::ToPing));
-            self.ping_or_timeout.reset();
+        let delta = client.broadcast_delta().await;
+        if let Some(flush_at) = self.flush_broadcasts_at {
+            self.pending_broadcasts
+                .extend(delta.map(Broadcast::vec_into_hashmap).unwrap_or_default());
+            if Instant::now() >= flush_at {
+                self.flush_broadcasts_at = None;
+                let broadcasts = std::mem::take(&mut self.pending_broadcasts);
+                return self.send_broadcasts(broadcasts, session).await;
+            }
+            // Otherwise continue on with a regular Ping
+        } else if let Some(broadcasts) = delta {
+            let broadcasts = Broadcast::vec_into_hashmap(broadcasts);
+            if self.broadcast_coalesce_window.is_zero() {
+                return self.send_broadcasts(broadcasts, session).await;
+            }
+            trace!("📢PingManager::ws_ping_or_broadcast coalescing");
+            self.pending_broadcasts = broadcasts;
+            self.flush_broadcasts_at = Some(Instant::now() + self.broadcast_coalesce_window);
+            return Ok(());
+        }
+
+        if self.app_level_ping {
+            trace!("🏓PingManager::ws_ping_or_broadcast app_ping");
+            session.text(ServerMessage::AppPing).await?;
         } else {
             trace!("🏓PingManager::ws_ping_or_broadcast ping");
             session.ping(&[]).await?;
-            self.set_waiting(Waiting::ForPong, client.app_settings())
-                .await;
+        }
+        self.set_waiting(Waiting::ForPong, client.app_settings())
+            .await;
+        Ok(())
+    }
+
+    /// Send the Client a WebPush Broadcast
+    async fn send_broadcasts(
+        &mut self,
+        broadcasts: HashMap<String, BroadcastValue>,
+        session: &mut impl Session,
+    ) -> Result<(), WSError> {
+        let smsg = ServerMessage::Broadcast { broadcasts };
+        trace!("📢PingManager::ws_ping_or_broadcast {:#?}", smsg);
+        session.text(smsg).await?;
+        // Broadcasts don't recieve a Pong but sync against the next Ping
+        // anyway
+        if let Waiting::ToPing = self.waiting {
+            self.ping_or_timeout.reset();
         }
         Ok(())
     }
@@ -100,6 +168,16 @@ impl PingManager {
         }
     }
 
+    /// Receive a WebPush `AppPong` from the Client
+    ///
+    /// Resetting the timer kicked off from the last `AppPing`
+    pub async fn on_app_pong(&mut self, settings: &Settings) {
+        trace!("🏓PingManager::on_app_pong waiting: {:?}", self.waiting);
+        if let Waiting::ForPong = self.waiting {
+            self.set_waiting(Waiting::ToPing, settings).await;
+        }
+    }
+
     /// Set the `Waiting` status
     async fn set_waiting(&mut self, waiting: Waiting, settings: &Settings) {
         let period = match waiting {
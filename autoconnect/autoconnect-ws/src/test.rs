@@ -4,13 +4,16 @@ use async_stream::stream;
 use futures::pin_mut;
 
 use autoconnect_common::{
-    protocol::ServerMessage,
+    protocol::{BroadcastValue, InvalidClientMessage, ProtocolVersion, ServerMessage},
     test_support::{hello_db, HELLO, UA},
 };
 use autoconnect_settings::{AppState, Settings};
 use autoconnect_ws_sm::UnidentifiedClient;
 
-use crate::{error::WSErrorKind, handler::webpush_ws, session::MockSession};
+use crate::{
+    connections::WorkerConnection, error::WSErrorKind, handler::webpush_ws,
+    negotiate_protocol_version, origin_allowed, session::MockSession, ws_handler,
+};
 
 #[ctor::ctor]
 fn init_test_logging() {
@@ -63,6 +66,86 @@ async fn basic() {
         .expect("Handler failed");
 }
 
+#[actix_web::test]
+async fn overloaded_advises_reconnect() {
+    let settings = Settings {
+        overload_client_limit: Some(0),
+        reconnect_advice_delay: Duration::from_secs(60),
+        ..Settings::test_settings()
+    };
+    let client = uclient(AppState::from_settings(settings).unwrap());
+    let mut session = MockSession::new();
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| {
+            matches!(
+                msg,
+                ServerMessage::Reconnect {
+                    reconnect_after_secs: 60
+                }
+            )
+        })
+        .return_once(|_| Ok(()));
+
+    let s = futures::stream::iter(vec![Ok(actix_ws::Message::Text(HELLO.into()))]);
+    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    assert_eq!(err.close_code(), actix_ws::CloseCode::Again);
+}
+
+#[actix_web::test]
+async fn malformed_json() {
+    let client = uclient(Default::default());
+    let mut session = MockSession::new();
+    session
+        .expect_text()
+        .times(1)
+        .withf(
+            |msg| matches!(msg, ServerMessage::Error { reason, .. } if reason == "malformed_json"),
+        )
+        .return_once(|_| Ok(()));
+
+    let s = futures::stream::iter(vec![Ok(actix_ws::Message::Text(
+        r#"{"messageType": "hello""#.into(),
+    ))]);
+    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    assert!(matches!(
+        err.kind,
+        WSErrorKind::InvalidClientMessage(InvalidClientMessage::MalformedJson(_))
+    ));
+}
+
+#[actix_web::test]
+async fn unknown_message_type() {
+    let client = uclient(AppState {
+        db: hello_db().into_boxed_arc(),
+        ..Default::default()
+    });
+    let mut session = MockSession::new();
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Error { reason, .. } if reason == "unknown_type"))
+        .return_once(|_| Ok(()));
+
+    let s = futures::stream::iter(vec![
+        Ok(actix_ws::Message::Text(HELLO.into())),
+        Ok(actix_ws::Message::Text(
+            r#"{"messageType": "gimme"}"#.into(),
+        )),
+    ]);
+    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    assert!(matches!(
+        err.kind,
+        WSErrorKind::InvalidClientMessage(InvalidClientMessage::UnknownType(ref t)) if t == "gimme"
+    ));
+}
+
 #[actix_web::test]
 async fn websocket_ping() {
     let settings = Settings {
@@ -136,3 +219,234 @@ async fn auto_ping_timeout_after_pong() {
     let err = webpush_ws(client, &mut session, s).await.unwrap_err();
     assert!(matches!(err.kind, WSErrorKind::PongTimeout));
 }
+
+const HELLO_APP_PING: &str =
+    r#"{"messageType": "hello", "use_webpush": true, "features": ["app_ping"]}"#;
+
+#[actix_web::test]
+async fn app_level_ping_timeout() {
+    let settings = Settings {
+        auto_ping_interval: Duration::from_secs_f32(0.15),
+        auto_ping_timeout: Duration::from_secs_f32(0.15),
+        app_level_ping: true,
+        ..Settings::test_settings()
+    };
+    let client = uclient(AppState {
+        db: hello_db().into_boxed_arc(),
+        ..AppState::from_settings(settings).unwrap()
+    });
+    let mut session = MockSession::new();
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::AppPing))
+        .return_once(|_| Ok(()));
+    // No WebSocket Pings are sent
+    session.expect_ping().never();
+
+    let s = stream! {
+        yield Ok(actix_ws::Message::Text(HELLO_APP_PING.into()));
+        tokio::time::sleep(Duration::from_secs_f32(0.35)).await;
+    };
+    pin_mut!(s);
+    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    assert!(matches!(err.kind, WSErrorKind::AppPongTimeout));
+}
+
+#[actix_web::test]
+async fn app_level_ping_not_requested() {
+    let settings = Settings {
+        auto_ping_interval: Duration::from_secs_f32(0.15),
+        auto_ping_timeout: Duration::from_secs_f32(0.15),
+        app_level_ping: true,
+        ..Settings::test_settings()
+    };
+    let client = uclient(AppState {
+        db: hello_db().into_boxed_arc(),
+        ..AppState::from_settings(settings).unwrap()
+    });
+    let mut session = MockSession::new();
+    // Only the Hello: no AppPings for a Client that didn't request them
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+    session.expect_ping().times(1).returning(|_| Ok(()));
+
+    let s = stream! {
+        yield Ok(actix_ws::Message::Text(HELLO.into()));
+        tokio::time::sleep(Duration::from_secs_f32(0.35)).await;
+    };
+    pin_mut!(s);
+    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    assert!(matches!(err.kind, WSErrorKind::PongTimeout));
+}
+
+#[actix_web::test]
+async fn broadcast_coalescing() {
+    let settings = Settings {
+        auto_ping_interval: Duration::from_secs_f32(0.15),
+        broadcast_coalesce_window: Duration::from_secs_f32(0.1),
+        ..Settings::test_settings()
+    };
+    let app_state = AppState {
+        db: hello_db().into_boxed_arc(),
+        ..AppState::from_settings(settings).unwrap()
+    };
+    let broadcaster = app_state.broadcaster.clone();
+    for id in ["foo/bar", "baz"] {
+        broadcaster
+            .write()
+            .await
+            .add_broadcast((id.to_owned(), "v1".to_owned()).into());
+    }
+    let client = uclient(app_state);
+    let mut session = MockSession::new();
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+    // Both changes arrive in a single Broadcast
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| match msg {
+            ServerMessage::Broadcast { broadcasts } => {
+                broadcasts.len() == 2
+                    && broadcasts["foo/bar"] == BroadcastValue::Value("v2".to_owned())
+                    && broadcasts["baz"] == BroadcastValue::Value("v2".to_owned())
+            }
+            _ => false,
+        })
+        .return_once(|_| Ok(()));
+    session.expect_ping().never();
+
+    let hello = serde_json::json!({
+        "messageType": "hello",
+        "use_webpush": true,
+        "broadcasts": {"foo/bar": "v1", "baz": "v1"},
+    });
+    let s = stream! {
+        yield Ok(actix_ws::Message::Text(hello.to_string().into()));
+        tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        // Pending at the next Ping (0.15s), coalescing until 0.25s
+        broadcaster
+            .write()
+            .await
+            .add_broadcast(("foo/bar".to_owned(), "v2".to_owned()).into());
+        tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        broadcaster
+            .write()
+            .await
+            .add_broadcast(("baz".to_owned(), "v2".to_owned()).into());
+        tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+    };
+    pin_mut!(s);
+    webpush_ws(client, &mut session, s)
+        .await
+        .expect("Handler failed");
+}
+
+/// Perform a WebSocket handshake offering the `subprotocol`, returning the
+/// response's selected subprotocol
+async fn handshake_subprotocol(subprotocol: Option<&str>) -> Option<String> {
+    let app = actix_web::test::init_service(
+        actix_web::App::new()
+            .app_data(actix_web::web::Data::new(AppState::default()))
+            .route("/", actix_web::web::get().to(ws_handler)),
+    )
+    .await;
+    let mut req = actix_web::test::TestRequest::get()
+        .uri("/")
+        .insert_header(("Upgrade", "websocket"))
+        .insert_header(("Connection", "Upgrade"))
+        .insert_header(("Sec-WebSocket-Version", "13"))
+        .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="));
+    if let Some(subprotocol) = subprotocol {
+        req = req.insert_header(("Sec-WebSocket-Protocol", subprotocol));
+    }
+    let resp = actix_web::test::call_service(&app, req.to_request()).await;
+    assert_eq!(
+        resp.status(),
+        actix_web::http::StatusCode::SWITCHING_PROTOCOLS
+    );
+    resp.headers()
+        .get("Sec-WebSocket-Protocol")
+        .map(|value| value.to_str().unwrap().to_owned())
+}
+
+#[actix_web::test]
+async fn subprotocol_negotiation() {
+    assert_eq!(
+        handshake_subprotocol(Some("push-notification-v2"))
+            .await
+            .as_deref(),
+        Some("push-notification-v2")
+    );
+    // Unknown subprotocols fall back to v1 (without echoing one)
+    assert_eq!(
+        handshake_subprotocol(Some("push-notification-v9")).await,
+        None
+    );
+    assert_eq!(handshake_subprotocol(None).await, None);
+}
+
+#[test]
+fn negotiated_protocol_version() {
+    let req = actix_web::test::TestRequest::default()
+        .insert_header(("Sec-WebSocket-Protocol", "chat, push-notification-v2"))
+        .to_http_request();
+    assert_eq!(negotiate_protocol_version(&req), Some(ProtocolVersion::V2));
+    let req = actix_web::test::TestRequest::default()
+        .insert_header(("Sec-WebSocket-Protocol", "chat"))
+        .to_http_request();
+    assert_eq!(negotiate_protocol_version(&req), None);
+}
+
+#[test]
+fn allowed_origin() {
+    let allowed = vec!["https://example.com".to_owned()];
+    let req = actix_web::test::TestRequest::default()
+        .insert_header(("Origin", "https://example.com"))
+        .to_http_request();
+    assert!(origin_allowed(&req, &allowed));
+    // Non-browser clients don't send an Origin
+    let req = actix_web::test::TestRequest::default().to_http_request();
+    assert!(origin_allowed(&req, &allowed));
+}
+
+#[test]
+fn disallowed_origin() {
+    let allowed = vec!["https://example.com".to_owned()];
+    let req = actix_web::test::TestRequest::default()
+        .insert_header(("Origin", "https://example.org"))
+        .to_http_request();
+    assert!(!origin_allowed(&req, &allowed));
+}
+
+#[test]
+fn allow_all_origins() {
+    let req = actix_web::test::TestRequest::default()
+        .insert_header(("Origin", "https://example.org"))
+        .to_http_request();
+    assert!(origin_allowed(&req, &[]));
+}
+
+#[test]
+fn worker_connection_cap() {
+    let first = WorkerConnection::open(Some(2)).unwrap();
+    let _second = WorkerConnection::open(Some(2)).unwrap();
+    assert!(matches!(WorkerConnection::open(Some(2)), Err(2)));
+    // Closing a connection frees room for another
+    drop(first);
+    let _third = WorkerConnection::open(Some(2)).unwrap();
+    // No cap
+    let _fourth = WorkerConnection::open(None).unwrap();
+}
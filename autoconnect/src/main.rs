@@ -4,21 +4,27 @@
 #[macro_use]
 extern crate slog_scope;
 
-use std::{env, time::Duration, vec::Vec};
+use std::{
+    env,
+    net::SocketAddr,
+    time::{Duration, Instant},
+    vec::Vec,
+};
 
 use actix_http::HttpService;
-use actix_server::Server;
+use actix_server::{Server, ServerBuilder};
 use actix_service::map_config;
 use actix_web::dev::AppConfig;
 use docopt::Docopt;
 use serde::Deserialize;
 
-use autoconnect_settings::{AppState, Settings};
+use autoconnect_common::protocol::protocol_schema;
+use autoconnect_settings::{AppState, SentrySessionMode, Settings};
 use autoconnect_web::{build_app, config, config_router};
 use autopush_common::{
     db::spawn_pool_periodic_reporter,
     errors::{ApcErrorKind, Result},
-    logging,
+    logging, metrics, otel,
 };
 
 const USAGE: &str = "
@@ -27,23 +33,95 @@ Usage: autoconnect [options]
 Options:
     -h, --help                          Show this message.
     --config=CONFIGFILE                 Connection configuration file path.
+    --dump-protocol-schema              Print the JSON Schema of the WebPush protocol messages and exit.
+    --check-config                      Validate the configuration, print the resolved settings and exit.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_config: Option<String>,
+    flag_dump_protocol_schema: bool,
+    flag_check_config: bool,
+}
+
+/// Load and validate the settings, printing the resolved settings (with their
+/// secrets redacted). Returns the process exit code.
+fn check_config(filenames: &[String]) -> i32 {
+    // Validated as they're loaded
+    match Settings::with_env_and_config_files(filenames) {
+        Ok(settings) => {
+            println!("{:#?}", settings.redacted());
+            0
+        }
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            1
+        }
+    }
+}
+
+/// Bind the public (WebSocket) server to a Unix domain socket at
+/// `unix_socket_path` when specified, otherwise to the TCP `addr`
+fn bind_autoconnect(
+    builder: ServerBuilder,
+    unix_socket_path: Option<&str>,
+    addr: SocketAddr,
+    app_state: AppState,
+) -> std::io::Result<ServerBuilder> {
+    let Some(path) = unix_socket_path else {
+        return builder.bind("autoconnect", addr, move || {
+            let app = build_app!(app_state, config);
+            HttpService::build()
+                // XXX: AppConfig::default() does *not* have correct values
+                // https://github.com/actix/actix-web/issues/3180
+                .finish(map_config(app, |_| AppConfig::default()))
+                .tcp()
+        });
+    };
+    #[cfg(unix)]
+    {
+        use actix_http::Protocol;
+        use actix_service::{fn_service, ServiceFactoryExt};
+
+        builder.bind_uds("autoconnect", path, move || {
+            let app = build_app!(app_state, config);
+            fn_service(|io: actix_rt::net::UnixStream| async { Ok((io, Protocol::Http1, None)) })
+                .and_then(
+                    HttpService::build()
+                        // XXX: See above
+                        .finish(map_config(app, |_| AppConfig::default())),
+                )
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("unix_socket_path ({path}) requires a unix platform"),
+        ))
+    }
 }
 
 #[actix_web::main]
 async fn main() -> Result<()> {
+    let started = Instant::now();
     env_logger::init();
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
+    if args.flag_dump_protocol_schema {
+        let schema = serde_json::to_string_pretty(&protocol_schema())
+            .map_err(|e| ApcErrorKind::GeneralError(e.to_string()))?;
+        println!("{schema}");
+        return Ok(());
+    }
     let mut filenames = Vec::new();
     if let Some(config_filename) = args.flag_config {
         filenames.push(config_filename);
     }
+    if args.flag_check_config {
+        std::process::exit(check_config(&filenames));
+    }
     let settings =
         Settings::with_env_and_config_files(&filenames).map_err(ApcErrorKind::ConfigError)?;
     logging::init_logging(
@@ -53,6 +131,9 @@ async fn main() -> Result<()> {
     )
     .expect("Logging failed to initialize");
     debug!("Starting up autoconnect...");
+    if let Some(endpoint) = &settings.otel_endpoint {
+        otel::init(env!("CARGO_PKG_NAME"), endpoint)?;
+    }
 
     // Sentry requires the environment variable "SENTRY_DSN".
     if env::var("SENTRY_DSN")
@@ -62,18 +143,27 @@ async fn main() -> Result<()> {
         print!("SENTRY_DSN not set. Logging disabled.");
     }
 
+    let (session_mode, auto_session_tracking) = match settings.sentry_session_mode {
+        SentrySessionMode::Request => (sentry::SessionMode::Request, true),
+        SentrySessionMode::Application => (sentry::SessionMode::Application, true),
+        SentrySessionMode::None => (sentry::SessionMode::Request, false),
+    };
     let _guard = sentry::init(sentry::ClientOptions {
         release: sentry::release_name!(),
-        session_mode: sentry::SessionMode::Request, // new session per request
-        auto_session_tracking: true,
+        session_mode,
+        auto_session_tracking,
         ..autopush_common::sentry::client_options()
     });
 
+    let bind_ip = settings.bind_ip().map_err(ApcErrorKind::ConfigError)?;
     let port = settings.port;
-    let router_port = settings.router_port;
+    let unix_socket_path = settings.unix_socket_path.clone();
+    let router_port = settings.router_bind_port();
     let actix_max_connections = settings.actix_max_connections;
-    let actix_workers = settings.actix_workers;
+    let actix_workers = settings
+        .actix_worker_count(std::thread::available_parallelism().map_or(1, |cpus| cpus.get()));
     let app_state = AppState::from_settings(settings)?;
+    let metrics = app_state.metrics.clone();
     app_state.init_and_spawn_megaphone_updater().await?;
     spawn_pool_periodic_reporter(
         Duration::from_secs(10),
@@ -82,29 +172,31 @@ async fn main() -> Result<()> {
     );
 
     info!(
-        "Starting autoconnect on port: {} router_port: {} ({})",
-        port,
-        router_port,
+        "Starting autoconnect on {} router_port: {} ({})",
+        unix_socket_path.as_ref().map_or_else(
+            || SocketAddr::new(bind_ip, port).to_string(),
+            |path| format!("socket: {path}")
+        ),
+        router_port.map_or_else(|| "disabled".to_owned(), |port| port.to_string()),
         logging::parallelism_banner()
     );
 
     let router_app_state = app_state.clone();
-    let mut builder = Server::build()
-        .bind("autoconnect", ("0.0.0.0", port), move || {
-            let app = build_app!(app_state, config);
-            HttpService::build()
-                // XXX: AppConfig::default() does *not* have correct values
-                // https://github.com/actix/actix-web/issues/3180
-                .finish(map_config(app, |_| AppConfig::default()))
-                .tcp()
-        })?
-        .bind("autoconnect-router", ("0.0.0.0", router_port), move || {
+    let mut builder = bind_autoconnect(
+        Server::build(),
+        unix_socket_path.as_deref(),
+        SocketAddr::new(bind_ip, port),
+        app_state,
+    )?;
+    if let Some(router_port) = router_port {
+        builder = builder.bind("autoconnect-router", (bind_ip, router_port), move || {
             let app = build_app!(router_app_state, config_router);
             HttpService::build()
                 // XXX:
                 .finish(map_config(app, |_| AppConfig::default()))
                 .tcp()
         })?;
+    }
     if let Some(max_connections) = actix_max_connections {
         builder = builder.max_concurrent_connections(max_connections);
     }
@@ -113,6 +205,63 @@ async fn main() -> Result<()> {
     }
     builder.run().await?;
 
+    metrics::shutdown(&metrics, started.elapsed());
+    otel::shutdown();
     info!("Shutting down autoconnect");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    #[cfg(unix)]
+    use std::os::unix::fs::FileTypeExt;
+
+    use super::check_config;
+
+    #[cfg(unix)]
+    #[actix_rt::test]
+    async fn bind_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("autoconnect.sock");
+        let path = path.to_str().unwrap();
+        let server = super::bind_autoconnect(
+            actix_server::Server::build(),
+            Some(path),
+            ([0, 0, 0, 0], 0).into(),
+            Default::default(),
+        )
+        .unwrap()
+        .run();
+        assert!(std::fs::metadata(path).unwrap().file_type().is_socket());
+        server.handle().stop(false).await;
+    }
+
+    fn config_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn check_config_exit_code() {
+        let file = config_file("max_data_bytes = 4096\n");
+        assert_eq!(
+            check_config(&[file.path().to_string_lossy().into_owned()]),
+            0
+        );
+
+        // Fails validation
+        let file = config_file("actix_workers_fraction = 0.0\n");
+        assert_eq!(
+            check_config(&[file.path().to_string_lossy().into_owned()]),
+            1
+        );
+
+        let file = config_file("max_data_bytes = \"lots\"\n");
+        assert_eq!(
+            check_config(&[file.path().to_string_lossy().into_owned()]),
+            1
+        );
+    }
+}
@@ -28,6 +28,8 @@ pub type ApiResult<T> = Result<T, ApiError>;
 /// A link for more info on the returned error
 const ERROR_URL: &str = "http://autopush.readthedocs.io/en/latest/http.html#error-codes";
 const RETRY_AFTER_PERIOD: &str = "120"; // retry after 2 minutes;
+/// The content type for [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807) error bodies
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
 
 /// The main error type.
 #[derive(Debug)]
@@ -98,12 +100,20 @@ pub enum ApiErrorKind {
     NoUser,
 
     #[error("No such subscription")]
-    NoSubscription,
+    NoSubscription(GoneReason),
+
+    /// The endpoint's token has passed its expiry
+    #[error("Endpoint expired")]
+    ExpiredEndpoint,
 
     /// A specific issue with the encryption headers
     #[error("{0}")]
     InvalidEncryption(String),
 
+    /// The notification's metadata header isn't valid JSON
+    #[error("Invalid message metadata: {0}")]
+    InvalidMessageMeta(String),
+
     /// Used if the API version given is not v1 or v2
     #[error("Invalid API version")]
     InvalidApiVersion,
@@ -126,6 +136,11 @@ pub enum ApiErrorKind {
     #[error("Invalid Local Auth {0}")]
     InvalidLocalAuth(String),
 
+    /// The channel's exceeded its notification rate: retry after the
+    /// specified number of seconds
+    #[error("Too many notifications for this subscription")]
+    ChannelRateLimited(u64),
+
     #[error("General error {0}")]
     General(String),
 
@@ -145,7 +160,8 @@ impl ApiErrorKind {
             | ApiErrorKind::NoTTL
             | ApiErrorKind::InvalidRouterType
             | ApiErrorKind::InvalidRouterToken
-            | ApiErrorKind::InvalidMessageId => StatusCode::BAD_REQUEST,
+            | ApiErrorKind::InvalidMessageId
+            | ApiErrorKind::InvalidMessageMeta(_) => StatusCode::BAD_REQUEST,
 
             ApiErrorKind::VapidError(_)
             | ApiErrorKind::Jwt(_)
@@ -156,10 +172,14 @@ impl ApiErrorKind {
 
             ApiErrorKind::InvalidToken | ApiErrorKind::InvalidApiVersion => StatusCode::NOT_FOUND,
 
-            ApiErrorKind::NoUser | ApiErrorKind::NoSubscription => StatusCode::GONE,
+            ApiErrorKind::NoUser
+            | ApiErrorKind::NoSubscription(_)
+            | ApiErrorKind::ExpiredEndpoint => StatusCode::GONE,
 
             ApiErrorKind::LogCheck => StatusCode::IM_A_TEAPOT,
 
+            ApiErrorKind::ChannelRateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+
             ApiErrorKind::Conditional(_) => StatusCode::SERVICE_UNAVAILABLE,
 
             ApiErrorKind::Database(e) => e.status(),
@@ -185,6 +205,7 @@ impl ApiErrorKind {
             ApiErrorKind::InvalidRouterType => "invalid_router_type",
             ApiErrorKind::InvalidRouterToken => "invalid_router_token",
             ApiErrorKind::InvalidMessageId => "invalid_message_id",
+            ApiErrorKind::InvalidMessageMeta(_) => "invalid_message_meta",
 
             ApiErrorKind::VapidError(_) => "vapid_error",
             ApiErrorKind::Jwt(_) | ApiErrorKind::Serde(_) => "jwt",
@@ -196,9 +217,11 @@ impl ApiErrorKind {
             ApiErrorKind::InvalidApiVersion => "invalid_api_version",
 
             ApiErrorKind::NoUser => "no_user",
-            ApiErrorKind::NoSubscription => "no_subscription",
+            ApiErrorKind::NoSubscription(_) => "no_subscription",
+            ApiErrorKind::ExpiredEndpoint => "expired_endpoint",
 
             ApiErrorKind::LogCheck => "log_check",
+            ApiErrorKind::ChannelRateLimited(_) => "channel_rate_limited",
 
             ApiErrorKind::General(_) => "general",
             ApiErrorKind::Io(_) => "io",
@@ -219,6 +242,7 @@ impl ApiErrorKind {
             ApiErrorKind::Database(e) => e.is_sentry_event(),
             // Ignore common webpush errors
             ApiErrorKind::NoTTL | ApiErrorKind::InvalidEncryption(_) |
+            ApiErrorKind::InvalidMessageMeta(_) |
             // Ignore common VAPID erros
             ApiErrorKind::VapidError(_)
                 | ApiErrorKind::Jwt(_)
@@ -226,11 +250,12 @@ impl ApiErrorKind {
                 | ApiErrorKind::InvalidAuthentication
                 | ApiErrorKind::InvalidLocalAuth(_) |
             // Ignore missing or invalid user errors
-            ApiErrorKind::NoUser | ApiErrorKind::NoSubscription |
+            ApiErrorKind::NoUser | ApiErrorKind::NoSubscription(_) | ApiErrorKind::ExpiredEndpoint |
             // Ignore oversized payload.
             ApiErrorKind::PayloadError(_) |
             ApiErrorKind::Validation(_) |
             ApiErrorKind::Conditional(_) |
+            ApiErrorKind::ChannelRateLimited(_) |
             ApiErrorKind::ReqwestError(_) => false,
             _ => true,
         }
@@ -254,7 +279,9 @@ impl ApiErrorKind {
                 Some(104)
             }
 
-            ApiErrorKind::NoSubscription => Some(106),
+            ApiErrorKind::ExpiredEndpoint => Some(105),
+
+            ApiErrorKind::NoSubscription(_) => Some(106),
 
             ApiErrorKind::InvalidRouterType => Some(108),
 
@@ -281,9 +308,50 @@ impl ApiErrorKind {
             | ApiErrorKind::RegistrationSecretHash(_)
             | ApiErrorKind::EndpointUrl(_)
             | ApiErrorKind::InvalidMessageId
+            | ApiErrorKind::InvalidMessageMeta(_)
+            | ApiErrorKind::ChannelRateLimited(_)
             | ApiErrorKind::ReqwestError(_) => None,
         }
     }
+
+    /// The reason for a Gone (410) response
+    pub fn gone_reason(&self) -> Option<GoneReason> {
+        match self {
+            ApiErrorKind::NoSubscription(reason) => Some(*reason),
+            ApiErrorKind::NoUser | ApiErrorKind::Router(RouterError::UserWasDeleted) => {
+                Some(GoneReason::UserExpired)
+            }
+            ApiErrorKind::ExpiredEndpoint => Some(GoneReason::EndpointExpired),
+            // e.g. the bridge reporting an unregistered device
+            ApiErrorKind::Router(e) if e.status() == StatusCode::GONE => Some(GoneReason::BadToken),
+            _ => None,
+        }
+    }
+}
+
+/// Why an endpoint is Gone (a 410), letting senders decide whether its
+/// subscription may be renewed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GoneReason {
+    /// The channel was unsubscribed (or never existed)
+    ChannelUnsubscribed,
+    /// The user's record expired or was dropped
+    UserExpired,
+    /// The endpoint passed its expiry
+    EndpointExpired,
+    /// The bridge (e.g. FCM) no longer accepts the device's token
+    BadToken,
+}
+
+impl GoneReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GoneReason::ChannelUnsubscribed => "channel_unsubscribed",
+            GoneReason::UserExpired => "user_expired",
+            GoneReason::EndpointExpired => "endpoint_expired",
+            GoneReason::BadToken => "bad_token",
+        }
+    }
 }
 
 impl Display for ApiError {
@@ -328,26 +396,55 @@ impl ResponseError for ApiError {
             StatusCode::SERVICE_UNAVAILABLE => {
                 builder.insert_header((header::RETRY_AFTER, RETRY_AFTER_PERIOD));
             }
+            StatusCode::TOO_MANY_REQUESTS => {
+                // Pass along the bridge's (or the channel limiter's) advised
+                // delay
+                let retry_after = match &self.kind {
+                    ApiErrorKind::Router(e) => e.retry_after(),
+                    ApiErrorKind::ChannelRateLimited(retry_after) => Some(*retry_after),
+                    _ => None,
+                };
+                if let Some(retry_after) = retry_after {
+                    builder.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+                }
+            }
             _ => {}
         }
 
-        builder.json(self)
+        builder
+            .content_type(PROBLEM_CONTENT_TYPE)
+            .body(serde_json::to_string(self).unwrap_or_default())
     }
 }
 
+/// Serialize as an RFC 7807 "problem details" object.
+///
+/// The `type`, `title`, `status` and `detail` members are defined by the RFC,
+/// `errno` (and `reason`, for Gone responses) are autopush extension members.
+/// The legacy `code`, `error`, `message` and `more_info` fields are retained
+/// for older clients.
 impl Serialize for ApiError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
         let status = self.kind.status();
-        let mut map = serializer.serialize_map(Some(5))?;
-
+        let detail = self.kind.to_string();
+        let reason = self.kind.gone_reason();
+        let mut map = serializer.serialize_map(Some(9 + reason.is_some() as usize))?;
+
+        map.serialize_entry("type", ERROR_URL)?;
+        map.serialize_entry("title", &status.canonical_reason())?;
+        map.serialize_entry("status", &status.as_u16())?;
+        map.serialize_entry("detail", &detail)?;
         map.serialize_entry("code", &status.as_u16())?;
         map.serialize_entry("errno", &self.kind.errno())?;
         map.serialize_entry("error", &status.canonical_reason())?;
-        map.serialize_entry("message", &self.kind.to_string())?;
+        map.serialize_entry("message", &detail)?;
         map.serialize_entry("more_info", ERROR_URL)?;
+        if let Some(reason) = reason {
+            map.serialize_entry("reason", reason.as_str())?;
+        }
         map.end()
     }
 }
@@ -415,13 +512,103 @@ fn errno_from_validation_errors(e: &ValidationErrors) -> Option<usize> {
 
 #[cfg(test)]
 mod tests {
+    use actix_web::{http::header, ResponseError};
     use autopush_common::{db::error::DbError, sentry::event_from_error};
 
     use crate::routers::RouterError;
 
-    use super::{ApiError, ApiErrorKind};
+    use super::{ApiError, ApiErrorKind, GoneReason, ERROR_URL, PROBLEM_CONTENT_TYPE};
     use crate::error::ReportableError;
 
+    async fn problem_body(e: ApiError) -> serde_json::Value {
+        let resp = e.error_response();
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            PROBLEM_CONTENT_TYPE
+        );
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn problem_json_no_user() {
+        let body = problem_body(ApiErrorKind::NoUser.into()).await;
+        assert_eq!(body["type"], ERROR_URL);
+        assert_eq!(body["title"], "Gone");
+        assert_eq!(body["status"], 410);
+        assert_eq!(body["detail"], "UAID not found");
+        assert_eq!(body["errno"], 103);
+        // legacy fields
+        assert_eq!(body["code"], 410);
+        assert_eq!(body["error"], "Gone");
+        assert_eq!(body["message"], "UAID not found");
+        assert_eq!(body["more_info"], ERROR_URL);
+    }
+
+    #[actix_rt::test]
+    async fn problem_json_gone_reason() {
+        for (kind, errno, reason) in [
+            (
+                ApiErrorKind::NoSubscription(GoneReason::ChannelUnsubscribed),
+                106,
+                "channel_unsubscribed",
+            ),
+            (
+                ApiErrorKind::NoSubscription(GoneReason::UserExpired),
+                106,
+                "user_expired",
+            ),
+            (ApiErrorKind::NoUser, 103, "user_expired"),
+            (ApiErrorKind::ExpiredEndpoint, 105, "endpoint_expired"),
+            (
+                ApiErrorKind::Router(RouterError::NotFound),
+                106,
+                "bad_token",
+            ),
+        ] {
+            let body = problem_body(kind.into()).await;
+            assert_eq!(body["status"], 410);
+            assert_eq!(body["errno"], errno);
+            assert_eq!(body["reason"], reason);
+        }
+
+        // Only Gone responses include a reason
+        let body = problem_body(ApiErrorKind::InvalidToken.into()).await;
+        assert!(body.get("reason").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn problem_json_no_errno() {
+        let body = problem_body(ApiErrorKind::General("oops".to_owned()).into()).await;
+        assert_eq!(body["title"], "Internal Server Error");
+        assert_eq!(body["status"], 500);
+        assert_eq!(body["detail"], "General error oops");
+        assert!(body["errno"].is_null());
+    }
+
+    #[test]
+    fn throttled_retry_after() {
+        let e: ApiError = ApiErrorKind::Router(RouterError::Throttled(Some(30))).into();
+        let resp = e.error_response();
+        assert_eq!(resp.status(), actix_http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "30");
+
+        let e: ApiError = ApiErrorKind::Router(RouterError::Throttled(None)).into();
+        assert!(e
+            .error_response()
+            .headers()
+            .get(header::RETRY_AFTER)
+            .is_none());
+    }
+
+    #[test]
+    fn channel_rate_limited_retry_after() {
+        let e: ApiError = ApiErrorKind::ChannelRateLimited(2).into();
+        let resp = e.error_response();
+        assert_eq!(resp.status(), actix_http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "2");
+    }
+
     #[test]
     fn sentry_event_with_extras() {
         let dbe = DbError::Integrity("foo".to_owned(), Some("bar".to_owned()));
@@ -2,7 +2,9 @@ use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::server::AppState;
 use actix_web::dev::Payload;
 use actix_web::{web::Data, FromRequest, HttpRequest};
-use autopush_common::notification::{STANDARD_NOTIFICATION_PREFIX, TOPIC_NOTIFICATION_PREFIX};
+use autopush_common::notification::{
+    ChidMessageId, STANDARD_NOTIFICATION_PREFIX, TOPIC_NOTIFICATION_PREFIX,
+};
 use fernet::MultiFernet;
 use futures::future;
 use uuid::Uuid;
@@ -38,7 +40,10 @@ impl FromRequest for MessageId {
             .into_inner()
             .expect("No server state found");
 
-        future::ready(MessageId::decrypt(&app_state.fernet, message_id_param))
+        future::ready(MessageId::decrypt(
+            &app_state.internal_fernet,
+            message_id_param,
+        ))
     }
 }
 
@@ -105,6 +110,28 @@ impl MessageId {
         }
     }
 
+    /// Build the message ID of a notification stored under `sort_key` (its
+    /// `chidmessageid`), the inverse of [MessageId::sort_key]
+    pub fn from_sort_key(uaid: Uuid, sort_key: &str) -> ApiResult<Self> {
+        let chidmessageid: ChidMessageId = sort_key
+            .parse()
+            .map_err(|e| ApiErrorKind::General(format!("Invalid chidmessageid: {e}")))?;
+        Ok(
+            match (chidmessageid.topic, chidmessageid.sortkey_timestamp) {
+                (Some(topic), _) => MessageId::WithTopic {
+                    uaid,
+                    channel_id: chidmessageid.channel_id,
+                    topic,
+                },
+                (None, timestamp) => MessageId::WithoutTopic {
+                    uaid,
+                    channel_id: chidmessageid.channel_id,
+                    timestamp: timestamp.unwrap_or_default(),
+                },
+            },
+        )
+    }
+
     /// Get the UAID of the associated notification
     pub fn uaid(&self) -> Uuid {
         match self {
@@ -118,22 +145,12 @@ impl MessageId {
         match self {
             MessageId::WithTopic {
                 channel_id, topic, ..
-            } => format!(
-                "{}:{}:{}",
-                TOPIC_NOTIFICATION_PREFIX,
-                channel_id.as_hyphenated(),
-                topic
-            ),
+            } => ChidMessageId::topic(*channel_id, topic.clone()).to_string(),
             MessageId::WithoutTopic {
                 channel_id,
                 timestamp,
                 ..
-            } => format!(
-                "{}:{}:{}",
-                STANDARD_NOTIFICATION_PREFIX,
-                timestamp,
-                channel_id.as_hyphenated()
-            ),
+            } => ChidMessageId::standard(*channel_id, *timestamp).to_string(),
         }
     }
 }
@@ -2,8 +2,9 @@ use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::extractors::{
     message_id::MessageId, notification_headers::NotificationHeaders, subscription::Subscription,
 };
+use crate::headers::util::get_header;
 use crate::server::AppState;
-use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use actix_web::{dev::Payload, error::PayloadError, web, FromRequest, HttpRequest};
 use autopush_common::util::{b64_encode_url, ms_since_epoch, sec_since_epoch};
 use cadence::CountedExt;
 use fernet::MultiFernet;
@@ -26,6 +27,8 @@ pub struct Notification {
     pub sort_key_timestamp: u64,
     /// The encrypted notification body
     pub data: Option<String>,
+    /// Opaque sender supplied metadata (from the `Push-Meta` header)
+    pub meta: Option<serde_json::Value>,
 }
 
 impl FromRequest for Notification {
@@ -50,6 +53,9 @@ impl FromRequest for Notification {
                     ApiErrorKind::PayloadError(e)
                 })?;
 
+            let meta =
+                Self::meta_from_request(&req, data.len(), app_state.settings.max_data_bytes)?;
+
             // Convert data to base64
             let data = if data.is_empty() {
                 None
@@ -61,7 +67,7 @@ impl FromRequest for Notification {
             let timestamp = sec_since_epoch();
             let sort_key_timestamp = ms_since_epoch();
             let message_id = Self::generate_message_id(
-                &app_state.fernet,
+                &app_state.internal_fernet,
                 subscription.user.uaid,
                 subscription.channel_id,
                 headers.topic.as_deref(),
@@ -85,6 +91,7 @@ impl FromRequest for Notification {
                 timestamp,
                 sort_key_timestamp,
                 data,
+                meta,
             })
         }
         .boxed_local()
@@ -104,6 +111,8 @@ impl From<Notification> for autopush_common::notification::Notification {
             data: notification.data,
             sortkey_timestamp,
             reliability_id: notification.subscription.reliability_id,
+            meta: notification.meta,
+            delivery_attempts: 0,
             headers: {
                 let headers: HashMap<String, String> = notification.headers.into();
                 if headers.is_empty() {
@@ -151,6 +160,24 @@ impl Notification {
         message_id.encrypt(fernet)
     }
 
+    /// Parse the optional JSON `Push-Meta` header. It's stored along with
+    /// the body, so counts towards the `max_data_bytes` payload limit
+    fn meta_from_request(
+        req: &HttpRequest,
+        data_len: usize,
+        max_data_bytes: usize,
+    ) -> ApiResult<Option<serde_json::Value>> {
+        let Some(meta) = get_header(req, "push-meta") else {
+            return Ok(None);
+        };
+        if data_len + meta.len() > max_data_bytes {
+            return Err(ApiErrorKind::PayloadError(PayloadError::Overflow.into()).into());
+        }
+        serde_json::from_str(meta)
+            .map(Some)
+            .map_err(|e| ApiErrorKind::InvalidMessageMeta(e.to_string()).into())
+    }
+
     pub fn has_topic(&self) -> bool {
         self.headers.topic.is_some()
     }
@@ -175,6 +202,9 @@ impl Notification {
         if let Some(reliability_id) = &self.subscription.reliability_id {
             map.insert("reliability_id", serde_json::to_value(reliability_id)?);
         }
+        if let Some(meta) = &self.meta {
+            map.insert("meta", meta.clone());
+        }
 
         if let Some(data) = &self.data {
             map.insert("data", serde_json::to_value(data)?);
@@ -186,3 +216,53 @@ impl Notification {
         Ok(map)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::TestRequest};
+    use serde_json::json;
+
+    use super::Notification;
+    use crate::error::ApiErrorKind;
+
+    #[test]
+    fn meta_header() {
+        let req = TestRequest::post().to_http_request();
+        assert_eq!(
+            Notification::meta_from_request(&req, 0, 4096).unwrap(),
+            None
+        );
+
+        let req = TestRequest::post()
+            .insert_header(("Push-Meta", r#"{"tracking_id": "abc123"}"#))
+            .to_http_request();
+        assert_eq!(
+            Notification::meta_from_request(&req, 0, 4096).unwrap(),
+            Some(json!({"tracking_id": "abc123"}))
+        );
+
+        let req = TestRequest::post()
+            .insert_header(("Push-Meta", "not json"))
+            .to_http_request();
+        assert!(matches!(
+            Notification::meta_from_request(&req, 0, 4096)
+                .unwrap_err()
+                .kind,
+            ApiErrorKind::InvalidMessageMeta(_)
+        ));
+    }
+
+    /// The header counts towards the payload limit along with the body
+    #[test]
+    fn meta_header_too_large() {
+        let meta = r#"{"tracking_id": "abc123"}"#;
+        let req = TestRequest::post()
+            .insert_header(("Push-Meta", meta))
+            .to_http_request();
+        assert!(Notification::meta_from_request(&req, 4096 - meta.len(), 4096).is_ok());
+
+        let err = Notification::meta_from_request(&req, 4096 - meta.len() + 1, 4096).unwrap_err();
+        assert_eq!(err.kind.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(err.kind.errno(), Some(104));
+    }
+}
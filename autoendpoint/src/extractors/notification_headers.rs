@@ -2,7 +2,14 @@ use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::headers::crypto_key::CryptoKeyHeader;
 use crate::headers::util::{get_header, get_owned_header};
 use actix_web::HttpRequest;
-use autopush_common::{util::InsertOpt, MAX_NOTIFICATION_TTL};
+use autopush_common::{
+    notification::{
+        ContentEncoding, CRYPTO_KEY_HEADER, ENCODING_HEADER, ENCRYPTION_HEADER,
+        ENCRYPTION_KEY_HEADER,
+    },
+    util::InsertOpt,
+    MAX_NOTIFICATION_TTL,
+};
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::cmp::min;
@@ -49,10 +56,10 @@ impl From<NotificationHeaders> for HashMap<String, String> {
     fn from(headers: NotificationHeaders) -> Self {
         let mut map = HashMap::new();
 
-        map.insert_opt("encoding", headers.encoding);
-        map.insert_opt("encryption", headers.encryption);
-        map.insert_opt("encryption_key", headers.encryption_key);
-        map.insert_opt("crypto_key", headers.crypto_key);
+        map.insert_opt(ENCODING_HEADER, headers.encoding);
+        map.insert_opt(ENCRYPTION_HEADER, headers.encryption);
+        map.insert_opt(ENCRYPTION_KEY_HEADER, headers.encryption_key);
+        map.insert_opt(CRYPTO_KEY_HEADER, headers.crypto_key);
 
         map
     }
@@ -120,10 +127,10 @@ impl NotificationHeaders {
             ApiErrorKind::InvalidEncryption("Missing Content-Encoding header".to_string())
         })?;
 
-        match encoding {
-            "aesgcm" => self.validate_encryption_04_rules()?,
-            "aes128gcm" => self.validate_encryption_06_rules()?,
-            _ => {
+        match encoding.parse::<ContentEncoding>() {
+            Ok(ContentEncoding::AesGcm) => self.validate_encryption_04_rules()?,
+            Ok(ContentEncoding::Aes128Gcm) => self.validate_encryption_06_rules()?,
+            Err(_) => {
                 return Err(ApiErrorKind::InvalidEncryption(
                     "Unknown Content-Encoding header".to_string(),
                 )
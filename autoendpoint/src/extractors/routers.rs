@@ -79,6 +79,11 @@ impl FromRequest for Routers {
                 metrics: app_state.metrics.clone(),
                 http: app_state.http.clone(),
                 endpoint_url: app_state.settings.endpoint_url(),
+                internal_fernet: app_state.internal_fernet.clone(),
+                internal_compression: app_state.settings.internal_compression,
+                internal_encoding: app_state.settings.internal_encoding,
+                min_store_ttl: app_state.settings.min_store_ttl,
+                store_only_channels: app_state.settings.store_only_channels,
             },
             fcm: app_state.fcm_router.clone(),
             apns: app_state.apns_router.clone(),
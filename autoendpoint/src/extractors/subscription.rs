@@ -1,26 +1,31 @@
 use std::borrow::Cow;
 use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use actix_web::{dev::Payload, web::Data, FromRequest, HttpRequest};
 use autopush_common::{
-    db::User,
+    db::{client::DbClient, User},
+    endpoint::ENDPOINT_EXPIRY_LEN,
     tags::Tags,
-    util::{b64_decode_std, b64_decode_url},
+    util::{b64_decode_std, b64_decode_url, sec_since_epoch},
 };
 use cadence::{CountedExt, StatsdClient};
+use fernet::MultiFernet;
 use futures::{future::LocalBoxFuture, FutureExt};
 use jsonwebtoken::{Algorithm, DecodingKey, Validation};
 use openssl::hash::MessageDigest;
 use uuid::Uuid;
 
-use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::error::{ApiError, ApiErrorKind, ApiResult, GoneReason};
 use crate::extractors::{
     token_info::{ApiVersion, TokenInfo},
     user::validate_user,
 };
 use crate::headers::{
     crypto_key::CryptoKeyHeader,
-    vapid::{VapidClaims, VapidError, VapidHeader, VapidHeaderWithKey, VapidVersionData},
+    vapid::{
+        validate_sub, VapidClaims, VapidError, VapidHeader, VapidHeaderWithKey, VapidVersionData,
+    },
 };
 use crate::metrics::Metrics;
 use crate::server::AppState;
@@ -57,15 +62,7 @@ impl FromRequest for Subscription {
             let metrics = Metrics::from(&app_state);
 
             // Decrypt the token
-            let token = app_state
-                .fernet
-                .decrypt(&repad_base64(&token_info.token))
-                .map_err(|e| {
-                    // Since we're decrypting and endpoint, we get a lot of spam links.
-                    // This can fill our logs.
-                    trace!("🔐 fernet: {:?}", e);
-                    ApiErrorKind::InvalidToken
-                })?;
+            let token = decrypt_token(&app_state.fernet, &token_info.token, &metrics)?;
 
             // Parse VAPID and extract public key.
             let vapid: Option<VapidHeaderWithKey> = parse_vapid(&token_info, &app_state.metrics)?
@@ -116,14 +113,22 @@ impl FromRequest for Subscription {
 
             trace!("UAID: {:?}, CHID: {:?}", uaid, channel_id);
 
-            let user = app_state
-                .db
-                .get_user(&uaid)
-                .await?
-                .ok_or(ApiErrorKind::NoSubscription)?;
+            // Throttled channels shouldn't cost any storage reads
+            if let Err(retry_after) = app_state.channel_limiter.check(&channel_id) {
+                metrics.clone().incr("notification.channel.rate_limited");
+                return Err(ApiErrorKind::ChannelRateLimited(retry_after).into());
+            }
+
+            let user = get_user(app_state.db.as_ref(), &uaid).await?;
 
             trace!("user: {:?}", &user);
-            validate_user(&user, &channel_id, &app_state).await?;
+            validate_user(
+                &user,
+                &channel_id,
+                app_state.db.as_ref(),
+                &app_state.metrics,
+            )
+            .await?;
 
             // Validate the VAPID JWT token and record the version
             if let Some(vapid) = &vapid {
@@ -134,6 +139,20 @@ impl FromRequest for Subscription {
                     .incr(&format!("updates.vapid.draft{:02}", vapid.vapid.version()))?;
             }
 
+            // v2 endpoints already include the hash of their key
+            if app_state.settings.vapid_key_pinning
+                && token_info.api_version == ApiVersion::Version1
+            {
+                check_vapid_key_pin(
+                    app_state.db.as_ref(),
+                    &uaid,
+                    &channel_id,
+                    vapid.as_ref(),
+                    &metrics,
+                )
+                .await?;
+            }
+
             Ok(Subscription {
                 user,
                 channel_id,
@@ -145,6 +164,59 @@ impl FromRequest for Subscription {
     }
 }
 
+/// The minimum length of a Fernet token: a version byte, 8 byte timestamp,
+/// 16 byte IV, at least one 16 byte ciphertext block and 32 byte HMAC
+const MIN_FERNET_TOKEN_LEN: usize = 1 + 8 + 16 + 16 + 32;
+
+/// Log decryption failures at most this often
+const DECRYPT_ERROR_LOG_INTERVAL: u64 = 60;
+
+/// When a decryption failure was last logged (in seconds since the epoch)
+static DECRYPT_ERROR_LOGGED: AtomicU64 = AtomicU64::new(0);
+
+/// Decrypt an endpoint token, recording why it failed on error
+fn decrypt_token(fernet: &MultiFernet, token: &str, metrics: &Metrics) -> ApiResult<Vec<u8>> {
+    let token = repad_base64(token);
+    fernet.decrypt(&token).map_err(|e| {
+        let kind = decrypt_error_kind(&token);
+        let mut tags = Tags::default();
+        tags.tags.insert("error".to_owned(), kind.to_owned());
+        metrics
+            .clone()
+            .incr_with_tags("endpoint.decrypt.error", Some(tags));
+        // Since we're decrypting an endpoint, we get a lot of spam links.
+        // Don't let those fill our logs, only log often enough to notice
+        // e.g. a bad key rotation
+        let now = sec_since_epoch();
+        let last = DECRYPT_ERROR_LOGGED.load(Ordering::Relaxed);
+        if now >= last + DECRYPT_ERROR_LOG_INTERVAL
+            && DECRYPT_ERROR_LOGGED
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            info!("🔐 Endpoint decryption failed: {kind}");
+        }
+        trace!("🔐 fernet: {:?}", e);
+        ApiErrorKind::InvalidToken.into()
+    })
+}
+
+/// Classify why a token failed decryption.
+///
+/// The fernet crate doesn't distinguish its failures, so check the token's
+/// structure: a well formed token that fails decryption was minted with a key
+/// we don't know (e.g. a rotated out key) or was tampered with. Endpoint
+/// tokens are decrypted without a TTL, so they never expire here.
+fn decrypt_error_kind(token: &str) -> &'static str {
+    let Ok(decoded) = b64_decode_url(token) else {
+        return "bad_encoding";
+    };
+    if decoded.len() < MIN_FERNET_TOKEN_LEN || decoded[0] != 0x80 {
+        return "bad_format";
+    }
+    "unknown_key"
+}
+
 /// Add back padding to a base64 string
 fn repad_base64(data: &str) -> Cow<'_, str> {
     let trailing_chars = data.len() % 4;
@@ -213,11 +285,22 @@ fn extract_public_key(vapid: VapidHeader, token_info: &TokenInfo) -> ApiResult<V
 
 /// `/webpush/v1/` validations
 fn version_1_validation(token: &[u8]) -> ApiResult<()> {
-    if token.len() != 32 {
+    match token.len() {
+        // Legacy tokens without an expiry
+        32 => Ok(()),
+        len if len == 32 + ENDPOINT_EXPIRY_LEN => validate_expiry(&token[32..]),
         // Corrupted token
-        return Err(ApiErrorKind::InvalidToken.into());
+        _ => Err(ApiErrorKind::InvalidToken.into()),
     }
+}
 
+/// Reject endpoints whose token's expiry has passed
+fn validate_expiry(expiry: &[u8]) -> ApiResult<()> {
+    // Note: It is safe to unwrap as the callers verify the slice length
+    let expiry = u64::from_be_bytes(expiry.try_into().unwrap());
+    if expiry <= sec_since_epoch() {
+        return Err(ApiErrorKind::ExpiredEndpoint.into());
+    }
     Ok(())
 }
 
@@ -240,20 +323,20 @@ fn decode_public_key(public_key: &str) -> ApiResult<Vec<u8>> {
 
 /// `/webpush/v2/` validations
 fn version_2_validation(token: &[u8], vapid: Option<&VapidHeaderWithKey>) -> ApiResult<()> {
-    if token.len() != 64 {
+    match token.len() {
+        // Legacy tokens without an expiry
+        64 => (),
+        len if len == 64 + ENDPOINT_EXPIRY_LEN => validate_expiry(&token[64..])?,
         // Corrupted token
-        return Err(ApiErrorKind::InvalidToken.into());
+        _ => return Err(ApiErrorKind::InvalidToken.into()),
     }
 
     // Verify that the sender is authorized to send notifications.
-    // The last 32 bytes of the token is the hashed public key.
-    let token_key = &token[32..];
+    // Bytes 32 to 64 of the token are the hashed public key.
+    let token_key = &token[32..64];
     let public_key = &vapid.ok_or(VapidError::MissingKey)?.public_key;
 
-    // Hash the VAPID public key
-    let public_key = decode_public_key(public_key)?;
-    let key_hash = openssl::hash::hash(MessageDigest::sha256(), &public_key)
-        .map_err(ApiErrorKind::TokenHashValidation)?;
+    let key_hash = hash_public_key(public_key)?;
 
     // Verify that the VAPID public key equals the (expected) token public key
     if !openssl::memcmp::eq(&key_hash, token_key) {
@@ -263,6 +346,63 @@ fn version_2_validation(token: &[u8], vapid: Option<&VapidHeaderWithKey>) -> Api
     Ok(())
 }
 
+/// Hash (SHA-256) the VAPID public key
+fn hash_public_key(public_key: &str) -> ApiResult<Vec<u8>> {
+    let public_key = decode_public_key(public_key)?;
+    Ok(openssl::hash::hash(MessageDigest::sha256(), &public_key)
+        .map_err(ApiErrorKind::TokenHashValidation)?
+        .to_vec())
+}
+
+/// Read the subscription's user, which is Gone once expired (or dropped)
+async fn get_user(db: &dyn DbClient, uaid: &Uuid) -> ApiResult<User> {
+    db.get_user(uaid)
+        .await?
+        .ok_or_else(|| ApiErrorKind::NoSubscription(GoneReason::UserExpired).into())
+}
+
+/// Verify the VAPID public key matches the one pinned to the channel, pinning
+/// it when there's none yet.
+///
+/// Once pinned, messages without VAPID are also rejected.
+async fn check_vapid_key_pin(
+    db: &dyn DbClient,
+    uaid: &Uuid,
+    channel_id: &Uuid,
+    vapid: Option<&VapidHeaderWithKey>,
+    metrics: &Metrics,
+) -> ApiResult<()> {
+    let key_hash = vapid
+        .map(|vapid| hash_public_key(&vapid.public_key))
+        .transpose()?;
+    let mut pinned = db.get_vapid_key_pin(uaid, channel_id).await?;
+    if let (None, Some(key_hash)) = (&pinned, &key_hash) {
+        if db.pin_vapid_key(uaid, channel_id, key_hash).await? {
+            return Ok(());
+        }
+        // Another request pinned it first (or the channel's gone): verify
+        // against its pin
+        pinned = db.get_vapid_key_pin(uaid, channel_id).await?;
+    }
+    match (pinned, key_hash) {
+        (None, _) => (),
+        (Some(pinned), Some(key_hash))
+            if pinned.len() == key_hash.len() && openssl::memcmp::eq(&pinned, &key_hash) => {}
+        (Some(_), _) => {
+            let mut tags = Tags::default();
+            tags.tags.insert(
+                "error".to_owned(),
+                VapidError::KeyPinMismatch.as_metric().to_owned(),
+            );
+            metrics
+                .clone()
+                .incr_with_tags("notification.auth.error", Some(tags));
+            return Err(VapidError::KeyPinMismatch.into());
+        }
+    }
+    Ok(())
+}
+
 // Perform a very brain dead conversion of a string to a CamelCaseVersion
 fn term_to_label(term: &str) -> String {
     term.split(' ').fold("".to_owned(), |prev, word: &str| {
@@ -390,21 +530,40 @@ fn validate_vapid_jwt(
         return Err(VapidError::FutureExpirationToken.into());
     }
 
+    if let Err(e) = validate_sub(token_data.claims.sub.as_deref()) {
+        let mut tags = Tags::default();
+        tags.tags
+            .insert("error".to_owned(), e.as_metric().to_owned());
+        metrics
+            .clone()
+            .incr_with_tags("notification.auth.bad_vapid.sub", Some(tags));
+        if !settings.vapid_sub_warn_only {
+            return Err(e.into());
+        }
+        warn!("🔐 Vapid: Allowing an invalid sub: {}", e);
+    }
+
     Ok(())
 }
 
 #[cfg(test)]
 pub mod tests {
-    use super::{term_to_label, validate_vapid_jwt, VapidClaims};
-    use crate::error::ApiErrorKind;
+    use super::{
+        check_vapid_key_pin, decrypt_token, get_user, hash_public_key, term_to_label,
+        validate_vapid_jwt, version_1_validation, version_2_validation, VapidClaims,
+    };
+    use crate::error::{ApiErrorKind, GoneReason};
     use crate::extractors::subscription::repad_base64;
     use crate::headers::vapid::{VapidError, VapidHeader, VapidHeaderWithKey, VapidVersionData};
     use crate::metrics::Metrics;
     use crate::settings::Settings;
 
-    use autopush_common::util::b64_decode_std;
+    use autopush_common::db::mock::MockDbClient;
+    use autopush_common::util::{b64_decode_std, sec_since_epoch};
+    use fernet::{Fernet, MultiFernet};
     use lazy_static::lazy_static;
     use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
 
     pub const PUB_KEY: &str =
         "BM3bVjW_wuZC54alIbqjTbaBNtthriVtdZlchOyOSdbVYeYQu2i5inJdft7jUWIAy4O9xHBbY196Gf-1odb8hds";
@@ -441,6 +600,54 @@ pub mod tests {
         }
     }
 
+    /// Decrypt a token, returning the error kind recorded on failure
+    fn decrypt_error(fernet: &MultiFernet, token: &str) -> Option<String> {
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = Metrics::from(cadence::StatsdClient::builder("", sink).build());
+        let result = decrypt_token(fernet, token, &metrics);
+        drop(metrics);
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        match result {
+            Ok(_) => {
+                assert!(sent.is_empty(), "{sent:?}");
+                None
+            }
+            Err(e) => {
+                assert!(matches!(e.kind, ApiErrorKind::InvalidToken));
+                assert_eq!(sent.len(), 1, "{sent:?}");
+                let metric = sent[0]
+                    .strip_prefix("endpoint.decrypt.error:1|c|#error:")
+                    .unwrap_or_else(|| panic!("{sent:?}"));
+                Some(metric.to_owned())
+            }
+        }
+    }
+
+    #[test]
+    fn decrypt_token_errors() {
+        let fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+        let token = fernet.encrypt(&[0; 32]);
+        assert_eq!(decrypt_error(&fernet, token.trim_end_matches('=')), None);
+
+        assert_eq!(
+            decrypt_error(&fernet, "garbage!").as_deref(),
+            Some("bad_encoding")
+        );
+        assert_eq!(
+            decrypt_error(&fernet, "Z2FyYmFnZQ").as_deref(),
+            Some("bad_format")
+        );
+        // Minted with a key that's not (or no longer) current
+        let old = Fernet::new(&Fernet::generate_key()).unwrap();
+        assert_eq!(
+            decrypt_error(&fernet, &old.encrypt(&[0; 32])).as_deref(),
+            Some("unknown_key")
+        );
+    }
+
     #[test]
     fn repad_base64_1_padding() {
         assert_eq!(repad_base64("Zm9vYmE"), "Zm9vYmE=")
@@ -509,6 +716,63 @@ pub mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn vapid_sub_valid() {
+        let domain = "https://push.services.mozilla.org";
+        let test_settings = Settings {
+            endpoint_url: domain.to_owned(),
+            ..Default::default()
+        };
+        for sub in ["mailto:admin@example.com", "https://example.com/contact"] {
+            let header = make_vapid(
+                sub,
+                domain,
+                VapidClaims::default_exp() - 100,
+                PUB_KEY.to_owned(),
+            );
+            let result = validate_vapid_jwt(&header, &test_settings, &Metrics::noop());
+            assert!(result.is_ok(), "{sub}");
+        }
+    }
+
+    #[test]
+    fn vapid_sub_invalid() {
+        let domain = "https://push.services.mozilla.org";
+        let mut test_settings = Settings {
+            endpoint_url: domain.to_owned(),
+            ..Default::default()
+        };
+        let header = make_vapid(
+            "admin@example.com",
+            domain,
+            VapidClaims::default_exp() - 100,
+            PUB_KEY.to_owned(),
+        );
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = Metrics::from(cadence::StatsdClient::builder("", sink).build());
+        let result = validate_vapid_jwt(&header, &test_settings, &metrics);
+        assert!(matches!(
+            result.unwrap_err().kind,
+            ApiErrorKind::VapidError(VapidError::SubInvalid)
+        ));
+        drop(metrics);
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(
+            sent.iter()
+                .any(|m| m.starts_with("notification.auth.bad_vapid.sub:1|c")
+                    && m.contains("error:invalid_sub")),
+            "{sent:?}"
+        );
+
+        // May be downgraded to a warning
+        test_settings.vapid_sub_warn_only = true;
+        let result = validate_vapid_jwt(&header, &test_settings, &Metrics::noop());
+        assert!(result.is_ok());
+    }
+
     #[test]
     fn vapid_exp_is_string() {
         #[derive(Debug, Deserialize, Serialize)]
@@ -662,4 +926,173 @@ pub mod tests {
         );
         assert_eq!("UntouchedField", term_to_label("UntouchedField"));
     }
+
+    #[test]
+    fn endpoint_expiry() {
+        let ids = [7u8; 32];
+        let token = |suffix: &[u8]| [&ids[..], suffix].concat();
+        // Legacy tokens never expire
+        assert!(version_1_validation(&ids).is_ok());
+
+        let fresh = (sec_since_epoch() + 60).to_be_bytes();
+        assert!(version_1_validation(&token(&fresh)).is_ok());
+
+        let expired = (sec_since_epoch() - 60).to_be_bytes();
+        assert!(matches!(
+            version_1_validation(&token(&expired)).unwrap_err().kind,
+            ApiErrorKind::ExpiredEndpoint
+        ));
+        assert!(matches!(
+            version_1_validation(&token(&[0; 4])).unwrap_err().kind,
+            ApiErrorKind::InvalidToken
+        ));
+
+        // The expiry is checked before the VAPID key
+        let key_hash = [1u8; 32];
+        let v2_token = |suffix: &[u8]| [&ids[..], &key_hash[..], suffix].concat();
+        assert!(matches!(
+            version_2_validation(&v2_token(&expired), None)
+                .unwrap_err()
+                .kind,
+            ApiErrorKind::ExpiredEndpoint
+        ));
+        assert!(matches!(
+            version_2_validation(&v2_token(&fresh), None)
+                .unwrap_err()
+                .kind,
+            ApiErrorKind::VapidError(VapidError::MissingKey)
+        ));
+        assert!(matches!(
+            version_2_validation(&v2_token(&[]), None).unwrap_err().kind,
+            ApiErrorKind::VapidError(VapidError::MissingKey)
+        ));
+    }
+
+    #[tokio::test]
+    async fn missing_user_expired() {
+        let mut db = MockDbClient::new();
+        db.expect_get_user().times(1).return_once(|_| Ok(None));
+
+        let err = get_user(&db, &Uuid::new_v4()).await.unwrap_err();
+        assert_eq!(err.kind.gone_reason(), Some(GoneReason::UserExpired));
+    }
+
+    #[tokio::test]
+    async fn vapid_key_pin_first_use() {
+        let vapid = make_vapid(
+            "mailto:admin@example.com",
+            "http://localhost:8080",
+            VapidClaims::default_exp(),
+            PUB_KEY.to_owned(),
+        );
+        let key_hash = hash_public_key(PUB_KEY).unwrap();
+        let mut db = MockDbClient::new();
+        db.expect_get_vapid_key_pin()
+            .times(1)
+            .return_once(|_, _| Ok(None));
+        db.expect_pin_vapid_key()
+            .withf(move |_, _, hash| hash.to_vec() == key_hash)
+            .times(1)
+            .return_once(|_, _, _| Ok(true));
+        check_vapid_key_pin(
+            &db,
+            &Uuid::new_v4(),
+            &Uuid::new_v4(),
+            Some(&vapid),
+            &Metrics::noop(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn vapid_key_pin_first_use_race() {
+        let vapid = make_vapid(
+            "mailto:admin@example.com",
+            "http://localhost:8080",
+            VapidClaims::default_exp(),
+            PUB_KEY.to_owned(),
+        );
+        let mut db = MockDbClient::new();
+        let mut seq = mockall::Sequence::new();
+        db.expect_get_vapid_key_pin()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(None));
+        // A concurrent request pinned a different key first
+        db.expect_pin_vapid_key()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _, _| Ok(false));
+        db.expect_get_vapid_key_pin()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(Some(vec![1; 32])));
+        let err = check_vapid_key_pin(
+            &db,
+            &Uuid::new_v4(),
+            &Uuid::new_v4(),
+            Some(&vapid),
+            &Metrics::noop(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.kind,
+            ApiErrorKind::VapidError(VapidError::KeyPinMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn vapid_key_pin_matches() {
+        let vapid = make_vapid(
+            "mailto:admin@example.com",
+            "http://localhost:8080",
+            VapidClaims::default_exp(),
+            PUB_KEY.to_owned(),
+        );
+        let key_hash = hash_public_key(PUB_KEY).unwrap();
+        let mut db = MockDbClient::new();
+        db.expect_get_vapid_key_pin()
+            .times(1)
+            .return_once(move |_, _| Ok(Some(key_hash)));
+        check_vapid_key_pin(
+            &db,
+            &Uuid::new_v4(),
+            &Uuid::new_v4(),
+            Some(&vapid),
+            &Metrics::noop(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn vapid_key_pin_mismatch() {
+        let vapid = make_vapid(
+            "mailto:admin@example.com",
+            "http://localhost:8080",
+            VapidClaims::default_exp(),
+            PUB_KEY.to_owned(),
+        );
+        for vapid in [Some(&vapid), None] {
+            let mut db = MockDbClient::new();
+            db.expect_get_vapid_key_pin()
+                .times(1)
+                .return_once(|_, _| Ok(Some(vec![1; 32])));
+            let err = check_vapid_key_pin(
+                &db,
+                &Uuid::new_v4(),
+                &Uuid::new_v4(),
+                vapid,
+                &Metrics::noop(),
+            )
+            .await
+            .unwrap_err();
+            assert!(matches!(
+                err.kind,
+                ApiErrorKind::VapidError(VapidError::KeyPinMismatch)
+            ));
+        }
+    }
 }
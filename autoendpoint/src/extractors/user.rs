@@ -1,8 +1,7 @@
 //! User validations
 
-use crate::error::{ApiErrorKind, ApiResult};
+use crate::error::{ApiErrorKind, ApiResult, GoneReason};
 use crate::extractors::routers::RouterType;
-use crate::server::AppState;
 use actix_http::StatusCode;
 use autopush_common::db::{client::DbClient, User};
 use cadence::{CountedExt, StatsdClient};
@@ -17,14 +16,15 @@ use uuid::Uuid;
 pub async fn validate_user(
     user: &User,
     channel_id: &Uuid,
-    app_state: &AppState,
+    db: &dyn DbClient,
+    metrics: &StatsdClient,
 ) -> ApiResult<RouterType> {
     let router_type = match user.router_type.parse::<RouterType>() {
         Ok(router_type) => router_type,
         Err(_) => {
             debug!("Unknown router type, dropping user"; "user" => ?user);
-            drop_user(user.uaid, app_state.db.as_ref(), &app_state.metrics).await?;
-            return Err(ApiErrorKind::NoSubscription.into());
+            drop_user(user.uaid, db, metrics).await?;
+            return Err(ApiErrorKind::NoSubscription(GoneReason::UserExpired).into());
         }
     };
 
@@ -35,19 +35,18 @@ pub async fn validate_user(
     if router_type == RouterType::GCM {
         debug!("Encountered GCM record, dropping user"; "user" => ?user);
         // record the bridge error for accounting reasons.
-        app_state
-            .metrics
+        metrics
             .incr_with_tags("notification.bridge.error")
             .with_tag("platform", "gcm")
             .with_tag("reason", "gcm_kill")
             .with_tag("error", &StatusCode::GONE.to_string())
             .send();
-        drop_user(user.uaid, app_state.db.as_ref(), &app_state.metrics).await?;
+        drop_user(user.uaid, db, metrics).await?;
         return Err(ApiErrorKind::Router(crate::routers::RouterError::NotFound).into());
     }
 
     if router_type == RouterType::WebPush {
-        validate_webpush_user(user, channel_id, app_state.db.as_ref()).await?;
+        validate_webpush_user(user, channel_id, db).await?;
     }
 
     Ok(router_type)
@@ -59,7 +58,7 @@ async fn validate_webpush_user(user: &User, channel_id: &Uuid, db: &dyn DbClient
     let channel_ids = db.get_channels(&user.uaid).await?;
 
     if !channel_ids.contains(channel_id) {
-        return Err(ApiErrorKind::NoSubscription.into());
+        return Err(ApiErrorKind::NoSubscription(GoneReason::ChannelUnsubscribed).into());
     }
 
     Ok(())
@@ -76,3 +75,49 @@ pub async fn drop_user(uaid: Uuid, db: &dyn DbClient, metrics: &StatsdClient) ->
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use autopush_common::db::{mock::MockDbClient, User};
+    use cadence::{NopMetricSink, StatsdClient};
+    use uuid::Uuid;
+
+    use super::validate_user;
+    use crate::error::GoneReason;
+
+    #[tokio::test]
+    async fn unknown_router_type_expired() {
+        let user = User {
+            router_type: "unknown".to_owned(),
+            ..Default::default()
+        };
+        let mut db = MockDbClient::new();
+        db.expect_remove_user().times(1).return_once(|_| Ok(()));
+        let metrics = StatsdClient::from_sink("autopush", NopMetricSink);
+
+        let err = validate_user(&user, &Uuid::new_v4(), &db, &metrics)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind.gone_reason(), Some(GoneReason::UserExpired));
+    }
+
+    #[tokio::test]
+    async fn missing_channel_unsubscribed() {
+        let user = User::default();
+        let mut db = MockDbClient::new();
+        db.expect_get_channels()
+            .times(1)
+            .return_once(|_| Ok(HashSet::from([Uuid::new_v4()])));
+        let metrics = StatsdClient::from_sink("autopush", NopMetricSink);
+
+        let err = validate_user(&user, &Uuid::new_v4(), &db, &metrics)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.kind.gone_reason(),
+            Some(GoneReason::ChannelUnsubscribed)
+        );
+    }
+}
@@ -1,45 +1,18 @@
-use crate::headers::util::split_key_value;
-use std::collections::HashMap;
+use autopush_common::notification::CryptoParams;
 
 /// Parses the Crypto-Key header (and similar headers) described by
 /// `http://tools.ietf.org/html/draft-ietf-httpbis-encryption-encoding-00#section-4`
-pub struct CryptoKeyHeader {
-    /// The sections (comma separated) and their items (key-value semicolon separated)
-    sections: Vec<HashMap<String, String>>,
-}
+pub struct CryptoKeyHeader(CryptoParams);
 
 impl CryptoKeyHeader {
     /// Parse a Crypto-Key header
     pub fn parse(header: &str) -> Option<Self> {
-        let mut sections = Vec::new();
-
-        for section_str in header.split(',') {
-            let mut section = HashMap::new();
-
-            for item_str in section_str.split(';') {
-                let (key, value) = split_key_value(item_str)?;
-
-                section.insert(
-                    key.trim().to_owned(),
-                    value.trim_matches(&[' ', '"'] as &[char]).to_owned(),
-                );
-            }
-
-            sections.push(section);
-        }
-
-        Some(Self { sections })
+        CryptoParams::parse(header).map(Self)
     }
 
     /// Get the value of the first item with the given key
     pub fn get_by_key(&self, key: &str) -> Option<&str> {
-        for section in &self.sections {
-            if let Some(value) = section.get(key) {
-                return Some(value.as_str());
-            }
-        }
-
-        None
+        self.0.get(key)
     }
 }
 
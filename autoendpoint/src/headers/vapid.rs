@@ -152,19 +152,12 @@ impl VapidHeader {
             warn!("🔐 Vapid: {:?} {:?}", e, &self.token);
         })?;
 
-        if let Some(sub) = data.sub {
-            if !sub.starts_with("mailto:") && !sub.starts_with("https://") {
-                info!("🔐 Vapid: Bad Format {:?}", sub);
-                return Err(VapidError::SubBadFormat);
-            }
-            if sub.is_empty() {
-                info!("🔐 Empty Vapid sub");
-                return Err(VapidError::SubEmpty);
-            }
-            info!("🔐 Vapid: sub: {:?}", sub);
-            return Ok(sub.to_owned());
-        }
-        Err(VapidError::SubMissing)
+        validate_sub(data.sub.as_deref()).inspect_err(|e| {
+            info!("🔐 Vapid: {} {:?}", e, data.sub);
+        })?;
+        let sub = data.sub.unwrap_or_default();
+        info!("🔐 Vapid: sub: {:?}", sub);
+        Ok(sub)
     }
 
     pub fn claims(&self) -> Result<VapidClaims, VapidError> {
@@ -172,6 +165,28 @@ impl VapidHeader {
     }
 }
 
+/// Validate the format of a VAPID `sub` claim: RFC 8292 requires a `mailto:`
+/// or `https:` URI.
+pub fn validate_sub(sub: Option<&str>) -> Result<(), VapidError> {
+    let sub = sub.ok_or(VapidError::SubMissing)?;
+    if sub.is_empty() {
+        return Err(VapidError::SubEmpty);
+    }
+    let uri = url::Url::parse(sub).map_err(|_| VapidError::SubInvalid)?;
+    let valid = match uri.scheme() {
+        "mailto" => uri
+            .path()
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty()),
+        "https" => uri.host_str().is_some_and(|host| !host.is_empty()),
+        _ => false,
+    };
+    if !valid {
+        return Err(VapidError::SubBadFormat);
+    }
+    Ok(())
+}
+
 #[derive(Debug, Error, Eq, PartialEq)]
 pub enum VapidError {
     #[error("Missing VAPID token")]
@@ -188,6 +203,8 @@ pub enum VapidError {
     InvalidExpiry,
     #[error("VAPID public key mismatch")]
     KeyMismatch,
+    #[error("VAPID public key does not match the subscription's pinned key")]
+    KeyPinMismatch,
     #[error("The VAPID token expiration is too long")]
     FutureExpirationToken,
     #[error("Unknown auth scheme")]
@@ -212,6 +229,7 @@ impl VapidError {
             Self::InvalidAudience => "invalid_audience",
             Self::InvalidExpiry => "invalid_expiry",
             Self::KeyMismatch => "key_mismatch",
+            Self::KeyPinMismatch => "key_pin_mismatch",
             Self::FutureExpirationToken => "future_expiration_token",
             Self::UnknownScheme => "unknown_scheme",
             Self::SubInvalid => "invalid_sub",
@@ -225,7 +243,7 @@ impl VapidError {
 #[cfg(test)]
 mod tests {
 
-    use super::{VapidClaims, VapidHeader, VapidVersionData};
+    use super::{validate_sub, VapidClaims, VapidError, VapidHeader, VapidVersionData};
 
     // This was generated externally using the py_vapid package.
     const VALID_HEADER: &str = "vapid t=eyJ0eXAiOiJKV1QiLCJhbGciOiJFUzI1NiJ9.ey\
@@ -267,4 +285,24 @@ mod tests {
             Ok("mailto:admin@example.com".to_owned())
         )
     }
+
+    #[test]
+    fn sub_validation() {
+        assert_eq!(validate_sub(Some("mailto:admin@example.com")), Ok(()));
+        assert_eq!(validate_sub(Some("https://example.com/contact")), Ok(()));
+
+        assert_eq!(validate_sub(None), Err(VapidError::SubMissing));
+        assert_eq!(validate_sub(Some("")), Err(VapidError::SubEmpty));
+        assert_eq!(
+            validate_sub(Some("admin@example.com")),
+            Err(VapidError::SubInvalid)
+        );
+        for sub in ["mailto:admin", "mailto:@example.com", "http://example.com"] {
+            assert_eq!(
+                validate_sub(Some(sub)),
+                Err(VapidError::SubBadFormat),
+                "{sub}"
+            );
+        }
+    }
 }
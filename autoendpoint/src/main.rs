@@ -9,6 +9,7 @@ mod error;
 mod extractors;
 mod headers;
 mod metrics;
+mod rate_limit;
 mod routers;
 mod routes;
 mod server;
@@ -17,6 +18,7 @@ mod settings;
 use docopt::Docopt;
 use serde::Deserialize;
 use std::error::Error;
+use std::time::Instant;
 
 use autopush_common::logging;
 
@@ -26,18 +28,41 @@ Usage: autoendpoint [options]
 Options:
     -h, --help              Show this message
     --config=CONFIGFILE     AutoEndpoint configuration file path.
+    --check-config          Validate the configuration, print the resolved settings and exit.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_config: Option<String>,
+    flag_check_config: bool,
+}
+
+/// Load and validate the settings, printing the resolved settings (with their
+/// secrets redacted). Returns the process exit code.
+fn check_config(filename: &Option<String>) -> i32 {
+    let result = settings::Settings::with_env_and_config_file(filename)
+        .and_then(|settings| settings.validate().map(|_| settings));
+    match result {
+        Ok(settings) => {
+            println!("{:#?}", settings.redacted());
+            0
+        }
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            1
+        }
+    }
 }
 
 #[actix_rt::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
+    if args.flag_check_config {
+        std::process::exit(check_config(&args.flag_config));
+    }
     let settings = settings::Settings::with_env_and_config_file(&args.flag_config)?;
     let host_port = format!("{}:{}", &settings.host, &settings.port);
     logging::init_logging(
@@ -47,6 +72,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     )
     .expect("Logging failed to initialize");
     debug!("Starting up autoendpoint...");
+    if let Some(endpoint) = &settings.otel_endpoint {
+        autopush_common::otel::init(env!("CARGO_PKG_NAME"), endpoint)?;
+    }
 
     let _sentry = sentry::init(sentry::ClientOptions {
         release: sentry::release_name!(),
@@ -55,7 +83,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     });
 
     // Run server...
-    let server = server::Server::with_settings(settings)
+    let (server, metrics) = server::Server::with_settings(settings)
         .await
         .expect("Could not start server");
     info!(
@@ -66,7 +94,43 @@ async fn main() -> Result<(), Box<dyn Error>> {
     server.await?;
 
     // Shutdown
+    autopush_common::metrics::shutdown(&metrics, started.elapsed());
+    autopush_common::otel::shutdown();
     info!("Shutting down autoendpoint");
     logging::reset_logging();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::check_config;
+
+    fn config_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn check_config_exit_code() {
+        let file = config_file("max_data_bytes = 4096\n");
+        assert_eq!(
+            check_config(&Some(file.path().to_string_lossy().into_owned())),
+            0
+        );
+
+        let file = config_file("crypto_keys = \"[not-a-fernet-key]\"\n");
+        assert_eq!(
+            check_config(&Some(file.path().to_string_lossy().into_owned())),
+            1
+        );
+
+        let file = config_file("max_data_bytes = \"lots\"\n");
+        assert_eq!(
+            check_config(&Some(file.path().to_string_lossy().into_owned())),
+            1
+        );
+    }
+}
@@ -170,6 +170,8 @@ pub fn metrics_from_settings(settings: &Settings) -> Result<StatsdClient, Metric
         &settings.statsd_label,
         &settings.statsd_host,
         settings.statsd_port,
+        &settings.statsd_cluster,
+        settings.metrics_required,
     )?
     .build();
     Ok(client)
@@ -0,0 +1,225 @@
+//! Per channel rate limiting of incoming notifications
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+/// Rotate the tracked channels early once this many were seen in the
+/// current generation
+const MAX_TRACKED_CHANNELS: usize = 100_000;
+
+/// A token bucket per channel: each notification takes a token, with tokens
+/// refilled at `rate` per second up to `burst`.
+///
+/// Buckets are tracked in two generations, rotated every `period` (the time
+/// an empty bucket takes to fully refill): a bucket untouched for a whole
+/// generation is full, equivalent to an untracked one, so it's dropped
+/// without scanning. A generation exceeding `MAX_TRACKED_CHANNELS` rotates
+/// early, which may forget (be lenient to) some recently throttled
+/// channels.
+///
+/// Buckets live in this node's memory: with multiple nodes behind a load
+/// balancer, a channel's effective limit is multiplied by the node count.
+pub struct ChannelRateLimiter {
+    /// Tokens refilled per second (0 disables limiting)
+    rate: f64,
+    /// The maximum number of tokens (notifications sent at once)
+    burst: f64,
+    /// How long a bucket takes to refill from empty
+    period: Duration,
+    buckets: Mutex<Generations>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Generations {
+    /// Buckets used since `rotated`
+    current: HashMap<Uuid, Bucket>,
+    /// Buckets last used in the generation before
+    previous: HashMap<Uuid, Bucket>,
+    rotated: Instant,
+}
+
+impl Generations {
+    /// Begin a new generation when due, returning the expired buckets (to
+    /// be dropped outside of the lock)
+    fn rotate(&mut self, now: Instant, period: Duration) -> Vec<HashMap<Uuid, Bucket>> {
+        let idle = now.saturating_duration_since(self.rotated);
+        if idle >= period.saturating_mul(2) {
+            self.rotated = now;
+            vec![mem::take(&mut self.current), mem::take(&mut self.previous)]
+        } else if idle >= period || self.current.len() >= MAX_TRACKED_CHANNELS {
+            self.rotated = now;
+            vec![mem::replace(
+                &mut self.previous,
+                mem::take(&mut self.current),
+            )]
+        } else {
+            vec![]
+        }
+    }
+}
+
+impl ChannelRateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        let period = if rate > 0.0 {
+            Duration::try_from_secs_f64(burst / rate).unwrap_or(Duration::MAX)
+        } else {
+            Duration::ZERO
+        };
+        Self {
+            rate,
+            burst,
+            period,
+            buckets: Mutex::new(Generations {
+                current: Default::default(),
+                previous: Default::default(),
+                rotated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token for a notification to `channel_id`.
+    ///
+    /// Returns the number of seconds until a token's available when the
+    /// channel's exceeded its rate
+    pub fn check(&self, channel_id: &Uuid) -> Result<(), u64> {
+        self.check_at(channel_id, Instant::now())
+    }
+
+    fn check_at(&self, channel_id: &Uuid, now: Instant) -> Result<(), u64> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().expect("ChannelRateLimiter poisoned");
+        let expired = buckets.rotate(now, self.period);
+        let Generations {
+            current, previous, ..
+        } = &mut *buckets;
+        let bucket = current.entry(*channel_id).or_insert_with(|| {
+            previous.remove(channel_id).unwrap_or(Bucket {
+                tokens: self.burst,
+                updated: now,
+            })
+        });
+        let tokens = self.refill(bucket, now);
+        let result = if tokens < 1.0 {
+            Err(((1.0 - tokens) / self.rate).ceil() as u64)
+        } else {
+            bucket.tokens = tokens - 1.0;
+            Ok(())
+        };
+        drop(buckets);
+        drop(expired);
+        result
+    }
+
+    /// Refill a bucket's tokens for the time elapsed since it was last
+    /// updated
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> (usize, usize) {
+        let buckets = self.buckets.lock().unwrap();
+        (buckets.current.len(), buckets.previous.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use uuid::Uuid;
+
+    use super::{ChannelRateLimiter, MAX_TRACKED_CHANNELS};
+
+    #[test]
+    fn burst_throttled() {
+        let limiter = ChannelRateLimiter::new(1.0, 5);
+        let channel_id = Uuid::new_v4();
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(limiter.check_at(&channel_id, now).is_ok());
+        }
+        assert_eq!(limiter.check_at(&channel_id, now), Err(1));
+        // Other channels are unaffected
+        assert!(limiter.check_at(&Uuid::new_v4(), now).is_ok());
+        // A token's refilled after a second
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at(&channel_id, later).is_ok());
+        assert!(limiter.check_at(&channel_id, later).is_err());
+    }
+
+    #[test]
+    fn slower_rate_passes() {
+        let limiter = ChannelRateLimiter::new(2.0, 1);
+        let channel_id = Uuid::new_v4();
+        let start = Instant::now();
+        for i in 0..20 {
+            let now = start + Duration::from_millis(500 * i);
+            assert!(limiter.check_at(&channel_id, now).is_ok());
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let limiter = ChannelRateLimiter::new(0.0, 0);
+        let channel_id = Uuid::new_v4();
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.check_at(&channel_id, now).is_ok());
+        }
+    }
+
+    #[test]
+    fn idle_channels_evicted() {
+        // Buckets refill from empty in 2 seconds
+        let limiter = ChannelRateLimiter::new(1.0, 2);
+        let channel_id = Uuid::new_v4();
+        let start = Instant::now();
+        assert!(limiter.check_at(&channel_id, start).is_ok());
+        assert!(limiter.check_at(&channel_id, start).is_ok());
+        assert!(limiter.check_at(&channel_id, start).is_err());
+        assert!(limiter
+            .check_at(&channel_id, start + Duration::from_millis(1500))
+            .is_ok());
+        assert_eq!(limiter.tracked(), (1, 0));
+
+        // Carried over from the previous generation (with half a token
+        // left), so only one more's available
+        let rotated = start + Duration::from_secs(2);
+        assert!(limiter.check_at(&Uuid::new_v4(), rotated).is_ok());
+        assert_eq!(limiter.tracked(), (1, 1));
+        let later = start + Duration::from_millis(2500);
+        assert!(limiter.check_at(&channel_id, later).is_ok());
+        assert!(limiter.check_at(&channel_id, later).is_err());
+        assert_eq!(limiter.tracked(), (2, 0));
+
+        // Both generations are idle
+        assert!(limiter
+            .check_at(&Uuid::new_v4(), rotated + Duration::from_secs(4))
+            .is_ok());
+        assert_eq!(limiter.tracked(), (1, 0));
+    }
+
+    #[test]
+    fn tracked_channels_bounded() {
+        let limiter = ChannelRateLimiter::new(1.0, 10);
+        let now = Instant::now();
+        for _ in 0..MAX_TRACKED_CHANNELS * 2 + 1 {
+            assert!(limiter.check_at(&Uuid::new_v4(), now).is_ok());
+        }
+        assert_eq!(limiter.tracked(), (1, MAX_TRACKED_CHANNELS));
+    }
+}
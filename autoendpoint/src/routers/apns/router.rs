@@ -7,6 +7,7 @@ use crate::routers::apns::error::ApnsError;
 use crate::routers::apns::settings::{ApnsChannel, ApnsSettings};
 use crate::routers::common::{
     build_message_data, incr_error_metric, incr_success_metrics, message_size_check,
+    BridgeErrorReason,
 };
 use crate::routers::{Router, RouterError, RouterResponse};
 use a2::{
@@ -16,16 +17,33 @@ use a2::{
     Response,
 };
 use actix_web::http::StatusCode;
+use again::RetryPolicy;
 use async_trait::async_trait;
-use cadence::StatsdClient;
+use cadence::{CountedExt, StatsdClient};
 use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 use uuid::Uuid;
 
+/// Map an APNS error to the normalized `notification.bridge.error` reason
+fn apns_error_reason(error: &a2::Error) -> BridgeErrorReason {
+    match error {
+        a2::Error::ResponseError(response) => {
+            match response.code {
+                // APNS returns 404 for a bad path, not an unknown device
+                404 => BridgeErrorReason::UpstreamClientError,
+                code => BridgeErrorReason::from_status(code),
+            }
+        }
+        a2::Error::ConnectionError(_) => BridgeErrorReason::ConnectionUnavailable,
+        _ => BridgeErrorReason::Unknown,
+    }
+}
+
 /// Apple Push Notification Service router
 pub struct ApnsRouter {
     /// A map from release channel to APNS client
@@ -110,16 +128,27 @@ pub struct ApsAlertHolder {
 impl ApnsRouter {
     /// Create a new APNS router. APNS clients will be initialized for each
     /// channel listed in the settings.
+    ///
+    /// Requests time out after `request_timeout` or
+    /// `settings.request_timeout_secs`, whichever is shorter (a2 only
+    /// supports whole seconds, so `request_timeout` is rounded up)
     pub async fn new(
         settings: ApnsSettings,
         endpoint_url: Url,
+        request_timeout: Duration,
         metrics: Arc<StatsdClient>,
         db: Box<dyn DbClient>,
     ) -> Result<Self, ApnsError> {
         let channels = settings.channels()?;
+        let request_timeout_secs = request_timeout.as_millis().div_ceil(1000) as u64;
+        let request_timeout_secs = Some(
+            settings
+                .request_timeout_secs
+                .map_or(request_timeout_secs, |secs| secs.min(request_timeout_secs)),
+        );
 
         let clients: HashMap<String, ApnsClientData> = futures::stream::iter(channels)
-            .then(|(name, settings)| Self::create_client(name, settings))
+            .then(|(name, settings)| Self::create_client(name, settings, request_timeout_secs))
             .try_collect()
             .await?;
 
@@ -137,6 +166,7 @@ impl ApnsRouter {
     async fn create_client(
         name: String,
         settings: ApnsChannel,
+        request_timeout_secs: Option<u64>,
     ) -> Result<(String, ApnsClientData), ApnsError> {
         let endpoint = if settings.sandbox {
             Endpoint::Sandbox
@@ -160,7 +190,7 @@ impl ApnsRouter {
         let apns_settings = ApnsSettings::default();
         let config = a2::ClientConfig {
             endpoint,
-            request_timeout_secs: apns_settings.request_timeout_secs,
+            request_timeout_secs,
             pool_idle_timeout_secs: apns_settings.pool_idle_timeout_secs,
         };
         let client = ApnsClientData {
@@ -186,22 +216,30 @@ impl ApnsRouter {
 
     /// Handle an error by logging, updating metrics, etc
     async fn handle_error(&self, error: a2::Error, uaid: Uuid, channel: &str) -> ApiError {
+        let reason = apns_error_reason(&error);
         match &error {
             a2::Error::ResponseError(response) => {
                 // capture the APNs error as a metric response. This allows us to spot trends.
                 // While APNS can return a number of errors (see a2::response::ErrorReason) we
                 // shouldn't encounter many of those.
-                let reason = response
-                    .error
-                    .as_ref()
-                    .map(|r| format!("{:?}", r.reason))
-                    .unwrap_or_else(|| "Unknown".to_owned());
+                let upstream_reason = response.error.as_ref().map(|r| format!("{:?}", r.reason));
                 let code = StatusCode::from_u16(response.code).unwrap_or(StatusCode::BAD_GATEWAY);
-                incr_error_metric(&self.metrics, "apns", channel, &reason, code, None);
-                if response.code == 410 {
+                incr_error_metric(
+                    &self.metrics,
+                    "apns",
+                    channel,
+                    reason,
+                    code,
+                    None,
+                    upstream_reason.as_deref(),
+                );
+                if reason == BridgeErrorReason::Unregistered {
                     debug!("APNS recipient has been unregistered, removing user");
                     if let Err(e) = self.db.remove_user(&uaid).await {
-                        warn!("Error while removing user due to APNS 410: {}", e);
+                        warn!(
+                            "Error while removing user due to APNS {}: {}",
+                            response.code, e
+                        );
                     }
 
                     return ApiError::from(ApnsError::Unregistered);
@@ -215,9 +253,10 @@ impl ApnsRouter {
                     &self.metrics,
                     "apns",
                     channel,
-                    "connection_unavailable",
+                    reason,
                     StatusCode::SERVICE_UNAVAILABLE,
                     None,
+                    None,
                 );
             }
             _ => {
@@ -226,9 +265,10 @@ impl ApnsRouter {
                     &self.metrics,
                     "apns",
                     channel,
-                    "unknown",
+                    reason,
                     StatusCode::BAD_GATEWAY,
                     None,
+                    None,
                 );
             }
         }
@@ -465,9 +505,29 @@ impl Router for ApnsRouter {
             .map_err(ApnsError::SizeLimit)?;
         message_size_check(payload_json.as_bytes(), self.settings.max_data)?;
 
-        // Send to APNS
+        // Send to APNS, retrying its upstream server errors
         trace!("Sending message to APNS: {:?}", payload);
-        if let Err(e) = client.send(payload).await {
+        let result =
+            RetryPolicy::exponential(Duration::from_millis(self.settings.retry_backoff_millis))
+                .with_max_retries(self.settings.retry_attempts)
+                .with_jitter(true)
+                .retry_if(
+                    || client.send(payload.clone()),
+                    |e: &a2::Error| {
+                        let retry =
+                            matches!(e, a2::Error::ResponseError(response) if response.code >= 500);
+                        if retry {
+                            debug!("🌉Retrying APNS request: {}", channel);
+                            self.metrics
+                                .incr_with_tags("bridge.retry")
+                                .with_tag("platform", "apns")
+                                .send();
+                        }
+                        retry
+                    },
+                )
+                .await;
+        if let Err(e) = result {
             return Err(self
                 .handle_error(e, notification.subscription.user.uaid, channel)
                 .await);
@@ -494,7 +554,10 @@ mod tests {
     use crate::routers::apns::error::ApnsError;
     use crate::routers::apns::router::{ApnsClient, ApnsClientData, ApnsRouter};
     use crate::routers::apns::settings::ApnsSettings;
-    use crate::routers::common::tests::{make_notification, CHANNEL_ID};
+    use crate::routers::common::tests::{
+        assert_bridge_error, make_notification, spy_metrics, CHANNEL_ID,
+    };
+    use crate::routers::common::BridgeErrorReason;
     use crate::routers::{Router, RouterError, RouterResponse};
     use a2::request::payload::Payload;
     use a2::{Error, Response};
@@ -504,6 +567,7 @@ mod tests {
     use cadence::StatsdClient;
     use mockall::predicate;
     use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
     use url::Url;
 
@@ -558,7 +622,10 @@ mod tests {
                 );
                 map
             },
-            settings: ApnsSettings::default(),
+            settings: ApnsSettings {
+                retry_backoff_millis: 1,
+                ..Default::default()
+            },
             endpoint_url: Url::parse("http://localhost:8080/").unwrap(),
             metrics: Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
             db,
@@ -699,7 +766,9 @@ mod tests {
             .with(predicate::eq(notification.subscription.user.uaid))
             .times(1)
             .return_once(|_| Ok(()));
-        let router = make_router(client, db.into_boxed_arc());
+        let mut router = make_router(client, db.into_boxed_arc());
+        let (metrics, sent) = spy_metrics();
+        router.metrics = metrics;
 
         let result = router.route_notification(&notification).await;
         assert!(result.is_err());
@@ -710,6 +779,14 @@ mod tests {
             ),
             "result = {result:?}"
         );
+        // APNS' own reason is reported alongside the normalized one
+        let sent = sent();
+        assert_bridge_error(&sent, "apns", BridgeErrorReason::Unregistered);
+        assert!(
+            sent.iter()
+                .any(|m| m.contains("upstream_reason:Unregistered")),
+            "{sent:?}"
+        );
     }
 
     /// APNS errors (other than Unregistered) are wrapped and returned
@@ -749,6 +826,92 @@ mod tests {
         );
     }
 
+    /// APNS errors are reported with a normalized reason tag
+    #[tokio::test]
+    async fn upstream_error_reason() {
+        for (code, expected) in [
+            (410, BridgeErrorReason::Unregistered),
+            (403, BridgeErrorReason::Authentication),
+            (404, BridgeErrorReason::UpstreamClientError),
+            (503, BridgeErrorReason::UpstreamServerError),
+        ] {
+            let client = MockApnsClient::new(move |_| {
+                Err(a2::Error::ResponseError(a2::Response {
+                    error: None,
+                    apns_id: None,
+                    code,
+                }))
+            });
+            let mut db = MockDbClient::new();
+            db.expect_remove_user()
+                .times(usize::from(expected == BridgeErrorReason::Unregistered))
+                .return_once(|_| Ok(()));
+            let mut router = make_router(client, db.into_boxed_arc());
+            let (metrics, sent) = spy_metrics();
+            router.metrics = metrics;
+            let notification = make_notification(default_router_data(), None, RouterType::APNS);
+
+            let result = router.route_notification(&notification).await;
+            assert!(result.is_err());
+            assert_bridge_error(&sent(), "apns", expected);
+        }
+    }
+
+    /// Upstream server errors are retried until APNS accepts the notification
+    #[tokio::test]
+    async fn upstream_server_error_retried() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let client = MockApnsClient::new({
+            let attempts = attempts.clone();
+            move |_| {
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    return Err(a2::Error::ResponseError(a2::Response {
+                        error: None,
+                        apns_id: None,
+                        code: 503,
+                    }));
+                }
+                Ok(apns_success_response())
+            }
+        });
+        let mut router = make_router(client, MockDbClient::new().into_boxed_arc());
+        let (metrics, sent) = spy_metrics();
+        router.metrics = metrics;
+        let notification = make_notification(default_router_data(), None, RouterType::APNS);
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {result:?}");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        let retries = sent()
+            .into_iter()
+            .filter(|m| m.starts_with("autopush.bridge.retry"))
+            .count();
+        assert_eq!(retries, 2);
+    }
+
+    /// Client errors aren't retried
+    #[tokio::test]
+    async fn upstream_client_error_not_retried() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let client = MockApnsClient::new({
+            let attempts = attempts.clone();
+            move |_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(a2::Error::ResponseError(a2::Response {
+                    error: None,
+                    apns_id: None,
+                    code: 400,
+                }))
+            }
+        });
+        let router = make_router(client, MockDbClient::new().into_boxed_arc());
+        let notification = make_notification(default_router_data(), None, RouterType::APNS);
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
     /// An error is returned if the user's APS data is invalid
     #[tokio::test]
     async fn invalid_aps_data() {
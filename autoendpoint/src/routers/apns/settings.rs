@@ -15,6 +15,13 @@ pub struct ApnsSettings {
     // Utilized by apns router config in creating the client.
     pub request_timeout_secs: Option<u64>,
     pub pool_idle_timeout_secs: Option<u64>,
+    /// The number of times to retry APNS requests that fail with an upstream
+    /// 5xx error (which Apple advises retrying). Timeouts and connection
+    /// errors aren't retried, as APNS may have received the request
+    pub retry_attempts: usize,
+    /// The base number of milliseconds to wait before retrying an APNS
+    /// request. Doubles with each attempt, with a random jitter added
+    pub retry_backoff_millis: u64,
 }
 
 /// Settings for a specific APNS release channel
@@ -38,6 +45,8 @@ impl Default for ApnsSettings {
             max_data: 4096,
             request_timeout_secs: Some(20),
             pool_idle_timeout_secs: Some(600),
+            retry_attempts: 2,
+            retry_backoff_millis: 100,
         }
     }
 }
@@ -9,6 +9,7 @@ use cadence::{Counted, CountedExt, StatsdClient, Timed};
 use std::collections::HashMap;
 use uuid::Uuid;
 
+use super::apns::error::ApnsError;
 use super::fcm::error::FcmError;
 
 /// Convert a notification into a WebPush message
@@ -45,12 +46,103 @@ pub fn message_size_check(data: &[u8], max_data: usize) -> Result<(), RouterErro
     }
 }
 
+/// Normalized reasons a notification failed to be delivered via a router.
+///
+/// These are reported as the `reason` tag of the `notification.bridge.error`
+/// metric so that client side failures (e.g. an unregistered token) can be
+/// separated from bridge side failures (e.g. an upstream 5xx).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BridgeErrorReason {
+    /// The recipient's token or subscription is no longer valid (reported as
+    /// `recipient_gone`, as before the tags were normalized)
+    Unregistered,
+    /// We failed to authenticate with the bridge
+    Authentication,
+    /// The bridge did not respond in time
+    Timeout,
+    /// The bridge could not be reached
+    ConnectionUnavailable,
+    /// The message exceeded the bridge's size limit
+    TooMuchData,
+    /// The bridge rejected the request (4xx)
+    UpstreamClientError,
+    /// The bridge failed to handle the request (5xx)
+    UpstreamServerError,
+    /// The message could not be stored
+    Storage,
+    Unknown,
+}
+
+impl BridgeErrorReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BridgeErrorReason::Unregistered => "recipient_gone",
+            BridgeErrorReason::Authentication => "authentication",
+            BridgeErrorReason::Timeout => "timeout",
+            BridgeErrorReason::ConnectionUnavailable => "connection_unavailable",
+            BridgeErrorReason::TooMuchData => "too_much_data",
+            BridgeErrorReason::UpstreamClientError => "upstream_client_error",
+            BridgeErrorReason::UpstreamServerError => "upstream_server_error",
+            BridgeErrorReason::Storage => "storage",
+            BridgeErrorReason::Unknown => "unknown",
+        }
+    }
+
+    /// Classify an HTTP status code returned by a bridge
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            401 | 403 => BridgeErrorReason::Authentication,
+            404 | 410 => BridgeErrorReason::Unregistered,
+            408 | 504 => BridgeErrorReason::Timeout,
+            413 => BridgeErrorReason::TooMuchData,
+            400..=499 => BridgeErrorReason::UpstreamClientError,
+            500..=599 => BridgeErrorReason::UpstreamServerError,
+            _ => BridgeErrorReason::Unknown,
+        }
+    }
+
+    /// Classify an FCM
+    /// [ErrorCode](https://firebase.google.com/docs/reference/fcm/rest/v1/ErrorCode)
+    pub fn from_fcm_error_code(error_code: &str) -> Self {
+        match error_code {
+            "UNREGISTERED" => BridgeErrorReason::Unregistered,
+            "THIRD_PARTY_AUTH_ERROR" | "SENDER_ID_MISMATCH" => BridgeErrorReason::Authentication,
+            "UNAVAILABLE" | "INTERNAL" | "UNKNOWN" => BridgeErrorReason::UpstreamServerError,
+            _ => BridgeErrorReason::UpstreamClientError,
+        }
+    }
+}
+
+impl From<&RouterError> for BridgeErrorReason {
+    fn from(error: &RouterError) -> Self {
+        match error {
+            RouterError::NotFound | RouterError::UserWasDeleted => BridgeErrorReason::Unregistered,
+            RouterError::Authentication => BridgeErrorReason::Authentication,
+            RouterError::RequestTimeout => BridgeErrorReason::Timeout,
+            RouterError::Connect(_) => BridgeErrorReason::ConnectionUnavailable,
+            RouterError::TooMuchData(_) => BridgeErrorReason::TooMuchData,
+            RouterError::SaveDb(_, _) => BridgeErrorReason::Storage,
+            RouterError::Throttled(_) => BridgeErrorReason::UpstreamClientError,
+            RouterError::Fcm(FcmError::Upstream { error_code, .. }) => {
+                BridgeErrorReason::from_fcm_error_code(error_code)
+            }
+            RouterError::Fcm(FcmError::EmptyResponse(status))
+            | RouterError::Fcm(FcmError::InvalidResponse(_, _, status)) => {
+                BridgeErrorReason::from_status(status.as_u16())
+            }
+            RouterError::Apns(ApnsError::Unregistered) => BridgeErrorReason::Unregistered,
+            _ => BridgeErrorReason::Unknown,
+        }
+    }
+}
+
 /// Handle a bridge error by logging, updating metrics, etc
 /// This function uses the standard `slog` recording mechanisms and
-/// optionally calls a generic metric recording function for some
-/// types of errors. The error is returned by this function for later
-/// processing. This can include being called by the sentry middleware,
-/// which uses the `RecordableError` trait to optionally record metrics.
+/// calls a generic metric recording function for the error. Recipients the
+/// bridge reports as not found are removed from the database. The error is
+/// returned by this function for later processing. This can include being
+/// called by the sentry middleware, which uses the `RecordableError` trait
+/// to optionally record metrics.
 /// see [autopush_common::middleware::sentry::SentryWrapperMiddleware].`call()` method
 pub async fn handle_error(
     error: RouterError,
@@ -61,88 +153,31 @@ pub async fn handle_error(
     uaid: Uuid,
     vapid: Option<VapidHeaderWithKey>,
 ) -> ApiError {
-    match &error {
-        RouterError::Authentication => {
-            error!("Bridge authentication error");
-            incr_error_metric(
-                metrics,
-                platform,
-                app_id,
-                "authentication",
-                error.status(),
-                error.errno(),
-            );
-        }
-        RouterError::RequestTimeout => {
-            // Bridge timeouts are common.
-            info!("Bridge timeout");
-            incr_error_metric(
-                metrics,
-                platform,
-                app_id,
-                "timeout",
-                error.status(),
-                error.errno(),
-            );
-        }
-        RouterError::Connect(e) => {
-            warn!("Bridge unavailable: {}", e);
-            incr_error_metric(
-                metrics,
-                platform,
-                app_id,
-                "connection_unavailable",
-                error.status(),
-                error.errno(),
-            );
-        }
-        RouterError::NotFound => {
-            debug!("Bridge recipient not found, removing user");
-            incr_error_metric(
-                metrics,
-                platform,
-                app_id,
-                "recipient_gone",
-                error.status(),
-                error.errno(),
-            );
-
-            if let Err(e) = db.remove_user(&uaid).await {
-                warn!("Error while removing user due to bridge not_found: {}", e);
-            }
-        }
-        RouterError::TooMuchData(_) => {
-            // Do not log this error since it's fairly common.
-            incr_error_metric(
-                metrics,
-                platform,
-                app_id,
-                "too_much_data",
-                error.status(),
-                error.errno(),
-            );
-        }
-        RouterError::Fcm(FcmError::Upstream {
-            error_code: status, ..
-        }) => incr_error_metric(
-            metrics,
-            platform,
-            app_id,
-            &format!("upstream_{}", status),
-            error.status(),
-            error.errno(),
-        ),
+    let reason = BridgeErrorReason::from(&error);
+    match (&error, reason) {
+        (RouterError::Authentication, _) => error!("Bridge authentication error"),
+        // Bridge timeouts are common.
+        (RouterError::RequestTimeout, _) => info!("Bridge timeout"),
+        (RouterError::Connect(e), _) => warn!("Bridge unavailable: {}", e),
+        // Do not log these errors since they're fairly common.
+        (RouterError::TooMuchData(_), _) | (RouterError::Fcm(FcmError::Upstream { .. }), _) => {}
+        (_, BridgeErrorReason::Unregistered) => {}
+        _ => warn!("Unknown error while sending bridge request: {}", error),
+    }
+    incr_error_metric(
+        metrics,
+        platform,
+        app_id,
+        reason,
+        error.status(),
+        error.errno(),
+        None,
+    );
 
-        _ => {
-            warn!("Unknown error while sending bridge request: {}", error);
-            incr_error_metric(
-                metrics,
-                platform,
-                app_id,
-                "unknown",
-                error.status(),
-                error.errno(),
-            );
+    if matches!(error, RouterError::NotFound) {
+        debug!("Bridge recipient not found, removing user");
+        if let Err(e) = db.remove_user(&uaid).await {
+            warn!("Error while removing user due to bridge not_found: {}", e);
         }
     }
 
@@ -156,24 +191,31 @@ pub async fn handle_error(
     err
 }
 
-/// Increment `notification.bridge.error`
+/// Increment `notification.bridge.error`, optionally tagged with the
+/// bridge's own `upstream_reason` for the error
 pub fn incr_error_metric(
     metrics: &StatsdClient,
     platform: &str,
     app_id: &str,
-    reason: &str,
+    reason: BridgeErrorReason,
     status: StatusCode,
     errno: Option<usize>,
+    upstream_reason: Option<&str>,
 ) {
     // I'd love to extract the status and errno from the passed ApiError, but a2 error handling makes that impossible.
-    metrics
+    let error = status.to_string();
+    let errno = errno.unwrap_or(0).to_string();
+    let mut metric = metrics
         .incr_with_tags("notification.bridge.error")
         .with_tag("platform", platform)
         .with_tag("app_id", app_id)
-        .with_tag("reason", reason)
-        .with_tag("error", &status.to_string())
-        .with_tag("errno", &errno.unwrap_or(0).to_string())
-        .send();
+        .with_tag("reason", reason.as_str())
+        .with_tag("error", &error)
+        .with_tag("errno", &errno);
+    if let Some(upstream_reason) = upstream_reason {
+        metric = metric.with_tag("upstream_reason", upstream_reason);
+    }
+    metric.send();
 }
 
 /// Update metrics after successfully routing the notification
@@ -215,11 +257,41 @@ pub mod tests {
     use crate::extractors::routers::RouterType;
     use crate::extractors::subscription::Subscription;
     use autopush_common::db::User;
+    use cadence::StatsdClient;
     use std::collections::HashMap;
+    use std::sync::Arc;
     use uuid::Uuid;
 
+    use super::BridgeErrorReason;
+
     pub const CHANNEL_ID: &str = "deadbeef-13f9-4639-87f9-2ff731824f34";
 
+    /// Create a metrics client that captures what's sent to it. The returned
+    /// function collects the metrics sent so far.
+    pub fn spy_metrics() -> (Arc<StatsdClient>, impl Fn() -> Vec<String>) {
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::from_sink("autopush", sink));
+        let sent = move || {
+            rx.try_iter()
+                .map(|m| String::from_utf8(m).unwrap())
+                .collect()
+        };
+        (metrics, sent)
+    }
+
+    /// Assert a `notification.bridge.error` metric was sent with the given tags
+    pub fn assert_bridge_error(sent: &[String], platform: &str, reason: BridgeErrorReason) {
+        let platform = format!("platform:{platform}");
+        let reason = format!("reason:{}", reason.as_str());
+        assert!(
+            sent.iter()
+                .any(|m| m.starts_with("autopush.notification.bridge.error:")
+                    && m.contains(&platform)
+                    && m.contains(&reason)),
+            "{reason} not found in {sent:?}"
+        );
+    }
+
     /// Get the test channel ID as a Uuid
     pub fn channel_id() -> Uuid {
         Uuid::parse_str(CHANNEL_ID).unwrap()
@@ -255,6 +327,27 @@ pub mod tests {
             timestamp: 0,
             sort_key_timestamp: 0,
             data,
+            meta: None,
         }
     }
+
+    #[test]
+    fn reason_from_status() {
+        assert_eq!(
+            BridgeErrorReason::from_status(401),
+            BridgeErrorReason::Authentication
+        );
+        assert_eq!(
+            BridgeErrorReason::from_status(410),
+            BridgeErrorReason::Unregistered
+        );
+        assert_eq!(
+            BridgeErrorReason::from_status(429),
+            BridgeErrorReason::UpstreamClientError
+        );
+        assert_eq!(
+            BridgeErrorReason::from_status(503),
+            BridgeErrorReason::UpstreamServerError
+        );
+    }
 }
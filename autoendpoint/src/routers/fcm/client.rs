@@ -2,10 +2,13 @@ use crate::routers::common::message_size_check;
 use crate::routers::fcm::error::FcmError;
 use crate::routers::fcm::settings::{FcmServerCredential, FcmSettings};
 use crate::routers::RouterError;
+use again::RetryPolicy;
+use cadence::{CountedExt, Histogrammed, StatsdClient};
 use reqwest::StatusCode;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 use url::Url;
 use yup_oauth2::authenticator::DefaultAuthenticator;
@@ -19,16 +22,22 @@ pub struct FcmClient {
     endpoint: Url,
     timeout: Duration,
     max_data: usize,
+    retry_attempts: usize,
+    retry_backoff: Duration,
     authenticator: Option<DefaultAuthenticator>,
     http_client: reqwest::Client,
+    metrics: Arc<StatsdClient>,
 }
 
 impl FcmClient {
-    /// Create an `FcmClient` using the provided credential
+    /// Create an `FcmClient` using the provided credential. Requests time out
+    /// after `request_timeout` or `settings.timeout`, whichever is shorter
     pub async fn new(
         settings: &FcmSettings,
         server_credential: FcmServerCredential,
         http: reqwest::Client,
+        request_timeout: Duration,
+        metrics: Arc<StatsdClient>,
     ) -> std::io::Result<Self> {
         // `map`ping off of `serde_json::from_str` gets hairy and weird, requiring
         // async blocks and a number of other specialty items. Doing a very stupid
@@ -73,10 +82,13 @@ impl FcmClient {
                     server_credential.project_id
                 ))
                 .expect("Project ID is not URL-safe"),
-            timeout: Duration::from_secs(settings.timeout as u64),
+            timeout: Duration::from_secs(settings.timeout as u64).min(request_timeout),
             max_data: settings.max_data,
+            retry_attempts: settings.retry_attempts,
+            retry_backoff: Duration::from_millis(settings.retry_backoff_millis),
             authenticator: auth,
             http_client: http,
+            metrics,
         })
     }
 
@@ -112,23 +124,27 @@ impl FcmClient {
             .map_err(FcmError::OAuthToken)?;
         let token = server_access_token.token().ok_or(FcmError::NoOAuthToken)?;
 
-        // Make the request
-        let response = self
-            .http_client
-            .post(self.endpoint.clone())
-            .header("Authorization", format!("Bearer {}", token))
-            .header("Content-Type", "application/json")
-            .json(&message)
-            .timeout(self.timeout)
-            .send()
-            .await
-            .map_err(|e| {
-                if e.is_timeout() {
-                    RouterError::RequestTimeout
-                } else {
-                    RouterError::Connect(e)
+        let mut response = self.post(token, &message).await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            // Honor FCM's advised delay when it fits in our retry budget,
+            // otherwise pass it along to the caller
+            let retry_after = retry_after(&response);
+            self.metrics
+                .histogram_with_tags("bridge.throttled", retry_after.unwrap_or(0))
+                .with_tag("platform", "fcmv1")
+                .send();
+            match retry_after.map(Duration::from_secs) {
+                Some(delay) if delay <= self.retry_budget() => {
+                    debug!("🌉FCM throttled, retrying in {:?}", delay);
+                    actix_rt::time::sleep(delay).await;
+                    response = self.post(token, &message).await?;
+                    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+                        return Err(RouterError::Throttled(retry_after(&response)));
+                    }
                 }
-            })?;
+                _ => return Err(RouterError::Throttled(retry_after)),
+            }
+        }
 
         // Handle error
         let status = response.status();
@@ -180,6 +196,89 @@ impl FcmClient {
 
         Ok(())
     }
+
+    /// The longest time `post` may spend on a message: every attempt timing
+    /// out, plus the (exponential) backoff between them
+    fn retry_budget(&self) -> Duration {
+        let attempts = self.retry_attempts.saturating_add(1) as u32;
+        let backoff_factor = 2u32
+            .checked_pow(self.retry_attempts as u32)
+            .unwrap_or(u32::MAX)
+            - 1;
+        self.timeout
+            .saturating_mul(attempts)
+            .saturating_add(self.retry_backoff.saturating_mul(backoff_factor))
+    }
+
+    /// POST the message to FCM, retrying upstream server errors
+    async fn post(
+        &self,
+        token: &str,
+        message: &serde_json::Value,
+    ) -> Result<reqwest::Response, RouterError> {
+        // Retry upstream server errors (which FCM considers safe to retry).
+        // Like APNS, timeouts and connection errors aren't retried: FCM may
+        // have received the request, so a retry could deliver the
+        // notification twice. Client errors are never retried
+        let result = RetryPolicy::exponential(self.retry_backoff)
+            .with_max_retries(self.retry_attempts)
+            .with_jitter(true)
+            .retry_if(
+                || async {
+                    let response = self
+                        .http_client
+                        .post(self.endpoint.clone())
+                        .header("Authorization", format!("Bearer {}", token))
+                        .header("Content-Type", "application/json")
+                        .json(&message)
+                        .timeout(self.timeout)
+                        .send()
+                        .await
+                        .map_err(FailedAttempt::Request)?;
+                    if response.status().is_server_error() {
+                        return Err(FailedAttempt::ServerError(response));
+                    }
+                    Ok(response)
+                },
+                |e: &FailedAttempt| {
+                    let retry = matches!(e, FailedAttempt::ServerError(_));
+                    if retry {
+                        debug!("🌉Retrying FCM request: {:?}", &self.endpoint);
+                        self.metrics
+                            .incr_with_tags("bridge.retry")
+                            .with_tag("platform", "fcmv1")
+                            .send();
+                    }
+                    retry
+                },
+            )
+            .await;
+        match result {
+            Ok(response) | Err(FailedAttempt::ServerError(response)) => Ok(response),
+            Err(FailedAttempt::Request(e)) if e.is_timeout() => Err(RouterError::RequestTimeout),
+            Err(FailedAttempt::Request(e)) => Err(RouterError::Connect(e)),
+        }
+    }
+}
+
+/// Parse the delay (in seconds) advised by a response's `Retry-After` header.
+/// The HTTP-date form isn't supported
+fn retry_after(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// A FCM request attempt that may be retried
+#[derive(Debug)]
+enum FailedAttempt {
+    Request(reqwest::Error),
+    ServerError(reqwest::Response),
 }
 
 #[derive(Deserialize)]
@@ -197,11 +296,17 @@ struct FcmErrorResponse {
 
 #[cfg(test)]
 pub mod tests {
+    use crate::routers::common::tests::spy_metrics;
+    use crate::routers::common::BridgeErrorReason;
     use crate::routers::fcm::client::FcmClient;
     use crate::routers::fcm::error::FcmError;
     use crate::routers::fcm::settings::{FcmServerCredential, FcmSettings};
     use crate::routers::RouterError;
+    use cadence::StatsdClient;
     use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
     use url::Url;
 
     pub const PROJECT_ID: &str = "yup-test-243420";
@@ -250,15 +355,31 @@ pub mod tests {
     async fn make_client(
         server: &mockito::ServerGuard,
         credential: FcmServerCredential,
+    ) -> FcmClient {
+        make_client_with_metrics(
+            server,
+            credential,
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+        )
+        .await
+    }
+
+    async fn make_client_with_metrics(
+        server: &mockito::ServerGuard,
+        credential: FcmServerCredential,
+        metrics: Arc<StatsdClient>,
     ) -> FcmClient {
         FcmClient::new(
             &FcmSettings {
                 base_url: Url::parse(&server.url()).unwrap(),
                 server_credentials: serde_json::json!(credential).to_string(),
+                retry_backoff_millis: 1,
                 ..Default::default()
             },
             credential,
             reqwest::Client::new(),
+            Duration::from_secs(3),
+            metrics,
         )
         .await
         .unwrap()
@@ -425,4 +546,278 @@ pub mod tests {
             "result = {result:?}"
         );
     }
+
+    /// Upstream server errors are retried until the request succeeds
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let mut server = mockito::Server::new_async().await;
+        let (metrics, sent) = spy_metrics();
+
+        let client = make_client_with_metrics(
+            &server,
+            FcmServerCredential {
+                project_id: PROJECT_ID.to_owned(),
+                is_gcm: None,
+                server_access_token: make_service_key(&server),
+            },
+            metrics,
+        )
+        .await;
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let unavailable_mock = mock_fcm_endpoint_builder(&mut server, PROJECT_ID)
+            .with_status(503)
+            .with_body(r#"{"error":{"status":"UNAVAILABLE","message":"test-message"}}"#)
+            .expect(2)
+            .create_async()
+            .await;
+        let success_mock = mock_fcm_endpoint_builder(&mut server, PROJECT_ID)
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let result = client
+            .send(HashMap::new(), "test-token".to_string(), 42)
+            .await;
+        assert!(result.is_ok(), "result = {result:?}");
+        unavailable_mock.assert();
+        success_mock.assert();
+        let retries = sent()
+            .into_iter()
+            .filter(|m| m.starts_with("autopush.bridge.retry:1|c"))
+            .count();
+        assert_eq!(retries, 2);
+    }
+
+    /// Client errors are not retried
+    #[tokio::test]
+    async fn no_retry_client_errors() {
+        let mut server = mockito::Server::new_async().await;
+        let (metrics, sent) = spy_metrics();
+
+        let client = make_client_with_metrics(
+            &server,
+            FcmServerCredential {
+                project_id: PROJECT_ID.to_owned(),
+                is_gcm: None,
+                server_access_token: make_service_key(&server),
+            },
+            metrics,
+        )
+        .await;
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let fcm_mock = mock_fcm_endpoint_builder(&mut server, PROJECT_ID)
+            .with_status(400)
+            .with_body(r#"{"error":{"status":"INVALID_ARGUMENT","message":"test-message"}}"#)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let result = client
+            .send(HashMap::new(), "test-token".to_string(), 42)
+            .await;
+        assert!(result.is_err());
+        fcm_mock.assert();
+        assert!(!sent()
+            .iter()
+            .any(|m| m.starts_with("autopush.bridge.retry")));
+    }
+
+    /// A 429 with a `Retry-After` within the retry budget is retried after
+    /// the advised delay
+    #[tokio::test]
+    async fn throttled_retry_after() {
+        let mut server = mockito::Server::new_async().await;
+        let (metrics, sent) = spy_metrics();
+        let client = make_client_with_metrics(
+            &server,
+            FcmServerCredential {
+                project_id: PROJECT_ID.to_owned(),
+                is_gcm: None,
+                server_access_token: make_service_key(&server),
+            },
+            metrics,
+        )
+        .await;
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let fcm_mock = mock_fcm_endpoint_builder(&mut server, PROJECT_ID)
+            .with_status(429)
+            .with_header("Retry-After", "1")
+            .expect(2)
+            .create_async()
+            .await;
+
+        let start = std::time::Instant::now();
+        let err = client
+            .send(HashMap::new(), "test-token".to_string(), 42)
+            .await
+            .unwrap_err();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(matches!(err, RouterError::Throttled(Some(1))), "{err:?}");
+        assert_eq!(err.status(), actix_http::StatusCode::TOO_MANY_REQUESTS);
+        fcm_mock.assert();
+        assert!(sent()
+            .iter()
+            .any(|m| m.starts_with("autopush.bridge.throttled:1|h")));
+    }
+
+    /// The retry budget covers every attempt timing out plus the backoff
+    /// between them, so it's longer than a single attempt's timeout
+    #[tokio::test]
+    async fn retry_budget() {
+        let server = mockito::Server::new_async().await;
+        let client = make_client(
+            &server,
+            FcmServerCredential {
+                project_id: PROJECT_ID.to_owned(),
+                is_gcm: None,
+                server_access_token: make_service_key(&server),
+            },
+        )
+        .await;
+        // 3 attempts of 3 seconds, with 1ms and 2ms of backoff
+        assert_eq!(client.retry_budget(), Duration::from_millis(9003));
+    }
+
+    /// A `Retry-After` beyond the retry budget is passed back to the caller
+    #[tokio::test]
+    async fn throttled_retry_after_exceeds_budget() {
+        let mut server = mockito::Server::new_async().await;
+        let (metrics, sent) = spy_metrics();
+        let client = make_client_with_metrics(
+            &server,
+            FcmServerCredential {
+                project_id: PROJECT_ID.to_owned(),
+                is_gcm: None,
+                server_access_token: make_service_key(&server),
+            },
+            metrics,
+        )
+        .await;
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let fcm_mock = mock_fcm_endpoint_builder(&mut server, PROJECT_ID)
+            .with_status(429)
+            .with_header("Retry-After", "600")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let err = client
+            .send(HashMap::new(), "test-token".to_string(), 42)
+            .await
+            .unwrap_err();
+        assert_eq!(err.retry_after(), Some(600));
+        fcm_mock.assert();
+        assert!(sent()
+            .iter()
+            .any(|m| m.starts_with("autopush.bridge.throttled:600|h")));
+    }
+
+    /// A bridge that never responds times out as a `RequestTimeout`, within
+    /// the (shorter) bridge request timeout
+    #[tokio::test]
+    async fn slow_bridge_timeout() {
+        let mut server = mockito::Server::new_async().await;
+        let _token_mock = mock_token_endpoint(&mut server).await;
+
+        // Accepts connections but never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let slow_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let credential = FcmServerCredential {
+            project_id: PROJECT_ID.to_owned(),
+            is_gcm: None,
+            server_access_token: make_service_key(&server),
+        };
+        let client = FcmClient::new(
+            &FcmSettings {
+                base_url: Url::parse(&slow_url).unwrap(),
+                server_credentials: serde_json::json!(credential).to_string(),
+                timeout: 10,
+                retry_attempts: 0,
+                ..Default::default()
+            },
+            credential,
+            reqwest::Client::new(),
+            Duration::from_millis(200),
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+        )
+        .await
+        .unwrap();
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.send(HashMap::new(), "test-token".to_string(), 42),
+        )
+        .await
+        .expect("The request did not time out");
+        let err = result.unwrap_err();
+        assert!(
+            matches!(err, RouterError::RequestTimeout),
+            "result = {err:?}"
+        );
+        assert_eq!(BridgeErrorReason::from(&err), BridgeErrorReason::Timeout);
+    }
+
+    /// Timeouts aren't retried, as FCM may have received the request
+    #[tokio::test]
+    async fn timeout_not_retried() {
+        let mut server = mockito::Server::new_async().await;
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let (metrics, sent) = spy_metrics();
+
+        // Accepts connections but never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let slow_url = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                sockets.push(socket);
+            }
+        });
+
+        let credential = FcmServerCredential {
+            project_id: PROJECT_ID.to_owned(),
+            is_gcm: None,
+            server_access_token: make_service_key(&server),
+        };
+        let client = FcmClient::new(
+            &FcmSettings {
+                base_url: Url::parse(&slow_url).unwrap(),
+                server_credentials: serde_json::json!(credential).to_string(),
+                timeout: 10,
+                retry_attempts: 2,
+                retry_backoff_millis: 1,
+                ..Default::default()
+            },
+            credential,
+            reqwest::Client::new(),
+            Duration::from_millis(200),
+            metrics,
+        )
+        .await
+        .unwrap();
+
+        let err = client
+            .send(HashMap::new(), "test-token".to_string(), 42)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, RouterError::RequestTimeout),
+            "result = {err:?}"
+        );
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert!(!sent()
+            .iter()
+            .any(|m| m.starts_with("autopush.bridge.retry")));
+    }
 }
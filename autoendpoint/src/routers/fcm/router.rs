@@ -13,6 +13,7 @@ use cadence::StatsdClient;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 use uuid::Uuid;
 
@@ -27,18 +28,26 @@ pub struct FcmRouter {
 }
 
 impl FcmRouter {
-    /// Create a new `FcmRouter`
+    /// Create a new `FcmRouter`. Requests time out after `request_timeout`
+    /// or `settings.timeout`, whichever is shorter
     pub async fn new(
         settings: FcmSettings,
         endpoint_url: Url,
         http: reqwest::Client,
+        request_timeout: Duration,
         metrics: Arc<StatsdClient>,
         db: Box<dyn DbClient>,
     ) -> Result<Self, FcmError> {
         let server_credentials = settings.credentials()?;
-        let clients = Self::create_clients(&settings, server_credentials, http.clone())
-            .await
-            .map_err(FcmError::OAuthClientBuild)?;
+        let clients = Self::create_clients(
+            &settings,
+            server_credentials,
+            http.clone(),
+            request_timeout,
+            metrics.clone(),
+        )
+        .await
+        .map_err(FcmError::OAuthClientBuild)?;
         Ok(Self {
             settings,
             endpoint_url,
@@ -53,13 +62,22 @@ impl FcmRouter {
         settings: &FcmSettings,
         server_credentials: HashMap<String, FcmServerCredential>,
         http: reqwest::Client,
+        request_timeout: Duration,
+        metrics: Arc<StatsdClient>,
     ) -> std::io::Result<HashMap<String, FcmClient>> {
         let mut clients = HashMap::new();
 
         for (profile, server_credential) in server_credentials {
             clients.insert(
                 profile,
-                FcmClient::new(settings, server_credential, http.clone()).await?,
+                FcmClient::new(
+                    settings,
+                    server_credential,
+                    http.clone(),
+                    request_timeout,
+                    metrics.clone(),
+                )
+                .await?,
             );
         }
         trace!("Initialized {} FCM clients", clients.len());
@@ -195,7 +213,10 @@ impl Router for FcmRouter {
 mod tests {
     use crate::error::ApiErrorKind;
     use crate::extractors::routers::RouterType;
-    use crate::routers::common::tests::{make_notification, CHANNEL_ID};
+    use crate::routers::common::tests::{
+        assert_bridge_error, make_notification, spy_metrics, CHANNEL_ID,
+    };
+    use crate::routers::common::BridgeErrorReason;
     use crate::routers::fcm::client::tests::{
         make_service_key, mock_fcm_endpoint_builder, mock_token_endpoint, GCM_PROJECT_ID,
         PROJECT_ID,
@@ -222,6 +243,23 @@ mod tests {
         fcm_credential: String,
         gcm_credential: String,
         db: Box<dyn DbClient>,
+    ) -> FcmRouter {
+        make_router_with_metrics(
+            server,
+            fcm_credential,
+            gcm_credential,
+            db,
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+        )
+        .await
+    }
+
+    async fn make_router_with_metrics(
+        server: &mut mockito::ServerGuard,
+        fcm_credential: String,
+        gcm_credential: String,
+        db: Box<dyn DbClient>,
+        metrics: Arc<StatsdClient>,
     ) -> FcmRouter {
         let url = &server.url();
         FcmRouter::new(
@@ -243,7 +281,8 @@ mod tests {
             },
             Url::parse("http://localhost:8080/").unwrap(),
             reqwest::Client::new(),
-            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+            Duration::from_secs(3),
+            metrics,
             db,
         )
         .await
@@ -415,4 +454,75 @@ mod tests {
             "result = {result:?}"
         );
     }
+
+    /// FCM errors are reported with a normalized reason tag, only a 404 with
+    /// a valid body (`RouterError::NotFound`) additionally drops the user
+    #[tokio::test]
+    async fn upstream_error_reason() {
+        for (status, body, expected, removed) in [
+            (
+                404,
+                r#"{"error":{"status":"UNREGISTERED","message":"test-message"}}"#,
+                BridgeErrorReason::Unregistered,
+                true,
+            ),
+            (
+                400,
+                r#"{"error":{"status":"UNREGISTERED","message":"test-message"}}"#,
+                BridgeErrorReason::Unregistered,
+                false,
+            ),
+            // Empty and invalid bodies
+            (404, "", BridgeErrorReason::Unregistered, false),
+            (404, "not json", BridgeErrorReason::Unregistered, false),
+            (
+                401,
+                r#"{"error":{"status":"UNAUTHENTICATED","message":"test-message"}}"#,
+                BridgeErrorReason::Authentication,
+                false,
+            ),
+            (
+                400,
+                r#"{"error":{"status":"INVALID_ARGUMENT","message":"test-message"}}"#,
+                BridgeErrorReason::UpstreamClientError,
+                false,
+            ),
+            (
+                503,
+                r#"{"error":{"status":"UNAVAILABLE","message":"test-message"}}"#,
+                BridgeErrorReason::UpstreamServerError,
+                false,
+            ),
+        ] {
+            let mut server = mockito::Server::new_async().await;
+
+            let notification = make_notification(default_router_data(), None, RouterType::FCM);
+            let mut db = MockDbClient::new();
+            db.expect_remove_user()
+                .with(predicate::eq(notification.subscription.user.uaid))
+                .times(usize::from(removed))
+                .return_once(|_| Ok(()));
+
+            let (metrics, sent) = spy_metrics();
+            let service_key = make_service_key(&server);
+            let router = make_router_with_metrics(
+                &mut server,
+                service_key,
+                "whatever".to_string(),
+                db.into_boxed_arc(),
+                metrics,
+            )
+            .await;
+            let _token_mock = mock_token_endpoint(&mut server).await;
+            let _fcm_mock = mock_fcm_endpoint_builder(&mut server, PROJECT_ID)
+                .with_status(status)
+                .with_body(body)
+                .create_async()
+                .await;
+
+            let result = router.route_notification(&notification).await;
+            assert!(result.is_err());
+            assert_bridge_error(&sent(), "fcmv1", expected);
+        }
+    }
 }
@@ -40,8 +40,17 @@ pub struct FcmSettings {
     pub max_data: usize,
     /// The base URL to use for FCM requests
     pub base_url: Url,
-    /// The number of seconds to wait for FCM requests to complete
+    /// The number of seconds to wait for FCM requests to complete (capped by
+    /// `bridge_request_timeout_millis`)
     pub timeout: usize,
+    /// The number of times to retry FCM requests that fail with an upstream
+    /// 5xx error. Timeouts and connection errors aren't retried, as FCM may
+    /// have received the request. A throttled request is retried once when
+    /// FCM's `Retry-After` fits within the total time these retries may take
+    pub retry_attempts: usize,
+    /// The base number of milliseconds to wait before retrying a FCM request.
+    /// Doubles with each attempt, with a random jitter applied
+    pub retry_backoff_millis: u64,
 }
 
 /// Credential information for each application
@@ -61,6 +70,8 @@ impl Default for FcmSettings {
             max_data: 4096,
             base_url: Url::parse("https://fcm.googleapis.com").unwrap(),
             timeout: 3,
+            retry_attempts: 2,
+            retry_backoff_millis: 100,
         }
     }
 }
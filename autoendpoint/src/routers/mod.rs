@@ -44,6 +44,10 @@ pub struct RouterResponse {
     pub status: StatusCode,
     pub headers: HashMap<&'static str, String>,
     pub body: Option<String>,
+    /// Whether the notification was delivered directly to a connected client
+    /// rather than stored for later (only meaningful for WebPush). This is
+    /// never persisted.
+    pub delivered_directly: bool,
 }
 
 impl RouterResponse {
@@ -59,6 +63,7 @@ impl RouterResponse {
                 map
             },
             body: None,
+            delivered_directly: false,
         }
     }
 }
@@ -110,6 +115,9 @@ pub enum RouterError {
 
     #[error("Bridge reports user was not found")]
     NotFound,
+
+    #[error("Bridge is throttling requests")]
+    Throttled(Option<u64>),
 }
 
 impl RouterError {
@@ -127,6 +135,8 @@ impl RouterError {
 
             RouterError::TooMuchData(_) => StatusCode::PAYLOAD_TOO_LARGE,
 
+            RouterError::Throttled(_) => StatusCode::TOO_MANY_REQUESTS,
+
             RouterError::Authentication | RouterError::RequestTimeout | RouterError::Connect(_) => {
                 StatusCode::BAD_GATEWAY
             }
@@ -155,6 +165,17 @@ impl RouterError {
             RouterError::Connect(_) => Some(902),
 
             RouterError::RequestTimeout => Some(903),
+
+            RouterError::Throttled(_) => Some(904),
+        }
+    }
+
+    /// The delay (in seconds) the bridge advised waiting before retrying, if
+    /// any
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            RouterError::Throttled(retry_after) => *retry_after,
+            _ => None,
         }
     }
 }
@@ -179,6 +200,7 @@ impl ReportableError for RouterError {
             | RouterError::Connect(_)
             | RouterError::NotFound
             | RouterError::RequestTimeout
+            | RouterError::Throttled(_)
             | RouterError::TooMuchData(_) => false,
             RouterError::SaveDb(e, _) => e.is_sentry_event(),
             _ => true,
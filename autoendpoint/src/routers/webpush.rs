@@ -1,6 +1,10 @@
 use async_trait::async_trait;
 use cadence::{Counted, CountedExt, StatsdClient, Timed};
-use reqwest::{Response, StatusCode};
+use fernet::MultiFernet;
+use reqwest::{
+    header::{CONTENT_ENCODING, CONTENT_TYPE},
+    Response, StatusCode,
+};
 use serde_json::Value;
 use std::collections::{hash_map::RandomState, HashMap};
 use std::sync::Arc;
@@ -8,11 +12,16 @@ use url::Url;
 use uuid::Uuid;
 
 use crate::error::{ApiError, ApiErrorKind, ApiResult};
-use crate::extractors::{notification::Notification, router_data_input::RouterDataInput};
+use crate::extractors::{
+    message_id::MessageId, notification::Notification, router_data_input::RouterDataInput,
+};
 use crate::headers::vapid::VapidHeaderWithKey;
+use crate::routers::common::{incr_error_metric, BridgeErrorReason};
 use crate::routers::{Router, RouterError, RouterResponse};
+use crate::settings::InternalEncoding;
 
 use autopush_common::db::{client::DbClient, User};
+use autopush_common::otel;
 
 /// The router for desktop user agents.
 ///
@@ -24,6 +33,18 @@ pub struct WebPushRouter {
     pub metrics: Arc<StatsdClient>,
     pub http: reqwest::Client,
     pub endpoint_url: Url,
+    /// Encrypts the message IDs of stored notifications
+    pub internal_fernet: MultiFernet,
+    /// Compress (with zstd) notifications forwarded to the connection servers
+    pub internal_compression: bool,
+    /// The encoding of notifications forwarded to the connection servers
+    pub internal_encoding: InternalEncoding,
+    /// Notifications for disconnected users with a TTL below this aren't
+    /// stored
+    pub min_store_ttl: u64,
+    /// Check for "store only" channels, whose notifications are never
+    /// delivered directly
+    pub store_only_channels: bool,
 }
 
 #[async_trait(?Send)]
@@ -46,8 +67,12 @@ impl Router for WebPushRouter {
         );
         trace!("✉ Notification = {:?}", notification);
 
+        // Store only channels are never delivered directly: the Client
+        // collects them when it checks storage
+        let store_only = user.node_id.is_some() && self.is_store_only(notification).await?;
+
         // Check if there is a node connected to the client
-        if let Some(node_id) = &user.node_id {
+        if let Some(node_id) = user.node_id.as_ref().filter(|_| !store_only) {
             trace!(
                 "✉ User has a node ID, sending notification to node: {}",
                 &node_id
@@ -60,7 +85,9 @@ impl Router for WebPushRouter {
                     if response.status() == 200 {
                         // The node has received the notification
                         trace!("✉ Node received notification");
-                        return Ok(self.make_delivered_response(notification));
+                        return Ok(
+                            self.make_delivered_response(notification, &notification.message_id)
+                        );
                     }
 
                     trace!(
@@ -83,6 +110,8 @@ impl Router for WebPushRouter {
             }
         }
 
+        // A TTL of zero means deliver now or never (RFC 8030 5.2): never
+        // store it, and accept it even though it's dropped
         if notification.headers.ttl == 0 {
             let topic = notification.headers.topic.is_some().to_string();
             trace!(
@@ -94,12 +123,29 @@ impl Router for WebPushRouter {
                 // TODO: include `internal` if meta is set.
                 .with_tag("topic", &topic)
                 .send();
-            return Ok(self.make_delivered_response(notification));
+            return Ok(self.make_dropped_response(notification));
+        }
+
+        // A user who isn't connected will likely not reconnect before a very
+        // short TTL expires, so don't bother storing it
+        if user.node_id.is_none() && (notification.headers.ttl as u64) < self.min_store_ttl {
+            trace!("✉ User is not connected and the TTL is too short, not storing notification");
+            self.metrics
+                .incr_with_tags("notification.message.not_stored")
+                .with_tag("reason", "ttl_too_short")
+                .send();
+            return Ok(self.make_dropped_response(notification));
         }
 
         // Save notification, node is not present or busy
         trace!("✉ Node is not present or busy, storing notification");
-        self.store_notification(notification).await?;
+        // The stored notification's Location is built from the key it was
+        // stored under
+        let message_id = self.store_notification(notification).await?;
+        if store_only {
+            trace!("✉ Store only channel, returning stored response");
+            return Ok(self.make_stored_response(notification, &message_id));
+        }
 
         // Retrieve the user data again, they may have reconnected or the node
         // is no longer busy.
@@ -115,7 +161,7 @@ impl Router for WebPushRouter {
             Err(e) => {
                 // Database error, but we already stored the message so it's ok
                 debug!("✉ Database error while re-fetching user: {}", e);
-                return Ok(self.make_stored_response(notification));
+                return Ok(self.make_stored_response(notification, &message_id));
             }
         };
 
@@ -125,9 +171,16 @@ impl Router for WebPushRouter {
             // The user is not connected to a node, nothing more to do
             None => {
                 trace!("✉ User is not connected to a node, returning stored response");
-                return Ok(self.make_stored_response(notification));
+                return Ok(self.make_stored_response(notification, &message_id));
             }
         };
+        // The user may have connected since, before its channel was checked
+        if notification.subscription.user.node_id.is_none()
+            && self.is_store_only(notification).await?
+        {
+            trace!("✉ Store only channel, returning stored response");
+            return Ok(self.make_stored_response(notification, &message_id));
+        }
 
         // Notify the node to check for messages
         trace!("✉ Notifying node to check for messages");
@@ -146,17 +199,17 @@ impl Router for WebPushRouter {
                         .with_tag("app_id", "direct")
                         .send();
 
-                    Ok(self.make_delivered_response(notification))
+                    Ok(self.make_delivered_response(notification, &message_id))
                 } else {
                     trace!("✉ Node has not delivered the message, returning stored response");
-                    Ok(self.make_stored_response(notification))
+                    Ok(self.make_stored_response(notification, &message_id))
                 }
             }
             Err(error) => {
                 // Can't communicate with the node, attempt to stop using it
                 debug!("✉ Error while triggering notification check: {}", error);
                 self.remove_node_id(&user, node_id).await?;
-                Ok(self.make_stored_response(notification))
+                Ok(self.make_stored_response(notification, &message_id))
             }
         }
     }
@@ -165,6 +218,17 @@ impl Router for WebPushRouter {
 impl WebPushRouter {
     /// Use the same sort of error chokepoint that all the mobile clients use.
     fn handle_error(&self, error: ApiErrorKind, vapid: Option<VapidHeaderWithKey>) -> ApiError {
+        if let ApiErrorKind::Router(e) = &error {
+            incr_error_metric(
+                &self.metrics,
+                "websocket",
+                "direct",
+                BridgeErrorReason::from(e),
+                e.status(),
+                e.errno(),
+                None,
+            );
+        }
         let mut err = ApiError::from(error);
         if let Some(Ok(claims)) = vapid.map(|v| v.vapid.claims()) {
             let mut extras = err.extras.unwrap_or_default();
@@ -176,6 +240,19 @@ impl WebPushRouter {
         err
     }
 
+    /// Whether the notification's channel is "store only" (when
+    /// `store_only_channels` is enabled)
+    async fn is_store_only(&self, notification: &Notification) -> ApiResult<bool> {
+        if !self.store_only_channels {
+            return Ok(false);
+        }
+        let subscription = &notification.subscription;
+        Ok(self
+            .db
+            .is_store_only(&subscription.user.uaid, &subscription.channel_id)
+            .await?)
+    }
+
     /// Send the notification to the node
     async fn send_notification(
         &self,
@@ -185,7 +262,23 @@ impl WebPushRouter {
         let url = format!("{}/push/{}", node_id, notification.subscription.user.uaid);
         let notification = notification.serialize_for_delivery()?;
 
-        Ok(self.http.put(&url).json(&notification).send().await?)
+        let body = match self.internal_encoding {
+            InternalEncoding::Json => serde_json::to_vec(&notification)?,
+            InternalEncoding::MessagePack => rmp_serde::to_vec(&notification)
+                .map_err(|e| ApiErrorKind::General(format!("MessagePack encode error: {e}")))?,
+        };
+
+        let request = self
+            .http
+            .put(&url)
+            .header(CONTENT_TYPE, self.internal_encoding.content_type());
+        let request = if self.internal_compression {
+            let body = zstd::encode_all(body.as_slice(), zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            request.header(CONTENT_ENCODING, "zstd").body(body)
+        } else {
+            request.body(body)
+        };
+        Ok(request.send().await?)
     }
 
     /// Notify the node to check for notifications for the user
@@ -199,13 +292,15 @@ impl WebPushRouter {
         self.http.put(&url).send().await
     }
 
-    /// Store a notification in the database
-    async fn store_notification(&self, notification: &Notification) -> ApiResult<()> {
-        self.db
-            .save_message(
-                &notification.subscription.user.uaid,
-                notification.clone().into(),
-            )
+    /// Store a notification in the database, returning its message ID built
+    /// from the key it was stored under
+    async fn store_notification(&self, notification: &Notification) -> ApiResult<String> {
+        let uaid = &notification.subscription.user.uaid;
+        let message_id = Some(notification.message_id.as_str());
+        let save = self
+            .db
+            .save_message_returning(uaid, notification.clone().into());
+        let chidmessageid = otel::in_span(otel::DB_SAVE_MESSAGE, uaid, message_id, save)
             .await
             .map_err(|e| {
                 self.handle_error(
@@ -223,7 +318,15 @@ impl WebPushRouter {
                     )),
                     notification.subscription.vapid.clone(),
                 )
-            })
+            })?;
+        debug!("✉ Stored notification"; "chidmessageid" => &chidmessageid);
+        // It's stored: don't fail the request over its Location
+        Ok(MessageId::from_sort_key(*uaid, &chidmessageid)
+            .map(|message_id| message_id.encrypt(&self.internal_fernet))
+            .unwrap_or_else(|e| {
+                warn!("✉ Unexpected chidmessageid {}: {}", &chidmessageid, e);
+                notification.message_id.clone()
+            }))
     }
 
     /// Remove the node ID from a user. This is done if the user is no longer
@@ -242,23 +345,49 @@ impl WebPushRouter {
 
     /// Update metrics and create a response for when a notification has been directly forwarded to
     /// an autopush server.
-    fn make_delivered_response(&self, notification: &Notification) -> RouterResponse {
-        self.make_response(notification, "Direct", StatusCode::CREATED)
+    fn make_delivered_response(
+        &self,
+        notification: &Notification,
+        message_id: &str,
+    ) -> RouterResponse {
+        self.make_response(notification, Some(message_id), true, StatusCode::CREATED)
     }
 
     /// Update metrics and create a response for when a notification has been stored in the database
     /// for future transmission.
-    fn make_stored_response(&self, notification: &Notification) -> RouterResponse {
-        self.make_response(notification, "Stored", StatusCode::CREATED)
+    fn make_stored_response(
+        &self,
+        notification: &Notification,
+        message_id: &str,
+    ) -> RouterResponse {
+        self.make_response(notification, Some(message_id), false, StatusCode::CREATED)
     }
 
-    /// Update metrics and create a response after routing a notification
+    /// Update metrics and create a response for when a notification was
+    /// accepted but neither delivered nor stored. There's no message to
+    /// locate, so the response has no `Location`.
+    fn make_dropped_response(&self, notification: &Notification) -> RouterResponse {
+        self.make_response(notification, None, false, StatusCode::CREATED)
+    }
+
+    /// Update metrics and create a response after routing a notification,
+    /// located at its `message_id` (`None` when it was dropped)
     fn make_response(
         &self,
         notification: &Notification,
-        destination_tag: &str,
+        message_id: Option<&str>,
+        delivered_directly: bool,
         status: StatusCode,
     ) -> RouterResponse {
+        let (destination_tag, path) = match (delivered_directly, message_id) {
+            (true, _) => ("Direct", "direct"),
+            (false, Some(_)) => ("Stored", "stored"),
+            (false, None) => ("Dropped", "dropped"),
+        };
+        self.metrics
+            .incr_with_tags("notification.delivery")
+            .with_tag("path", path)
+            .send();
         self.metrics
             .count_with_tags(
                 "notification.message_data",
@@ -271,17 +400,20 @@ impl WebPushRouter {
             status: actix_http::StatusCode::from_u16(status.as_u16()).unwrap_or_default(),
             headers: {
                 let mut map = HashMap::new();
-                map.insert(
-                    "Location",
-                    self.endpoint_url
-                        .join(&format!("/m/{}", notification.message_id))
-                        .expect("Message ID is not URL-safe")
-                        .to_string(),
-                );
+                if let Some(message_id) = message_id {
+                    map.insert(
+                        "Location",
+                        self.endpoint_url
+                            .join(&format!("/m/{message_id}"))
+                            .expect("Message ID is not URL-safe")
+                            .to_string(),
+                    );
+                }
                 map.insert("TTL", notification.headers.ttl.to_string());
                 map
             },
             body: None,
+            delivered_directly,
         }
     }
 }
@@ -293,19 +425,37 @@ mod test {
 
     use reqwest;
 
+    use crate::extractors::routers::RouterType;
     use crate::extractors::subscription::tests::{make_vapid, PUB_KEY};
     use crate::headers::vapid::VapidClaims;
+    use crate::routers::common::tests::{
+        assert_bridge_error, channel_id, make_notification, spy_metrics,
+    };
     use autopush_common::errors::ReportableError;
+    use autopush_common::notification::ChidMessageId;
 
     use super::*;
     use autopush_common::db::mock::MockDbClient;
 
+    /// A `chidmessageid` returned by storage
+    fn stored_id() -> String {
+        ChidMessageId::standard(channel_id(), 1).to_string()
+    }
+
     fn make_router(db: Box<dyn DbClient>) -> WebPushRouter {
         WebPushRouter {
             db,
             metrics: Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
             http: reqwest::Client::new(),
             endpoint_url: Url::parse("http://localhost:8080/").unwrap(),
+            internal_fernet: MultiFernet::new(vec![fernet::Fernet::new(
+                &fernet::Fernet::generate_key(),
+            )
+            .unwrap()]),
+            internal_compression: false,
+            internal_encoding: InternalEncoding::Json,
+            min_store_ttl: 0,
+            store_only_channels: false,
         }
     }
 
@@ -323,4 +473,359 @@ mod test {
         let err = router.handle_error(ApiErrorKind::LogCheck, Some(vapid));
         assert!(err.extras().contains(&("sub", sub.to_owned())));
     }
+
+    /// A TTL of zero is delivered to a connected client without storing it
+    #[tokio::test]
+    async fn zero_ttl_connected() {
+        let mut server = mockito::Server::new_async().await;
+        // No storage calls are expected
+        let router = make_router(Box::new(MockDbClient::new()));
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.subscription.user.node_id = Some(server.url());
+        let node_mock = server
+            .mock(
+                "PUT",
+                format!("/push/{}", notification.subscription.user.uaid).as_str(),
+            )
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, actix_http::StatusCode::CREATED);
+        assert_eq!(response.headers.get("TTL"), Some(&"0".to_owned()));
+        node_mock.assert_async().await;
+    }
+
+    /// Notifications are forwarded zstd compressed when enabled
+    #[tokio::test]
+    async fn internal_compression() {
+        let mut server = mockito::Server::new_async().await;
+        let mut router = make_router(Box::new(MockDbClient::new()));
+        router.internal_compression = true;
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.subscription.user.node_id = Some(server.url());
+        let node_mock = server
+            .mock(
+                "PUT",
+                format!("/push/{}", notification.subscription.user.uaid).as_str(),
+            )
+            .match_header("content-encoding", "zstd")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert!(response.delivered_directly);
+        node_mock.assert_async().await;
+    }
+
+    /// Notifications are forwarded MessagePack encoded when enabled
+    #[tokio::test]
+    async fn internal_encoding_msgpack() {
+        let mut server = mockito::Server::new_async().await;
+        let mut router = make_router(Box::new(MockDbClient::new()));
+        router.internal_encoding = InternalEncoding::MessagePack;
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.subscription.user.node_id = Some(server.url());
+        let node_mock = server
+            .mock(
+                "PUT",
+                format!("/push/{}", notification.subscription.user.uaid).as_str(),
+            )
+            .match_header("content-type", "application/msgpack")
+            .match_request(|request| {
+                let body = request.body().unwrap();
+                rmp_serde::from_slice::<serde_json::Value>(body)
+                    .is_ok_and(|value| value.get("channelID").is_some())
+            })
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert!(response.delivered_directly);
+        node_mock.assert_async().await;
+    }
+
+    /// A TTL of zero for an unreachable client is dropped without storing it
+    #[tokio::test]
+    async fn zero_ttl_disconnected() {
+        // No storage calls are expected
+        let mut router = make_router(Box::new(MockDbClient::new()));
+        let (metrics, sent) = spy_metrics();
+        router.metrics = metrics;
+        let notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        assert!(notification.subscription.user.node_id.is_none());
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, actix_http::StatusCode::CREATED);
+        // It's neither delivered nor stored, so there's nothing to locate
+        assert!(!response.delivered_directly);
+        assert!(!response.headers.contains_key("Location"));
+        assert_eq!(response.headers.get("TTL"), Some(&"0".to_owned()));
+        let sent = sent();
+        assert!(sent.iter().any(
+            |m| m.starts_with("autopush.notification.delivery:") && m.contains("path:dropped")
+        ));
+        assert!(!sent
+            .iter()
+            .any(|m| m.contains("path:direct") || m.contains("path:stored")));
+    }
+
+    /// A connected client is delivered to directly
+    #[tokio::test]
+    async fn delivery_path_direct() {
+        let mut server = mockito::Server::new_async().await;
+        let mut router = make_router(Box::new(MockDbClient::new()));
+        let (metrics, sent) = spy_metrics();
+        router.metrics = metrics;
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.headers.ttl = 60;
+        notification.subscription.user.node_id = Some(server.url());
+        let node_mock = server
+            .mock(
+                "PUT",
+                format!("/push/{}", notification.subscription.user.uaid).as_str(),
+            )
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert!(response.delivered_directly);
+        assert!(
+            sent()
+                .iter()
+                .any(|m| m.starts_with("autopush.notification.delivery:")
+                    && m.contains("path:direct"))
+        );
+        node_mock.assert_async().await;
+    }
+
+    /// A disconnected client has the notification stored
+    #[tokio::test]
+    async fn delivery_path_stored() {
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.headers.ttl = 60;
+        let user = notification.subscription.user.clone();
+        let mut db = MockDbClient::new();
+        db.expect_save_message_returning()
+            .times(1)
+            .return_once(|_, _| Ok(stored_id()));
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let mut router = make_router(Box::new(db));
+        let (metrics, sent) = spy_metrics();
+        router.metrics = metrics;
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert!(!response.delivered_directly);
+        assert!(
+            sent()
+                .iter()
+                .any(|m| m.starts_with("autopush.notification.delivery:")
+                    && m.contains("path:stored"))
+        );
+        // Located by the key it was stored under
+        let location = Url::parse(&response.headers["Location"]).unwrap();
+        let message_id = location.path().strip_prefix("/m/").unwrap();
+        let message_id = MessageId::decrypt(&router.internal_fernet, message_id).unwrap();
+        assert_eq!(message_id.uaid(), notification.subscription.user.uaid);
+        assert_eq!(message_id.sort_key(), stored_id());
+    }
+
+    /// A stored notification's spans: received by the endpoint, dispatched to
+    /// the router then saved
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn otel_spans() {
+        use opentelemetry::trace::SpanId;
+        use opentelemetry_sdk::{testing::trace::InMemorySpanExporter, trace::TracerProvider};
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        opentelemetry::global::set_tracer_provider(provider.clone());
+
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.headers.ttl = 60;
+        let user = notification.subscription.user.clone();
+        let mut db = MockDbClient::new();
+        db.expect_save_message_returning()
+            .times(1)
+            .return_once(|_, _| Ok(stored_id()));
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let router = make_router(Box::new(db));
+        crate::routes::webpush::receive(&router, &notification)
+            .await
+            .unwrap();
+
+        provider.force_flush();
+        // Only this notification's spans (other tests may record their own)
+        let uaid_hash = autopush_common::util::elide(
+            notification.subscription.user.uaid.as_simple().to_string(),
+        );
+        let spans: Vec<_> = exporter
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .filter(|span| {
+                span.attributes
+                    .iter()
+                    .any(|kv| kv.key.as_str() == "uaid_hash" && kv.value.as_str() == uaid_hash)
+            })
+            .collect();
+        let span = |name: &str| {
+            let span = spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap_or_else(|| panic!("Missing span {name}: {spans:?}"));
+            assert!(span
+                .attributes
+                .iter()
+                .any(|kv| kv.key.as_str() == "message_id"
+                    && kv.value.as_str() == notification.message_id.as_str()));
+            span
+        };
+        let receive = span(otel::ENDPOINT_RECEIVE);
+        let dispatch = span(otel::ROUTER_DISPATCH);
+        let save_message = span(otel::DB_SAVE_MESSAGE);
+        assert_eq!(spans.len(), 3);
+        assert_eq!(receive.parent_span_id, SpanId::INVALID);
+        assert_eq!(dispatch.parent_span_id, receive.span_context.span_id());
+        assert_eq!(save_message.parent_span_id, dispatch.span_context.span_id());
+        assert_eq!(
+            save_message.span_context.trace_id(),
+            receive.span_context.trace_id()
+        );
+    }
+
+    /// A store only channel's notification is stored, not delivered, to a
+    /// connected client
+    #[tokio::test]
+    async fn store_only_connected() {
+        let mut server = mockito::Server::new_async().await;
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.headers.ttl = 60;
+        notification.subscription.user.node_id = Some(server.url());
+        let mut db = MockDbClient::new();
+        db.expect_is_store_only()
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        db.expect_save_message_returning()
+            .times(1)
+            .return_once(|_, _| Ok(stored_id()));
+        let mut router = make_router(Box::new(db));
+        router.store_only_channels = true;
+        let uaid = notification.subscription.user.uaid;
+        let push_mock = server
+            .mock("PUT", format!("/push/{uaid}").as_str())
+            .expect(0)
+            .create_async()
+            .await;
+        let notif_mock = server
+            .mock("PUT", format!("/notif/{uaid}").as_str())
+            .expect(0)
+            .create_async()
+            .await;
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, actix_http::StatusCode::CREATED);
+        assert!(!response.delivered_directly);
+        push_mock.assert_async().await;
+        notif_mock.assert_async().await;
+    }
+
+    /// A store only channel's user who connects after it's stored isn't
+    /// notified to check storage
+    #[tokio::test]
+    async fn store_only_reconnected() {
+        let server = mockito::Server::new_async().await;
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.headers.ttl = 60;
+        let mut user = notification.subscription.user.clone();
+        user.node_id = Some(server.url());
+        let mut db = MockDbClient::new();
+        db.expect_save_message_returning()
+            .times(1)
+            .return_once(|_, _| Ok(stored_id()));
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        db.expect_is_store_only()
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        let mut router = make_router(Box::new(db));
+        router.store_only_channels = true;
+
+        // Nothing's mocked on the server: a request would fail and remove
+        // the node_id (unexpected by the db mock)
+        let response = router.route_notification(&notification).await.unwrap();
+        assert!(!response.delivered_directly);
+    }
+
+    /// Disconnected users' notifications with a TTL below `min_store_ttl`
+    /// aren't stored
+    #[tokio::test]
+    async fn min_store_ttl_skips_storage() {
+        // No storage calls are expected
+        let mut router = make_router(Box::new(MockDbClient::new()));
+        router.min_store_ttl = 10;
+        let (metrics, sent) = spy_metrics();
+        router.metrics = metrics;
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.headers.ttl = 9;
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, actix_http::StatusCode::CREATED);
+        assert!(!response.delivered_directly);
+        let sent = sent();
+        assert!(sent.iter().any(
+            |m| m.starts_with("autopush.notification.message.not_stored:")
+                && m.contains("reason:ttl_too_short")
+        ));
+        // It was never stored, so there's nothing to locate (or delete)
+        assert!(!response.headers.contains_key("Location"));
+        assert!(sent.iter().any(
+            |m| m.starts_with("autopush.notification.delivery:") && m.contains("path:dropped")
+        ));
+        assert!(!sent.iter().any(|m| m.contains("path:stored")));
+    }
+
+    /// A TTL of exactly `min_store_ttl` is still stored
+    #[tokio::test]
+    async fn min_store_ttl_boundary_stored() {
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.headers.ttl = 10;
+        let user = notification.subscription.user.clone();
+        let mut db = MockDbClient::new();
+        db.expect_save_message_returning()
+            .times(1)
+            .return_once(|_, _| Ok(stored_id()));
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let mut router = make_router(Box::new(db));
+        router.min_store_ttl = 10;
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, actix_http::StatusCode::CREATED);
+        assert!(!response.delivered_directly);
+        assert!(response.headers.contains_key("Location"));
+    }
+
+    #[tokio::test]
+    async fn error_reason() {
+        let mut router = make_router(Box::new(MockDbClient::new()));
+        let (metrics, sent) = spy_metrics();
+        router.metrics = metrics;
+
+        router.handle_error(ApiErrorKind::Router(RouterError::UserWasDeleted), None);
+        assert_bridge_error(&sent(), "websocket", BridgeErrorReason::Unregistered);
+    }
 }
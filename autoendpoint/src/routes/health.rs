@@ -22,12 +22,15 @@ pub async fn health_route(state: Data<AppState>) -> Json<serde_json::Value> {
     routers.insert("apns", state.apns_router.active());
     routers.insert("fcm", state.fcm_router.active());
 
-    let health = json!({
+    let mut health = json!({
     "status": "OK",
     "version": env!("CARGO_PKG_VERSION"),
     "router_table": router_health,
     "message_table": message_health,
     "routers": routers});
+    if state.settings.deep_health_check {
+        health["db_write"] = interpret_table_health(state.db.write_health_check().await);
+    }
 
     Json(health)
 }
@@ -3,17 +3,19 @@ use actix_web::{HttpRequest, HttpResponse};
 use cadence::{CountedExt, Histogrammed, StatsdClient};
 use uuid::Uuid;
 
-use crate::error::{ApiErrorKind, ApiResult};
+use crate::error::{ApiErrorKind, ApiResult, GoneReason};
 use crate::extractors::{
-    authorization_check::AuthorizationCheck, new_channel_data::NewChannelData,
+    authorization_check::AuthorizationCheck,
+    new_channel_data::NewChannelData,
     registration_path_args::RegistrationPathArgs,
     registration_path_args_with_uaid::RegistrationPathArgsWithUaid,
-    router_data_input::RouterDataInput, routers::Routers,
+    router_data_input::RouterDataInput,
+    routers::{RouterType, Routers},
 };
 use crate::headers::util::get_header;
 use crate::server::AppState;
 
-use autopush_common::db::User;
+use autopush_common::db::{client::DbClient, User};
 use autopush_common::endpoint::make_endpoint;
 
 /// Handle the `POST /v1/{router_type}/{app_id}/registration` route
@@ -35,6 +37,12 @@ pub async fn register_uaid_route(
     incr_metric("ua.command.register", &app_state.metrics, &request);
 
     // Register user and channel in database
+    check_supported_router_type(
+        &app_state.supported_router_types,
+        &app_state.metrics,
+        path_args.router_type,
+        None,
+    )?;
     let user = User::builder()
         .router_type(path_args.router_type.to_string())
         .router_data(router_data)
@@ -55,6 +63,7 @@ pub async fn register_uaid_route(
         router_data_input.key.as_deref(),
         app_state.settings.endpoint_url().as_str(),
         &app_state.fernet,
+        app_state.settings.endpoint_ttl,
     )
     .map_err(ApiErrorKind::EndpointUrl)?;
     trace!("🌍 endpoint = {}", endpoint_url);
@@ -110,6 +119,12 @@ pub async fn update_token_route(
     let router_data = router.register(&router_data_input, &app_id)?;
 
     // Update the user in the database
+    check_supported_router_type(
+        &app_state.supported_router_types,
+        &app_state.metrics,
+        router_type,
+        Some(&user.router_type),
+    )?;
     user.router_type = path_args.router_type.to_string();
     user.router_data = Some(router_data);
     trace!("🌍 Updating user with UAID {uaid}");
@@ -146,6 +161,7 @@ pub async fn new_channel_route(
         channel_data.key.as_deref(),
         app_state.settings.endpoint_url().as_str(),
         &app_state.fernet,
+        app_state.settings.endpoint_ttl,
     )
     .map_err(ApiErrorKind::EndpointUrl)?;
     trace!("endpoint = {endpoint_url}");
@@ -211,24 +227,55 @@ pub async fn unregister_channel_route(
     app_state: Data<AppState>,
     request: HttpRequest,
 ) -> ApiResult<HttpResponse> {
-    let channel_id = request
+    let chid = request
         .match_info()
         .get("chid")
-        .expect("{chid} must be part of the path")
-        .parse::<Uuid>()
-        .map_err(|_| ApiErrorKind::NoSubscription)?;
+        .expect("{chid} must be part of the path");
     let uaid = path_args.user.uaid;
-    debug!("🌍 Unregistering CHID {channel_id} for UAID {uaid}");
+    debug!("🌍 Unregistering CHID {chid} for UAID {uaid}");
 
     incr_metric("ua.command.unregister", &app_state.metrics, &request);
-    let channel_did_exist = app_state.db.remove_channel(&uaid, &channel_id).await?;
+    unregister_channel(app_state.db.as_ref(), &uaid, chid).await?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-    if channel_did_exist {
-        Ok(HttpResponse::Ok().finish())
-    } else {
+/// Remove the `chid` channel from a user, which is Gone when it's invalid
+/// or didn't exist
+async fn unregister_channel(db: &dyn DbClient, uaid: &Uuid, chid: &str) -> ApiResult<()> {
+    let channel_id = chid
+        .parse::<Uuid>()
+        .map_err(|_| ApiErrorKind::NoSubscription(GoneReason::ChannelUnsubscribed))?;
+    if !db.remove_channel(uaid, &channel_id).await? {
         debug!("Channel did not exist");
-        Err(ApiErrorKind::NoSubscription.into())
+        return Err(ApiErrorKind::NoSubscription(GoneReason::ChannelUnsubscribed).into());
+    }
+    Ok(())
+}
+
+/// Reject registering (or switching an existing user to) a `router_type`
+/// that's not in `Settings::supported_router_types`, before `add_user` (or
+/// `update_user`) writes it. The routes are the only writers of bridged
+/// router types: autoconnect's Hello only ever creates webpush users.
+///
+/// `existing` is the user's current `router_type`: refreshing the token of
+/// an existing (even since unlisted, e.g. legacy GCM) one is always accepted
+fn check_supported_router_type(
+    supported: &[RouterType],
+    metrics: &StatsdClient,
+    router_type: RouterType,
+    existing: Option<&str>,
+) -> ApiResult<()> {
+    if supported.contains(&router_type)
+        || existing.map(str::parse::<RouterType>) == Some(Ok(router_type))
+    {
+        return Ok(());
     }
+    metrics
+        .incr_with_tags("ua.registration.rejected")
+        .with_tag("reason", "unsupported_router_type")
+        .with_tag("router_type", &router_type.to_string())
+        .send();
+    Err(ApiErrorKind::InvalidRouterType.into())
 }
 
 /// Increment a metric with data from the request
@@ -242,3 +289,89 @@ fn incr_metric(name: &str, metrics: &StatsdClient, request: &HttpRequest) {
         .with_tag("host", get_header(request, "Host").unwrap_or("unknown"))
         .send()
 }
+
+#[cfg(test)]
+mod tests {
+    use autopush_common::db::mock::MockDbClient;
+    use uuid::Uuid;
+
+    use super::{check_supported_router_type, unregister_channel};
+    use crate::error::{ApiErrorKind, GoneReason};
+    use crate::extractors::routers::RouterType;
+    use crate::routers::common::tests::spy_metrics;
+
+    const SUPPORTED: [RouterType; 3] = [RouterType::WebPush, RouterType::FCM, RouterType::APNS];
+
+    #[test]
+    fn supported_router_type_allowed() {
+        let (metrics, sent) = spy_metrics();
+        assert!(check_supported_router_type(&SUPPORTED, &metrics, RouterType::FCM, None).is_ok());
+        // Switching between supported types
+        assert!(
+            check_supported_router_type(&SUPPORTED, &metrics, RouterType::APNS, Some("fcm"))
+                .is_ok()
+        );
+        assert!(sent().is_empty());
+    }
+
+    #[test]
+    fn unsupported_router_type_rejected() {
+        let (metrics, sent) = spy_metrics();
+        let err =
+            check_supported_router_type(&SUPPORTED, &metrics, RouterType::GCM, None).unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::InvalidRouterType));
+        // Nor can an existing user switch to it
+        let err = check_supported_router_type(&SUPPORTED, &metrics, RouterType::GCM, Some("fcm"))
+            .unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::InvalidRouterType));
+
+        let sent = sent();
+        assert_eq!(sent.len(), 2);
+        assert!(sent
+            .iter()
+            .all(|m| m.starts_with("autopush.ua.registration.rejected:1|c")
+                && m.contains("reason:unsupported_router_type")
+                && m.contains("router_type:gcm")));
+    }
+
+    /// Existing legacy GCM users may still refresh their tokens
+    #[test]
+    fn legacy_gcm_user_token_refresh() {
+        let (metrics, sent) = spy_metrics();
+        assert!(
+            check_supported_router_type(&SUPPORTED, &metrics, RouterType::GCM, Some("gcm")).is_ok()
+        );
+        assert!(sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unregister_missing_channel_unsubscribed() {
+        let chid = Uuid::new_v4();
+        let mut db = MockDbClient::new();
+        db.expect_remove_channel()
+            .withf(move |_, channel_id| channel_id == &chid)
+            .times(1)
+            .return_once(|_, _| Ok(false));
+
+        let err = unregister_channel(&db, &Uuid::new_v4(), &chid.to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.kind.gone_reason(),
+            Some(GoneReason::ChannelUnsubscribed)
+        );
+    }
+
+    #[tokio::test]
+    async fn unregister_invalid_channel_unsubscribed() {
+        let db = MockDbClient::new();
+
+        let err = unregister_channel(&db, &Uuid::new_v4(), "not-a-chid")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.kind.gone_reason(),
+            Some(GoneReason::ChannelUnsubscribed)
+        );
+    }
+}
@@ -4,9 +4,11 @@ use crate::error::{ApiErrorKind, ApiResult};
 use crate::extractors::message_id::MessageId;
 use crate::extractors::notification::Notification;
 use crate::extractors::routers::{RouterType, Routers};
+use crate::routers::{Router, RouterResponse};
 use crate::server::AppState;
 use actix_web::web::Data;
 use actix_web::HttpResponse;
+use autopush_common::otel;
 
 /// Handle the `POST /wpush/{api_version}/{token}` and `POST /wpush/{token}` routes
 pub async fn webpush_route(
@@ -25,7 +27,29 @@ pub async fn webpush_route(
         RouterType::from_str(&notification.subscription.user.router_type)
             .map_err(|_| ApiErrorKind::InvalidRouterType)?,
     );
-    Ok(router.route_notification(&notification).await?.into())
+    Ok(receive(router, &notification).await?.into())
+}
+
+/// Route a received notification, traced as an `endpoint.receive` span
+/// with a `router.dispatch` child (with the `otel` feature)
+pub(crate) async fn receive(
+    router: &dyn Router,
+    notification: &Notification,
+) -> ApiResult<RouterResponse> {
+    let uaid = notification.subscription.user.uaid;
+    let message_id = Some(notification.message_id.as_str());
+    otel::in_span(
+        otel::ENDPOINT_RECEIVE,
+        &uaid,
+        message_id,
+        otel::in_span(
+            otel::ROUTER_DISPATCH,
+            &uaid,
+            message_id,
+            router.route_notification(notification),
+        ),
+    )
+    .await
 }
 
 /// Handle the `DELETE /m/{message_id}` route
@@ -18,7 +18,9 @@ use autopush_common::{
     middleware::sentry::SentryWrapper,
 };
 
+use crate::extractors::routers::RouterType;
 use crate::metrics;
+use crate::rate_limit::ChannelRateLimiter;
 #[cfg(feature = "stub")]
 use crate::routers::stub::router::StubRouter;
 use crate::routers::{apns::router::ApnsRouter, fcm::router::FcmRouter};
@@ -42,6 +44,8 @@ pub struct AppState {
     pub metrics: Arc<StatsdClient>,
     pub settings: Settings,
     pub fernet: MultiFernet,
+    /// Signs internal tokens (message IDs)
+    pub internal_fernet: MultiFernet,
     pub db: Box<dyn DbClient>,
     pub http: reqwest::Client,
     pub fcm_router: Arc<FcmRouter>,
@@ -49,15 +53,24 @@ pub struct AppState {
     #[cfg(feature = "stub")]
     pub stub_router: Arc<StubRouter>,
     pub vapid_tracker: Arc<VapidTracker>,
+    /// Parsed once from `Settings::supported_router_types`
+    pub supported_router_types: Arc<Vec<RouterType>>,
+    /// Shared across workers: notifications per channel are limited
+    /// node-wide
+    pub channel_limiter: Arc<ChannelRateLimiter>,
 }
 
 pub struct Server;
 
 impl Server {
-    pub async fn with_settings(settings: Settings) -> ApiResult<dev::Server> {
+    /// Build the server, also returning its metrics client (for reporting on
+    /// shutdown)
+    pub async fn with_settings(settings: Settings) -> ApiResult<(dev::Server, Arc<StatsdClient>)> {
         let metrics = Arc::new(metrics::metrics_from_settings(&settings)?);
-        let bind_address = format!("{}:{}", settings.host, settings.port);
+        // A tuple (unlike a "host:port" string) also accepts IPv6 hosts
+        let bind_address = (settings.host.clone(), settings.port);
         let fernet = settings.make_fernet();
+        let internal_fernet = settings.make_internal_fernet();
         let endpoint_url = settings.endpoint_url();
         let db_settings = DbSettings {
             dsn: settings.db_dsn.clone(),
@@ -90,11 +103,18 @@ impl Server {
             .timeout(Duration::from_millis(settings.request_timeout_millis))
             .build()
             .expect("Could not generate request client");
+        let bridge_timeout = Duration::from_millis(settings.bridge_request_timeout_millis);
+        let bridge_http = reqwest::ClientBuilder::new()
+            .connect_timeout(Duration::from_millis(settings.connection_timeout_millis))
+            .timeout(bridge_timeout)
+            .build()
+            .expect("Could not generate bridge request client");
         let fcm_router = Arc::new(
             FcmRouter::new(
                 settings.fcm.clone(),
                 endpoint_url.clone(),
-                http.clone(),
+                bridge_http,
+                bridge_timeout,
                 metrics.clone(),
                 db.clone(),
             )
@@ -104,18 +124,25 @@ impl Server {
             ApnsRouter::new(
                 settings.apns.clone(),
                 endpoint_url.clone(),
+                bridge_timeout,
                 metrics.clone(),
                 db.clone(),
             )
             .await?,
         );
         let vapid_tracker = Arc::new(VapidTracker(settings.tracking_keys()));
+        let supported_router_types = Arc::new(settings.supported_router_types());
+        let channel_limiter = Arc::new(ChannelRateLimiter::new(
+            settings.channel_rate_limit,
+            settings.channel_rate_limit_burst,
+        ));
         #[cfg(feature = "stub")]
         let stub_router = Arc::new(StubRouter::new(settings.stub.clone())?);
         let app_state = AppState {
             metrics: metrics.clone(),
             settings,
             fernet,
+            internal_fernet,
             db,
             http,
             fcm_router,
@@ -123,6 +150,8 @@ impl Server {
             #[cfg(feature = "stub")]
             stub_router,
             vapid_tracker,
+            supported_router_types,
+            channel_limiter,
         };
 
         spawn_pool_periodic_reporter(
@@ -201,6 +230,6 @@ impl Server {
         .bind(bind_address)?
         .run();
 
-        Ok(server)
+        Ok((server, metrics))
     }
 }
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::db::error::DbError;
+
+/// The qualifier of the cell recording which codec compressed a message's
+/// `data` cell. Rows without it store `data` uncompressed.
+pub(crate) const DATA_CODEC_QUALIFIER: &str = "data_codec";
+
+/// Compression applied to the `data` cell of stored messages.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageCompression {
+    /// Store the data as is
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl MessageCompression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, DbError> {
+        let compressed = match self {
+            Self::None => return Ok(data.to_vec()),
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+            Self::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        };
+        compressed.map_err(|e| DbError::Serialization(format!("Could not compress data: {e}")))
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, DbError> {
+        let decompressed = match self {
+            Self::None => return Ok(data.to_vec()),
+            Self::Gzip => {
+                let mut result = Vec::new();
+                flate2::read::GzDecoder::new(data)
+                    .read_to_end(&mut result)
+                    .map(|_| result)
+            }
+            Self::Zstd => zstd::decode_all(data),
+        };
+        decompressed.map_err(|e| DbError::Serialization(format!("Could not decompress data: {e}")))
+    }
+}
+
+impl fmt::Display for MessageCompression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MessageCompression {
+    type Err = DbError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(DbError::Serialization(format!(
+                "Unknown message compression codec: {s}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageCompression;
+
+    #[test]
+    fn round_trip() {
+        let data = "{\"some\": \"json envelope\", \"some more\": \"json envelope\"}".repeat(10);
+        for codec in [
+            MessageCompression::None,
+            MessageCompression::Gzip,
+            MessageCompression::Zstd,
+        ] {
+            let compressed = codec.compress(data.as_bytes()).unwrap();
+            if codec != MessageCompression::None {
+                assert!(compressed.len() < data.len());
+            }
+            assert_eq!(codec.decompress(&compressed).unwrap(), data.as_bytes());
+            assert_eq!(codec.as_str().parse::<MessageCompression>().unwrap(), codec);
+        }
+        assert!("lzma".parse::<MessageCompression>().is_err());
+    }
+}
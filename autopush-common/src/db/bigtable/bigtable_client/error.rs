@@ -135,12 +135,23 @@ pub enum BigTableError {
 
     #[error("BigTable config error: {0}")]
     Config(String),
+
+    /// The channel failed to connect in time
+    #[error("BigTable unreachable")]
+    Unreachable,
+
+    /// A deadline bound read (see `fetch_deadline`) exceeded its deadline
+    /// before reading any rows
+    #[error("BigTable fetch deadline exceeded")]
+    FetchDeadlineExceeded,
 }
 
 impl BigTableError {
     pub fn status(&self) -> StatusCode {
         match self {
-            BigTableError::PoolTimeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            BigTableError::PoolTimeout(_) | BigTableError::FetchDeadlineExceeded => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             BigTableError::Status(e, _) => e.status(),
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
@@ -151,7 +162,7 @@ impl ReportableError for BigTableError {
     fn is_sentry_event(&self) -> bool {
         #[allow(clippy::match_like_matches_macro)]
         match self {
-            BigTableError::PoolTimeout(_) => false,
+            BigTableError::PoolTimeout(_) | BigTableError::FetchDeadlineExceeded => false,
             _ => true,
         }
     }
@@ -169,6 +180,10 @@ impl ReportableError for BigTableError {
             BigTableError::PoolTimeout(_) => "storage.bigtable.error.pool_timeout",
             BigTableError::GRPC(_) => "storage.bigtable.error.grpc",
             BigTableError::Config(_) => "storage.bigtable.error.config",
+            BigTableError::Unreachable => "storage.bigtable.error.unreachable",
+            BigTableError::FetchDeadlineExceeded => {
+                "storage.bigtable.error.fetch_deadline_exceeded"
+            }
         };
         Some(err)
     }
@@ -4,9 +4,10 @@ use std::time::{Duration, SystemTime};
 
 use futures::StreamExt;
 use google_cloud_rust_raw::bigtable::v2::bigtable::{ReadRowsResponse, ReadRowsResponse_CellChunk};
-use grpcio::ClientSStreamReceiver;
+use grpcio::{ClientSStreamReceiver, RpcStatusCode};
 
 use super::{cell::Cell, error::BigTableError, row::Row, FamilyId, Qualifier, RowKey};
+use crate::util::elide;
 
 /// List of the potential states when we are reading each value from the
 /// returned stream and composing a "row"
@@ -378,13 +379,54 @@ impl RowMerger {
 
     /// Iterate through all the returned chunks and compile them into a hash of finished cells indexed by row_key
     pub async fn process_chunks(
-        mut stream: ClientSStreamReceiver<ReadRowsResponse>,
+        stream: ClientSStreamReceiver<ReadRowsResponse>,
     ) -> Result<BTreeMap<RowKey, Row>, BigTableError> {
-        // Work object
-        let mut merger = Self::default();
+        let mut rows = BTreeMap::<RowKey, Row>::new();
+        Self::process_chunks_into(stream, &mut rows).await?;
+        Ok(rows)
+    }
 
-        // finished collection
+    /// Like [RowMerger::process_chunks], but a read exceeding its deadline
+    /// after completing some rows returns them (along with `true`) rather
+    /// than failing
+    pub async fn process_chunks_until_deadline(
+        stream: ClientSStreamReceiver<ReadRowsResponse>,
+    ) -> Result<(BTreeMap<RowKey, Row>, bool), BigTableError> {
         let mut rows = BTreeMap::<RowKey, Row>::new();
+        let result = Self::process_chunks_into(stream, &mut rows).await;
+        Self::partial_on_deadline(result, rows)
+    }
+
+    /// Resolve a deadline bound read: exceeding the deadline after
+    /// completing some `rows` returns them (along with `true`), exceeding it
+    /// before completing any is a [BigTableError::FetchDeadlineExceeded]
+    fn partial_on_deadline(
+        result: Result<(), BigTableError>,
+        rows: BTreeMap<RowKey, Row>,
+    ) -> Result<(BTreeMap<RowKey, Row>, bool), BigTableError> {
+        match result {
+            Ok(()) => Ok((rows, false)),
+            Err(BigTableError::InvalidRowResponse(grpcio::Error::RpcFailure(status)))
+                if status.code() == RpcStatusCode::DEADLINE_EXCEEDED =>
+            {
+                if rows.is_empty() {
+                    debug!("🚣 Deadline exceeded before any rows");
+                    return Err(BigTableError::FetchDeadlineExceeded);
+                }
+                debug!("🚣 Deadline exceeded after {} rows", rows.len());
+                Ok((rows, true))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Compile the stream's chunks into `rows` (the finished collection)
+    async fn process_chunks_into(
+        mut stream: ClientSStreamReceiver<ReadRowsResponse>,
+        rows: &mut BTreeMap<RowKey, Row>,
+    ) -> Result<(), BigTableError> {
+        // Work object
+        let mut merger = Self::default();
 
         while let (Some(row_resp_res), s) = stream.into_future().await {
             stream = s;
@@ -416,7 +458,11 @@ impl RowMerger {
             }
 
             for mut chunk in row.chunks {
-                debug!("🧩 Chunk >> {:?}", &chunk);
+                debug!(
+                    "🧩 Chunk >> {} ({} byte value)",
+                    elide(chunk.get_row_key()),
+                    chunk.get_value().len()
+                );
                 if chunk.get_reset_row() {
                     debug!("‼ resetting row");
                     merger.reset_row(chunk)?;
@@ -455,6 +501,47 @@ impl RowMerger {
         }
         merger.finalize().await?;
         debug!("🚣 Rows: {}", &rows.len());
-        Ok(rows)
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use grpcio::{RpcStatus, RpcStatusCode};
+
+    use super::{BigTableError, Row, RowMerger};
+
+    fn deadline_exceeded() -> Result<(), BigTableError> {
+        Err(BigTableError::InvalidRowResponse(
+            grpcio::Error::RpcFailure(RpcStatus::new(RpcStatusCode::DEADLINE_EXCEEDED)),
+        ))
+    }
+
+    #[test]
+    fn partial_on_deadline() {
+        let rows = BTreeMap::from([("row".to_owned(), Row::new("row".to_owned()))]);
+
+        let (read, partial) = RowMerger::partial_on_deadline(Ok(()), rows.clone()).unwrap();
+        assert_eq!(read.len(), 1);
+        assert!(!partial);
+
+        let (read, partial) =
+            RowMerger::partial_on_deadline(deadline_exceeded(), rows.clone()).unwrap();
+        assert_eq!(read.len(), 1);
+        assert!(partial);
+
+        assert!(matches!(
+            RowMerger::partial_on_deadline(deadline_exceeded(), BTreeMap::new()),
+            Err(BigTableError::FetchDeadlineExceeded)
+        ));
+        let unavailable = Err(BigTableError::InvalidRowResponse(
+            grpcio::Error::RpcFailure(RpcStatus::new(RpcStatusCode::UNAVAILABLE)),
+        ));
+        assert!(matches!(
+            RowMerger::partial_on_deadline(unavailable, rows),
+            Err(BigTableError::InvalidRowResponse(_))
+        ));
     }
 }
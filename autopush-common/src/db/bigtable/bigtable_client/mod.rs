@@ -2,19 +2,20 @@ use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fmt::Display;
+use std::future::Future;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use again::RetryPolicy;
 use async_trait::async_trait;
-use cadence::{CountedExt, StatsdClient};
+use cadence::{Counted, CountedExt, StatsdClient};
 use futures_util::StreamExt;
 use google_cloud_rust_raw::bigtable::admin::v2::bigtable_table_admin::DropRowRangeRequest;
 use google_cloud_rust_raw::bigtable::admin::v2::bigtable_table_admin_grpc::BigtableTableAdminClient;
 use google_cloud_rust_raw::bigtable::v2::bigtable::ReadRowsRequest;
 use google_cloud_rust_raw::bigtable::v2::bigtable_grpc::BigtableClient;
-use google_cloud_rust_raw::bigtable::v2::data::{RowFilter, RowFilter_Chain};
+use google_cloud_rust_raw::bigtable::v2::data::{RowFilter, RowFilter_Chain, RowFilter_Condition};
 use google_cloud_rust_raw::bigtable::v2::{bigtable, data};
 use grpcio::{Channel, Metadata, RpcStatus, RpcStatusCode};
 use protobuf::RepeatedField;
@@ -24,15 +25,20 @@ use uuid::Uuid;
 use crate::db::{
     client::{DbClient, FetchMessageResponse},
     error::{DbError, DbResult},
-    DbSettings, Notification, NotificationRecord, User, MAX_ROUTER_TTL, USER_RECORD_VERSION,
+    reporter::time_operation,
+    sort_timestamp_messages, DbSettings, Notification, User, MAX_ROUTER_TTL, USER_RECORD_VERSION,
 };
+use crate::notification::ChidMessageId;
+use crate::util::{elide, ms_since_epoch, sec_since_epoch};
 
+use self::compression::{MessageCompression, DATA_CODEC_QUALIFIER};
 pub use self::metadata::MetadataBuilder;
 use self::row::{Row, RowCells};
 use super::pool::BigTablePool;
 use super::BigTableDbSettings;
 
 pub mod cell;
+pub mod compression;
 pub mod error;
 pub(crate) mod merge;
 pub mod metadata;
@@ -52,6 +58,20 @@ const ROUTER_FAMILY: &str = "router";
 const MESSAGE_FAMILY: &str = "message"; // The default family for messages
 const MESSAGE_TOPIC_FAMILY: &str = "message_topic";
 
+/// The qualifier prefix of the router cells marking channels as store only
+/// (`store_only:<chid>`, see [DbClient::add_channel_with_store_only])
+const STORE_ONLY_PREFIX: &str = "store_only:";
+/// The qualifier prefix of the router cells holding the VAPID key hash
+/// pinned to a channel (`vapid_pin:<chid>`, see [DbClient::pin_vapid_key]).
+/// Kept apart from the `chid:<chid>` cells, which `update_user` rewrites
+const VAPID_PIN_PREFIX: &str = "vapid_pin:";
+
+/// The reserved row written to (then deleted) by `write_health_check`
+const HEALTH_ROW_KEY: &str = "__health__";
+/// How long a `write_health_check` cell lives if its deletion fails, so that
+/// garbage collection cleans it up
+const HEALTH_CELL_TTL: Duration = Duration::from_secs(60);
+
 pub(crate) const RETRY_COUNT: usize = 5;
 
 /// Semi convenience wrapper to ensure that the UAID is formatted and displayed consistently.
@@ -78,6 +98,8 @@ pub struct BigTableClientImpl {
     metrics: Arc<StatsdClient>,
     /// Connection Channel (used for alternate calls)
     pool: BigTablePool,
+    /// Optional pool of connections to the `read_dsn` for reads
+    read_pool: Option<BigTablePool>,
     metadata: Metadata,
     admin_metadata: Metadata,
 }
@@ -168,6 +190,54 @@ fn filter_chain(filters: impl Into<RepeatedField<RowFilter>>) -> RowFilter {
     filter
 }
 
+/// Return a RowFilter applying `true_filter` to rows matching `predicate`
+/// and `false_filter` to the rest
+fn condition_filter(
+    predicate: RowFilter,
+    true_filter: RowFilter,
+    false_filter: RowFilter,
+) -> RowFilter {
+    let mut condition = RowFilter_Condition::default();
+    condition.set_predicate_filter(predicate);
+    condition.set_true_filter(true_filter);
+    condition.set_false_filter(false_filter);
+    let mut filter = RowFilter::default();
+    filter.set_condition(condition);
+    filter
+}
+
+/// Return a RowFilter matching every cell
+fn pass_all_filter() -> RowFilter {
+    let mut filter = RowFilter::default();
+    filter.set_pass_all_filter(true);
+    filter
+}
+
+/// Return a RowFilter matching no cells
+fn block_all_filter() -> RowFilter {
+    let mut filter = RowFilter::default();
+    filter.set_block_all_filter(true);
+    filter
+}
+
+/// Return a RowFilter matching a stored `current_timestamp` that's at or
+/// past `timestamp`. Big endian values compare the same as their numeric
+/// values
+fn current_timestamp_at_least_filter(timestamp: u64) -> RowFilter {
+    let mut cq_filter = RowFilter::default();
+    cq_filter.set_column_qualifier_regex_filter("^current_timestamp$".as_bytes().to_vec());
+    let mut value_range = data::ValueRange::default();
+    value_range.set_start_value_closed(timestamp.to_be_bytes().to_vec());
+    let mut value_filter = RowFilter::default();
+    value_filter.set_value_range_filter(value_range);
+    filter_chain(vec![
+        router_gc_policy_filter(),
+        family_filter(format!("^{ROUTER_FAMILY}$")),
+        cq_filter,
+        value_filter,
+    ])
+}
+
 /// Return a ReadRowsRequest against table for a given row key
 fn read_row_request(
     table_name: &str,
@@ -203,13 +273,19 @@ fn to_string(value: Vec<u8>, name: &str) -> Result<String, DbError> {
 
 /// Parse the "set" (see [DbClient::add_channels]) of channel ids in a bigtable Row.
 ///
-/// Cells should solely contain the set of channels otherwise an Error is returned.
+/// Cells should solely contain the set of channels (and their store only
+/// marks and VAPID key pins) otherwise an Error is returned.
 fn channels_from_cells(cells: &RowCells) -> DbResult<HashSet<Uuid>> {
     let mut result = HashSet::new();
     for cells in cells.values() {
         let Some(cell) = cells.last() else {
             continue;
         };
+        if cell.qualifier.starts_with(STORE_ONLY_PREFIX)
+            || cell.qualifier.starts_with(VAPID_PIN_PREFIX)
+        {
+            continue;
+        }
         let Some((_, chid)) = cell.qualifier.split_once("chid:") else {
             return Err(DbError::Integrity(
                 "get_channels expected: chid:<chid>".to_owned(),
@@ -318,11 +394,72 @@ pub fn retryable_bt_err(
             | error::BigTableError::Read(e)
             | error::BigTableError::Write(e)
             | error::BigTableError::GRPC(e) => retryable_grpcio_err(metrics)(e),
+            // A fresh read may make progress before its deadline
+            error::BigTableError::FetchDeadlineExceeded => {
+                metric(metrics, "FetchDeadlineExceeded", None);
+                true
+            }
             _ => false,
         }
     }
 }
 
+/// Read a [User] from its router row
+fn row_to_user(uaid: &Uuid, mut row: Row) -> DbResult<User> {
+    let mut result = User {
+        uaid: *uaid,
+        connected_at: to_u64(
+            row.take_required_cell("connected_at")?.value,
+            "connected_at",
+        )?,
+        router_type: to_string(row.take_required_cell("router_type")?.value, "router_type")?,
+        record_version: Some(to_u64(
+            row.take_required_cell("record_version")?.value,
+            "record_version",
+        )?),
+        version: Some(
+            row.take_required_cell("version")?
+                .value
+                .try_into()
+                .map_err(|e| {
+                    DbError::Serialization(format!("Could not deserialize version: {e:?}"))
+                })?,
+        ),
+        ..Default::default()
+    };
+
+    if let Some(cell) = row.take_cell("router_data") {
+        result.router_data = from_str(&to_string(cell.value, "router_type")?).map_err(|e| {
+            DbError::Serialization(format!("Could not deserialize router_type: {e:?}"))
+        })?;
+    }
+
+    if let Some(cell) = row.take_cell("node_id") {
+        result.node_id = Some(to_string(cell.value, "node_id")?);
+    }
+
+    if let Some(cell) = row.take_cell("node_region") {
+        result.node_region = Some(to_string(cell.value, "node_region")?);
+    }
+
+    if let Some(cell) = row.take_cell("current_timestamp") {
+        result.current_timestamp = Some(to_u64(cell.value, "current_timestamp")?)
+    }
+
+    if let Some(cell) = row.take_cell("last_disconnect_reason") {
+        result.last_disconnect_reason = Some(to_string(cell.value, "last_disconnect_reason")?);
+    }
+
+    if let Some(cell) = row.take_cell("last_disconnect_at") {
+        result.last_disconnect_at = Some(to_u64(cell.value, "last_disconnect_at")?);
+    }
+
+    // Read the channels last, after removal of all non channel cells
+    result.priv_channels = channels_from_cells(&row.cells)?;
+
+    Ok(result)
+}
+
 /// Determine if a router record is "incomplete" (doesn't include [User]
 /// columns):
 ///
@@ -333,13 +470,22 @@ pub fn retryable_bt_err(
 ///    initial `add_user` was never completed:
 ///    https://github.com/mozilla-services/autopush-rs/pull/640
 ///
-/// 2) When router TTLs are eventually enabled: `add_channel` and
-///    `increment_storage` can write cells with later expiry times than the other
-///    router cells
+/// 2) When router TTLs are eventually enabled: `add_channel`,
+///    `increment_storage` and `set_last_disconnect` can write cells with later
+///    expiry times than the other router cells
 fn is_incomplete_router_record(cells: &RowCells) -> bool {
-    cells
-        .keys()
-        .all(|k| ["current_timestamp", "version"].contains(&k.as_str()) || k.starts_with("chid:"))
+    cells.keys().all(|k| {
+        [
+            "current_timestamp",
+            "version",
+            "last_disconnect_reason",
+            "last_disconnect_at",
+        ]
+        .contains(&k.as_str())
+            || k.starts_with("chid:")
+            || k.starts_with(STORE_ONLY_PREFIX)
+            || k.starts_with(VAPID_PIN_PREFIX)
+    })
 }
 
 fn call_opts(metadata: Metadata) -> ::grpcio::CallOption {
@@ -377,6 +523,18 @@ impl BigTableClientImpl {
         let db_settings = BigTableDbSettings::try_from(settings.db_settings.as_ref())?;
         info!("🉑 {:#?}", db_settings);
         let pool = BigTablePool::new(settings, &metrics)?;
+        let read_pool = db_settings
+            .read_dsn
+            .as_ref()
+            .map(|read_dsn| {
+                debug!("🏊 BT read Pool new");
+                let read_settings = DbSettings {
+                    dsn: Some(read_dsn.clone()),
+                    ..settings.clone()
+                };
+                BigTablePool::new(&read_settings, &metrics)
+            })
+            .transpose()?;
 
         // create the metadata header blocks required by Google for accessing GRPC resources.
         let metadata = db_settings.metadata()?;
@@ -387,12 +545,63 @@ impl BigTableClientImpl {
             metadata,
             admin_metadata,
             pool,
+            read_pool,
         })
     }
 
     /// Spawn a task to periodically evict idle connections
     pub fn spawn_sweeper(&self, interval: Duration) {
         self.pool.spawn_sweeper(interval);
+        if let Some(read_pool) = &self.read_pool {
+            read_pool.spawn_sweeper(interval);
+        }
+    }
+
+    /// The pool used for reads: the `read_dsn`'s if configured, otherwise
+    /// the primary
+    fn read_pool(&self) -> &BigTablePool {
+        self.read_pool.as_ref().unwrap_or(&self.pool)
+    }
+
+    /// The routing profile id used for reads
+    fn read_profile_id(&self) -> &str {
+        self.settings
+            .read_profile_id
+            .as_deref()
+            .unwrap_or(&self.settings.app_profile_id)
+    }
+
+    /// Time a database operation, reporting it when slower than
+    /// `slow_query_threshold`
+    async fn timed<T>(&self, operation: &str, fut: impl Future<Output = T>) -> T {
+        time_operation(
+            &self.metrics,
+            self.settings.slow_query_threshold,
+            &self.name(),
+            operation,
+            fut,
+        )
+        .await
+    }
+
+    /// Whether to emit this operation's high volume trace logs, per
+    /// `db_trace_sample_rate`
+    fn trace_sampled(&self) -> bool {
+        rand::random::<f64>() < self.settings.db_trace_sample_rate
+    }
+
+    /// Clamp a requested fetch `limit` to the configured `max_fetch_limit`,
+    /// emitting a metric when it's reduced
+    fn fetch_limit(&self, limit: usize, fetch_type: &str) -> usize {
+        let effective = self.settings.clamp_fetch_limit(limit);
+        if effective != limit {
+            debug!("🉑 Clamping {fetch_type} fetch limit from {limit} to {effective}");
+            self.metrics
+                .incr_with_tags("database.fetch.clamped")
+                .with_tag("type", fetch_type)
+                .send();
+        }
+        effective
     }
 
     /// Return a ReadRowsRequest for a given row key
@@ -404,6 +613,26 @@ impl BigTableClientImpl {
         )
     }
 
+    /// Return a ReadRowsRequest spanning all of a user's messages: both the
+    /// topic ("01:") and timestamp ("02:") messages
+    fn message_rows_request(&self, uaid: &Uuid) -> bigtable::ReadRowsRequest {
+        let mut req = ReadRowsRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+        req.set_app_profile_id(self.settings.app_profile_id.clone());
+
+        let mut rows = data::RowSet::default();
+        let mut row_range = data::RowRange::default();
+        let (start_key, _) = ChidMessageId::topic_bounds();
+        let (_, end_key) = ChidMessageId::standard_bounds(None);
+        row_range.set_start_key_open(format!("{}#{start_key}", uaid.simple()).into_bytes());
+        row_range.set_end_key_open(format!("{}#{end_key}", uaid.simple()).into_bytes());
+        let mut row_ranges = RepeatedField::default();
+        row_ranges.push(row_range);
+        rows.set_row_ranges(row_ranges);
+        req.set_rows(rows);
+        req
+    }
+
     /// Return a MutateRowRequest for a given row key
     fn mutate_row_request(&self, row_key: &str) -> bigtable::MutateRowRequest {
         let mut req = bigtable::MutateRowRequest::default();
@@ -428,36 +657,40 @@ impl BigTableClientImpl {
         req: bigtable::MutateRowRequest,
     ) -> Result<(), error::BigTableError> {
         let bigtable = self.pool.get().await?;
-        retry_policy(self.settings.retry_count)
-            .retry_if(
+        self.timed(
+            "mutate_row",
+            retry_policy(self.settings.retry_count).retry_if(
                 || async {
                     bigtable
                         .conn
                         .mutate_row_opt(&req, call_opts(self.metadata.clone()))
                 },
                 retryable_grpcio_err(&self.metrics),
-            )
-            .await
-            .map_err(error::BigTableError::Write)?;
+            ),
+        )
+        .await
+        .map_err(error::BigTableError::Write)?;
         Ok(())
     }
 
     /// Perform a MutateRowsRequest
-    #[allow(unused)]
     async fn mutate_rows(
         &self,
         req: bigtable::MutateRowsRequest,
     ) -> Result<(), error::BigTableError> {
         let bigtable = self.pool.get().await?;
         // ClientSStreamReceiver will cancel an operation if it's dropped before it's done.
-        let resp = retry_policy(self.settings.retry_count)
-            .retry_if(
-                || async {
-                    bigtable
-                        .conn
-                        .mutate_rows_opt(&req, call_opts(self.metadata.clone()))
-                },
-                retryable_grpcio_err(&self.metrics),
+        let resp = self
+            .timed(
+                "mutate_rows",
+                retry_policy(self.settings.retry_count).retry_if(
+                    || async {
+                        bigtable
+                            .conn
+                            .mutate_rows_opt(&req, call_opts(self.metadata.clone()))
+                    },
+                    retryable_grpcio_err(&self.metrics),
+                ),
             )
             .await
             .map_err(error::BigTableError::Write)?;
@@ -515,22 +748,91 @@ impl BigTableClientImpl {
 
     /// Take a big table ReadRowsRequest (containing the keys and filters) and return a set of row data indexed by row key.
     ///
-    ///
+    /// Always reads from the primary: use this for any read that's followed
+    /// by a write (or delete) based on its result
     async fn read_rows(
         &self,
         req: ReadRowsRequest,
     ) -> Result<BTreeMap<RowKey, row::Row>, error::BigTableError> {
-        let bigtable = self.pool.get().await?;
-        let resp = retry_policy(self.settings.retry_count)
-            .retry_if(
-                || async {
-                    let resp: grpcio::ClientSStreamReceiver<bigtable::ReadRowsResponse> = bigtable
-                        .conn
-                        .read_rows_opt(&req, call_opts(self.metadata.clone()))
-                        .map_err(error::BigTableError::Read)?;
-                    merge::RowMerger::process_chunks(resp).await
-                },
-                retryable_bt_err(&self.metrics),
+        self.read_rows_from(&self.pool, req).await
+    }
+
+    /// Read rows from the `read_dsn`/`read_profile_id` when configured
+    /// (otherwise the primary)
+    ///
+    /// A replica may lag behind the primary, so this is only for reads that
+    /// don't lead to a write: fetching messages and listing channels
+    async fn read_replica_rows(
+        &self,
+        mut req: ReadRowsRequest,
+    ) -> Result<BTreeMap<RowKey, row::Row>, error::BigTableError> {
+        req.set_app_profile_id(self.read_profile_id().to_owned());
+        self.read_rows_from(self.read_pool(), req).await
+    }
+
+    /// Read a single row from the replica. See [Self::read_replica_rows]
+    async fn read_replica_row(
+        &self,
+        req: bigtable::ReadRowsRequest,
+    ) -> Result<Option<row::Row>, error::BigTableError> {
+        let mut rows = self.read_replica_rows(req).await?;
+        Ok(rows.pop_first().map(|(_, v)| v))
+    }
+
+    async fn read_rows_from(
+        &self,
+        pool: &BigTablePool,
+        req: ReadRowsRequest,
+    ) -> Result<BTreeMap<RowKey, row::Row>, error::BigTableError> {
+        let bigtable = pool.get().await?;
+        let resp = self
+            .timed(
+                "read_rows",
+                retry_policy(self.settings.retry_count).retry_if(
+                    || async {
+                        let resp: grpcio::ClientSStreamReceiver<bigtable::ReadRowsResponse> =
+                            bigtable
+                                .conn
+                                .read_rows_opt(&req, call_opts(self.metadata.clone()))
+                                .map_err(error::BigTableError::Read)?;
+                        merge::RowMerger::process_chunks(resp).await
+                    },
+                    retryable_bt_err(&self.metrics),
+                ),
+            )
+            .await?;
+        Ok(resp)
+    }
+
+    /// Read rows from the replica within `deadline` (see
+    /// [Self::read_replica_rows]). Unlike [Self::read_rows], exceeding it
+    /// after some rows were read isn't retried: those rows are returned along
+    /// with `true`. Exceeding it before any were read is retried, then
+    /// returned as a [error::BigTableError::FetchDeadlineExceeded]
+    async fn read_rows_with_deadline(
+        &self,
+        mut req: ReadRowsRequest,
+        deadline: Duration,
+    ) -> Result<(BTreeMap<RowKey, row::Row>, bool), error::BigTableError> {
+        req.set_app_profile_id(self.read_profile_id().to_owned());
+        let bigtable = self.read_pool().get().await?;
+        let resp = self
+            .timed(
+                "read_rows",
+                retry_policy(self.settings.retry_count).retry_if(
+                    || async {
+                        let resp: grpcio::ClientSStreamReceiver<bigtable::ReadRowsResponse> =
+                            bigtable
+                                .conn
+                                .read_rows_opt(
+                                    &req,
+                                    call_opts(self.metadata.clone()).timeout(deadline),
+                                )
+                                .map_err(error::BigTableError::Read)?;
+                        merge::RowMerger::process_chunks_until_deadline(resp).await
+                    },
+                    retryable_bt_err(&self.metrics),
+                ),
             )
             .await?;
         Ok(resp)
@@ -557,6 +859,7 @@ impl BigTableClientImpl {
         cells: HashMap<FamilyId, Vec<crate::db::bigtable::bigtable_client::cell::Cell>>,
     ) -> Result<protobuf::RepeatedField<data::Mutation>, error::BigTableError> {
         let mut mutations = protobuf::RepeatedField::default();
+        let sampled = self.trace_sampled();
         for (family_id, cells) in cells {
             for cell in cells {
                 let mut mutation = data::Mutation::default();
@@ -571,7 +874,9 @@ impl BigTableClientImpl {
                 // Yes, this is passing milli bounded time as a micro. Otherwise I get
                 // a `Timestamp granularity mismatch` error
                 set_cell.set_timestamp_micros((timestamp.as_millis() * 1000) as i64);
-                debug!("🉑 expiring in {:?}", timestamp.as_millis());
+                if sampled {
+                    debug!("🉑 expiring in {:?}", timestamp.as_millis());
+                }
                 mutation.set_set_cell(set_cell);
                 mutations.push(mutation);
             }
@@ -608,16 +913,19 @@ impl BigTableClientImpl {
         req: bigtable::CheckAndMutateRowRequest,
     ) -> Result<bool, error::BigTableError> {
         let bigtable = self.pool.get().await?;
-        let resp = retry_policy(self.settings.retry_count)
-            .retry_if(
-                || async {
-                    // Note: check_and_mutate_row_async may return before the row
-                    // is written, which can cause race conditions for reads
-                    bigtable
-                        .conn
-                        .check_and_mutate_row_opt(&req, call_opts(self.metadata.clone()))
-                },
-                retryable_grpcio_err(&self.metrics),
+        let resp = self
+            .timed(
+                "check_and_mutate",
+                retry_policy(self.settings.retry_count).retry_if(
+                    || async {
+                        // Note: check_and_mutate_row_async may return before the row
+                        // is written, which can cause race conditions for reads
+                        bigtable
+                            .conn
+                            .check_and_mutate_row_opt(&req, call_opts(self.metadata.clone()))
+                    },
+                    retryable_grpcio_err(&self.metrics),
+                ),
             )
             .await
             .map_err(error::BigTableError::Write)?;
@@ -675,6 +983,27 @@ impl BigTableClientImpl {
         self.mutate_row(req).await
     }
 
+    /// Delete the given rows in a single MutateRowsRequest
+    async fn delete_row_keys(
+        &self,
+        row_keys: impl Iterator<Item = &RowKey>,
+    ) -> Result<(), error::BigTableError> {
+        let mut req = bigtable::MutateRowsRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+        req.set_app_profile_id(self.settings.app_profile_id.clone());
+        let mut entries = RepeatedField::default();
+        for row_key in row_keys {
+            let mut mutation = data::Mutation::default();
+            mutation.set_delete_from_row(data::Mutation_DeleteFromRow::default());
+            let mut entry = bigtable::MutateRowsRequest_Entry::default();
+            entry.set_row_key(row_key.as_bytes().to_vec());
+            entry.set_mutations(RepeatedField::from_vec(vec![mutation]));
+            entries.push(entry);
+        }
+        req.set_entries(entries);
+        self.mutate_rows(req).await
+    }
+
     /// This uses the admin interface to drop row ranges.
     /// This will drop ALL data associated with these rows.
     /// Note that deletion may take up to a week to occur.
@@ -722,6 +1051,71 @@ impl BigTableClientImpl {
             .collect()
     }
 
+    /// Drop any already expired notifications, which may still be read right
+    /// at the boundary of the GC policy filter, emitting a metric of how many
+    /// were dropped
+    fn filter_expired(&self, mut messages: Vec<Notification>, topic: bool) -> Vec<Notification> {
+        let now_sec = sec_since_epoch();
+        let count = messages.len();
+        messages.retain(|msg| !msg.expired(now_sec));
+        let expired = count - messages.len();
+        if expired > 0 {
+            self.metrics
+                .count_with_tags("notification.message.filtered_expired", expired as i64)
+                .with_tag("topic", &topic.to_string())
+                .with_tag("database", &self.name())
+                .send();
+        }
+        messages
+    }
+
+    /// Return a request reading timestamp messages after `timestamp`
+    fn timestamp_messages_request(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> Result<ReadRowsRequest, error::BigTableError> {
+        let mut req = ReadRowsRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+        req.set_app_profile_id(self.settings.app_profile_id.clone());
+
+        let mut rows = data::RowSet::default();
+        let mut row_range = data::RowRange::default();
+
+        // Fetch everything after the last message with timestamp
+        let (start_key, end_key) = ChidMessageId::standard_bounds(timestamp);
+        row_range.set_start_key_open(format!("{}#{start_key}", uaid.simple()).into_bytes());
+        row_range.set_end_key_open(format!("{}#{end_key}", uaid.simple()).into_bytes());
+
+        let mut row_ranges = RepeatedField::default();
+        row_ranges.push(row_range);
+        rows.set_row_ranges(row_ranges);
+        req.set_rows(rows);
+
+        // We can fetch data and do [some remote filtering](https://cloud.google.com/bigtable/docs/filters),
+        // unfortunately I don't think the filtering we need will be super helpful.
+        //
+        //
+        /*
+        //NOTE: if you filter on a given field, BigTable will only
+        // return that specific field. Adding filters for the rest of
+        // the known elements may NOT return those elements or may
+        // cause the message to not be returned because any of
+        // those elements are not present. It may be preferable to
+        // therefore run two filters, one to fetch the candidate IDs
+        // and another to fetch the content of the messages.
+         */
+        let mut filters = message_gc_policy_filter()?;
+        filters.push(family_filter(format!("^{MESSAGE_FAMILY}$")));
+
+        req.set_filter(filter_chain(filters));
+        if limit > 0 {
+            req.set_rows_limit(limit as i64);
+        }
+        Ok(req)
+    }
+
     fn row_to_notification(&self, row_key: &str, mut row: Row) -> Result<Notification, DbError> {
         let Some((_, chidmessageid)) = row_key.split_once('#') else {
             return Err(DbError::Integrity(
@@ -729,7 +1123,7 @@ impl BigTableClientImpl {
                 None,
             ));
         };
-        let range_key = NotificationRecord::parse_chidmessageid(chidmessageid).map_err(|e| {
+        let range_key = chidmessageid.parse::<ChidMessageId>().map_err(|e| {
             DbError::Integrity(
                 format!("rows_to_notification expected chidmessageid: {e}"),
                 None,
@@ -749,7 +1143,14 @@ impl BigTableClientImpl {
 
         // Backfill the Optional fields
         if let Some(cell) = row.take_cell("data") {
-            notif.data = Some(to_string(cell.value, "data")?);
+            // Rows written without compression have no codec marker
+            let value = match row.take_cell(DATA_CODEC_QUALIFIER) {
+                Some(codec) => to_string(codec.value, DATA_CODEC_QUALIFIER)?
+                    .parse::<MessageCompression>()?
+                    .decompress(&cell.value)?,
+                None => cell.value,
+            };
+            notif.data = Some(to_string(value, "data")?);
         }
         if let Some(cell) = row.take_cell("headers") {
             notif.headers = Some(
@@ -758,11 +1159,26 @@ impl BigTableClientImpl {
             );
         }
         if let Some(cell) = row.take_cell("reliability_id") {
-            trace!("🚣  Is reliable");
             notif.reliability_id = Some(to_string(cell.value, "reliability_id")?);
         }
+        if let Some(cell) = row.take_cell("meta") {
+            notif.meta = Some(
+                serde_json::from_str(&to_string(cell.value, "meta")?)
+                    .map_err(|e| DbError::Serialization(e.to_string()))?,
+            );
+        }
+        if let Some(cell) = row.take_cell("delivery_attempts") {
+            notif.delivery_attempts =
+                u32::try_from(to_u64(cell.value, "delivery_attempts")?).unwrap_or(u32::MAX);
+        }
 
-        trace!("🚣  Deserialized message row: {:?}", &notif);
+        if self.trace_sampled() {
+            trace!(
+                "🚣  Deserialized message row: {} (reliable: {})",
+                elide(row_key),
+                notif.reliability_id.is_some()
+            );
+        }
         Ok(notif)
     }
 
@@ -831,6 +1247,30 @@ impl BigTableClientImpl {
                 ..Default::default()
             });
         };
+        if let Some(node_region) = &user.node_region {
+            cells.push(cell::Cell {
+                qualifier: "node_region".to_owned(),
+                value: node_region.as_bytes().to_vec(),
+                timestamp: expiry,
+                ..Default::default()
+            });
+        };
+        if let Some(reason) = &user.last_disconnect_reason {
+            cells.push(cell::Cell {
+                qualifier: "last_disconnect_reason".to_owned(),
+                value: reason.as_bytes().to_vec(),
+                timestamp: expiry,
+                ..Default::default()
+            });
+        };
+        if let Some(last_disconnect_at) = user.last_disconnect_at {
+            cells.push(cell::Cell {
+                qualifier: "last_disconnect_at".to_owned(),
+                value: last_disconnect_at.to_be_bytes().to_vec(),
+                timestamp: expiry,
+                ..Default::default()
+            });
+        };
 
         cells.extend(channels_to_cells(
             Cow::Borrowed(&user.priv_channels),
@@ -840,6 +1280,44 @@ impl BigTableClientImpl {
         row.add_cells(ROUTER_FAMILY, cells);
         row
     }
+
+    /// Write only the `router_type`, `router_data` and a newly generated
+    /// `version` of a user's record, provided it still has the given
+    /// `version`
+    async fn update_router_at_version(
+        &self,
+        uaid: &Uuid,
+        version: &Uuid,
+        router_type: String,
+        router_data: &serde_json::Value,
+    ) -> DbResult<bool> {
+        let mut row = Row::new(uaid.simple().to_string());
+        let expiry = SystemTime::now() + Duration::from_secs(MAX_ROUTER_TTL);
+        row.add_cells(
+            ROUTER_FAMILY,
+            vec![
+                cell::Cell {
+                    qualifier: "router_type".to_owned(),
+                    value: router_type.into_bytes(),
+                    timestamp: expiry,
+                    ..Default::default()
+                },
+                cell::Cell {
+                    qualifier: "router_data".to_owned(),
+                    value: router_data.to_string().into_bytes(),
+                    timestamp: expiry,
+                    ..Default::default()
+                },
+                new_version_cell(expiry),
+            ],
+        );
+
+        let mut filters = vec![router_gc_policy_filter()];
+        filters.extend(version_filter(version));
+        Ok(self
+            .check_and_mutate_row(row, filter_chain(filters), true)
+            .await?)
+    }
 }
 
 #[derive(Clone)]
@@ -918,6 +1396,20 @@ impl DbClient for BigTableClientImpl {
         Ok(())
     }
 
+    async fn add_user_if_absent(&self, user: &User) -> DbResult<Option<User>> {
+        match self.add_user(user).await {
+            Ok(()) => return Ok(None),
+            Err(DbError::Conditional) => (),
+            Err(e) => return Err(e),
+        }
+        if let Some(existing) = self.get_user(&user.uaid).await? {
+            return Ok(Some(existing));
+        }
+        // `get_user` dropped an incomplete record that was in the way
+        self.add_user(user).await?;
+        Ok(None)
+    }
+
     /// BigTable doesn't really have the concept of an "update". You simply write the data and
     /// the individual cells create a new version. Depending on the garbage collection rules for
     /// the family, these can either persist or be automatically deleted.
@@ -948,94 +1440,285 @@ impl DbClient for BigTableClientImpl {
         // Always write a newly generated version
         let row = self.user_to_row(user, &new_version);
 
-        let predicate_matched = self.check_and_mutate_row(row, filter, true).await?;
+        let mut req = self.check_and_mutate_row_request(&row.row_key);
+        // user_to_row only writes a set node_region: clear any previous one
+        // so it can't describe a different node_id
+        let mut mutations = if user.node_region.is_none() {
+            self.get_delete_mutations(ROUTER_FAMILY, &["node_region"], None)?
+        } else {
+            RepeatedField::default()
+        };
+        mutations.extend(self.get_mutations(row.cells)?);
+        req.set_predicate_filter(filter);
+        req.set_true_mutations(mutations);
+
+        let predicate_matched = self.check_and_mutate(req).await?;
         user.version = Some(new_version);
         Ok(predicate_matched)
     }
 
+    async fn update_router(
+        &self,
+        uaid: &Uuid,
+        router_type: String,
+        router_data: serde_json::Value,
+    ) -> DbResult<bool> {
+        if !router_data.is_object() {
+            return Err(DbError::General(
+                "update_router expected a router_data object".to_owned(),
+            ));
+        }
+        let Some(version) = self.get_user(uaid).await?.and_then(|user| user.version) else {
+            return Ok(false);
+        };
+        self.update_router_at_version(uaid, &version, router_type, &router_data)
+            .await
+    }
+
     async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
         let row_key = uaid.as_simple().to_string();
         let mut req = self.read_row_request(&row_key);
         let mut filters = vec![router_gc_policy_filter()];
         filters.push(family_filter(format!("^{ROUTER_FAMILY}$")));
         req.set_filter(filter_chain(filters));
-        let Some(mut row) = self.read_row(req).await? else {
+        let Some(row) = self.read_row(req).await? else {
             return Ok(None);
         };
 
-        trace!("🉑 Found a record for {}", row_key);
+        if self.trace_sampled() {
+            trace!("🉑 Found a record for {}", elide(&row_key));
+        }
 
-        let connected_at_cell = match row.take_required_cell("connected_at") {
-            Ok(cell) => cell,
-            Err(_) => {
-                if !is_incomplete_router_record(&row.cells) {
-                    return Err(DbError::Integrity(
-                        "Expected column: connected_at".to_owned(),
-                        Some(format!("{row:#?}")),
-                    ));
-                }
-                // Special case incomplete records: they're equivalent to no
-                // user exists. Incompletes caused by the migration bug in #640
-                // will have their migration re-triggered by returning None:
-                // https://github.com/mozilla-services/autopush-rs/pull/640
-                trace!("🉑 Dropping an incomplete user record for {}", row_key);
-                self.metrics
-                    .incr_with_tags("database.drop_user")
-                    .with_tag("reason", "incomplete_record")
-                    .send();
-                self.remove_user(uaid).await?;
-                return Ok(None);
+        if !row.cells.contains_key("connected_at") {
+            if !is_incomplete_router_record(&row.cells) {
+                return Err(DbError::Integrity(
+                    "Expected column: connected_at".to_owned(),
+                    Some(format!("{row:#?}")),
+                ));
             }
-        };
-
-        let mut result = User {
-            uaid: *uaid,
-            connected_at: to_u64(connected_at_cell.value, "connected_at")?,
-            router_type: to_string(row.take_required_cell("router_type")?.value, "router_type")?,
-            record_version: Some(to_u64(
-                row.take_required_cell("record_version")?.value,
-                "record_version",
-            )?),
-            version: Some(
-                row.take_required_cell("version")?
-                    .value
-                    .try_into()
-                    .map_err(|e| {
-                        DbError::Serialization(format!("Could not deserialize version: {e:?}"))
-                    })?,
-            ),
-            ..Default::default()
-        };
-
-        if let Some(cell) = row.take_cell("router_data") {
-            result.router_data = from_str(&to_string(cell.value, "router_type")?).map_err(|e| {
-                DbError::Serialization(format!("Could not deserialize router_type: {e:?}"))
-            })?;
+            // Special case incomplete records: they're equivalent to no
+            // user exists. Incompletes caused by the migration bug in #640
+            // will have their migration re-triggered by returning None:
+            // https://github.com/mozilla-services/autopush-rs/pull/640
+            trace!(
+                "🉑 Dropping an incomplete user record for {}",
+                elide(&row_key)
+            );
+            self.metrics
+                .incr_with_tags("database.drop_user")
+                .with_tag("reason", "incomplete_record")
+                .send();
+            self.remove_user(uaid).await?;
+            return Ok(None);
         }
+        Ok(Some(row_to_user(uaid, row)?))
+    }
 
-        if let Some(cell) = row.take_cell("node_id") {
-            result.node_id = Some(to_string(cell.value, "node_id")?);
-        }
+    async fn get_user_with_channels(&self, uaid: &Uuid) -> DbResult<Option<(User, HashSet<Uuid>)>> {
+        // The router family read by `get_user` already includes the `chid:`
+        // cells, so the channels come along with the same request
+        Ok(self.get_user(uaid).await?.map(|user| {
+            let channels = user.priv_channels.clone();
+            (user, channels)
+        }))
+    }
 
-        if let Some(cell) = row.take_cell("current_timestamp") {
-            result.current_timestamp = Some(to_u64(cell.value, "current_timestamp")?)
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        let row_key = uaid.simple().to_string();
+        self.delete_row(&row_key).await?;
+        Ok(())
+    }
+
+    /// The router row's only deleted if none of its cells were written after
+    /// `now - max_idle`, so a client reconnecting meanwhile isn't dropped.
+    async fn remove_user_if_stale(&self, uaid: &Uuid, max_idle: Duration) -> DbResult<bool> {
+        // Every write to the router record (a Hello, a bridged user's token
+        // refresh via update_user, registering a channel, etc.) stamps its
+        // cells with now + MAX_ROUTER_TTL. `connected_at` alone can't be used
+        // as bridged (FCM/APNS) users never refresh it
+        let refreshed_since = (SystemTime::now() + Duration::from_secs(MAX_ROUTER_TTL))
+            .checked_sub(max_idle)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let refreshed_since_millis = refreshed_since
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(error::BigTableError::WriteTime)?
+            .as_millis() as i64;
+        let mut range = data::TimestampRange::default();
+        range.set_start_timestamp_micros(refreshed_since_millis * 1000);
+        let mut refreshed_filter = data::RowFilter::default();
+        refreshed_filter.set_timestamp_range_filter(range);
+
+        // Match a router record none of whose cells were written since
+        // `refreshed_since`. Evaluated atomically with the delete, so a
+        // racing Hello (or refresh) keeps the user
+        let predicate = condition_filter(
+            filter_chain(vec![
+                family_filter(format!("^{ROUTER_FAMILY}$")),
+                refreshed_filter,
+            ]),
+            block_all_filter(),
+            family_filter(format!("^{ROUTER_FAMILY}$")),
+        );
+        let mut req = self.check_and_mutate_row_request(&uaid.simple().to_string());
+        req.set_predicate_filter(predicate);
+        let mut mutation = data::Mutation::default();
+        mutation.set_delete_from_row(data::Mutation_DeleteFromRow::default());
+        req.set_true_mutations(RepeatedField::from_vec(vec![mutation]));
+
+        let removed = self.check_and_mutate(req).await?;
+        if removed {
+            self.metrics
+                .incr_with_tags("database.drop_user")
+                .with_tag("reason", "stale")
+                .send();
+        }
+        Ok(removed)
+    }
+
+    /// Copies every row of the user (their router record including channels
+    /// and their unexpired messages) under the new UAID before deleting the
+    /// originals, so a partially failed transfer may simply be retried.
+    async fn transfer_user(&self, from: &Uuid, to: &Uuid) -> DbResult<()> {
+        let from_key = from.simple().to_string();
+        let to_key = to.simple().to_string();
+
+        let mut req = self.read_row_request(&from_key);
+        req.set_filter(router_gc_policy_filter());
+        let mut rows = self.read_rows(req).await?;
+        let mut req = self.message_rows_request(from);
+        req.set_filter(filter_chain(message_gc_policy_filter()?));
+        rows.append(&mut self.read_rows(req).await?);
+        if rows.is_empty() {
+            // Nothing left to transfer
+            return Ok(());
         }
 
-        // Read the channels last, after removal of all non channel cells
-        result.priv_channels = channels_from_cells(&row.cells)?;
+        for (row_key, row) in &rows {
+            // Message row keys are suffixed with "#<chidmessageid>"
+            let mut new_row = Row::new(format!("{}{}", to_key, &row_key[from_key.len()..]));
+            // Read cells are indexed by qualifier but written by family
+            for cell in row.cells.values().flatten() {
+                new_row
+                    .cells
+                    .entry(cell.family.clone())
+                    .or_default()
+                    .push(cell.clone());
+            }
+            self.write_row(new_row).await?;
+        }
+        self.delete_row_keys(rows.keys()).await?;
 
-        Ok(Some(result))
+        self.metrics
+            .incr_with_tags("database.transfer_user")
+            .with_tag("database", &self.name())
+            .send();
+        Ok(())
     }
 
-    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+    async fn scan_users(
+        &self,
+        start: Option<Uuid>,
+        limit: usize,
+    ) -> DbResult<(Vec<User>, Option<Uuid>)> {
+        let mut req = ReadRowsRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+        req.set_app_profile_id(self.settings.app_profile_id.clone());
+        if let Some(start) = start {
+            let mut rows = data::RowSet::default();
+            let mut row_range = data::RowRange::default();
+            row_range.set_start_key_open(start.simple().to_string().into_bytes());
+            let mut row_ranges = RepeatedField::default();
+            row_ranges.push(row_range);
+            rows.set_row_ranges(row_ranges);
+            req.set_rows(rows);
+        }
+        // Only the router rows (keyed by the bare UAID), not the messages
+        let mut row_key_filter = RowFilter::default();
+        row_key_filter.set_row_key_regex_filter(b"^[0-9a-f]{32}$".to_vec());
+        req.set_filter(filter_chain(vec![
+            row_key_filter,
+            router_gc_policy_filter(),
+            family_filter(format!("^{ROUTER_FAMILY}$")),
+        ]));
+        if limit > 0 {
+            req.set_rows_limit(limit as i64);
+        }
+
+        let rows = self.read_rows(req).await?;
+        let read = rows.len();
+        let mut last = None;
+        let mut users = Vec::with_capacity(read);
+        for (row_key, row) in rows {
+            let uaid = Uuid::parse_str(&row_key).map_err(|e| {
+                DbError::Integrity(format!("Invalid router row key: {e}"), Some(row_key))
+            })?;
+            last = Some(uaid);
+            // Skip incomplete records, as get_user would
+            if !row.cells.contains_key("connected_at") && is_incomplete_router_record(&row.cells) {
+                continue;
+            }
+            users.push(row_to_user(&uaid, row)?);
+        }
+        let next = (limit > 0 && read == limit).then_some(last).flatten();
+        Ok((users, next))
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
         let row_key = uaid.simple().to_string();
-        self.delete_row(&row_key).await?;
+        let mut req = self.mutate_row_request(&row_key);
+        let expiry = SystemTime::now() + Duration::from_secs(MAX_ROUTER_TTL);
+
+        // (Re)adding a channel clears any VAPID key pinned to it
+        let pin_column = format!("{VAPID_PIN_PREFIX}{}", channel_id.as_hyphenated());
+        let mut mutations =
+            self.get_delete_mutations(ROUTER_FAMILY, &[pin_column.as_ref()], None)?;
+        let mut row = Row::new(row_key);
+        row.add_cells(
+            ROUTER_FAMILY,
+            channels_to_cells(Cow::Owned(HashSet::from([*channel_id])), expiry),
+        );
+        mutations.extend(self.get_mutations(row.cells)?);
+        req.set_mutations(mutations);
+        self.mutate_row(req).await?;
         Ok(())
     }
 
-    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
-        let channels = HashSet::from_iter([channel_id.to_owned()]);
-        self.add_channels(uaid, channels).await
+    async fn add_channel_with_store_only(
+        &self,
+        uaid: &Uuid,
+        channel_id: &Uuid,
+        store_only: bool,
+    ) -> DbResult<()> {
+        let row_key = uaid.simple().to_string();
+        let mut req = self.mutate_row_request(&row_key);
+        let expiry = SystemTime::now() + Duration::from_secs(MAX_ROUTER_TTL);
+        let mut cells = channels_to_cells(Cow::Owned(HashSet::from([*channel_id])), expiry);
+        let store_only_column = format!("{STORE_ONLY_PREFIX}{}", channel_id.as_hyphenated());
+        // (Re)adding a channel clears any VAPID key pinned to it
+        let pin_column = format!("{VAPID_PIN_PREFIX}{}", channel_id.as_hyphenated());
+
+        // A single MutateRow applies atomically: the channel's never visible
+        // without its (current) mark
+        let mut mutations = if store_only {
+            cells.push(cell::Cell {
+                qualifier: store_only_column,
+                timestamp: expiry,
+                ..Default::default()
+            });
+            self.get_delete_mutations(ROUTER_FAMILY, &[pin_column.as_ref()], None)?
+        } else {
+            self.get_delete_mutations(
+                ROUTER_FAMILY,
+                &[store_only_column.as_ref(), pin_column.as_ref()],
+                None,
+            )?
+        };
+        let mut row = Row::new(row_key);
+        row.add_cells(ROUTER_FAMILY, cells);
+        mutations.extend(self.get_mutations(row.cells)?);
+        req.set_mutations(mutations);
+        self.mutate_row(req).await?;
+        Ok(())
     }
 
     /// Add channels in bulk (used mostly during migration)
@@ -1082,20 +1765,174 @@ impl DbClient for BigTableClientImpl {
             cq_filter,
         ]));
 
-        let Some(row) = self.read_row(req).await? else {
+        let Some(row) = self.read_replica_row(req).await? else {
             return Ok(Default::default());
         };
         channels_from_cells(&row.cells)
     }
 
+    async fn get_channels_page(
+        &self,
+        uaid: &Uuid,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> DbResult<(HashSet<Uuid>, Option<String>)> {
+        let row_key = uaid.simple().to_string();
+        let mut req = self.read_row_request(&row_key);
+
+        // The `chid:` qualifiers following the cursor (';' sorts directly
+        // after ':')
+        let mut range = data::ColumnRange::default();
+        range.set_family_name(ROUTER_FAMILY.to_owned());
+        match cursor {
+            Some(cursor) => range.set_start_qualifier_open(format!("chid:{cursor}").into_bytes()),
+            None => range.set_start_qualifier_closed(b"chid:".to_vec()),
+        }
+        range.set_end_qualifier_open(b"chid;".to_vec());
+        let mut range_filter = data::RowFilter::default();
+        range_filter.set_column_range_filter(range);
+
+        let mut filters = vec![router_gc_policy_filter(), range_filter];
+        if limit > 0 {
+            let mut limit_filter = data::RowFilter::default();
+            limit_filter.set_cells_per_row_limit_filter(limit as i32);
+            filters.push(limit_filter);
+        }
+        req.set_filter(filter_chain(filters));
+
+        let Some(row) = self.read_replica_row(req).await? else {
+            return Ok(Default::default());
+        };
+        let channels = channels_from_cells(&row.cells)?;
+        // Qualifiers are read in order, so the page ends at the greatest one
+        let next_cursor = (limit > 0 && channels.len() >= limit)
+            .then(|| {
+                channels
+                    .iter()
+                    .map(|chid| chid.as_hyphenated().to_string())
+                    .max()
+            })
+            .flatten();
+        Ok((channels, next_cursor))
+    }
+
+    async fn channel_exists(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        let row_key = uaid.simple().to_string();
+        let mut req = self.read_row_request(&row_key);
+
+        // Only match the single column representing the channel_id
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter(
+            format!("^chid:{}$", channel_id.as_hyphenated()).into_bytes(),
+        );
+        req.set_filter(filter_chain(vec![
+            router_gc_policy_filter(),
+            family_filter(format!("^{ROUTER_FAMILY}$")),
+            cq_filter,
+        ]));
+
+        Ok(self.read_row(req).await?.is_some())
+    }
+
+    /// The pin is stored as the value of the channel's `vapid_pin:<chid>`
+    /// cell
+    async fn get_vapid_key_pin(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<Option<Vec<u8>>> {
+        let row_key = uaid.simple().to_string();
+        let mut req = self.read_row_request(&row_key);
+
+        let column = format!("{VAPID_PIN_PREFIX}{}", channel_id.as_hyphenated());
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter(format!("^{column}$").into_bytes());
+        req.set_filter(filter_chain(vec![
+            router_gc_policy_filter(),
+            family_filter(format!("^{ROUTER_FAMILY}$")),
+            cq_filter,
+        ]));
+
+        let Some(mut row) = self.read_row(req).await? else {
+            return Ok(None);
+        };
+        Ok(row
+            .take_cell(&column)
+            .map(|cell| cell.value)
+            .filter(|value| !value.is_empty()))
+    }
+
+    async fn pin_vapid_key(
+        &self,
+        uaid: &Uuid,
+        channel_id: &Uuid,
+        key_hash: &[u8],
+    ) -> DbResult<bool> {
+        let row_key = uaid.simple().to_string();
+        let mut req = self.check_and_mutate_row_request(&row_key);
+
+        let column = format!("chid:{}", channel_id.as_hyphenated());
+        let pin_column = format!("{VAPID_PIN_PREFIX}{}", channel_id.as_hyphenated());
+        let mut row = Row::new(row_key);
+        let expiry = std::time::SystemTime::now() + Duration::from_secs(MAX_ROUTER_TTL);
+        row.cells.insert(
+            ROUTER_FAMILY.to_owned(),
+            vec![cell::Cell {
+                qualifier: pin_column.clone(),
+                value: key_hash.to_vec(),
+                timestamp: expiry,
+                ..Default::default()
+            }],
+        );
+
+        // Only pin a channel that still exists (so as not to leave a pin for
+        // a removed one) and isn't pinned yet, so concurrent first uses
+        // can't overwrite each other's pin
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter(format!("^{column}$").into_bytes());
+        let mut pin_filter = data::RowFilter::default();
+        pin_filter.set_column_qualifier_regex_filter(format!("^{pin_column}$").into_bytes());
+        req.set_predicate_filter(filter_chain(vec![
+            router_gc_policy_filter(),
+            family_filter(format!("^{ROUTER_FAMILY}$")),
+            condition_filter(pin_filter, block_all_filter(), cq_filter),
+        ]));
+        req.set_true_mutations(self.get_mutations(row.cells)?);
+
+        Ok(self.check_and_mutate(req).await?)
+    }
+
+    async fn is_store_only(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        let row_key = uaid.simple().to_string();
+        let mut req = self.read_row_request(&row_key);
+
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter(
+            format!("^{STORE_ONLY_PREFIX}{}$", channel_id.as_hyphenated()).into_bytes(),
+        );
+        let mut strip_value_filter = data::RowFilter::default();
+        strip_value_filter.set_strip_value_transformer(true);
+        req.set_filter(filter_chain(vec![
+            router_gc_policy_filter(),
+            family_filter(format!("^{ROUTER_FAMILY}$")),
+            cq_filter,
+            strip_value_filter,
+        ]));
+
+        Ok(self.read_row(req).await?.is_some())
+    }
+
     /// Delete the channel. Does not delete its associated pending messages.
     async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
         let row_key = uaid.simple().to_string();
         let mut req = self.check_and_mutate_row_request(&row_key);
 
-        // Delete the column representing the channel_id
+        // Delete the column representing the channel_id (and its store only
+        // mark and VAPID key pin)
         let column = format!("chid:{}", channel_id.as_hyphenated());
-        let mut mutations = self.get_delete_mutations(ROUTER_FAMILY, &[column.as_ref()], None)?;
+        let store_only = format!("{STORE_ONLY_PREFIX}{}", channel_id.as_hyphenated());
+        let pin = format!("{VAPID_PIN_PREFIX}{}", channel_id.as_hyphenated());
+        let mut mutations = self.get_delete_mutations(
+            ROUTER_FAMILY,
+            &[column.as_ref(), store_only.as_ref(), pin.as_ref()],
+            None,
+        )?;
 
         // and write a new version cell
         let mut row = Row::new(row_key);
@@ -1113,6 +1950,69 @@ impl DbClient for BigTableClientImpl {
         Ok(self.check_and_mutate(req).await?)
     }
 
+    /// Delete multiple channels in one mutation. Does not delete their
+    /// associated pending messages.
+    async fn remove_channels(&self, uaid: &Uuid, channel_ids: &[Uuid]) -> DbResult<usize> {
+        let columns: Vec<String> = channel_ids
+            .iter()
+            .map(|channel_id| format!("chid:{}", channel_id.as_hyphenated()))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        if columns.is_empty() {
+            return Ok(0);
+        }
+        let row_key = uaid.simple().to_string();
+
+        // Count the channels actually present
+        let mut req = self.read_row_request(&row_key);
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter
+            .set_column_qualifier_regex_filter(format!("^({})$", columns.join("|")).into_bytes());
+        let mut strip_value_filter = data::RowFilter::default();
+        strip_value_filter.set_strip_value_transformer(true);
+        req.set_filter(filter_chain(vec![
+            router_gc_policy_filter(),
+            family_filter(format!("^{ROUTER_FAMILY}$")),
+            cq_filter,
+            strip_value_filter,
+        ]));
+        let Some(row) = self.read_row(req).await? else {
+            return Ok(0);
+        };
+        let present = channels_from_cells(&row.cells)?.len();
+
+        // Delete the columns representing the channel_ids (and their store
+        // only marks and VAPID key pins)
+        let store_only: Vec<String> = columns
+            .iter()
+            .map(|column| column.replacen("chid:", STORE_ONLY_PREFIX, 1))
+            .collect();
+        let pins: Vec<String> = columns
+            .iter()
+            .map(|column| column.replacen("chid:", VAPID_PIN_PREFIX, 1))
+            .collect();
+        let columns: Vec<&str> = columns
+            .iter()
+            .chain(&store_only)
+            .chain(&pins)
+            .map(String::as_str)
+            .collect();
+        let mut mutations = self.get_delete_mutations(ROUTER_FAMILY, &columns, None)?;
+
+        // and write a new version cell
+        let mut row = Row::new(row_key.clone());
+        let expiry = std::time::SystemTime::now() + Duration::from_secs(MAX_ROUTER_TTL);
+        row.cells
+            .insert(ROUTER_FAMILY.to_owned(), vec![new_version_cell(expiry)]);
+        mutations.extend(self.get_mutations(row.cells)?);
+
+        let mut req = self.mutate_row_request(&row_key);
+        req.set_mutations(mutations);
+        self.mutate_row(req).await?;
+        Ok(present)
+    }
+
     /// Remove the node_id
     async fn remove_node_id(
         &self,
@@ -1122,7 +2022,12 @@ impl DbClient for BigTableClientImpl {
         version: &Option<Uuid>,
     ) -> DbResult<bool> {
         let row_key = uaid.simple().to_string();
-        trace!("🉑 Removing node_id for: {row_key} (version: {version:?}) ",);
+        if self.trace_sampled() {
+            trace!(
+                "🉑 Removing node_id for: {} (version: {version:?}) ",
+                elide(&row_key)
+            );
+        }
         let Some(ref version) = version else {
             return Err(DbError::General("Expected a user version field".to_owned()));
         };
@@ -1132,31 +2037,82 @@ impl DbClient for BigTableClientImpl {
         let mut filters = vec![router_gc_policy_filter()];
         filters.extend(version_filter(version));
         req.set_predicate_filter(filter_chain(filters));
-        req.set_true_mutations(self.get_delete_mutations(ROUTER_FAMILY, &["node_id"], None)?);
+        req.set_true_mutations(self.get_delete_mutations(
+            ROUTER_FAMILY,
+            &["node_id", "node_region"],
+            None,
+        )?);
 
         Ok(self.check_and_mutate(req).await?)
     }
 
-    /// Write the notification to storage.
-    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
-        let row_key = format!("{}#{}", uaid.simple(), message.chidmessageid());
-        debug!("🗄️ Saving message {} :: {:?}", &row_key, &message);
-        trace!(
-            "🉑 timestamp: {:?}",
-            &message.timestamp.to_be_bytes().to_vec()
+    async fn set_last_disconnect(
+        &self,
+        uaid: &Uuid,
+        reason: &str,
+        disconnected_at: u64,
+    ) -> DbResult<bool> {
+        let row_key = uaid.simple().to_string();
+        let expiry = std::time::SystemTime::now() + Duration::from_secs(MAX_ROUTER_TTL);
+        let mut row = Row::new(row_key);
+        row.add_cells(
+            ROUTER_FAMILY,
+            vec![
+                cell::Cell {
+                    qualifier: "last_disconnect_reason".to_owned(),
+                    value: reason.as_bytes().to_vec(),
+                    timestamp: expiry,
+                    ..Default::default()
+                },
+                cell::Cell {
+                    qualifier: "last_disconnect_at".to_owned(),
+                    value: disconnected_at.to_be_bytes().to_vec(),
+                    timestamp: expiry,
+                    ..Default::default()
+                },
+            ],
         );
+
+        // Only write to an existing user, so a removed one isn't left with a
+        // lingering record
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter("^connected_at$".as_bytes().to_vec());
+        let filter = filter_chain(vec![
+            router_gc_policy_filter(),
+            family_filter(format!("^{ROUTER_FAMILY}$")),
+            cq_filter,
+        ]);
+        Ok(self.check_and_mutate_row(row, filter, true).await?)
+    }
+
+    /// Write the notification to storage.
+    async fn save_message_returning(&self, uaid: &Uuid, message: Notification) -> DbResult<String> {
+        // Computed once: it may include the current time
+        let chidmessageid = message.chidmessageid();
+        let row_key = format!("{}#{}", uaid.simple(), chidmessageid);
+        let sampled = self.trace_sampled();
+        if sampled {
+            debug!(
+                "🗄️ Saving message {} (ttl: {})",
+                elide(&row_key),
+                message.ttl
+            );
+            trace!("🉑 timestamp: {:?}", message.timestamp);
+        }
         let mut row = Row::new(row_key);
 
         // Remember, `timestamp` is effectively the time to kill the message, not the
         // current time.
         let expiry = SystemTime::now() + Duration::from_secs(message.ttl);
-        trace!(
-            "🉑 Message Expiry {}",
-            expiry
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_millis()
-        );
+        if sampled {
+            trace!(
+                "🉑 Message Expiry {}",
+                expiry
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis()
+            );
+        }
 
         let mut cells: Vec<cell::Cell> = Vec::new();
 
@@ -1188,18 +2144,33 @@ impl DbClient for BigTableClientImpl {
         ]);
         if let Some(headers) = message.headers {
             if !headers.is_empty() {
+                let headers = json!(headers).to_string();
+                self.settings.check_headers_size(headers.len())?;
                 cells.push(cell::Cell {
                     qualifier: "headers".to_owned(),
-                    value: json!(headers).to_string().into_bytes(),
+                    value: headers.into_bytes(),
                     timestamp: expiry,
                     ..Default::default()
                 });
             }
         }
         if let Some(data) = message.data {
+            let codec = self.settings.compress_messages;
+            let value = match codec {
+                MessageCompression::None => data.into_bytes(),
+                _ => {
+                    cells.push(cell::Cell {
+                        qualifier: DATA_CODEC_QUALIFIER.to_owned(),
+                        value: codec.as_str().as_bytes().to_vec(),
+                        timestamp: expiry,
+                        ..Default::default()
+                    });
+                    codec.compress(data.as_bytes())?
+                }
+            };
             cells.push(cell::Cell {
                 qualifier: "data".to_owned(),
-                value: data.into_bytes(),
+                value,
                 timestamp: expiry,
                 ..Default::default()
             });
@@ -1213,16 +2184,37 @@ impl DbClient for BigTableClientImpl {
                 ..Default::default()
             });
         }
+        if let Some(meta) = message.meta {
+            cells.push(cell::Cell {
+                qualifier: "meta".to_owned(),
+                value: meta.to_string().into_bytes(),
+                timestamp: expiry,
+                ..Default::default()
+            });
+        }
         row.add_cells(family, cells);
-        trace!("🉑 Adding row");
-        self.write_row(row).await?;
+        if sampled {
+            trace!("🉑 Adding row");
+        }
+        if is_topic {
+            // A topic message replaces any previous one of the same topic
+            // (the same row): reset its delivery attempts in the same
+            // mutation so they aren't inherited
+            let mut req = self.mutate_row_request(&row.row_key);
+            let mut mutations = self.get_delete_mutations(family, &["delivery_attempts"], None)?;
+            mutations.extend(self.get_mutations(row.cells)?);
+            req.set_mutations(mutations);
+            self.mutate_row(req).await?;
+        } else {
+            self.write_row(row).await?;
+        }
 
         self.metrics
             .incr_with_tags("notification.message.stored")
             .with_tag("topic", &is_topic.to_string())
             .with_tag("database", &self.name())
             .send();
-        Ok(())
+        Ok(chidmessageid)
     }
 
     /// Save a batch of messages to the database.
@@ -1250,15 +2242,23 @@ impl DbClient for BigTableClientImpl {
     /// the `current_timestamp` to determine what records to return, since we return
     /// records with timestamps later than `current_timestamp`.
     ///
-    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+    async fn increment_storage(
+        &self,
+        uaid: &Uuid,
+        timestamp: u64,
+        version: &Uuid,
+    ) -> DbResult<bool> {
         let row_key = uaid.simple().to_string();
-        debug!(
-            "🉑 Updating {} current_timestamp:  {:?}",
-            &row_key,
-            timestamp.to_be_bytes().to_vec()
-        );
+        if self.trace_sampled() {
+            debug!(
+                "🉑 Updating {} current_timestamp: {} (version: {})",
+                elide(&row_key),
+                timestamp,
+                version
+            );
+        }
         let expiry = std::time::SystemTime::now() + Duration::from_secs(MAX_ROUTER_TTL);
-        let mut row = Row::new(row_key.clone());
+        let mut row = Row::new(row_key);
 
         row.cells.insert(
             ROUTER_FAMILY.to_owned(),
@@ -1269,24 +2269,102 @@ impl DbClient for BigTableClientImpl {
                     timestamp: expiry,
                     ..Default::default()
                 },
-                new_version_cell(expiry),
+                // Rewrite the expected version (rather than generating a new
+                // one) to refresh its expiry while leaving it valid for
+                // subsequent increments
+                cell::Cell {
+                    qualifier: "version".to_owned(),
+                    value: version.as_bytes().to_vec(),
+                    timestamp: expiry,
+                    ..Default::default()
+                },
             ],
         );
 
-        self.write_row(row).await?;
-
-        Ok(())
+        let mut version_filters = vec![router_gc_policy_filter()];
+        version_filters.extend(version_filter(version));
+        // Match only when the version matches and there's no stored
+        // current_timestamp that's already at or past this one, so neither
+        // a concurrent update_user nor an out of order increment is lost
+        let filter = condition_filter(
+            filter_chain(version_filters),
+            condition_filter(
+                current_timestamp_at_least_filter(timestamp),
+                block_all_filter(),
+                pass_all_filter(),
+            ),
+            block_all_filter(),
+        );
+        Ok(self.check_and_mutate_row(row, filter, true).await?)
     }
 
-    /// Delete the notification from storage.
-    async fn remove_message(&self, uaid: &Uuid, chidmessageid: &str) -> DbResult<()> {
-        trace!(
-            "🉑 attemping to delete {:?} :: {:?}",
-            uaid.to_string(),
-            chidmessageid
-        );
+    async fn get_message(
+        &self,
+        uaid: &Uuid,
+        chidmessageid: &str,
+    ) -> DbResult<Option<Notification>> {
         let row_key = format!("{}#{}", uaid.simple(), chidmessageid);
-        debug!("🉑🔥 Deleting message {}", &row_key);
+        let mut req = self.read_row_request(&row_key);
+        req.set_filter(filter_chain(message_gc_policy_filter()?));
+
+        let Some(row) = self.read_row(req).await? else {
+            return Ok(None);
+        };
+        Ok(Some(self.row_to_notification(&row_key, row)?))
+    }
+
+    async fn pending_message_count(&self, uaid: &Uuid) -> DbResult<usize> {
+        let mut req = self.message_rows_request(uaid);
+        // Only the keys of unexpired rows are needed, skip their values
+        let mut filters = message_gc_policy_filter()?;
+        let mut strip_value_filter = data::RowFilter::default();
+        strip_value_filter.set_strip_value_transformer(true);
+        filters.push(strip_value_filter);
+        req.set_filter(filter_chain(filters));
+
+        Ok(self.read_replica_rows(req).await?.len())
+    }
+
+    async fn purge_expired(&self, uaid: &Uuid) -> DbResult<usize> {
+        let mut req = self.message_rows_request(uaid);
+        // Messages whose latest cells (their expiry doubles as the cell
+        // timestamp) have passed, but that are still awaiting GC
+        let bt_now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(error::BigTableError::WriteTime)?
+            .as_millis() as i64;
+        let mut range_filter = data::TimestampRange::default();
+        range_filter.set_end_timestamp_micros(bt_now * 1000);
+        let mut expired_filter = data::RowFilter::default();
+        expired_filter.set_timestamp_range_filter(range_filter);
+        let mut strip_value_filter = data::RowFilter::default();
+        strip_value_filter.set_strip_value_transformer(true);
+        req.set_filter(filter_chain(vec![
+            router_gc_policy_filter(),
+            expired_filter,
+            strip_value_filter,
+        ]));
+
+        let rows = self.read_rows(req).await?;
+        if rows.is_empty() {
+            return Ok(0);
+        }
+        self.delete_row_keys(rows.keys()).await?;
+
+        debug!("🉑🔥 Purged {} expired message(s)", rows.len());
+        self.metrics
+            .count_with_tags("notification.message.expired", rows.len() as i64)
+            .with_tag("database", &self.name())
+            .send();
+        Ok(rows.len())
+    }
+
+    /// Delete the notification from storage.
+    async fn remove_message(&self, uaid: &Uuid, chidmessageid: &str) -> DbResult<()> {
+        let row_key = format!("{}#{}", uaid.simple(), chidmessageid);
+        if self.trace_sampled() {
+            debug!("🉑🔥 Deleting message {}", elide(&row_key));
+        }
         self.delete_row(&row_key).await?;
         self.metrics
             .incr_with_tags("notification.message.deleted")
@@ -1295,6 +2373,114 @@ impl DbClient for BigTableClientImpl {
         Ok(())
     }
 
+    async fn increment_delivery_attempts(
+        &self,
+        uaid: &Uuid,
+        message: &Notification,
+    ) -> DbResult<bool> {
+        let row_key = format!("{}#{}", uaid.simple(), message.chidmessageid());
+        let family = if message.topic.is_some() {
+            MESSAGE_TOPIC_FAMILY
+        } else {
+            MESSAGE_FAMILY
+        };
+        // Expire alongside the rest of the message's cells
+        let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(message.timestamp + message.ttl);
+        let mut row = Row::new(row_key);
+        row.add_cells(
+            family,
+            vec![cell::Cell {
+                qualifier: "delivery_attempts".to_owned(),
+                value: u64::from(message.delivery_attempts.saturating_add(1))
+                    .to_be_bytes()
+                    .to_vec(),
+                timestamp: expiry,
+                ..Default::default()
+            }],
+        );
+
+        // Only write to an existing message, so an acknowledged (deleted) one
+        // isn't left behind as a partial row
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter("^version$".as_bytes().to_vec());
+        let mut filters = message_gc_policy_filter()?;
+        filters.push(family_filter(format!("^{family}$")));
+        filters.push(cq_filter);
+        Ok(self
+            .check_and_mutate_row(row, filter_chain(filters), true)
+            .await?)
+    }
+
+    /// Cell timestamps double as their expiry, so every cell of the message
+    /// is rewritten with the later expiry (replacing the originals, which
+    /// would otherwise still expire and be purged at the original time).
+    async fn extend_message_ttl(
+        &self,
+        uaid: &Uuid,
+        chidmessageid: &str,
+        new_ttl: u64,
+    ) -> DbResult<bool> {
+        let row_key = format!("{}#{}", uaid.simple(), chidmessageid);
+        let mut req = self.read_row_request(&row_key);
+        req.set_filter(filter_chain(message_gc_policy_filter()?));
+        let Some(mut row) = self.read_row(req).await? else {
+            return Ok(false);
+        };
+
+        // `ttl` is relative to the message's `timestamp`
+        let timestamp_cell = row.take_required_cell("timestamp")?;
+        let family = timestamp_cell.family.clone();
+        let timestamp = to_u64(timestamp_cell.value.clone(), "timestamp")?;
+        let ttl = sec_since_epoch().saturating_sub(timestamp) + new_ttl;
+        let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp + ttl);
+
+        let mut cells: HashMap<FamilyId, Vec<cell::Cell>> = HashMap::new();
+        for cell in row
+            .cells
+            .into_values()
+            .flatten()
+            .chain(std::iter::once(timestamp_cell))
+        {
+            let value = if cell.qualifier == "ttl" {
+                ttl.to_be_bytes().to_vec()
+            } else {
+                cell.value
+            };
+            cells.entry(cell.family).or_default().push(cell::Cell {
+                qualifier: cell.qualifier,
+                value,
+                timestamp: expiry,
+                ..Default::default()
+            });
+        }
+
+        let mut mutations = protobuf::RepeatedField::default();
+        for (family, family_cells) in &cells {
+            let qualifiers: Vec<&str> = family_cells.iter().map(|c| c.qualifier.as_str()).collect();
+            mutations.extend(self.get_delete_mutations(family, &qualifiers, None)?);
+        }
+        mutations.extend(self.get_mutations(cells)?);
+
+        // Only rewrite an existing message, so one acknowledged (deleted)
+        // meanwhile isn't resurrected
+        let mut cq_filter = data::RowFilter::default();
+        cq_filter.set_column_qualifier_regex_filter("^version$".as_bytes().to_vec());
+        let mut filters = message_gc_policy_filter()?;
+        filters.push(family_filter(format!("^{family}$")));
+        filters.push(cq_filter);
+        let mut req = self.check_and_mutate_row_request(&row_key);
+        req.set_predicate_filter(filter_chain(filters));
+        req.set_true_mutations(mutations);
+        let extended = self.check_and_mutate(req).await?;
+        if extended {
+            self.metrics
+                .incr_with_tags("notification.message.ttl_extended")
+                .with_tag("database", &self.name())
+                .send();
+        }
+        Ok(extended)
+    }
+
     /// Return `limit` pending messages from storage. `limit=0` for all messages.
     async fn fetch_topic_messages(
         &self,
@@ -1305,12 +2491,11 @@ impl DbClient for BigTableClientImpl {
         req.set_table_name(self.settings.table_name.clone());
         req.set_app_profile_id(self.settings.app_profile_id.clone());
 
-        let start_key = format!("{}#01:", uaid.simple());
-        let end_key = format!("{}#02:", uaid.simple());
+        let (start_key, end_key) = ChidMessageId::topic_bounds();
         let mut rows = data::RowSet::default();
         let mut row_range = data::RowRange::default();
-        row_range.set_start_key_open(start_key.into_bytes());
-        row_range.set_end_key_open(end_key.into_bytes());
+        row_range.set_start_key_open(format!("{}#{start_key}", uaid.simple()).into_bytes());
+        row_range.set_end_key_open(format!("{}#{end_key}", uaid.simple()).into_bytes());
         let mut row_ranges = RepeatedField::default();
         row_ranges.push(row_range);
         rows.set_row_ranges(row_ranges);
@@ -1320,18 +2505,20 @@ impl DbClient for BigTableClientImpl {
         filters.push(family_filter(format!("^{MESSAGE_TOPIC_FAMILY}$")));
 
         req.set_filter(filter_chain(filters));
+        let limit = self.fetch_limit(limit, "topic");
         if limit > 0 {
-            trace!("🉑 Setting limit to {limit}");
             req.set_rows_limit(limit as i64);
         }
-        let rows = self.read_rows(req).await?;
-        debug!(
-            "🉑 Fetch Topic Messages. Found {} row(s) of {}",
-            rows.len(),
-            limit
-        );
+        let rows = self.read_replica_rows(req).await?;
+        if self.trace_sampled() {
+            debug!(
+                "🉑 Fetch Topic Messages. Found {} row(s) of {}",
+                rows.len(),
+                limit
+            );
+        }
 
-        let messages = self.rows_to_notifications(rows)?;
+        let messages = self.filter_expired(self.rows_to_notifications(rows)?, true);
 
         // Note: Bigtable always returns a timestamp of None.
         // Under Bigtable `current_timestamp` is instead initially read
@@ -1350,64 +2537,54 @@ impl DbClient for BigTableClientImpl {
         timestamp: Option<u64>,
         limit: usize,
     ) -> DbResult<FetchMessageResponse> {
-        let mut req = ReadRowsRequest::default();
-        req.set_table_name(self.settings.table_name.clone());
-        req.set_app_profile_id(self.settings.app_profile_id.clone());
-
-        let mut rows = data::RowSet::default();
-        let mut row_range = data::RowRange::default();
-
-        let start_key = if let Some(ts) = timestamp {
-            // Fetch everything after the last message with timestamp: the "z"
-            // moves past the last message's channel_id's 1st hex digit
-            format!("{}#02:{}z", uaid.simple(), ts)
-        } else {
-            format!("{}#02:", uaid.simple())
-        };
-        let end_key = format!("{}#03:", uaid.simple());
-        row_range.set_start_key_open(start_key.into_bytes());
-        row_range.set_end_key_open(end_key.into_bytes());
-
-        let mut row_ranges = RepeatedField::default();
-        row_ranges.push(row_range);
-        rows.set_row_ranges(row_ranges);
-        req.set_rows(rows);
-
-        // We can fetch data and do [some remote filtering](https://cloud.google.com/bigtable/docs/filters),
-        // unfortunately I don't think the filtering we need will be super helpful.
-        //
-        //
-        /*
-        //NOTE: if you filter on a given field, BigTable will only
-        // return that specific field. Adding filters for the rest of
-        // the known elements may NOT return those elements or may
-        // cause the message to not be returned because any of
-        // those elements are not present. It may be preferable to
-        // therefore run two filters, one to fetch the candidate IDs
-        // and another to fetch the content of the messages.
-         */
-        let mut filters = message_gc_policy_filter()?;
-        filters.push(family_filter(format!("^{MESSAGE_FAMILY}$")));
+        let limit = self.fetch_limit(limit, "timestamp");
+        // The timestamp of the last message read, expired or not
+        let mut last_read = None;
+        loop {
+            let req = self.timestamp_messages_request(uaid, last_read.or(timestamp), limit)?;
+            let (rows, deadline_exceeded) = if self.settings.fetch_deadline.is_zero() {
+                (self.read_replica_rows(req).await?, false)
+            } else {
+                self.read_rows_with_deadline(req, self.settings.fetch_deadline)
+                    .await?
+            };
+            if deadline_exceeded {
+                self.metrics
+                    .incr_with_tags("database.fetch.deadline_exceeded")
+                    .with_tag("type", "timestamp")
+                    .send();
+            }
+            let read = rows.len();
+            if self.trace_sampled() {
+                debug!(
+                    "🉑 Fetch Timestamp Messages ({:?}) Found {} row(s) of {}",
+                    last_read.or(timestamp),
+                    read,
+                    limit,
+                );
+            }
 
-        req.set_filter(filter_chain(filters));
-        if limit > 0 {
-            req.set_rows_limit(limit as i64);
+            // Row key order doesn't necessarily match delivery order
+            let mut messages = self.rows_to_notifications(rows)?;
+            sort_timestamp_messages(&mut messages);
+            let prior_read = last_read;
+            if let Some(sortkey_timestamp) = messages.last().and_then(|m| m.sortkey_timestamp) {
+                last_read = Some(sortkey_timestamp);
+            }
+            let messages = self.filter_expired(messages, false);
+            // A full (or deadline exceeded) read of only expired messages
+            // doesn't mean there's none left: keep reading past them
+            if !messages.is_empty()
+                || limit == 0
+                || (read < limit && !deadline_exceeded)
+                || last_read == prior_read
+            {
+                return Ok(FetchMessageResponse {
+                    messages,
+                    timestamp: last_read,
+                });
+            }
         }
-        let rows = self.read_rows(req).await?;
-        debug!(
-            "🉑 Fetch Timestamp Messages ({:?}) Found {} row(s) of {}",
-            timestamp,
-            rows.len(),
-            limit,
-        );
-
-        let messages = self.rows_to_notifications(rows)?;
-        // The timestamp of the last message read
-        let timestamp = messages.last().and_then(|m| m.sortkey_timestamp);
-        Ok(FetchMessageResponse {
-            messages,
-            timestamp,
-        })
     }
 
     async fn health_check(&self) -> DbResult<bool> {
@@ -1419,6 +2596,32 @@ impl DbClient for BigTableClientImpl {
             .await?)
     }
 
+    /// Wait for the pool's dedicated ping channel to connect, without
+    /// checking out (and thus health checking) a pooled connection
+    async fn ping(&self) -> DbResult<()> {
+        Ok(self.pool.ping().await?)
+    }
+
+    /// Verify the write path by writing and then deleting a throwaway cell
+    /// under the reserved `HEALTH_ROW_KEY` row
+    async fn write_health_check(&self) -> DbResult<bool> {
+        self.health_check().await?;
+        let mut row = Row::new(HEALTH_ROW_KEY.to_owned());
+        row.cells.insert(
+            ROUTER_FAMILY.to_owned(),
+            vec![cell::Cell {
+                qualifier: "health_check".to_owned(),
+                value: ms_since_epoch().to_be_bytes().to_vec(),
+                timestamp: SystemTime::now() + HEALTH_CELL_TTL,
+                ..Default::default()
+            }],
+        );
+        self.write_row(row).await?;
+        self.delete_row(HEALTH_ROW_KEY).await?;
+        debug!("🉑 write health check");
+        Ok(true)
+    }
+
     /// Returns true, because there's only one table in BigTable. We divide things up
     /// by `family`.
     async fn router_table_exists(&self) -> DbResult<bool> {
@@ -1431,6 +2634,13 @@ impl DbClient for BigTableClientImpl {
         Ok(true)
     }
 
+    /// Bigtable keeps no row count statistics (`SampleRowKeys` only returns
+    /// tablet boundaries), and users share the table with messages, so
+    /// there's no cheap estimate.
+    async fn approximate_user_count(&self) -> DbResult<Option<u64>> {
+        Ok(None)
+    }
+
     fn box_clone(&self) -> Box<dyn DbClient> {
         Box::new(self.clone())
     }
@@ -1507,6 +2717,34 @@ mod tests {
         assert_eq!(escape_bytes(b"\x03"), b"\\\x03".to_vec());
     }
 
+    #[actix_rt::test]
+    async fn read_pool() {
+        let metrics = Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build());
+        let mut settings = DbSettings {
+            dsn: Some("grpc://localhost:8086".to_owned()),
+            db_settings: json!({"table_name": "projects/test/instances/test/tables/autopush"})
+                .to_string(),
+        };
+        // Reads fall back to the primary
+        let client = BigTableClientImpl::new(metrics.clone(), &settings).unwrap();
+        assert!(client.read_pool.is_none());
+        assert!(std::ptr::eq(client.read_pool(), &client.pool));
+        assert_eq!(client.read_profile_id(), "default");
+
+        settings.db_settings = json!({
+            "table_name": "projects/test/instances/test/tables/autopush",
+            "read_dsn": "grpc://localhost:8087",
+            "read_profile_id": "replica",
+        })
+        .to_string();
+        let client = BigTableClientImpl::new(metrics, &settings).unwrap();
+        let read_pool = client.read_pool.as_ref().unwrap();
+        assert!(std::ptr::eq(client.read_pool(), read_pool));
+        assert!(!std::ptr::eq(client.read_pool(), &client.pool));
+        assert_eq!(client.read_profile_id(), "replica");
+        assert_eq!(client.settings.app_profile_id, "default");
+    }
+
     #[actix_rt::test]
     async fn health_check() {
         let client = new_client().unwrap();
@@ -1516,6 +2754,28 @@ mod tests {
         assert!(result.unwrap());
     }
 
+    #[actix_rt::test]
+    async fn ping() -> DbResult<()> {
+        let client = new_client()?;
+        client.ping().await?;
+        // Unlike health_check, ping doesn't need a pooled connection
+        assert_eq!(client.pool_status().unwrap().size, 0);
+        client.health_check().await?;
+        assert_eq!(client.pool_status().unwrap().size, 1);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn write_health_check() -> DbResult<()> {
+        let client = new_client()?;
+
+        assert!(client.write_health_check().await?);
+        // The throwaway cell doesn't linger
+        let req = client.read_row_request(HEALTH_ROW_KEY);
+        assert!(client.read_row(req).await?.is_none());
+        Ok(())
+    }
+
     /// run a gauntlet of testing. These are a bit linear because they need
     /// to run in sequence.
     #[actix_rt::test]
@@ -1609,15 +2869,18 @@ mod tests {
         );
 
         // can we increment the storage for the user?
-        client
-            .increment_storage(
-                &fetched.uaid,
-                SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            )
-            .await?;
+        assert!(
+            client
+                .increment_storage(
+                    &fetched.uaid,
+                    SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                    &updated.version.unwrap(),
+                )
+                .await?
+        );
 
         let test_data = "An_encrypted_pile_of_crap".to_owned();
         let timestamp = now();
@@ -1766,69 +3029,1388 @@ mod tests {
     }
 
     #[actix_rt::test]
-    async fn version_check() {
+    async fn save_message_returning() -> DbResult<()> {
         let client = new_client().unwrap();
         let uaid = gen_test_uaid();
-        let user = User {
-            uaid,
+        let chid = Uuid::new_v4();
+        client.remove_user(&uaid).await?;
+        client
+            .add_user(&User {
+                uaid,
+                ..Default::default()
+            })
+            .await?;
+        client.add_channel(&uaid, &chid).await?;
+
+        let timestamp_notif = crate::db::Notification {
+            channel_id: chid,
+            version: "timestamp".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            sortkey_timestamp: Some(now()),
             ..Default::default()
         };
-        client.remove_user(&uaid).await.unwrap();
-
-        client.add_user(&user).await.unwrap();
-        let mut user = client.get_user(&uaid).await.unwrap().unwrap();
-        assert!(client.update_user(&mut user.clone()).await.unwrap());
+        let topic_notif = crate::db::Notification {
+            channel_id: chid,
+            version: "topic".to_owned(),
+            ttl: 300,
+            topic: Some("topic".to_owned()),
+            timestamp: now(),
+            ..Default::default()
+        };
+        let timestamp_id = client
+            .save_message_returning(&uaid, timestamp_notif)
+            .await?;
+        let topic_id = client.save_message_returning(&uaid, topic_notif).await?;
 
-        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
-        assert_ne!(user.version, fetched.version);
-        // should now fail w/ a stale version
-        assert!(!client.update_user(&mut user).await.unwrap());
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 999).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].chidmessageid(), timestamp_id);
+        let fetched = client.fetch_topic_messages(&uaid, 999).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].chidmessageid(), topic_id);
 
-        client.remove_user(&uaid).await.unwrap();
+        client.remove_user(&uaid).await
     }
 
     #[actix_rt::test]
-    async fn lingering_chid_record() {
+    async fn save_message_oversize_headers() -> DbResult<()> {
         let client = new_client().unwrap();
         let uaid = gen_test_uaid();
-        let chid = Uuid::parse_str(TEST_CHID).unwrap();
-        let user = User {
-            uaid,
+        let chid = Uuid::new_v4();
+        client.remove_user(&uaid).await?;
+        client
+            .add_user(&User {
+                uaid,
+                ..Default::default()
+            })
+            .await?;
+        client.add_channel(&uaid, &chid).await?;
+
+        let max = client.settings.max_headers_bytes;
+        let notif = crate::db::Notification {
+            channel_id: chid,
+            version: "oversize".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            sortkey_timestamp: Some(now()),
+            headers: Some(HashMap::from([("crypto_key".to_owned(), "a".repeat(max))])),
             ..Default::default()
         };
-        client.remove_user(&uaid).await.unwrap();
-
-        // add_channel doesn't check for the existence of a user
-        client.add_channel(&uaid, &chid).await.unwrap();
-
-        // w/ chid records in the router row, get_user should treat
-        // this as the user not existing
-        assert!(client.get_user(&uaid).await.unwrap().is_none());
-
-        client.add_user(&user).await.unwrap();
-        // get_user should have also cleaned up the chids
-        assert!(client.get_channels(&uaid).await.unwrap().is_empty());
+        assert!(matches!(
+            client.save_message(&uaid, notif).await,
+            Err(DbError::HeadersTooLarge(_, limit)) if limit == max
+        ));
+        // Nothing was written
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 999).await?;
+        assert!(fetched.messages.is_empty());
 
-        client.remove_user(&uaid).await.unwrap();
+        client.remove_user(&uaid).await
     }
 
     #[actix_rt::test]
-    async fn lingering_current_timestamp() {
-        let client = new_client().unwrap();
+    async fn topic_replacement_resets_delivery_attempts() -> DbResult<()> {
+        let client = new_client()?;
         let uaid = gen_test_uaid();
-        client.remove_user(&uaid).await.unwrap();
-
+        let chid = Uuid::new_v4();
+        client.remove_user(&uaid).await?;
         client
-            .increment_storage(&uaid, ms_since_epoch())
-            .await
-            .unwrap();
-        assert!(client.get_user(&uaid).await.unwrap().is_none());
+            .add_user(&User {
+                uaid,
+                ..Default::default()
+            })
+            .await?;
+        client.add_channel(&uaid, &chid).await?;
 
-        client.remove_user(&uaid).await.unwrap();
+        let topic_notif = |version: &str| crate::db::Notification {
+            channel_id: chid,
+            version: version.to_owned(),
+            ttl: 300,
+            topic: Some("topic".to_owned()),
+            timestamp: now(),
+            ..Default::default()
+        };
+        client.save_message(&uaid, topic_notif("first")).await?;
+        let fetched = client.fetch_topic_messages(&uaid, 999).await?;
+        assert!(
+            client
+                .increment_delivery_attempts(&uaid, &fetched.messages[0])
+                .await?
+        );
+        let fetched = client.fetch_topic_messages(&uaid, 999).await?;
+        assert_eq!(fetched.messages[0].delivery_attempts, 1);
+
+        // Its replacement starts over
+        client.save_message(&uaid, topic_notif("second")).await?;
+        let fetched = client.fetch_topic_messages(&uaid, 999).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].version, "second");
+        assert_eq!(fetched.messages[0].delivery_attempts, 0);
+
+        client.remove_user(&uaid).await
     }
 
     #[actix_rt::test]
-    async fn lingering_chid_w_version_record() {
+    async fn vapid_key_pin() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        let chid = Uuid::new_v4();
+        client.remove_user(&uaid).await?;
+        client
+            .add_user(&User {
+                uaid,
+                ..Default::default()
+            })
+            .await?;
+
+        // Can't pin to a missing channel
+        assert!(!client.pin_vapid_key(&uaid, &chid, &[1; 32]).await?);
+        assert!(!client.channel_exists(&uaid, &chid).await?);
+
+        client.add_channel(&uaid, &chid).await?;
+        assert_eq!(client.get_vapid_key_pin(&uaid, &chid).await?, None);
+        assert!(client.pin_vapid_key(&uaid, &chid, &[1; 32]).await?);
+        assert_eq!(
+            client.get_vapid_key_pin(&uaid, &chid).await?,
+            Some(vec![1; 32])
+        );
+        assert!(client.get_channels(&uaid).await?.contains(&chid));
+        // An existing pin isn't replaced
+        assert!(!client.pin_vapid_key(&uaid, &chid, &[2; 32]).await?);
+        assert_eq!(
+            client.get_vapid_key_pin(&uaid, &chid).await?,
+            Some(vec![1; 32])
+        );
+
+        // Re-adding the channel clears the pin
+        client.add_channel(&uaid, &chid).await?;
+        assert_eq!(client.get_vapid_key_pin(&uaid, &chid).await?, None);
+
+        // Removing the channel removes its pin
+        assert!(client.pin_vapid_key(&uaid, &chid, &[1; 32]).await?);
+        assert!(client.remove_channel(&uaid, &chid).await?);
+        assert_eq!(client.get_vapid_key_pin(&uaid, &chid).await?, None);
+
+        client.remove_user(&uaid).await
+    }
+
+    /// A pin survives the `update_user` of every Hello (which rewrites the
+    /// user's channels)
+    #[actix_rt::test]
+    async fn vapid_key_pin_survives_update_user() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        let chid = Uuid::new_v4();
+        client.remove_user(&uaid).await?;
+        client
+            .add_user(&User {
+                uaid,
+                ..Default::default()
+            })
+            .await?;
+        client.add_channel(&uaid, &chid).await?;
+        assert!(client.pin_vapid_key(&uaid, &chid, &[1; 32]).await?);
+
+        let mut user = client.get_user(&uaid).await?.unwrap();
+        user.connected_at = ms_since_epoch();
+        assert!(client.update_user(&mut user).await?);
+        assert_eq!(
+            client.get_vapid_key_pin(&uaid, &chid).await?,
+            Some(vec![1; 32])
+        );
+        assert_eq!(client.get_channels(&uaid).await?, HashSet::from([chid]));
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn fetch_deadline_pages_backlog() -> DbResult<()> {
+        let mut client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+
+        let chid = Uuid::new_v4();
+        let base = now();
+        let messages: Vec<_> = (0..200)
+            .map(|i| crate::db::Notification {
+                channel_id: chid,
+                version: format!("version{i}"),
+                ttl: 300,
+                timestamp: now(),
+                sortkey_timestamp: Some(base + i),
+                ..Default::default()
+            })
+            .collect();
+        client.save_messages(&uaid, messages).await?;
+
+        // An already passed deadline can't read a single row
+        client.settings.fetch_deadline = Duration::from_nanos(1);
+        let err = client
+            .fetch_timestamp_messages(&uaid, None, 999)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            DbError::BTError(error::BigTableError::FetchDeadlineExceeded)
+        ));
+
+        // Whether the emulator cuts a read short isn't deterministic (see
+        // merge's partial_on_deadline test for partial reads), so page
+        // through the backlog via the returned cursor (tolerating deadline
+        // errors when no rows were read) and ensure every message is read
+        // exactly once
+        client.settings.fetch_deadline = Duration::from_millis(50);
+        let mut seen = HashSet::new();
+        let mut timestamp = None;
+        let mut failures = 0;
+        loop {
+            let fetched = match client.fetch_timestamp_messages(&uaid, timestamp, 999).await {
+                Ok(fetched) => fetched,
+                Err(DbError::BTError(error::BigTableError::FetchDeadlineExceeded))
+                    if failures < 10 =>
+                {
+                    failures += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if fetched.messages.is_empty() {
+                break;
+            }
+            for message in fetched.messages {
+                assert!(seen.insert(message.version));
+            }
+            assert!(fetched.timestamp > timestamp);
+            timestamp = fetched.timestamp;
+        }
+        assert_eq!(seen.len(), 200);
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn add_user_if_absent() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let user = User {
+            uaid,
+            router_type: "webpush".to_owned(),
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await.unwrap();
+
+        assert!(client.add_user_if_absent(&user).await.unwrap().is_none());
+
+        let conflicting = User {
+            uaid,
+            router_type: "fcm".to_owned(),
+            ..Default::default()
+        };
+        let existing = client
+            .add_user_if_absent(&conflicting)
+            .await
+            .unwrap()
+            .expect("Expected the existing user");
+        assert_eq!(existing.router_type, "webpush");
+        assert_eq!(existing.version, user.version);
+        assert_eq!(client.get_user(&uaid).await.unwrap(), Some(existing));
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn transfer_user() -> DbResult<()> {
+        let client = new_client()?;
+        let from = gen_test_uaid();
+        let to = gen_test_uaid();
+        client.remove_user(&to).await?;
+        let user = User {
+            uaid: from,
+            ..Default::default()
+        };
+        client.remove_user(&from).await?;
+        client.add_user(&user).await?;
+        let channel_id = Uuid::new_v4();
+        client.add_channel(&from, &channel_id).await?;
+        let notif = crate::db::Notification {
+            channel_id,
+            version: "transferred".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            sortkey_timestamp: Some(now()),
+            ..Default::default()
+        };
+        let chidmessageid = notif.chidmessageid();
+        client.save_message(&from, notif).await?;
+
+        client.transfer_user(&from, &to).await?;
+        let transferred = client.get_user(&to).await?.unwrap();
+        assert_eq!(transferred.uaid, to);
+        assert_eq!(transferred.version, user.version);
+        assert_eq!(
+            client.get_channels(&to).await?,
+            HashSet::from_iter([channel_id])
+        );
+        let message = client.get_message(&to, &chidmessageid).await?.unwrap();
+        assert_eq!(message.version, "transferred");
+
+        assert!(client.get_user(&from).await?.is_none());
+        assert!(client.get_channels(&from).await?.is_empty());
+        assert!(client.get_message(&from, &chidmessageid).await?.is_none());
+
+        // Nothing left to transfer
+        client.transfer_user(&from, &to).await?;
+        assert!(client.get_user(&to).await?.is_some());
+
+        client.remove_user(&to).await?;
+        client.remove_message(&to, &chidmessageid).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn version_check() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let user = User {
+            uaid,
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await.unwrap();
+
+        client.add_user(&user).await.unwrap();
+        let mut user = client.get_user(&uaid).await.unwrap().unwrap();
+        assert!(client.update_user(&mut user.clone()).await.unwrap());
+
+        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
+        assert_ne!(user.version, fetched.version);
+        // should now fail w/ a stale version
+        assert!(!client.update_user(&mut user).await.unwrap());
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn update_router() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let chid = Uuid::parse_str(TEST_CHID).unwrap();
+        let user = User {
+            uaid,
+            router_type: "gcm".to_owned(),
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await.unwrap();
+        client.add_user(&user).await.unwrap();
+        client.add_channel(&uaid, &chid).await.unwrap();
+
+        let stale = client.get_user(&uaid).await.unwrap().unwrap();
+        // A concurrent login bumps the version
+        let mut login = stale.clone();
+        assert!(client.update_user(&mut login).await.unwrap());
+
+        let router_data = json!({"token": "some_token", "app_id": "some_app"});
+        assert!(!client
+            .update_router_at_version(
+                &uaid,
+                stale.version.as_ref().unwrap(),
+                "fcm".to_owned(),
+                &router_data
+            )
+            .await
+            .unwrap());
+        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
+        assert_eq!(fetched.router_type, "gcm");
+        assert_eq!(fetched.version, login.version);
+
+        assert!(client
+            .update_router(&uaid, "fcm".to_owned(), router_data.clone())
+            .await
+            .unwrap());
+        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
+        assert_eq!(fetched.router_type, "fcm");
+        assert_eq!(json!(fetched.router_data), router_data);
+        assert_ne!(fetched.version, login.version);
+        // The rest of the record is untouched
+        assert_eq!(fetched.connected_at, login.connected_at);
+        assert!(fetched.priv_channels.contains(&chid));
+
+        // Unknown users aren't created
+        let missing = gen_test_uaid();
+        assert!(!client
+            .update_router(&missing, "fcm".to_owned(), router_data)
+            .await
+            .unwrap());
+        assert!(client.get_user(&missing).await.unwrap().is_none());
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn get_message() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+
+        let test_notification = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            data: Some("An_encrypted_pile_of_crap".to_owned()),
+            sortkey_timestamp: Some(now()),
+            ..Default::default()
+        };
+        let chidmessageid = test_notification.chidmessageid();
+        client
+            .save_message(&uaid, test_notification.clone())
+            .await?;
+
+        let fetched = client.get_message(&uaid, &chidmessageid).await?.unwrap();
+        assert_eq!(fetched.channel_id, test_notification.channel_id);
+        assert_eq!(fetched.version, test_notification.version);
+        assert_eq!(fetched.data, test_notification.data);
+
+        client.remove_message(&uaid, &chidmessageid).await?;
+        assert!(client.get_message(&uaid, &chidmessageid).await?.is_none());
+
+        // never stored
+        let missing = format!("02:{}:{}", now(), Uuid::new_v4().as_hyphenated());
+        assert!(client.get_message(&uaid, &missing).await?.is_none());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn extend_message_ttl() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+
+        let notification = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test".to_owned(),
+            ttl: 1,
+            timestamp: now(),
+            data: Some("An_encrypted_pile_of_crap".to_owned()),
+            sortkey_timestamp: Some(now()),
+            ..Default::default()
+        };
+        let chidmessageid = notification.chidmessageid();
+        client.save_message(&uaid, notification.clone()).await?;
+        assert!(
+            client
+                .extend_message_ttl(&uaid, &chidmessageid, 300)
+                .await?
+        );
+
+        // Survives past its original expiry
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let fetched = client.get_message(&uaid, &chidmessageid).await?.unwrap();
+        assert_eq!(fetched.version, notification.version);
+        assert_eq!(fetched.data, notification.data);
+        assert_eq!(fetched.timestamp, notification.timestamp);
+        assert!(fetched.ttl >= 300);
+        assert!(!fetched.expired(now()));
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 0).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(client.purge_expired(&uaid).await?, 0);
+
+        client.remove_message(&uaid, &chidmessageid).await?;
+        assert!(
+            !client
+                .extend_message_ttl(&uaid, &chidmessageid, 300)
+                .await?
+        );
+        assert!(client.get_message(&uaid, &chidmessageid).await?.is_none());
+
+        client.remove_user(&uaid).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn remove_user_if_stale() -> DbResult<()> {
+        let client = new_client()?;
+        let max_idle = Duration::from_secs(86400);
+
+        let fresh = User {
+            uaid: gen_test_uaid(),
+            connected_at: ms_since_epoch(),
+            ..Default::default()
+        };
+        client.remove_user(&fresh.uaid).await?;
+        client.add_user(&fresh).await?;
+        assert!(!client.remove_user_if_stale(&fresh.uaid, max_idle).await?);
+        assert!(client.get_user(&fresh.uaid).await?.is_some());
+
+        // Router cells last written 2 days ago
+        let aged_uaid = gen_test_uaid();
+        client.remove_user(&aged_uaid).await?;
+        write_aged_router_record(&client, &aged_uaid, Duration::from_secs(2 * 86400)).await?;
+        assert!(client.get_user(&aged_uaid).await?.is_some());
+        assert!(client.remove_user_if_stale(&aged_uaid, max_idle).await?);
+        assert!(client.get_user(&aged_uaid).await?.is_none());
+        // Already removed
+        assert!(!client.remove_user_if_stale(&aged_uaid, max_idle).await?);
+
+        client.remove_user(&fresh.uaid).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn remove_user_if_stale_bridged_refresh() -> DbResult<()> {
+        let client = new_client()?;
+        let max_idle = Duration::from_secs(86400);
+
+        // A bridged user that registered (thus last "connected") long ago
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+        write_aged_router_record(&client, &uaid, Duration::from_secs(2 * 86400)).await?;
+        let mut user = client.get_user(&uaid).await?.unwrap();
+
+        // but recently refreshed its token
+        user.router_type = "fcm".to_owned();
+        user.router_data = Some(HashMap::from([(
+            "token".to_owned(),
+            serde_json::Value::String("refreshed".to_owned()),
+        )]));
+        assert!(client.update_user(&mut user).await?);
+        assert!(!client.remove_user_if_stale(&uaid, max_idle).await?);
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched.connected_at, user.connected_at);
+
+        client.remove_user(&uaid).await?;
+        Ok(())
+    }
+
+    /// Write a minimal router record as if it were last written `age` ago
+    async fn write_aged_router_record(
+        client: &BigTableClientImpl,
+        uaid: &Uuid,
+        age: Duration,
+    ) -> DbResult<()> {
+        let written_at = SystemTime::now() - age;
+        let expiry = written_at + Duration::from_secs(MAX_ROUTER_TTL);
+        let connected_at = written_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let user = User {
+            uaid: *uaid,
+            connected_at,
+            ..Default::default()
+        };
+        let mut row = client.user_to_row(&user, &Uuid::new_v4());
+        for cell in row.cells.values_mut().flatten() {
+            cell.timestamp = expiry;
+        }
+        client.write_row(row).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn pending_message_count() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+        assert_eq!(client.pending_message_count(&uaid).await?, 0);
+
+        let timestamp_notification = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            sortkey_timestamp: Some(now()),
+            ..Default::default()
+        };
+        let topic_notification = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            topic: Some("topic".to_owned()),
+            ..Default::default()
+        };
+        client
+            .save_messages(
+                &uaid,
+                vec![timestamp_notification.clone(), topic_notification],
+            )
+            .await?;
+        assert_eq!(client.pending_message_count(&uaid).await?, 2);
+
+        client
+            .remove_message(&uaid, &timestamp_notification.chidmessageid())
+            .await?;
+        assert_eq!(client.pending_message_count(&uaid).await?, 1);
+
+        client.remove_user(&uaid).await?;
+        assert_eq!(client.pending_message_count(&uaid).await?, 0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn fetch_filters_expired() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+        let base = ms_since_epoch();
+
+        // The rows outlive the messages' TTL, as if read right at the GC
+        // policy's boundary
+        let expired = |sortkey_timestamp: u64, topic: Option<&str>| crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "expired".to_owned(),
+            ttl: 300,
+            timestamp: now() - 600,
+            sortkey_timestamp: topic.is_none().then_some(sortkey_timestamp),
+            topic: topic.map(str::to_owned),
+            ..Default::default()
+        };
+        let live = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "live".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            sortkey_timestamp: Some(base + 10),
+            ..Default::default()
+        };
+        client
+            .save_messages(
+                &uaid,
+                vec![
+                    expired(base, None),
+                    expired(base + 1, None),
+                    expired(base + 2, None),
+                    live.clone(),
+                    expired(0, Some("topic")),
+                ],
+            )
+            .await?;
+
+        let topic = client.fetch_topic_messages(&uaid, 10).await?;
+        assert!(topic.messages.is_empty());
+
+        // Reads past a full page of expired messages
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 2).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].version, live.version);
+        assert_eq!(fetched.timestamp, live.sortkey_timestamp);
+
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 0).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].version, live.version);
+
+        client.remove_user(&uaid).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn scan_users() -> DbResult<()> {
+        let client = new_client()?;
+        let seeded: Vec<Uuid> = (0..3).map(|_| gen_test_uaid()).collect();
+        for uaid in &seeded {
+            client
+                .add_user(&User {
+                    uaid: *uaid,
+                    ..Default::default()
+                })
+                .await?;
+        }
+        // Messages rows aren't users
+        client
+            .save_message(
+                &seeded[0],
+                crate::db::Notification {
+                    channel_id: Uuid::new_v4(),
+                    version: "version".to_owned(),
+                    ttl: 300,
+                    timestamp: now(),
+                    sortkey_timestamp: Some(ms_since_epoch()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let mut scanned = Vec::new();
+        let mut start = None;
+        let mut pages = 0;
+        loop {
+            let (users, next) = client.scan_users(start, 2).await?;
+            assert!(users.len() <= 2);
+            scanned.extend(users.into_iter().map(|user| user.uaid));
+            pages += 1;
+            let Some(next) = next else {
+                break;
+            };
+            start = Some(next);
+        }
+        assert!(pages >= 2);
+        for uaid in &seeded {
+            assert_eq!(scanned.iter().filter(|u| *u == uaid).count(), 1);
+        }
+
+        for uaid in &seeded {
+            client.remove_user(uaid).await?;
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn message_meta() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+
+        let meta = json!({"tracking_id": "abc123", "attempt": 2});
+        let notif = |meta: Option<serde_json::Value>| crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "version".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            sortkey_timestamp: Some(ms_since_epoch()),
+            meta,
+            ..Default::default()
+        };
+        let with_meta = notif(Some(meta.clone()));
+        let without_meta = notif(None);
+        client
+            .save_messages(&uaid, vec![with_meta.clone(), without_meta.clone()])
+            .await?;
+
+        let fetched = client
+            .get_message(&uaid, &with_meta.chidmessageid())
+            .await?
+            .unwrap();
+        assert_eq!(fetched.meta, Some(meta));
+        let fetched = client
+            .get_message(&uaid, &without_meta.chidmessageid())
+            .await?
+            .unwrap();
+        assert_eq!(fetched.meta, None);
+
+        client.remove_user(&uaid).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn fetch_sorted_by_sortkey() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+
+        let notif =
+            |sortkey_timestamp: u64, channel_id: &str, version: &str| crate::db::Notification {
+                channel_id: Uuid::parse_str(channel_id).unwrap(),
+                version: version.to_owned(),
+                ttl: 300,
+                timestamp: now(),
+                sortkey_timestamp: Some(sortkey_timestamp),
+                ..Default::default()
+            };
+        // Row keys sort lexicographically: differing digit counts order 100
+        // before 10 before 9, and the tied 10s order by channel id, not by
+        // version
+        let messages = vec![
+            notif(9, "00000000-0000-0000-0000-000000000009", "a"),
+            notif(10, "ffffffff-0000-0000-0000-000000000010", "a"),
+            notif(10, "00000000-0000-0000-0000-000000000010", "b"),
+            notif(100, "00000000-0000-0000-0000-000000000100", "a"),
+        ];
+        let expected: Vec<_> = messages.iter().map(|m| m.chidmessageid()).collect();
+        let mut by_key = expected.clone();
+        by_key.sort();
+        assert_ne!(by_key, expected);
+        client.save_messages(&uaid, messages).await?;
+
+        let fetched = client.fetch_timestamp_messages(&uaid, None, 0).await?;
+        let order: Vec<_> = fetched.messages.iter().map(|m| m.chidmessageid()).collect();
+        assert_eq!(order, expected);
+        assert_eq!(fetched.timestamp, Some(100));
+
+        client.remove_user(&uaid).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn purge_expired() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+        assert_eq!(client.purge_expired(&uaid).await?, 0);
+
+        let live_notification = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "live".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            sortkey_timestamp: Some(now()),
+            ..Default::default()
+        };
+        // Already expired (but not yet GC'd)
+        let expired_notification = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "expired".to_owned(),
+            ttl: 0,
+            timestamp: now(),
+            sortkey_timestamp: Some(now()),
+            ..Default::default()
+        };
+        client
+            .save_messages(
+                &uaid,
+                vec![live_notification.clone(), expired_notification.clone()],
+            )
+            .await?;
+        assert_eq!(client.purge_expired(&uaid).await?, 1);
+        // Nothing left to purge
+        assert_eq!(client.purge_expired(&uaid).await?, 0);
+
+        assert!(client
+            .get_message(&uaid, &live_notification.chidmessageid())
+            .await?
+            .is_some());
+        let mut req = client.read_row_request(&format!(
+            "{}#{}",
+            uaid.simple(),
+            expired_notification.chidmessageid()
+        ));
+        req.set_filter(router_gc_policy_filter());
+        assert!(client.read_row(req).await?.is_none());
+
+        client.remove_user(&uaid).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn compressed_message() -> DbResult<()> {
+        let mut client = new_client()?;
+        client.settings.compress_messages = MessageCompression::Zstd;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+
+        let test_data = "An_encrypted_pile_of_crap".repeat(20);
+        let test_notification = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            data: Some(test_data.clone()),
+            sortkey_timestamp: Some(now()),
+            ..Default::default()
+        };
+        client
+            .save_message(&uaid, test_notification.clone())
+            .await?;
+
+        // the data cell is stored compressed alongside its codec marker
+        let row_key = format!("{}#{}", uaid.simple(), test_notification.chidmessageid());
+        let mut row = client
+            .read_row(client.read_row_request(&row_key))
+            .await?
+            .unwrap();
+        let codec = row.take_required_cell(DATA_CODEC_QUALIFIER)?;
+        assert_eq!(codec.value, b"zstd");
+        assert_ne!(row.take_required_cell("data")?.value, test_data.as_bytes());
+
+        let mut fetched = client.fetch_timestamp_messages(&uaid, None, 999).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages.pop().unwrap().data, Some(test_data));
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn uncompressed_legacy_message() -> DbResult<()> {
+        // a row written before compression was enabled
+        let legacy_client = new_client()?;
+        let uaid = gen_test_uaid();
+        legacy_client.remove_user(&uaid).await?;
+
+        let test_data = "An_encrypted_pile_of_crap".to_owned();
+        let test_notification = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "test".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            data: Some(test_data.clone()),
+            sortkey_timestamp: Some(now()),
+            ..Default::default()
+        };
+        legacy_client.save_message(&uaid, test_notification).await?;
+
+        // is still readable once compression is enabled
+        let mut client = new_client()?;
+        client.settings.compress_messages = MessageCompression::Gzip;
+        let mut fetched = client.fetch_timestamp_messages(&uaid, None, 999).await?;
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages.pop().unwrap().data, Some(test_data));
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn node_region_round_trip() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let user = User {
+            uaid,
+            node_id: Some("test_node".to_owned()),
+            node_region: Some("us-west1".to_owned()),
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await.unwrap();
+
+        client.add_user(&user).await.unwrap();
+        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
+        assert_eq!(fetched.node_id, user.node_id);
+        assert_eq!(fetched.node_region, user.node_region);
+
+        // the region is cleared along with the node_id
+        assert!(client
+            .remove_node_id(&uaid, "test_node", fetched.connected_at, &fetched.version)
+            .await
+            .unwrap());
+        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
+        assert_eq!(fetched.node_id, None);
+        assert_eq!(fetched.node_region, None);
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    /// Reconnecting to a different node replaces (or clears) the region
+    #[actix_rt::test]
+    async fn node_region_reconnect() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+        client
+            .add_user(&User {
+                uaid,
+                node_id: Some("node_a".to_owned()),
+                node_region: Some("us-west1".to_owned()),
+                ..Default::default()
+            })
+            .await?;
+
+        let mut user = client.get_user(&uaid).await?.unwrap();
+        user.node_id = Some("node_b".to_owned());
+        user.node_region = Some("europe-west1".to_owned());
+        user.connected_at = ms_since_epoch();
+        assert!(client.update_user(&mut user).await?);
+        let mut fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched.node_id.as_deref(), Some("node_b"));
+        assert_eq!(fetched.node_region.as_deref(), Some("europe-west1"));
+
+        // A node without a region doesn't inherit the previous node's
+        fetched.node_id = Some("node_c".to_owned());
+        fetched.node_region = None;
+        fetched.connected_at = ms_since_epoch();
+        assert!(client.update_user(&mut fetched).await?);
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched.node_id.as_deref(), Some("node_c"));
+        assert_eq!(fetched.node_region, None);
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn last_disconnect_round_trip() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+
+        // Skipped for a nonexistent user
+        assert!(!client.set_last_disconnect(&uaid, "clean", 1).await?);
+        assert!(client.get_user(&uaid).await?.is_none());
+
+        let user = User {
+            uaid,
+            ..Default::default()
+        };
+        client.add_user(&user).await?;
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched.last_disconnect_reason, None);
+        assert_eq!(fetched.last_disconnect_at, None);
+
+        let disconnected_at = ms_since_epoch();
+        assert!(
+            client
+                .set_last_disconnect(&uaid, "ping_timeout", disconnected_at)
+                .await?
+        );
+        let mut fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(
+            fetched.last_disconnect_reason.as_deref(),
+            Some("ping_timeout")
+        );
+        assert_eq!(fetched.last_disconnect_at, Some(disconnected_at));
+        // The user record's otherwise unchanged
+        assert_eq!(fetched.version, user.version);
+
+        // and survives an update_user
+        assert!(client.update_user(&mut fetched).await?);
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(
+            fetched.last_disconnect_reason.as_deref(),
+            Some("ping_timeout")
+        );
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn delivery_attempts_round_trip() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+
+        let timestamp_notif = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "timestamp".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            sortkey_timestamp: Some(ms_since_epoch()),
+            ..Default::default()
+        };
+        let topic_notif = crate::db::Notification {
+            channel_id: Uuid::new_v4(),
+            version: "topic".to_owned(),
+            ttl: 300,
+            timestamp: now(),
+            topic: Some("topic".to_owned()),
+            ..Default::default()
+        };
+        for notif in [timestamp_notif, topic_notif] {
+            let chidmessageid = client.save_message_returning(&uaid, notif).await?;
+            let mut fetched = client.get_message(&uaid, &chidmessageid).await?.unwrap();
+            assert_eq!(fetched.delivery_attempts, 0);
+
+            for attempts in 1..=2 {
+                assert!(client.increment_delivery_attempts(&uaid, &fetched).await?);
+                fetched = client.get_message(&uaid, &chidmessageid).await?.unwrap();
+                assert_eq!(fetched.delivery_attempts, attempts);
+            }
+
+            // Skipped once the message is deleted
+            client.remove_message(&uaid, &chidmessageid).await?;
+            assert!(!client.increment_delivery_attempts(&uaid, &fetched).await?);
+            assert!(client.get_message(&uaid, &chidmessageid).await?.is_none());
+        }
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn channel_exists() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let chid = Uuid::new_v4();
+        let other_chid = Uuid::new_v4();
+        let user = User {
+            uaid,
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await.unwrap();
+
+        // no user record at all
+        assert!(!client.channel_exists(&uaid, &chid).await.unwrap());
+
+        client.add_user(&user).await.unwrap();
+        client.add_channel(&uaid, &chid).await.unwrap();
+        assert!(client.channel_exists(&uaid, &chid).await.unwrap());
+        assert!(!client.channel_exists(&uaid, &other_chid).await.unwrap());
+
+        client.remove_channel(&uaid, &chid).await.unwrap();
+        assert!(!client.channel_exists(&uaid, &chid).await.unwrap());
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn get_channels_page() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let user = User {
+            uaid,
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await.unwrap();
+
+        // no user record at all
+        let (channels, cursor) = client.get_channels_page(&uaid, None, 10).await.unwrap();
+        assert!(channels.is_empty());
+        assert!(cursor.is_none());
+
+        let chids: HashSet<Uuid> = (0..25).map(|_| Uuid::new_v4()).collect();
+        client.add_user(&user).await.unwrap();
+        client.add_channels(&uaid, chids.clone()).await.unwrap();
+
+        let mut paged = HashSet::new();
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let (channels, next) = client.get_channels_page(&uaid, cursor, 10).await.unwrap();
+            assert!(channels.len() <= 10);
+            assert!(channels.is_disjoint(&paged));
+            paged.extend(channels);
+            pages += 1;
+            let Some(next) = next else {
+                break;
+            };
+            cursor = Some(next);
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(paged, chids);
+
+        // limit=0 reads every channel at once
+        let (channels, cursor) = client.get_channels_page(&uaid, None, 0).await.unwrap();
+        assert_eq!(channels, chids);
+        assert!(cursor.is_none());
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn get_user_with_channels() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let chids: HashSet<Uuid> = [Uuid::new_v4(), Uuid::new_v4()].into();
+        let user = User {
+            uaid,
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await.unwrap();
+
+        assert!(client
+            .get_user_with_channels(&uaid)
+            .await
+            .unwrap()
+            .is_none());
+
+        client.add_user(&user).await.unwrap();
+        client.add_channels(&uaid, chids.clone()).await.unwrap();
+        let (fetched, channels) = client.get_user_with_channels(&uaid).await.unwrap().unwrap();
+        assert_eq!(fetched, client.get_user(&uaid).await.unwrap().unwrap());
+        assert_eq!(channels, client.get_channels(&uaid).await.unwrap());
+        assert_eq!(channels, chids);
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn remove_channels() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let user = User {
+            uaid,
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await.unwrap();
+        let present: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let kept = Uuid::new_v4();
+        let absent = Uuid::new_v4();
+
+        // no user record at all
+        assert_eq!(client.remove_channels(&uaid, &present).await.unwrap(), 0);
+
+        client.add_user(&user).await.unwrap();
+        let mut channels: HashSet<Uuid> = present.iter().copied().collect();
+        channels.insert(kept);
+        client.add_channels(&uaid, channels).await.unwrap();
+        let version = client.get_user(&uaid).await.unwrap().unwrap().version;
+
+        // A mix of present, absent and duplicated channels
+        let to_remove = [present[0], absent, present[1], present[2], present[0]];
+        assert_eq!(client.remove_channels(&uaid, &to_remove).await.unwrap(), 3);
+        let user = client.get_user(&uaid).await.unwrap().unwrap();
+        assert_ne!(user.version, version);
+        assert_eq!(
+            client.get_channels(&uaid).await.unwrap(),
+            HashSet::from([kept])
+        );
+
+        // Already removed
+        assert_eq!(client.remove_channels(&uaid, &to_remove).await.unwrap(), 0);
+        assert_eq!(client.remove_channels(&uaid, &[]).await.unwrap(), 0);
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn store_only_channels() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        let user = User {
+            uaid,
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await?;
+        let chid = Uuid::new_v4();
+        let other_chid = Uuid::new_v4();
+        let bulk_chid = Uuid::new_v4();
+
+        client.add_user(&user).await?;
+        assert!(!client.is_store_only(&uaid, &chid).await?);
+
+        client
+            .add_channels(&uaid, HashSet::from([other_chid, bulk_chid]))
+            .await?;
+        client
+            .add_channel_with_store_only(&uaid, &chid, true)
+            .await?;
+        client
+            .add_channel_with_store_only(&uaid, &bulk_chid, true)
+            .await?;
+        assert!(client.is_store_only(&uaid, &chid).await?);
+        assert!(!client.is_store_only(&uaid, &other_chid).await?);
+
+        // Re-registering without the mark clears it
+        client
+            .add_channel_with_store_only(&uaid, &other_chid, true)
+            .await?;
+        client
+            .add_channel_with_store_only(&uaid, &other_chid, false)
+            .await?;
+        assert!(!client.is_store_only(&uaid, &other_chid).await?);
+
+        // The marks aren't channels themselves
+        let expected = HashSet::from([chid, other_chid, bulk_chid]);
+        assert_eq!(client.get_channels(&uaid).await?, expected);
+        let (_, channels) = client.get_user_with_channels(&uaid).await?.unwrap();
+        assert_eq!(channels, expected);
+
+        // Removing the channel clears its mark
+        assert!(client.remove_channel(&uaid, &chid).await?);
+        assert!(!client.is_store_only(&uaid, &chid).await?);
+        assert_eq!(client.remove_channels(&uaid, &[bulk_chid]).await?, 1);
+        assert!(!client.is_store_only(&uaid, &bulk_chid).await?);
+        client.add_channel(&uaid, &chid).await?;
+        assert!(!client.is_store_only(&uaid, &chid).await?);
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn lingering_chid_record() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        let chid = Uuid::parse_str(TEST_CHID).unwrap();
+        let user = User {
+            uaid,
+            ..Default::default()
+        };
+        client.remove_user(&uaid).await.unwrap();
+
+        // add_channel doesn't check for the existence of a user
+        client.add_channel(&uaid, &chid).await.unwrap();
+
+        // w/ chid records in the router row, get_user should treat
+        // this as the user not existing
+        assert!(client.get_user(&uaid).await.unwrap().is_none());
+
+        client.add_user(&user).await.unwrap();
+        // get_user should have also cleaned up the chids
+        assert!(client.get_channels(&uaid).await.unwrap().is_empty());
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn lingering_current_timestamp() {
+        let client = new_client().unwrap();
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await.unwrap();
+
+        assert!(!client
+            .increment_storage(&uaid, ms_since_epoch(), &Uuid::new_v4())
+            .await
+            .unwrap());
+        assert!(client.get_user(&uaid).await.unwrap().is_none());
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn lingering_last_disconnect() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+
+        // set_last_disconnect's cells outliving the rest of the record
+        let mut row = Row::new(uaid.simple().to_string());
+        row.add_cells(
+            ROUTER_FAMILY,
+            vec![
+                cell::Cell {
+                    qualifier: "last_disconnect_reason".to_owned(),
+                    value: b"clean".to_vec(),
+                    ..Default::default()
+                },
+                cell::Cell {
+                    qualifier: "last_disconnect_at".to_owned(),
+                    value: ms_since_epoch().to_be_bytes().to_vec(),
+                    ..Default::default()
+                },
+            ],
+        );
+        client.write_row(row).await?;
+        assert!(client.get_user(&uaid).await?.is_none());
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn increment_storage_version_guard() -> DbResult<()> {
+        let client = new_client()?;
+        let uaid = gen_test_uaid();
+        client.remove_user(&uaid).await?;
+        let mut user = User {
+            uaid,
+            ..Default::default()
+        };
+        client.add_user(&user).await?;
+        let stale_version = user.version.unwrap();
+        // A concurrent update_user changes the version
+        assert!(client.update_user(&mut user).await?);
+        let version = user.version.unwrap();
+        assert_ne!(stale_version, version);
+
+        let timestamp = ms_since_epoch();
+        assert!(
+            !client
+                .increment_storage(&uaid, timestamp, &stale_version)
+                .await?
+        );
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched.current_timestamp, None);
+        assert_eq!(fetched.version, Some(version));
+
+        assert!(client.increment_storage(&uaid, timestamp, &version).await?);
+        // The version's unchanged, allowing further increments
+        assert!(
+            client
+                .increment_storage(&uaid, timestamp + 1, &version)
+                .await?
+        );
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched.current_timestamp, Some(timestamp + 1));
+        assert_eq!(fetched.version, Some(version));
+
+        // A backwards (or repeated) timestamp is ignored, even at the
+        // current version
+        assert!(!client.increment_storage(&uaid, timestamp, &version).await?);
+        assert!(
+            !client
+                .increment_storage(&uaid, timestamp + 1, &version)
+                .await?
+        );
+        let fetched = client.get_user(&uaid).await?.unwrap();
+        assert_eq!(fetched.current_timestamp, Some(timestamp + 1));
+
+        client.remove_user(&uaid).await
+    }
+
+    #[actix_rt::test]
+    async fn lingering_chid_w_version_record() {
         let client = new_client().unwrap();
         let uaid = gen_test_uaid();
         let chid = Uuid::parse_str(TEST_CHID).unwrap();
@@ -1861,16 +4443,17 @@ mod tests {
             .await
             .unwrap();
 
-        client
+        assert!(client
             .increment_storage(
                 &uaid,
                 SystemTime::now()
                     .duration_since(SystemTime::UNIX_EPOCH)
                     .unwrap()
                     .as_secs(),
+                &user.version.unwrap(),
             )
             .await
-            .unwrap();
+            .unwrap());
 
         let req = client.read_row_request(&uaid.as_simple().to_string());
         let Some(mut row) = client.read_row(req).await.unwrap() else {
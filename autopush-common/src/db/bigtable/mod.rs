@@ -22,6 +22,7 @@
 mod bigtable_client;
 mod pool;
 
+pub use bigtable_client::compression::MessageCompression;
 pub use bigtable_client::error::BigTableError;
 pub use bigtable_client::BigTableClientImpl;
 
@@ -31,12 +32,24 @@ use std::time::Duration;
 
 use crate::db::bigtable::bigtable_client::MetadataBuilder;
 use crate::db::error::DbError;
-use crate::util::deserialize_opt_u32_to_duration;
+use crate::util::{deserialize_humantime_duration, deserialize_opt_u32_to_duration};
 
 fn retry_default() -> usize {
     bigtable_client::RETRY_COUNT
 }
 
+fn max_fetch_limit_default() -> usize {
+    1000
+}
+
+fn max_headers_bytes_default() -> usize {
+    4096
+}
+
+fn db_trace_sample_rate_default() -> f64 {
+    1.0
+}
+
 /// The settings for accessing the BigTable contents.
 #[derive(Clone, Debug, Deserialize)]
 pub struct BigTableDbSettings {
@@ -52,6 +65,16 @@ pub struct BigTableDbSettings {
     /// Should be used everywhere we set `table_name` when creating requests
     #[serde(default)]
     pub app_profile_id: String,
+    /// Optional DSN of a read optimized endpoint (e.g. a replica cluster).
+    /// When specified, fetching messages and listing channels use a separate
+    /// pool of connections to it. Writes, and any read that a write depends
+    /// on (e.g. reading the user on Hello), remain on the primary `dsn`.
+    #[serde(default)]
+    pub read_dsn: Option<String>,
+    /// Optional routing profile id for the reads using `read_dsn`. Defaults
+    /// to `app_profile_id`.
+    #[serde(default)]
+    pub read_profile_id: Option<String>,
     #[serde(default)]
     pub router_family: String,
     #[serde(default)]
@@ -86,6 +109,38 @@ pub struct BigTableDbSettings {
     /// Number of times to retry a GRPC function
     #[serde(default = "retry_default")]
     pub retry_count: usize,
+    /// Compression codec (`none`, `gzip` or `zstd`) applied to the data of
+    /// newly stored messages. Existing messages are read regardless.
+    #[serde(default)]
+    pub compress_messages: MessageCompression,
+    /// Max number of messages returned by a single fetch. Requested limits
+    /// above this (including `0`, meaning "all messages") are clamped to it.
+    /// `0` disables the cap.
+    #[serde(default = "max_fetch_limit_default")]
+    pub max_fetch_limit: usize,
+    /// Max size (in bytes) of a stored message's serialized `headers`.
+    /// Messages with larger headers are rejected. `0` disables the limit.
+    #[serde(default = "max_headers_bytes_default")]
+    pub max_headers_bytes: usize,
+    /// Connect to a Bigtable emulator, skipping the Google credentials. This
+    /// is implied by a loopback DSN host (e.g. `grpc://localhost:8086`) or by
+    /// setting `BIGTABLE_EMULATOR_HOST`.
+    #[serde(default)]
+    pub emulator: bool,
+    /// Fraction (`0.0` to `1.0`) of the high volume, per operation trace and
+    /// debug log messages to emit. Row keys in these are always elided.
+    #[serde(default = "db_trace_sample_rate_default")]
+    pub db_trace_sample_rate: f64,
+    /// Log (and emit a `database.slow` metric for) operations taking longer
+    /// than this, e.g. `"250ms"`. `0` disables.
+    #[serde(default, deserialize_with = "deserialize_humantime_duration")]
+    pub slow_query_threshold: Duration,
+    /// Deadline for each read of `fetch_timestamp_messages`, e.g. `"500ms"`.
+    /// An exceeded read returns the messages read so far (resuming from them
+    /// on the next fetch) instead of being retried. A read exceeding it before
+    /// reading any messages is retried, then fails. `0` disables.
+    #[serde(default, deserialize_with = "deserialize_humantime_duration")]
+    pub fetch_deadline: Duration,
 }
 
 // Used by test, but we don't want available for release.
@@ -107,6 +162,15 @@ impl Default for BigTableDbSettings {
             route_to_leader: Default::default(),
             retry_count: Default::default(),
             app_profile_id: Default::default(),
+            read_dsn: Default::default(),
+            read_profile_id: Default::default(),
+            compress_messages: Default::default(),
+            max_fetch_limit: max_fetch_limit_default(),
+            max_headers_bytes: max_headers_bytes_default(),
+            emulator: Default::default(),
+            db_trace_sample_rate: db_trace_sample_rate_default(),
+            slow_query_threshold: Default::default(),
+            fetch_deadline: Default::default(),
         }
     }
 }
@@ -140,6 +204,28 @@ impl BigTableDbSettings {
             .map_err(BigTableError::GRPC)
     }
 
+    /// Return the effective number of rows to read for a fetch requesting
+    /// `limit` rows (`0` for all), honoring `max_fetch_limit`
+    pub fn clamp_fetch_limit(&self, limit: usize) -> usize {
+        match (limit, self.max_fetch_limit) {
+            (_, 0) => limit,
+            (0, max) => max,
+            (limit, max) => limit.min(max),
+        }
+    }
+
+    /// Check a message's serialized `headers` don't exceed
+    /// `max_headers_bytes`
+    pub fn check_headers_size(&self, headers_len: usize) -> Result<(), DbError> {
+        if self.max_headers_bytes > 0 && headers_len > self.max_headers_bytes {
+            return Err(DbError::HeadersTooLarge(
+                headers_len,
+                self.max_headers_bytes,
+            ));
+        }
+        Ok(())
+    }
+
     pub fn get_instance_name(&self) -> Result<String, BigTableError> {
         let parts: Vec<&str> = self.table_name.split('/').collect();
         if parts.len() < 4 || parts[0] != "projects" || parts[2] != "instances" {
@@ -184,8 +270,67 @@ mod tests {
             settings.database_pool_create_timeout,
             Some(std::time::Duration::from_secs(123))
         );
+        assert_eq!(settings.db_trace_sample_rate, 1.0);
+        assert_eq!(
+            super::BigTableDbSettings::default().db_trace_sample_rate,
+            settings.db_trace_sample_rate
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_clamp_fetch_limit() -> Result<(), crate::db::error::DbError> {
+        let settings = super::BigTableDbSettings::try_from("{\"max_fetch_limit\": 100}")?;
+        assert_eq!(settings.clamp_fetch_limit(10), 10);
+        assert_eq!(settings.clamp_fetch_limit(100), 100);
+        assert_eq!(settings.clamp_fetch_limit(500), 100);
+        // "all messages" is clamped too
+        assert_eq!(settings.clamp_fetch_limit(0), 100);
+
+        // the cap applies by default
+        let settings = super::BigTableDbSettings::try_from("{}")?;
+        assert_eq!(
+            settings.clamp_fetch_limit(0),
+            super::max_fetch_limit_default()
+        );
+        assert_eq!(
+            super::BigTableDbSettings::default().max_fetch_limit,
+            settings.max_fetch_limit
+        );
+
+        // and may be disabled
+        let settings = super::BigTableDbSettings::try_from("{\"max_fetch_limit\": 0}")?;
+        assert_eq!(settings.clamp_fetch_limit(0), 0);
+        assert_eq!(settings.clamp_fetch_limit(5000), 5000);
         Ok(())
     }
+
+    #[test]
+    fn test_check_headers_size() -> Result<(), crate::db::error::DbError> {
+        let settings = super::BigTableDbSettings::try_from("{\"max_headers_bytes\": 64}")?;
+        let headers: std::collections::HashMap<_, _> =
+            [("encryption".to_owned(), "a".repeat(40))].into();
+        // {"encryption":"aaa..."} is 17 bytes of overhead
+        let under = serde_json::json!(headers).to_string();
+        assert_eq!(under.len(), 57);
+        assert!(settings.check_headers_size(under.len()).is_ok());
+        assert!(settings.check_headers_size(64).is_ok());
+
+        let headers: std::collections::HashMap<_, _> =
+            [("encryption".to_owned(), "a".repeat(48))].into();
+        let over = serde_json::json!(headers).to_string();
+        assert_eq!(over.len(), 65);
+        assert!(matches!(
+            settings.check_headers_size(over.len()),
+            Err(crate::db::error::DbError::HeadersTooLarge(65, 64))
+        ));
+
+        // 0 disables the limit
+        let settings = super::BigTableDbSettings::try_from("{\"max_headers_bytes\": 0}")?;
+        assert!(settings.check_headers_size(over.len()).is_ok());
+        Ok(())
+    }
+
     #[test]
     fn test_get_instance() -> Result<(), super::BigTableError> {
         let settings = super::BigTableDbSettings {
@@ -1,5 +1,6 @@
 use std::{
     fmt,
+    net::IpAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -16,6 +17,8 @@ use crate::db::DbSettings;
 
 const MAX_MESSAGE_LEN: i32 = 1 << 28; // 268,435,456 bytes
 const DEFAULT_GRPC_PORT: u16 = 443;
+/// How long `ping` waits for its channel to connect
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// The pool of BigTable Clients.
 /// Note: BigTable uses HTTP/2 as the backbone, so the only really important bit
@@ -27,6 +30,9 @@ const DEFAULT_GRPC_PORT: u16 = 443;
 pub struct BigTablePool {
     /// Pool of db connections
     pub pool: deadpool::managed::Pool<BigtableClientManager>,
+    /// A channel dedicated to `ping`, bypassing the pool (whose connections
+    /// are health checked when recycled)
+    ping_channel: Channel,
     _metrics: Arc<StatsdClient>,
 }
 
@@ -56,6 +62,15 @@ impl BigTablePool {
         self.pool.manager().get_channel()
     }
 
+    /// Check that Bigtable is reachable, without issuing an RPC
+    pub async fn ping(&self) -> Result<(), BigTableError> {
+        if self.ping_channel.wait_for_connected(PING_TIMEOUT).await {
+            Ok(())
+        } else {
+            Err(BigTableError::Unreachable)
+        }
+    }
+
     /// Creates a new pool of BigTable db connections.
     pub fn new(settings: &DbSettings, metrics: &Arc<StatsdClient>) -> DbResult<Self> {
         let Some(endpoint) = &settings.dsn else {
@@ -87,14 +102,19 @@ impl BigTablePool {
             )));
         }
         debug!("🉑 connection string {}", &connection);
+        let emulator = bt_settings.emulator
+            || is_local_host(&parsed)
+            || std::env::var("BIGTABLE_EMULATOR_HOST").is_ok();
 
         // Construct a new manager and put them in a pool for handling future requests.
         let manager = BigtableClientManager::new(
             &bt_settings,
-            settings.dsn.clone(),
+            emulator,
             connection.clone(),
             metrics.clone(),
         )?;
+        // Channels connect lazily, on first use
+        let ping_channel = manager.get_channel()?;
         let mut config = PoolConfig {
             // Prefer LIFO to allow the sweeper task to evict least frequently
             // used connections
@@ -120,6 +140,7 @@ impl BigTablePool {
 
         Ok(Self {
             pool,
+            ping_channel,
             _metrics: metrics.clone(),
         })
     }
@@ -139,6 +160,21 @@ impl BigTablePool {
     }
 }
 
+/// Whether the DSN's host is the local machine (i.e. a Bigtable emulator).
+///
+/// `grpc:` isn't a "special" scheme, so the `url` crate leaves IPv4 hosts
+/// unparsed as domains.
+fn is_local_host(dsn: &url::Url) -> bool {
+    match dsn.host() {
+        Some(url::Host::Domain(domain)) => {
+            domain == "localhost" || domain.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+        }
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
+}
+
 fn sweeper(pool: &deadpool::managed::Pool<BigtableClientManager>, max_idle: Duration) {
     pool.retain(|_, metrics| metrics.last_used() < max_idle);
 }
@@ -146,7 +182,8 @@ fn sweeper(pool: &deadpool::managed::Pool<BigtableClientManager>, max_idle: Dura
 /// BigTable Pool Manager. This contains everything needed to create a new connection.
 pub struct BigtableClientManager {
     settings: BigTableDbSettings,
-    dsn: Option<String>,
+    /// Skip the Google credentials, connecting to an emulator
+    emulator: bool,
     connection: String,
     metrics: Arc<StatsdClient>,
 }
@@ -154,13 +191,13 @@ pub struct BigtableClientManager {
 impl BigtableClientManager {
     fn new(
         settings: &BigTableDbSettings,
-        dsn: Option<String>,
+        emulator: bool,
         connection: String,
         metrics: Arc<StatsdClient>,
     ) -> Result<Self, BigTableError> {
         Ok(Self {
             settings: settings.clone(),
-            dsn,
+            emulator,
             connection,
             metrics,
         })
@@ -171,6 +208,7 @@ impl fmt::Debug for BigtableClientManager {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt.debug_struct("deadpool::BtClientManager")
             .field("settings", &self.settings.clone())
+            .field("emulator", &self.emulator)
             .finish()
     }
 }
@@ -231,19 +269,17 @@ impl Manager for BigtableClientManager {
 impl BigtableClientManager {
     /// Get a new Channel, based on the application settings.
     pub fn get_channel(&self) -> Result<Channel, BigTableError> {
-        Ok(Self::create_channel(self.dsn.clone())?.connect(self.connection.as_str()))
+        Ok(Self::create_channel(self.emulator)?.connect(self.connection.as_str()))
     }
     /// Channels are GRPCIO constructs that contain the actual command data paths.
     /// Channels seem to be fairly light weight.
-    pub fn create_channel(dsn: Option<String>) -> Result<ChannelBuilder, BigTableError> {
+    pub fn create_channel(emulator: bool) -> Result<ChannelBuilder, BigTableError> {
         debug!("🏊 Creating new channel...");
         let mut chan = ChannelBuilder::new(Arc::new(EnvBuilder::new().build()))
             .max_send_message_len(MAX_MESSAGE_LEN)
             .max_receive_message_len(MAX_MESSAGE_LEN);
         // Don't get the credentials if we are running in the emulator
-        if dsn.map(|v| v.contains("localhost")).unwrap_or(false)
-            || std::env::var("BIGTABLE_EMULATOR_HOST").is_ok()
-        {
+        if emulator {
             debug!("🉑 Using emulator");
         } else {
             chan = chan.set_credentials(
@@ -254,3 +290,49 @@ impl BigtableClientManager {
         Ok(chan)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::{NopMetricSink, StatsdClient};
+
+    use super::{is_local_host, BigTablePool};
+    use crate::db::DbSettings;
+
+    #[test]
+    fn local_hosts() {
+        for dsn in [
+            "grpc://localhost:8086",
+            "grpc://127.0.0.1:8086",
+            "grpc://[::1]:8086",
+        ] {
+            assert!(is_local_host(&url::Url::parse(dsn).unwrap()), "{dsn}");
+        }
+        for dsn in [
+            "grpc://bigtable.googleapis.com",
+            "grpc://bigtable-emulator:8086",
+        ] {
+            assert!(!is_local_host(&url::Url::parse(dsn).unwrap()), "{dsn}");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn emulator_without_credentials() {
+        // The emulator must not need Google credentials
+        std::env::remove_var("GOOGLE_APPLICATION_CREDENTIALS");
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        for (dsn, db_settings) in [
+            ("grpc://localhost:8086", "{}"),
+            ("grpc://bigtable-emulator:8086", r#"{"emulator": true}"#),
+        ] {
+            let settings = DbSettings {
+                dsn: Some(dsn.to_owned()),
+                db_settings: db_settings.to_owned(),
+            };
+            let pool = BigTablePool::new(&settings, &metrics).unwrap();
+            assert!(pool.pool.manager().emulator, "{dsn}");
+            assert!(pool.get_channel().is_ok(), "{dsn}");
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashSet;
 use std::fmt::Debug;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use mockall::automock;
@@ -26,30 +27,124 @@ pub trait DbClient: Send + Sync {
     /// exists.
     async fn add_user(&self, user: &User) -> DbResult<()>;
 
+    /// Add a new user to the database unless they already exist. Returns
+    /// `None` when the user was added, otherwise the existing user.
+    async fn add_user_if_absent(&self, user: &User) -> DbResult<Option<User>>;
+
     /// Update a user in the database. Returns whether the update occurred. The
     /// update will not occur if the user does not already exist, has a
     /// different router type, or has a newer `connected_at` timestamp.
     // TODO: make the bool a #[must_use]
     async fn update_user(&self, user: &mut User) -> DbResult<bool>;
 
+    /// Atomically replace a user's `router_type` and `router_data`, leaving
+    /// the rest of their record intact. Returns whether the update occurred:
+    /// it will not if the user does not exist or their record was
+    /// concurrently modified (e.g. by a login bumping its version).
+    async fn update_router(
+        &self,
+        uaid: &Uuid,
+        router_type: String,
+        router_data: serde_json::Value,
+    ) -> DbResult<bool>;
+
     /// Read a user from the database
     async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>>;
 
+    /// Read a user along with their set of channel IDs, in a single round
+    /// trip where the backend allows it
+    async fn get_user_with_channels(&self, uaid: &Uuid) -> DbResult<Option<(User, HashSet<Uuid>)>>;
+
     /// Delete a user from the router table
     async fn remove_user(&self, uaid: &Uuid) -> DbResult<()>;
 
+    /// Delete a user from the router table when their router record hasn't
+    /// been written within `max_idle`: neither a Hello nor a bridged user's
+    /// token refresh (whose `connected_at` is never updated) refreshed it.
+    ///
+    /// Returns whether the user was removed: a reconnect or refresh racing
+    /// the removal keeps the user.
+    async fn remove_user_if_stale(&self, uaid: &Uuid, max_idle: Duration) -> DbResult<bool>;
+
+    /// Move a user's router record, channels and pending messages from the
+    /// UAID `from` to `to` (e.g. to recover from a corrupted record),
+    /// removing the originals.
+    ///
+    /// Safe to retry after a partial failure.
+    async fn transfer_user(&self, from: &Uuid, to: &Uuid) -> DbResult<()>;
+
+    /// Read a page of up to `limit` users (`limit=0` for all), in UAID
+    /// order, following the `start` UAID (or from the first user).
+    ///
+    /// Also returns the continuation key to pass as the next `start`, `None`
+    /// once all users have been read.
+    async fn scan_users(
+        &self,
+        start: Option<Uuid>,
+        limit: usize,
+    ) -> DbResult<(Vec<User>, Option<Uuid>)>;
+
     /// Add a channel to a user
     async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()>;
 
+    /// Add a channel to a user, in the same write marking it as "store only"
+    /// (its notifications are only stored, never delivered directly to a
+    /// connected Client) or clearing a previous mark. Removing the channel
+    /// also clears the mark.
+    async fn add_channel_with_store_only(
+        &self,
+        uaid: &Uuid,
+        channel_id: &Uuid,
+        store_only: bool,
+    ) -> DbResult<()>;
+
     /// Add a batch of channels to a user
     async fn add_channels(&self, uaid: &Uuid, channels: HashSet<Uuid>) -> DbResult<()>;
 
     /// Get the set of channel IDs for a user
     async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>>;
 
+    /// Get a page of up to `limit` of a user's channel IDs (`limit=0` for
+    /// all) following the `cursor` (or from the first channel).
+    ///
+    /// Also returns the cursor to pass for the next page, `None` once all
+    /// channels have been read.
+    async fn get_channels_page(
+        &self,
+        uaid: &Uuid,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> DbResult<(HashSet<Uuid>, Option<String>)>;
+
+    /// Check whether a single channel ID is registered for a user, without
+    /// fetching the user's full channel set
+    async fn channel_exists(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool>;
+
+    /// Get the hash of the VAPID public key pinned to a channel (by
+    /// [DbClient::pin_vapid_key]), if any
+    async fn get_vapid_key_pin(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<Option<Vec<u8>>>;
+
+    /// Pin the hash of a VAPID public key to an existing channel that has no
+    /// pin yet. Returns whether it was pinned (false when the channel doesn't
+    /// exist or is already pinned). Re-adding the channel clears the pin.
+    async fn pin_vapid_key(
+        &self,
+        uaid: &Uuid,
+        channel_id: &Uuid,
+        key_hash: &[u8],
+    ) -> DbResult<bool>;
+
+    /// Whether a channel was marked "store only" (by
+    /// [DbClient::add_channel_with_store_only])
+    async fn is_store_only(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool>;
+
     /// Remove a channel from a user. Returns if the removed channel did exist.
     async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool>;
 
+    /// Remove multiple channels from a user in one operation, returning how
+    /// many of them were present
+    async fn remove_channels(&self, uaid: &Uuid, channel_ids: &[Uuid]) -> DbResult<usize>;
+
     /// Remove the node ID from a user in the router table. Returns whether the
     /// removal occurred. The node ID will only be removed if `connected_at`
     /// matches up with the item's `connected_at`.
@@ -61,8 +156,24 @@ pub trait DbClient: Send + Sync {
         version: &Option<Uuid>,
     ) -> DbResult<bool>;
 
+    /// Record why (and when, in milliseconds) the user's last connection
+    /// ended. Returns whether it was written: it's skipped when the user
+    /// doesn't exist
+    async fn set_last_disconnect(
+        &self,
+        uaid: &Uuid,
+        reason: &str,
+        disconnected_at: u64,
+    ) -> DbResult<bool>;
+
     /// Save a message to the message table
-    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()>;
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.save_message_returning(uaid, message).await.map(|_| ())
+    }
+
+    /// Save a message to the message table, returning the `chidmessageid`
+    /// it was stored under
+    async fn save_message_returning(&self, uaid: &Uuid, message: Notification) -> DbResult<String>;
 
     /// Save multiple messages to the message table
     async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()>;
@@ -75,6 +186,10 @@ pub trait DbClient: Send + Sync {
     ) -> DbResult<FetchMessageResponse>;
 
     /// Fetch stored messages later than a given
+    ///
+    /// Messages are returned in delivery order, sorted by their
+    /// `sortkey_timestamp` then `version` (see
+    /// [crate::db::sort_timestamp_messages]).
     async fn fetch_timestamp_messages(
         &self,
         uaid: &Uuid,
@@ -82,21 +197,85 @@ pub trait DbClient: Send + Sync {
         limit: usize,
     ) -> DbResult<FetchMessageResponse>;
 
-    /// Update the last read timestamp for a user
-    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()>;
+    /// Update the last read timestamp for a user, only while the user's
+    /// record `version` still matches the expected `version` (typically the
+    /// one read on HELLO), so it can't clobber a concurrent `update_user`,
+    /// and only when `timestamp` advances past the currently stored one, so
+    /// an out of order update can't move the read position backwards
+    ///
+    /// This only advances the user's read position: expired messages are
+    /// removed via `purge_expired` (or by the storage's eventual GC)
+    ///
+    /// Returns whether the timestamp was written
+    async fn increment_storage(
+        &self,
+        uaid: &Uuid,
+        timestamp: u64,
+        version: &Uuid,
+    ) -> DbResult<bool>;
+
+    /// Fetch a single stored notification by its `chidmessageid`
+    async fn get_message(&self, uaid: &Uuid, chidmessageid: &str)
+        -> DbResult<Option<Notification>>;
+
+    /// Count the unexpired notifications stored for a user
+    async fn pending_message_count(&self, uaid: &Uuid) -> DbResult<usize>;
+
+    /// Delete a user's expired notifications that storage hasn't yet garbage
+    /// collected. Returns the number of notifications purged.
+    async fn purge_expired(&self, uaid: &Uuid) -> DbResult<usize>;
 
     /// Delete a notification
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()>;
 
+    /// Record another attempt at delivering a stored notification (one more
+    /// than its `delivery_attempts`). Returns whether it was written: it's
+    /// skipped when the notification no longer exists
+    async fn increment_delivery_attempts(
+        &self,
+        uaid: &Uuid,
+        message: &Notification,
+    ) -> DbResult<bool>;
+
+    /// Extend the TTL of a stored, undelivered notification: it's kept for
+    /// `new_ttl` seconds from now. Returns false when the notification no
+    /// longer exists
+    async fn extend_message_ttl(
+        &self,
+        uaid: &Uuid,
+        chidmessageid: &str,
+        new_ttl: u64,
+    ) -> DbResult<bool>;
+
     /// Check if the router table exists
     async fn router_table_exists(&self) -> DbResult<bool>;
 
     /// Check if the message table exists
     async fn message_table_exists(&self) -> DbResult<bool>;
 
+    /// Return an approximate count of the stored users, for capacity
+    /// reporting.
+    ///
+    /// This is a best-effort estimate from the backend's own (possibly
+    /// hours old) statistics, never a scan: it may be off in either
+    /// direction. Returns `None` when the backend can't cheaply estimate it.
+    async fn approximate_user_count(&self) -> DbResult<Option<u64>>;
+
     /// Perform the health check on this data store
     async fn health_check(&self) -> DbResult<bool>;
 
+    /// Cheaply check that the data store is reachable, e.g. for frequent
+    /// liveness polling. Defaults to `health_check`.
+    async fn ping(&self) -> DbResult<()> {
+        self.health_check().await.map(|_| ())
+    }
+
+    /// Perform a deeper health check that also verifies the data store
+    /// accepts writes. Defaults to the read only `health_check`.
+    async fn write_health_check(&self) -> DbResult<bool> {
+        self.health_check().await
+    }
+
     /// Provide the module name.
     /// This was added for simple dual mode testing (legacy), but may be useful in
     /// other situations.
@@ -38,6 +38,9 @@ pub enum DbError {
     #[error("Unknown Database Error: {0}")]
     General(String),
 
+    #[error("Notification headers too large: {0} bytes (max {1})")]
+    HeadersTooLarge(usize, usize),
+
     // Return a 503 error
     #[error("Process pending, please wait.")]
     Backoff(String),
@@ -49,6 +52,7 @@ impl DbError {
             #[cfg(feature = "bigtable")]
             Self::BTError(e) => e.status(),
             Self::Backoff(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::HeadersTooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -76,6 +80,7 @@ impl ReportableError for DbError {
             #[cfg(feature = "bigtable")]
             DbError::BTError(e) => e.metric_label(),
             DbError::Backoff(_) => Some("storage.error.backoff"),
+            DbError::HeadersTooLarge(..) => Some("storage.error.headers_too_large"),
             _ => None,
         }
     }
@@ -10,6 +10,7 @@ use crate::notification::Notification;
 use async_trait::async_trait;
 use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 use super::client::FetchMessageResponse;
@@ -20,22 +21,68 @@ impl DbClient for Arc<MockDbClient> {
         Arc::as_ref(self).add_user(user).await
     }
 
+    async fn add_user_if_absent(&self, user: &User) -> DbResult<Option<User>> {
+        Arc::as_ref(self).add_user_if_absent(user).await
+    }
+
     async fn update_user(&self, user: &mut User) -> DbResult<bool> {
         Arc::as_ref(self).update_user(user).await
     }
 
+    async fn update_router(
+        &self,
+        uaid: &Uuid,
+        router_type: String,
+        router_data: serde_json::Value,
+    ) -> DbResult<bool> {
+        Arc::as_ref(self)
+            .update_router(uaid, router_type, router_data)
+            .await
+    }
+
     async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
         Arc::as_ref(self).get_user(uaid).await
     }
 
+    async fn get_user_with_channels(&self, uaid: &Uuid) -> DbResult<Option<(User, HashSet<Uuid>)>> {
+        Arc::as_ref(self).get_user_with_channels(uaid).await
+    }
+
     async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
         Arc::as_ref(self).remove_user(uaid).await
     }
 
+    async fn remove_user_if_stale(&self, uaid: &Uuid, max_idle: Duration) -> DbResult<bool> {
+        Arc::as_ref(self).remove_user_if_stale(uaid, max_idle).await
+    }
+
+    async fn transfer_user(&self, from: &Uuid, to: &Uuid) -> DbResult<()> {
+        Arc::as_ref(self).transfer_user(from, to).await
+    }
+
+    async fn scan_users(
+        &self,
+        start: Option<Uuid>,
+        limit: usize,
+    ) -> DbResult<(Vec<User>, Option<Uuid>)> {
+        Arc::as_ref(self).scan_users(start, limit).await
+    }
+
     async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
         Arc::as_ref(self).add_channel(uaid, channel_id).await
     }
 
+    async fn add_channel_with_store_only(
+        &self,
+        uaid: &Uuid,
+        channel_id: &Uuid,
+        store_only: bool,
+    ) -> DbResult<()> {
+        Arc::as_ref(self)
+            .add_channel_with_store_only(uaid, channel_id, store_only)
+            .await
+    }
+
     async fn add_channels(&self, uaid: &Uuid, channels: HashSet<Uuid>) -> DbResult<()> {
         Arc::as_ref(self).add_channels(uaid, channels).await
     }
@@ -44,10 +91,48 @@ impl DbClient for Arc<MockDbClient> {
         Arc::as_ref(self).get_channels(uaid).await
     }
 
+    async fn get_channels_page(
+        &self,
+        uaid: &Uuid,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> DbResult<(HashSet<Uuid>, Option<String>)> {
+        Arc::as_ref(self)
+            .get_channels_page(uaid, cursor, limit)
+            .await
+    }
+
+    async fn channel_exists(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        Arc::as_ref(self).channel_exists(uaid, channel_id).await
+    }
+
+    async fn get_vapid_key_pin(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<Option<Vec<u8>>> {
+        Arc::as_ref(self).get_vapid_key_pin(uaid, channel_id).await
+    }
+
+    async fn pin_vapid_key(
+        &self,
+        uaid: &Uuid,
+        channel_id: &Uuid,
+        key_hash: &[u8],
+    ) -> DbResult<bool> {
+        Arc::as_ref(self)
+            .pin_vapid_key(uaid, channel_id, key_hash)
+            .await
+    }
+
+    async fn is_store_only(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        Arc::as_ref(self).is_store_only(uaid, channel_id).await
+    }
+
     async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
         Arc::as_ref(self).remove_channel(uaid, channel_id).await
     }
 
+    async fn remove_channels(&self, uaid: &Uuid, channel_ids: &[Uuid]) -> DbResult<usize> {
+        Arc::as_ref(self).remove_channels(uaid, channel_ids).await
+    }
+
     async fn remove_node_id(
         &self,
         uaid: &Uuid,
@@ -60,10 +145,27 @@ impl DbClient for Arc<MockDbClient> {
             .await
     }
 
+    async fn set_last_disconnect(
+        &self,
+        uaid: &Uuid,
+        reason: &str,
+        disconnected_at: u64,
+    ) -> DbResult<bool> {
+        Arc::as_ref(self)
+            .set_last_disconnect(uaid, reason, disconnected_at)
+            .await
+    }
+
     async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
         Arc::as_ref(self).save_message(uaid, message).await
     }
 
+    async fn save_message_returning(&self, uaid: &Uuid, message: Notification) -> DbResult<String> {
+        Arc::as_ref(self)
+            .save_message_returning(uaid, message)
+            .await
+    }
+
     async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
         Arc::as_ref(self).save_messages(uaid, messages).await
     }
@@ -87,14 +189,58 @@ impl DbClient for Arc<MockDbClient> {
             .await
     }
 
-    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
-        Arc::as_ref(self).increment_storage(uaid, timestamp).await
+    async fn increment_storage(
+        &self,
+        uaid: &Uuid,
+        timestamp: u64,
+        version: &Uuid,
+    ) -> DbResult<bool> {
+        Arc::as_ref(self)
+            .increment_storage(uaid, timestamp, version)
+            .await
+    }
+
+    async fn get_message(
+        &self,
+        uaid: &Uuid,
+        chidmessageid: &str,
+    ) -> DbResult<Option<Notification>> {
+        Arc::as_ref(self).get_message(uaid, chidmessageid).await
+    }
+
+    async fn pending_message_count(&self, uaid: &Uuid) -> DbResult<usize> {
+        Arc::as_ref(self).pending_message_count(uaid).await
+    }
+
+    async fn purge_expired(&self, uaid: &Uuid) -> DbResult<usize> {
+        Arc::as_ref(self).purge_expired(uaid).await
     }
 
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
         Arc::as_ref(self).remove_message(uaid, sort_key).await
     }
 
+    async fn increment_delivery_attempts(
+        &self,
+        uaid: &Uuid,
+        message: &Notification,
+    ) -> DbResult<bool> {
+        Arc::as_ref(self)
+            .increment_delivery_attempts(uaid, message)
+            .await
+    }
+
+    async fn extend_message_ttl(
+        &self,
+        uaid: &Uuid,
+        chidmessageid: &str,
+        new_ttl: u64,
+    ) -> DbResult<bool> {
+        Arc::as_ref(self)
+            .extend_message_ttl(uaid, chidmessageid, new_ttl)
+            .await
+    }
+
     async fn router_table_exists(&self) -> DbResult<bool> {
         Arc::as_ref(self).router_table_exists().await
     }
@@ -103,10 +249,22 @@ impl DbClient for Arc<MockDbClient> {
         Arc::as_ref(self).message_table_exists().await
     }
 
+    async fn approximate_user_count(&self) -> DbResult<Option<u64>> {
+        Arc::as_ref(self).approximate_user_count().await
+    }
+
     async fn health_check(&self) -> DbResult<bool> {
         Arc::as_ref(self).health_check().await
     }
 
+    async fn write_health_check(&self) -> DbResult<bool> {
+        Arc::as_ref(self).write_health_check().await
+    }
+
+    async fn ping(&self) -> DbResult<()> {
+        Arc::as_ref(self).ping().await
+    }
+
     fn box_clone(&self) -> Box<dyn DbClient> {
         Box::new(Arc::clone(self))
     }
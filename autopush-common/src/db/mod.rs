@@ -13,8 +13,6 @@ use std::collections::{HashMap, HashSet};
 use std::result::Result as StdResult;
 
 use derive_builder::Builder;
-use lazy_static::lazy_static;
-use regex::RegexSet;
 use serde::Serializer;
 use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -33,7 +31,7 @@ pub mod mock;
 pub use reporter::spawn_pool_periodic_reporter;
 
 use crate::errors::{ApcErrorKind, Result};
-use crate::notification::{Notification, STANDARD_NOTIFICATION_PREFIX, TOPIC_NOTIFICATION_PREFIX};
+use crate::notification::{ChidMessageId, Notification};
 use crate::util::timing::{ms_since_epoch, sec_since_epoch};
 use crate::{MAX_NOTIFICATION_TTL, MAX_ROUTER_TTL};
 use models::{NotificationHeaders, RangeKey};
@@ -77,20 +75,34 @@ impl StorageType {
             return Self::from(default);
         }
         let dsn = dsn.clone().unwrap_or_default();
-        #[cfg(feature = "bigtable")]
-        if dsn.starts_with("grpc") {
-            trace!("Found grpc");
-            // Credentials can be stored in either a path provided in an environment
-            // variable, or $HOME/.config/gcloud/applicaion_default_credentals.json
-            //
-            // NOTE: if no credentials are found, application will panic
-            //
-            if let Ok(cred) = std::env::var("GOOGLE_APPLICATION_CREDENTIALS") {
-                trace!("Env: {:?}", cred);
+        let scheme = dsn
+            .split_once("://")
+            .map(|(scheme, _)| scheme.to_lowercase())
+            .unwrap_or_default();
+        match scheme.as_str() {
+            #[cfg(feature = "bigtable")]
+            "grpc" => {
+                trace!("Found grpc");
+                // Credentials can be stored in either a path provided in an environment
+                // variable, or $HOME/.config/gcloud/applicaion_default_credentals.json
+                //
+                // NOTE: if no credentials are found, application will panic
+                //
+                if let Ok(cred) = std::env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+                    trace!("Env: {:?}", cred);
+                }
+                Self::BigTable
+            }
+            _ => {
+                error!(
+                    "Unsupported DSN scheme {:?} in {:?}. Supported data types: {:?}",
+                    scheme,
+                    dsn,
+                    StorageType::available()
+                );
+                Self::INVALID
             }
-            return Self::BigTable;
         }
-        Self::INVALID
     }
 }
 
@@ -148,6 +160,9 @@ pub struct User {
     /// Last node/port the client was or may be connected to
     #[serde(skip_serializing_if = "Option::is_none")]
     pub node_id: Option<String>,
+    /// Optional region of the node in `node_id`, used as a routing hint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_region: Option<String>,
     /// Record version
     #[serde(skip_serializing_if = "Option::is_none")]
     pub record_version: Option<u64>,
@@ -156,6 +171,13 @@ pub struct User {
     //TODO: rename this to `last_notification_timestamp`
     #[serde(skip_serializing_if = "Option::is_none")]
     pub current_timestamp: Option<u64>,
+    /// Why the user's last connection ended (e.g. "ping_timeout"), when
+    /// `track_disconnect_reason` is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_disconnect_reason: Option<String>,
+    /// Time in milliseconds that the user's last connection ended
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_disconnect_at: Option<u64>,
     /// UUID4 version number for optimistic locking of updates on Bigtable
     #[serde(skip_serializing)]
     pub version: Option<Uuid>,
@@ -184,8 +206,11 @@ impl Default for User {
             router_type: "webpush".to_string(),
             router_data: None,
             node_id: None,
+            node_region: None,
             record_version: Some(USER_RECORD_VERSION),
             current_timestamp: None,
+            last_disconnect_reason: None,
+            last_disconnect_at: None,
             version: Some(Uuid::new_v4()),
             priv_channels: HashSet::new(),
         }
@@ -249,70 +274,37 @@ pub struct NotificationRecord {
     /// by Mozilla owned and consumed messages, like SendTab updates.)
     #[serde(skip_serializing_if = "Option::is_none")]
     reliability_id: Option<String>,
+    /// Opaque sender supplied metadata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<serde_json::Value>,
 }
 
 impl NotificationRecord {
     /// read the custom sort_key and convert it into something the database can use.
     pub(crate) fn parse_chidmessageid(key: &str) -> Result<RangeKey> {
-        lazy_static! {
-            static ref RE: RegexSet = RegexSet::new([
-                format!("^{}:\\S+:\\S+$", TOPIC_NOTIFICATION_PREFIX).as_str(),
-                format!("^{}:\\d+:\\S+$", STANDARD_NOTIFICATION_PREFIX).as_str(),
-                "^\\S{3,}:\\S+$"
-            ])
-            .unwrap();
-        }
-        if !RE.is_match(key) {
-            return Err(ApcErrorKind::GeneralError("Invalid chidmessageid".into()).into());
+        if let Ok(chidmessageid) = key.parse::<ChidMessageId>() {
+            return Ok(RangeKey {
+                channel_id: chidmessageid.channel_id,
+                topic: chidmessageid.topic,
+                sortkey_timestamp: chidmessageid.sortkey_timestamp,
+                legacy_version: None,
+            });
         }
-
+        // Ok, that's odd, but try to make some sense of it.
+        // (This is a bit of legacy code that we should be
+        // able to drop.)
         let v: Vec<&str> = key.split(':').collect();
-        match v[0] {
-            // This is a topic message (There Can Only Be One. <guitar riff>)
-            "01" => {
-                if v.len() != 3 {
-                    return Err(ApcErrorKind::GeneralError("Invalid topic key".into()).into());
-                }
-                let (channel_id, topic) = (v[1], v[2]);
-                let channel_id = Uuid::parse_str(channel_id)?;
-                Ok(RangeKey {
-                    channel_id,
-                    topic: Some(topic.to_string()),
-                    sortkey_timestamp: None,
-                    legacy_version: None,
-                })
-            }
-            // A "normal" pending message.
-            "02" => {
-                if v.len() != 3 {
-                    return Err(ApcErrorKind::GeneralError("Invalid topic key".into()).into());
-                }
-                let (sortkey, channel_id) = (v[1], v[2]);
-                let channel_id = Uuid::parse_str(channel_id)?;
-                Ok(RangeKey {
-                    channel_id,
-                    topic: None,
-                    sortkey_timestamp: Some(sortkey.parse()?),
-                    legacy_version: None,
-                })
-            }
-            // Ok, that's odd, but try to make some sense of it.
-            // (This is a bit of legacy code that we should be
-            // able to drop.)
-            _ => {
-                if v.len() != 2 {
-                    return Err(ApcErrorKind::GeneralError("Invalid topic key".into()).into());
-                }
-                let (channel_id, legacy_version) = (v[0], v[1]);
-                let channel_id = Uuid::parse_str(channel_id)?;
-                Ok(RangeKey {
-                    channel_id,
-                    topic: None,
-                    sortkey_timestamp: None,
-                    legacy_version: Some(legacy_version.to_string()),
-                })
-            }
+        if v.len() != 2 || v.iter().any(|segment| segment.is_empty()) {
+            return Err(ApcErrorKind::GeneralError("Invalid chidmessageid".into()).into());
         }
+        let (channel_id, legacy_version) = (v[0], v[1]);
+        let channel_id = Uuid::parse_str(channel_id)?;
+        Ok(RangeKey {
+            channel_id,
+            topic: None,
+            sortkey_timestamp: None,
+            legacy_version: Some(legacy_version.to_string()),
+        })
     }
 
     /// Convert the stored notifications into publishable notifications
@@ -338,6 +330,8 @@ impl NotificationRecord {
             headers: self.headers.map(|m| m.into()),
             sortkey_timestamp: key.sortkey_timestamp,
             reliability_id: None,
+            meta: self.meta,
+            delivery_attempts: 0,
         })
     }
 
@@ -352,14 +346,44 @@ impl NotificationRecord {
             data: val.data,
             headers: val.headers.map(|h| h.into()),
             updateid: Some(val.version),
+            meta: val.meta,
             ..Default::default()
         }
     }
 }
 
+/// Sort timestamp messages into delivery order: by `sortkey_timestamp`, then
+/// by `version`.
+///
+/// Storage returns rows in key order, which isn't guaranteed to match, so
+/// `fetch_timestamp_messages` implementations apply this before returning.
+pub fn sort_timestamp_messages(messages: &mut [Notification]) {
+    messages
+        .sort_by(|a, b| (a.sortkey_timestamp, &a.version).cmp(&(b.sortkey_timestamp, &b.version)));
+}
+
+/// Redact the credential bearing entries (the DSNs, e.g. Bigtable's
+/// `read_dsn`) of a `db_settings` JSON string, for display. Settings that
+/// aren't a JSON object are redacted entirely.
+pub fn redacted_db_settings(db_settings: &str) -> String {
+    if db_settings.is_empty() {
+        return db_settings.to_owned();
+    }
+    let Ok(serde_json::Value::Object(mut settings)) = serde_json::from_str(db_settings) else {
+        return "[redacted]".to_owned();
+    };
+    for (key, value) in settings.iter_mut() {
+        if key.ends_with("dsn") && !value.is_null() {
+            *value = serde_json::Value::from("[redacted]");
+        }
+    }
+    serde_json::Value::Object(settings).to_string()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{User, USER_RECORD_VERSION};
+    use super::{sort_timestamp_messages, StorageType, User, USER_RECORD_VERSION};
+    use crate::notification::Notification;
 
     #[test]
     fn user_defaults() {
@@ -368,4 +392,60 @@ mod tests {
         assert_eq!(user.router_type, "webpush".to_owned());
         assert_eq!(user.record_version, Some(USER_RECORD_VERSION));
     }
+
+    #[test]
+    fn timestamp_message_order() {
+        let notif = |sortkey_timestamp, version: &str| Notification {
+            sortkey_timestamp: Some(sortkey_timestamp),
+            version: version.to_owned(),
+            ..Default::default()
+        };
+        let mut messages = vec![
+            notif(30, "a"),
+            notif(10, "b"),
+            notif(20, "a"),
+            notif(10, "a"),
+        ];
+        sort_timestamp_messages(&mut messages);
+        let order: Vec<_> = messages
+            .iter()
+            .map(|m| (m.sortkey_timestamp.unwrap(), m.version.as_str()))
+            .collect();
+        assert_eq!(order, vec![(10, "a"), (10, "b"), (20, "a"), (30, "a")]);
+    }
+
+    #[test]
+    fn storage_type_from_dsn() {
+        #[cfg(feature = "bigtable")]
+        {
+            assert_eq!(StorageType::from_dsn(&None), StorageType::BigTable);
+            for dsn in ["grpc://localhost:8086", "GRPC://bigtable.googleapis.com"] {
+                assert_eq!(
+                    StorageType::from_dsn(&Some(dsn.to_owned())),
+                    StorageType::BigTable,
+                    "{dsn}"
+                );
+            }
+        }
+        // Backends without an implementation, typos and malformed DSNs
+        for dsn in [
+            "http://localhost:8000",
+            "https://dynamodb.us-east-1.amazonaws.com",
+            "redis://localhost:6379",
+            "rediss://localhost:6379",
+            "postgresql://localhost/autopush",
+            "dual",
+            "memory://",
+            "grcp://localhost:8086",
+            "grpc:/localhost:8086",
+            "localhost:8086",
+            "",
+        ] {
+            assert_eq!(
+                StorageType::from_dsn(&Some(dsn.to_owned())),
+                StorageType::INVALID,
+                "{dsn}"
+            );
+        }
+    }
 }
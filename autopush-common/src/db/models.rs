@@ -3,6 +3,9 @@ use std::collections::HashMap;
 use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::notification::{
+    CRYPTO_KEY_HEADER, ENCODING_HEADER, ENCRYPTION_HEADER, ENCRYPTION_KEY_HEADER,
+};
 use crate::util::InsertOpt;
 
 /// Direct representation of an incoming subscription notification header set
@@ -25,10 +28,10 @@ pub(crate) struct NotificationHeaders {
 impl From<NotificationHeaders> for HashMap<String, String> {
     fn from(val: NotificationHeaders) -> Self {
         let mut map = Self::new();
-        map.insert_opt("crypto_key", val.crypto_key);
-        map.insert_opt("encryption", val.encryption);
-        map.insert_opt("encryption_key", val.encryption_key);
-        map.insert_opt("encoding", val.encoding);
+        map.insert_opt(CRYPTO_KEY_HEADER, val.crypto_key);
+        map.insert_opt(ENCRYPTION_HEADER, val.encryption);
+        map.insert_opt(ENCRYPTION_KEY_HEADER, val.encryption_key);
+        map.insert_opt(ENCODING_HEADER, val.encoding);
         map
     }
 }
@@ -36,10 +39,10 @@ impl From<NotificationHeaders> for HashMap<String, String> {
 impl From<HashMap<String, String>> for NotificationHeaders {
     fn from(val: HashMap<String, String>) -> Self {
         Self {
-            crypto_key: val.get("crypto_key").map(|v| v.to_string()),
-            encryption: val.get("encryption").map(|v| v.to_string()),
-            encryption_key: val.get("encryption_key").map(|v| v.to_string()),
-            encoding: val.get("encoding").map(|v| v.to_string()),
+            crypto_key: val.get(CRYPTO_KEY_HEADER).map(|v| v.to_string()),
+            encryption: val.get(ENCRYPTION_HEADER).map(|v| v.to_string()),
+            encryption_key: val.get(ENCRYPTION_KEY_HEADER).map(|v| v.to_string()),
+            encoding: val.get(ENCODING_HEADER).map(|v| v.to_string()),
         }
     }
 }
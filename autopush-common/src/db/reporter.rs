@@ -1,12 +1,24 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use actix_web::rt;
-use cadence::{Gauged, StatsdClient};
+use cadence::{CountedExt, Gauged, StatsdClient};
 use gethostname::gethostname;
 
 use super::client::DbClient;
 
-/// Emit db pool (deadpool) metrics periodically
+/// How many times the pool status is sampled per reporting `interval`
+const POOL_SAMPLES_PER_INTERVAL: u32 = 10;
+
+/// Emit db pool (deadpool) metrics periodically, also counting failures to
+/// `ping` the db and reporting its approximate user count (when available)
+///
+/// Along with the current status, the in use connections sampled over each
+/// `interval` are summarized, so brief pool exhaustion between reports still
+/// shows up
 pub fn spawn_pool_periodic_reporter(
     interval: Duration,
     db: Box<dyn DbClient>,
@@ -16,11 +28,78 @@ pub fn spawn_pool_periodic_reporter(
     rt::spawn(async move {
         loop {
             pool_periodic_reporter(&*db, &metrics, &hostname);
-            rt::time::sleep(interval).await;
+            if let Err(e) = db.ping().await {
+                warn!("Database ping failed: {}", e);
+                metrics
+                    .incr_with_tags("database.ping.error")
+                    .with_tag("hostname", &hostname)
+                    .send();
+            }
+            report_user_count(&*db, &metrics, &hostname).await;
+            sample_pool_utilization(&*db, interval)
+                .await
+                .report(&metrics, &hostname);
         }
     });
 }
 
+/// The in use connections of the pool sampled over a reporting window
+#[derive(Debug, Default, PartialEq)]
+struct PoolUtilization {
+    min: u64,
+    max: u64,
+    total: u64,
+    samples: u64,
+}
+
+impl PoolUtilization {
+    fn record(&mut self, status: &deadpool::Status) {
+        let active = status.size.saturating_sub(status.available) as u64;
+        self.min = if self.samples == 0 {
+            active
+        } else {
+            self.min.min(active)
+        };
+        self.max = self.max.max(active);
+        self.total += active;
+        self.samples += 1;
+    }
+
+    /// Emit the window's min/max/avg in use connections
+    fn report(&self, metrics: &StatsdClient, hostname: &str) {
+        if self.samples == 0 {
+            return;
+        }
+        metrics
+            .gauge_with_tags("database.pool.active.min", self.min)
+            .with_tag("hostname", hostname)
+            .send();
+        metrics
+            .gauge_with_tags("database.pool.active.max", self.max)
+            .with_tag("hostname", hostname)
+            .send();
+        metrics
+            .gauge_with_tags(
+                "database.pool.active.avg",
+                self.total as f64 / self.samples as f64,
+            )
+            .with_tag("hostname", hostname)
+            .send();
+    }
+}
+
+/// Sample the pool's status over `interval`
+async fn sample_pool_utilization(db: &dyn DbClient, interval: Duration) -> PoolUtilization {
+    let mut utilization = PoolUtilization::default();
+    for _ in 0..POOL_SAMPLES_PER_INTERVAL {
+        if let Some(status) = db.pool_status() {
+            utilization.record(&status);
+        }
+        rt::time::sleep(interval / POOL_SAMPLES_PER_INTERVAL).await;
+    }
+    utilization
+}
+
 fn pool_periodic_reporter(db: &dyn DbClient, metrics: &StatsdClient, hostname: &str) {
     let Some(status) = db.pool_status() else {
         return;
@@ -41,3 +120,163 @@ fn pool_periodic_reporter(db: &dyn DbClient, metrics: &StatsdClient, hostname: &
         .with_tag("hostname", hostname)
         .send();
 }
+
+/// Emit the db's approximate user count, when its backend provides one
+async fn report_user_count(db: &dyn DbClient, metrics: &StatsdClient, hostname: &str) {
+    match db.approximate_user_count().await {
+        Ok(Some(count)) => metrics
+            .gauge_with_tags("database.users.approximate", count)
+            .with_tag("hostname", hostname)
+            .send(),
+        Ok(None) => (),
+        Err(e) => warn!("Database approximate_user_count failed: {}", e),
+    }
+}
+
+/// Await a database `operation`, logging a warning and emitting a
+/// `database.slow` metric when it takes longer than `threshold`
+/// (`Duration::ZERO` disables timing)
+pub async fn time_operation<T>(
+    metrics: &StatsdClient,
+    threshold: Duration,
+    database: &str,
+    operation: &str,
+    fut: impl Future<Output = T>,
+) -> T {
+    if threshold.is_zero() {
+        return fut.await;
+    }
+    let start = Instant::now();
+    let result = fut.await;
+    let elapsed = start.elapsed();
+    if elapsed > threshold {
+        warn!(
+            "🐢 Slow database operation";
+            "database" => database,
+            "operation" => operation,
+            "elapsed_ms" => elapsed.as_millis() as u64,
+            "threshold_ms" => threshold.as_millis() as u64,
+        );
+        metrics
+            .incr_with_tags("database.slow")
+            .with_tag("database", database)
+            .with_tag("operation", operation)
+            .send();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+
+    use cadence::{SpyMetricSink, StatsdClient};
+
+    use super::{
+        report_user_count, sample_pool_utilization, time_operation, POOL_SAMPLES_PER_INTERVAL,
+    };
+    use crate::db::mock::MockDbClient;
+
+    #[actix_rt::test]
+    async fn pool_utilization() {
+        // Briefly exhausted every other sample
+        let mut db = MockDbClient::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        db.expect_pool_status().returning(move || {
+            let available = if counter.fetch_add(1, Ordering::Relaxed) % 2 == 0 {
+                8
+            } else {
+                0
+            };
+            Some(deadpool::Status {
+                max_size: 10,
+                size: 10,
+                available,
+                waiting: 0,
+            })
+        });
+        let utilization = sample_pool_utilization(&db, Duration::from_millis(10)).await;
+        assert_eq!(
+            calls.load(Ordering::Relaxed),
+            POOL_SAMPLES_PER_INTERVAL as usize
+        );
+
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = StatsdClient::builder("", sink).build();
+        utilization.report(&metrics, "test");
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                "database.pool.active.min:2|g|#hostname:test",
+                "database.pool.active.max:10|g|#hostname:test",
+                "database.pool.active.avg:6|g|#hostname:test",
+            ]
+        );
+
+        // Nothing sampled (e.g. no pool), nothing reported
+        let mut db = MockDbClient::new();
+        db.expect_pool_status().returning(|| None);
+        sample_pool_utilization(&db, Duration::from_millis(10))
+            .await
+            .report(&metrics, "test");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn user_count() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = StatsdClient::builder("", sink).build();
+
+        let mut db = MockDbClient::new();
+        db.expect_approximate_user_count()
+            .times(1)
+            .return_once(|| Ok(Some(42)));
+        report_user_count(&db, &metrics, "test").await;
+        let metric = String::from_utf8(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(metric, "database.users.approximate:42|g|#hostname:test");
+
+        // No estimate, nothing reported
+        let mut db = MockDbClient::new();
+        db.expect_approximate_user_count()
+            .times(1)
+            .return_once(|| Ok(None));
+        report_user_count(&db, &metrics, "test").await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn slow_operation() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = StatsdClient::builder("", sink).build();
+        let threshold = Duration::from_millis(10);
+
+        let fast = time_operation(&metrics, threshold, "test", "fast", async { 1 }).await;
+        assert_eq!(fast, 1);
+        assert!(rx.try_recv().is_err());
+
+        let slow = time_operation(&metrics, threshold, "test", "slow", async {
+            actix_rt::time::sleep(Duration::from_millis(20)).await;
+            2
+        })
+        .await;
+        assert_eq!(slow, 2);
+        let metric = String::from_utf8(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(metric, "database.slow:1|c|#database:test,operation:slow");
+
+        // Disabled
+        time_operation(&metrics, Duration::ZERO, "test", "slow", async {
+            actix_rt::time::sleep(Duration::from_millis(20)).await;
+        })
+        .await;
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,26 +1,34 @@
 use crate::errors::{ApcErrorKind, Result};
-use crate::util::b64_decode_url;
+use crate::util::{b64_decode_url, sec_since_epoch};
 
 use fernet::MultiFernet;
 use openssl::hash;
 use url::Url;
 use uuid::Uuid;
 
+/// The length of the optional expiry (a big endian UNIX timestamp in seconds)
+/// suffixing an endpoint's token
+pub const ENDPOINT_EXPIRY_LEN: usize = 8;
+
 /// Create an v1 or v2 WebPush endpoint from the identifiers
 ///
-/// Both endpoints use bytes instead of hex to reduce ID length.
-//  v1 is the uaid + chid
-//  v2 is the uaid + chid + sha256(key).bytes
+/// Both endpoints use bytes instead of hex to reduce ID length. A `ttl` (in
+/// seconds) appends the endpoint's expiry, endpoints without one never
+/// expire.
+//  v1 is the uaid + chid [+ expiry]
+//  v2 is the uaid + chid + sha256(key).bytes [+ expiry]
 pub fn make_endpoint(
     uaid: &Uuid,
     chid: &Uuid,
     key: Option<&str>,
     endpoint_url: &str,
     fernet: &MultiFernet,
+    ttl: Option<u64>,
 ) -> Result<String> {
     let root = Url::parse(endpoint_url)?.join("wpush/")?;
     let mut base = uaid.as_bytes().to_vec();
     base.extend(chid.as_bytes());
+    let expiry = ttl.map(|ttl| (sec_since_epoch() + ttl).to_be_bytes());
 
     if let Some(k) = key {
         let raw_key = b64_decode_url(k).map_err(|e| {
@@ -32,12 +40,14 @@ pub fn make_endpoint(
             ApcErrorKind::PayloadError("Error creating message digest for key".to_owned())
         })?;
         base.extend(key_digest.iter());
+        base.extend(expiry.iter().flatten());
         let encrypted = fernet.encrypt(&base).trim_matches('=').to_string();
         let final_url = root.join(&format!("v2/{encrypted}")).map_err(|e| {
             ApcErrorKind::GeneralError(format!("Encrypted endpoint data is not URL-safe {:?}", e))
         })?;
         Ok(final_url.to_string())
     } else {
+        base.extend(expiry.iter().flatten());
         let encrypted = fernet.encrypt(&base).trim_matches('=').to_string();
         let final_url = root.join(&format!("v1/{encrypted}")).map_err(|e| {
             ApcErrorKind::GeneralError(format!("Encrypted endpoint data is not URL-safe {:?}", e))
@@ -13,6 +13,7 @@ pub mod logging;
 pub mod metrics;
 pub mod middleware;
 pub mod notification;
+pub mod otel;
 pub mod sentry;
 pub mod tags;
 pub mod test_support;
@@ -51,3 +52,11 @@ pub const MAX_NOTIFICATION_TTL: u64 = 30 * ONE_DAY_IN_SECONDS;
 pub const MAX_FCM_NOTIFICATION_TTL: u64 = 4 * 7 * ONE_DAY_IN_SECONDS;
 /// The maximum TTL for router records, 60 days in seconds
 pub const MAX_ROUTER_TTL: u64 = 2 * MAX_NOTIFICATION_TTL;
+/// The default max size of notification data in bytes accepted by
+/// autoendpoint
+///
+/// Max data is a bit hard to figure out, due to encryption. Using something
+/// like pywebpush, if you encode a block of 4096 bytes, you'll get a 4216
+/// byte data block. Since we're going to be receiving this, we have to
+/// presume base64 encoding, so we can bump things up to 5630 bytes max.
+pub const MAX_NOTIFICATION_DATA_BYTES: usize = 5630;
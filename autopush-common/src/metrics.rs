@@ -1,27 +1,200 @@
 //! Metrics tie-ins
+use std::io;
 use std::net::UdpSocket;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use std::time::{Duration, Instant};
 
 use cadence::{
-    BufferedUdpMetricSink, MetricError, NopMetricSink, QueuingMetricSink, StatsdClient,
-    StatsdClientBuilder,
+    BufferedUdpMetricSink, MetricError, MetricSink, NopMetricSink, QueuingMetricSink, StatsdClient,
+    StatsdClientBuilder, Timed,
 };
+use gethostname::gethostname;
 
 /// Create a cadence StatsdClientBuilder from the given options
+///
+/// Every metric emitted by the built client is tagged with this node's `host`
+/// and, when specified, its `cluster`.
+///
+/// When the statsd `host` can't be resolved or a socket can't be bound the
+/// client falls back to a `NopMetricSink`, unless `required` is set, in which
+/// case the error is returned instead.
 pub fn builder(
     prefix: &str,
     host: &Option<String>,
     port: u16,
+    cluster: &Option<String>,
+    required: bool,
 ) -> Result<StatsdClientBuilder, MetricError> {
     let builder = if let Some(host) = host {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_nonblocking(true)?;
-
-        let addr = (host.as_str(), port);
-        let udp_sink = BufferedUdpMetricSink::from(addr, socket)?;
-        let sink = QueuingMetricSink::from(udp_sink);
-        StatsdClient::builder(prefix, sink)
+        match udp_sink(host, port) {
+            Ok(sink) => StatsdClient::builder(prefix, sink),
+            Err(e) if !required => {
+                warn!(
+                    "⚠️ Metrics unavailable, statsd {}:{} unusable: {}",
+                    host, port, e
+                );
+                static BREADCRUMB: Once = Once::new();
+                BREADCRUMB.call_once(|| {
+                    sentry::add_breadcrumb(sentry::Breadcrumb {
+                        category: Some("metrics".to_owned()),
+                        message: Some(format!("Falling back to NopMetricSink: {e}")),
+                        level: sentry::Level::Warning,
+                        ..Default::default()
+                    })
+                });
+                StatsdClient::builder(prefix, NopMetricSink)
+            }
+            Err(e) => return Err(e),
+        }
     } else {
         StatsdClient::builder(prefix, NopMetricSink)
     };
-    Ok(builder.with_error_handler(|err| warn!("⚠️ Metric send error: {:?}", err)))
+    Ok(with_default_tags(builder, cluster)
+        .with_error_handler(|err| warn!("⚠️ Metric send error: {:?}", err)))
+}
+
+/// Build a queuing UDP sink sending to the statsd `host`
+fn udp_sink(host: &str, port: u16) -> Result<impl MetricSink, MetricError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_nonblocking(true)?;
+
+    let udp_sink = BufferedUdpMetricSink::from((host, port), socket)?;
+    Ok(queuing_sink(udp_sink))
+}
+
+/// How long to wait for queued metrics to be sent on shutdown
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The number of metrics handed to a `QueuingMetricSink` but not yet sent (or
+/// failed) by its worker thread
+static QUEUED_METRICS: AtomicUsize = AtomicUsize::new(0);
+
+/// Wrap `sink` in a `QueuingMetricSink` whose pending metrics are tracked (so
+/// they can be drained by [flush])
+fn queuing_sink<T>(sink: T) -> impl MetricSink
+where
+    T: MetricSink + Send + Sync + std::panic::RefUnwindSafe + 'static,
+{
+    Enqueue(QueuingMetricSink::from(Dequeue(sink)))
+}
+
+/// Counts metrics entering a `QueuingMetricSink`
+struct Enqueue<T>(T);
+
+impl<T: MetricSink> MetricSink for Enqueue<T> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        QUEUED_METRICS.fetch_add(1, Ordering::SeqCst);
+        self.0.emit(metric).map_err(|e| {
+            // Never queued
+            QUEUED_METRICS.fetch_sub(1, Ordering::SeqCst);
+            e
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Counts metrics leaving a `QueuingMetricSink` (wrapping the sink its worker
+/// thread emits to)
+struct Dequeue<T>(T);
+
+impl<T: MetricSink> MetricSink for Dequeue<T> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let result = self.0.emit(metric);
+        QUEUED_METRICS.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Wait (up to `timeout`) for queued metrics to be sent then flush the
+/// client's sink, so the last batch of metrics isn't lost on exit
+pub fn flush(client: &StatsdClient, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while QUEUED_METRICS.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    if let Err(e) = client.flush() {
+        warn!("⚠️ Metric flush error: {:?}", e);
+    }
+}
+
+/// Emit a final `process.shutdown` metric recording the process' `uptime`
+/// then [flush] the client
+pub fn shutdown(client: &StatsdClient, uptime: Duration) {
+    let _ = client.time("process.shutdown", uptime);
+    flush(client, SHUTDOWN_FLUSH_TIMEOUT);
+}
+
+/// Tag every metric with the `host` and optional `cluster`
+fn with_default_tags(
+    builder: StatsdClientBuilder,
+    cluster: &Option<String>,
+) -> StatsdClientBuilder {
+    let builder = builder.with_tag("host", gethostname().to_string_lossy());
+    match cluster {
+        Some(cluster) => builder.with_tag("cluster", cluster),
+        None => builder,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cadence::{CountedExt, SpyMetricSink, StatsdClient};
+    use gethostname::gethostname;
+
+    use super::{builder, flush, queuing_sink, with_default_tags};
+
+    #[test]
+    fn default_tags() {
+        let host = gethostname().to_string_lossy().to_string();
+        let (rx, sink) = SpyMetricSink::new();
+        let client = with_default_tags(
+            StatsdClient::builder("test", sink),
+            &Some("us-west".to_owned()),
+        )
+        .build();
+        client
+            .incr_with_tags("metric")
+            .with_tag("foo", "bar")
+            .send();
+        let metric = String::from_utf8(rx.try_recv().unwrap()).unwrap();
+        assert!(metric.starts_with("test.metric:1|c|#"), "{metric}");
+        let tags: Vec<_> = metric.split_once('#').unwrap().1.split(',').collect();
+        assert_eq!(tags.len(), 3);
+        for tag in ["foo:bar", &*format!("host:{host}"), "cluster:us-west"] {
+            assert!(tags.contains(&tag), "{metric}");
+        }
+
+        let (rx, sink) = SpyMetricSink::new();
+        let client = with_default_tags(StatsdClient::builder("test", sink), &None).build();
+        let _ = client.incr("metric");
+        let metric = String::from_utf8(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(metric, format!("test.metric:1|c|#host:{host}"));
+    }
+
+    #[test]
+    fn unavailable_host() {
+        let host = Some("statsd.nonexistent.invalid".to_owned());
+        let client = builder("test", &host, 8125, &None, false).unwrap().build();
+        assert!(client.incr("metric").is_ok());
+        assert!(builder("test", &host, 8125, &None, true).is_err());
+    }
+
+    #[test]
+    fn flush_drains_queue() {
+        let (rx, sink) = SpyMetricSink::new();
+        let client = StatsdClient::from_sink("test", queuing_sink(sink));
+        for _ in 0..100 {
+            client.incr("metric").unwrap();
+        }
+        flush(&client, std::time::Duration::from_secs(5));
+        assert_eq!(rx.try_iter().count(), 100);
+    }
 }
@@ -1,12 +1,16 @@
 //! Notification protocol
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::errors::{ApcError, ApcErrorKind};
 use crate::util::ms_since_epoch;
 
-#[derive(Serialize, Default, Deserialize, Clone, Debug)]
+#[derive(Serialize, Default, Deserialize, Clone, Debug, JsonSchema)]
 /// A Publishable Notification record. This is a notification that is either
 /// received from a third party or is outbound to a UserAgent. If the
 /// UserAgent is not currently available, it may be stored as a
@@ -29,11 +33,24 @@ pub struct Notification {
     pub headers: Option<HashMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reliability_id: Option<String>,
+    /// Opaque sender supplied metadata, returned along with the notification
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<serde_json::Value>,
+    /// How many times delivery of this (stored) notification has been
+    /// attempted
+    #[serde(skip)]
+    pub delivery_attempts: u32,
 }
 
 pub const TOPIC_NOTIFICATION_PREFIX: &str = "01";
 pub const STANDARD_NOTIFICATION_PREFIX: &str = "02";
 
+/// The keys of the crypto [Notification::headers]
+pub const ENCODING_HEADER: &str = "encoding";
+pub const ENCRYPTION_HEADER: &str = "encryption";
+pub const ENCRYPTION_KEY_HEADER: &str = "encryption_key";
+pub const CRYPTO_KEY_HEADER: &str = "crypto_key";
+
 impl Notification {
     /// Return an appropriate chidmessageid
     ///
@@ -46,26 +63,52 @@ impl Notification {
     /// Old format for non-topic messages that is no longer returned:
     ///     {chid}:{message_id}
     pub fn chidmessageid(&self) -> String {
-        let chid = self.channel_id.as_hyphenated();
         if let Some(ref topic) = self.topic {
-            format!("{TOPIC_NOTIFICATION_PREFIX}:{chid}:{topic}")
+            ChidMessageId::topic(self.channel_id, topic.clone()).to_string()
         } else if let Some(sortkey_timestamp) = self.sortkey_timestamp {
-            format!(
-                "{STANDARD_NOTIFICATION_PREFIX}:{}:{}",
-                if sortkey_timestamp == 0 {
-                    ms_since_epoch()
-                } else {
-                    sortkey_timestamp
-                },
-                chid
-            )
+            let sortkey_timestamp = if sortkey_timestamp == 0 {
+                ms_since_epoch()
+            } else {
+                sortkey_timestamp
+            };
+            ChidMessageId::standard(self.channel_id, sortkey_timestamp).to_string()
         } else {
             warn!("🚨 LEGACY MESSAGE!? {:?} ", self);
             // Legacy messages which we should never get anymore
-            format!("{}:{}", chid, self.version)
+            format!("{}:{}", self.channel_id.as_hyphenated(), self.version)
         }
     }
 
+    /// The `Content-Encoding` of the notification's data
+    pub fn content_encoding(&self) -> Result<Option<ContentEncoding>, ApcError> {
+        self.header(ENCODING_HEADER).map(str::parse).transpose()
+    }
+
+    /// The `Encryption` header of `aesgcm` encoded data (carrying the `salt`)
+    pub fn encryption(&self) -> Result<Option<CryptoParams>, ApcError> {
+        self.crypto_params(ENCRYPTION_HEADER, "Encryption")
+    }
+
+    /// The `Crypto-Key` header of `aesgcm` encoded data (carrying the `dh`
+    /// key)
+    pub fn crypto_key(&self) -> Result<Option<CryptoParams>, ApcError> {
+        self.crypto_params(CRYPTO_KEY_HEADER, "Crypto-Key")
+    }
+
+    fn header(&self, key: &str) -> Option<&str> {
+        self.headers.as_ref()?.get(key).map(String::as_str)
+    }
+
+    fn crypto_params(&self, key: &str, name: &str) -> Result<Option<CryptoParams>, ApcError> {
+        self.header(key)
+            .map(|header| {
+                header.parse().map_err(|_| {
+                    ApcErrorKind::GeneralError(format!("Invalid {name} header")).into()
+                })
+            })
+            .transpose()
+    }
+
     /// Convenience function to determine if the notification
     /// has aged out.
     pub fn expired(&self, at_sec: u64) -> bool {
@@ -76,3 +119,388 @@ impl Notification {
 fn default_ttl() -> u64 {
     0
 }
+
+/// The `Content-Encoding`s of encrypted WebPush data
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ContentEncoding {
+    /// RFC 8188/8291: the encryption parameters are included in the data
+    Aes128Gcm,
+    /// The legacy draft encoding: the parameters are in the `Encryption` and
+    /// `Crypto-Key` headers
+    AesGcm,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Aes128Gcm => "aes128gcm",
+            Self::AesGcm => "aesgcm",
+        }
+    }
+}
+
+impl fmt::Display for ContentEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ContentEncoding {
+    type Err = ApcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes128gcm" => Ok(Self::Aes128Gcm),
+            "aesgcm" => Ok(Self::AesGcm),
+            _ => Err(ApcErrorKind::GeneralError("Unknown Content-Encoding header".into()).into()),
+        }
+    }
+}
+
+/// A parsed `Encryption` or `Crypto-Key` header (and similar headers) as
+/// described by
+/// `http://tools.ietf.org/html/draft-ietf-httpbis-encryption-encoding-00#section-4`
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CryptoParams {
+    /// The sections (comma separated) and their items (key-value semicolon separated)
+    sections: Vec<HashMap<String, String>>,
+}
+
+impl CryptoParams {
+    /// Parse the header's structure (without validating its values)
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut sections = Vec::new();
+        for section_str in header.split(',') {
+            let mut section = HashMap::new();
+            for item_str in section_str.split(';') {
+                let (key, value) = item_str.split_once('=')?;
+                section.insert(
+                    key.trim().to_owned(),
+                    value.trim_matches(&[' ', '"'] as &[char]).to_owned(),
+                );
+            }
+            sections.push(section);
+        }
+        Some(Self { sections })
+    }
+
+    /// Get the value of the first item with the given key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.sections
+            .iter()
+            .find_map(|section| section.get(key))
+            .map(String::as_str)
+    }
+}
+
+impl FromStr for CryptoParams {
+    type Err = ApcError;
+
+    /// Parse the header, also validating that its key material (`salt` and
+    /// `dh` values) is URL safe base64
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ApcErrorKind::GeneralError("Invalid crypto header".into());
+        let params = Self::parse(s).ok_or_else(invalid)?;
+        let valid = params
+            .sections
+            .iter()
+            .flatten()
+            .all(|(key, value)| !matches!(key.as_str(), "salt" | "dh") || is_base64_url(value));
+        if !valid {
+            return Err(invalid().into());
+        }
+        Ok(params)
+    }
+}
+
+/// Whether `value` is (optionally padded) URL safe base64
+fn is_base64_url(value: &str) -> bool {
+    let unpadded = value.trim_end_matches('=');
+    !unpadded.is_empty()
+        && unpadded
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The key of a stored notification, see [Notification::chidmessageid]
+///
+/// Topic notifications (`topic` is set) are keyed by their channel and topic,
+/// replacing any prior notification of the same topic. Otherwise they're
+/// standard notifications keyed by their `sortkey_timestamp` (missing is
+/// treated as 0).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChidMessageId {
+    pub topic: Option<String>,
+    pub channel_id: Uuid,
+    pub sortkey_timestamp: Option<u64>,
+}
+
+impl ChidMessageId {
+    pub fn topic(channel_id: Uuid, topic: String) -> Self {
+        Self {
+            topic: Some(topic),
+            channel_id,
+            sortkey_timestamp: None,
+        }
+    }
+
+    pub fn standard(channel_id: Uuid, sortkey_timestamp: u64) -> Self {
+        Self {
+            topic: None,
+            channel_id,
+            sortkey_timestamp: Some(sortkey_timestamp),
+        }
+    }
+
+    /// The (exclusive) bounds of the `chidmessageid`s of all topic
+    /// notifications, in storage's (lexicographic) key order
+    pub fn topic_bounds() -> (String, String) {
+        prefix_bounds(TOPIC_NOTIFICATION_PREFIX)
+    }
+
+    /// The (exclusive) bounds of the `chidmessageid`s of all standard
+    /// notifications, or of only those after `sortkey_timestamp`, in
+    /// storage's (lexicographic) key order
+    pub fn standard_bounds(sortkey_timestamp: Option<u64>) -> (String, String) {
+        let (start, end) = prefix_bounds(STANDARD_NOTIFICATION_PREFIX);
+        let start = match sortkey_timestamp {
+            // Past all those of `sortkey_timestamp`: the separator is ':'
+            Some(sortkey_timestamp) => {
+                format!("{start}{sortkey_timestamp}{KEY_SEPARATOR_SUCCESSOR}")
+            }
+            None => start,
+        };
+        (start, end)
+    }
+}
+
+/// The character after the ':' separator, bounding keys that begin with a
+/// prefix followed by ':'
+const KEY_SEPARATOR_SUCCESSOR: char = ';';
+
+/// The (exclusive) bounds of keys beginning with `prefix` and a separator
+fn prefix_bounds(prefix: &str) -> (String, String) {
+    (
+        format!("{prefix}:"),
+        format!("{prefix}{KEY_SEPARATOR_SUCCESSOR}"),
+    )
+}
+
+impl fmt::Display for ChidMessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chid = self.channel_id.as_hyphenated();
+        if let Some(ref topic) = self.topic {
+            write!(f, "{TOPIC_NOTIFICATION_PREFIX}:{chid}:{topic}")
+        } else {
+            write!(
+                f,
+                "{STANDARD_NOTIFICATION_PREFIX}:{}:{chid}",
+                self.sortkey_timestamp.unwrap_or_default()
+            )
+        }
+    }
+}
+
+impl FromStr for ChidMessageId {
+    type Err = ApcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ApcErrorKind::GeneralError("Invalid chidmessageid".into());
+        let (prefix, rest) = s.split_once(':').ok_or_else(invalid)?;
+        let (first, second) = rest.split_once(':').ok_or_else(invalid)?;
+        match prefix {
+            TOPIC_NOTIFICATION_PREFIX => {
+                if second.is_empty() || second.contains(|c: char| c == ':' || c.is_whitespace()) {
+                    return Err(ApcErrorKind::GeneralError("Invalid topic key".into()).into());
+                }
+                Ok(Self::topic(Uuid::parse_str(first)?, second.to_owned()))
+            }
+            STANDARD_NOTIFICATION_PREFIX => {
+                if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid().into());
+                }
+                Ok(Self::standard(Uuid::parse_str(second)?, first.parse()?))
+            }
+            _ => Err(invalid().into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use std::collections::HashMap;
+
+    use super::{ChidMessageId, ContentEncoding, CryptoParams, Notification};
+
+    const DH: &str =
+        "BDw9T0eImd4ax818VcYqDK_DOhcuDswKeroYyNkdhYmygoLSDlSiWpuoWYUSSFxi25cyyNTR5k9Ny93DzZc0UI4";
+
+    fn with_headers(headers: &[(&str, &str)]) -> Notification {
+        Notification {
+            headers: Some(
+                headers
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<HashMap<_, _>>(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn crypto_headers() {
+        let notif = with_headers(&[
+            ("encoding", "aesgcm"),
+            ("encryption", "salt=abc_-DEF"),
+            (
+                "crypto_key",
+                &format!("keyid=\"p256dh\";dh=\"{DH}\",p256ecdsa=xyz"),
+            ),
+        ]);
+        assert_eq!(
+            notif.content_encoding().unwrap(),
+            Some(ContentEncoding::AesGcm)
+        );
+        assert_eq!(
+            notif.encryption().unwrap().unwrap().get("salt"),
+            Some("abc_-DEF")
+        );
+        let crypto_key = notif.crypto_key().unwrap().unwrap();
+        assert_eq!(crypto_key.get("keyid"), Some("p256dh"));
+        assert_eq!(crypto_key.get("dh"), Some(DH));
+        assert_eq!(crypto_key.get("p256ecdsa"), Some("xyz"));
+        assert_eq!(crypto_key.get("missing"), None);
+
+        let notif = with_headers(&[("encoding", "aes128gcm")]);
+        assert_eq!(
+            notif.content_encoding().unwrap(),
+            Some(ContentEncoding::Aes128Gcm)
+        );
+        assert!(notif.encryption().unwrap().is_none());
+        assert!(notif.crypto_key().unwrap().is_none());
+        let notif = Notification::default();
+        assert!(notif.content_encoding().unwrap().is_none());
+        assert!(notif.encryption().unwrap().is_none());
+    }
+
+    #[test]
+    fn crypto_headers_malformed() {
+        assert!(with_headers(&[("encoding", "gzip")])
+            .content_encoding()
+            .is_err());
+        assert!(with_headers(&[("encryption", "salt")])
+            .encryption()
+            .is_err());
+        assert!(with_headers(&[("encryption", "salt=")])
+            .encryption()
+            .is_err());
+        assert!(with_headers(&[("encryption", "salt=not base64!")])
+            .encryption()
+            .is_err());
+        assert!(with_headers(&[("crypto_key", "dh=abc;keyid")])
+            .crypto_key()
+            .is_err());
+        assert!(with_headers(&[("crypto_key", "dh=a+b/c")])
+            .crypto_key()
+            .is_err());
+        // Padding's allowed
+        assert!("salt=abc==".parse::<CryptoParams>().is_ok());
+    }
+
+    #[test]
+    fn chidmessageid_round_trip() {
+        let channel_id = Uuid::new_v4();
+        for id in [
+            ChidMessageId::topic(channel_id, "mytopic".to_owned()),
+            ChidMessageId::topic(channel_id, "a-base64_topic".to_owned()),
+            ChidMessageId::standard(channel_id, 0),
+            ChidMessageId::standard(channel_id, 1_700_000_000_000),
+            ChidMessageId::standard(channel_id, u64::MAX),
+        ] {
+            let s = id.to_string();
+            assert_eq!(s.parse::<ChidMessageId>().unwrap(), id, "{s}");
+        }
+
+        let topic = ChidMessageId::topic(channel_id, "mytopic".to_owned());
+        assert_eq!(
+            topic.to_string(),
+            format!("01:{}:mytopic", channel_id.as_hyphenated())
+        );
+        let standard = ChidMessageId::standard(channel_id, 123);
+        assert_eq!(
+            standard.to_string(),
+            format!("02:123:{}", channel_id.as_hyphenated())
+        );
+        // Simple (unhyphenated) channel ids are accepted
+        assert_eq!(
+            format!("02:123:{}", channel_id.as_simple())
+                .parse::<ChidMessageId>()
+                .unwrap(),
+            standard
+        );
+    }
+
+    #[test]
+    fn chidmessageid_matches_notification() {
+        let notif = Notification {
+            channel_id: Uuid::new_v4(),
+            topic: Some("mytopic".to_owned()),
+            ..Default::default()
+        };
+        let id: ChidMessageId = notif.chidmessageid().parse().unwrap();
+        assert_eq!(id.topic, notif.topic);
+        assert_eq!(id.channel_id, notif.channel_id);
+
+        let notif = Notification {
+            channel_id: Uuid::new_v4(),
+            sortkey_timestamp: Some(456),
+            ..Default::default()
+        };
+        let id: ChidMessageId = notif.chidmessageid().parse().unwrap();
+        assert_eq!(id, ChidMessageId::standard(notif.channel_id, 456));
+    }
+
+    #[test]
+    fn chidmessageid_invalid() {
+        let chid = Uuid::new_v4().as_hyphenated().to_string();
+        for s in [
+            "".to_owned(),
+            "02j3i2o".to_owned(),
+            "03:ffas:wef".to_owned(),
+            "01::mytopic".to_owned(),
+            format!("01:{chid}:"),
+            format!("01:{chid}:my:topic"),
+            format!("01:{chid}:my topic"),
+            "02:oops:ohnoes".to_owned(),
+            format!("02::{chid}"),
+            format!("02:+12:{chid}"),
+            format!("02:12:{chid}:extra"),
+            format!("02:{chid}:12"),
+            // Legacy keys aren't handled
+            format!("{chid}:version"),
+        ] {
+            assert!(s.parse::<ChidMessageId>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn chidmessageid_bounds() {
+        let channel_id = Uuid::new_v4();
+        let (start, end) = ChidMessageId::topic_bounds();
+        let topic = ChidMessageId::topic(channel_id, "mytopic".to_owned()).to_string();
+        assert!(start < topic && topic < end);
+
+        let standard = |ts| ChidMessageId::standard(channel_id, ts).to_string();
+        assert!(end < standard(0));
+        let (start, end) = ChidMessageId::standard_bounds(None);
+        assert!(start < standard(0) && standard(1_700_000_000_000) < end);
+
+        // Only those after the given timestamp
+        let (start, end) = ChidMessageId::standard_bounds(Some(1_700_000_000_000));
+        assert!(standard(1_700_000_000_000) < start);
+        assert!(start < standard(1_700_000_000_001) && standard(1_700_000_000_001) < end);
+        assert!(standard(1_699_999_999_999) < start);
+    }
+}
@@ -0,0 +1,97 @@
+//! Optional OpenTelemetry tracing of a notification's path: autoendpoint
+//! receiving and routing it, storing it and autoconnect delivering it.
+//!
+//! Spans are only recorded when built with the `otel` feature, otherwise
+//! [in_span] simply runs its future.
+use std::future::Future;
+
+use uuid::Uuid;
+
+use crate::errors::{ApcErrorKind, Result};
+
+/// autoendpoint receiving a notification
+pub const ENDPOINT_RECEIVE: &str = "endpoint.receive";
+/// autoendpoint dispatching a notification to its router
+pub const ROUTER_DISPATCH: &str = "router.dispatch";
+/// Storing a notification (for a disconnected client)
+pub const DB_SAVE_MESSAGE: &str = "db.save_message";
+/// autoconnect handing a notification to its connected client(s)
+pub const REGISTRY_NOTIFY: &str = "registry.notify";
+/// A client's read of its stored notifications
+pub const CHECK_STORAGE_FETCH: &str = "check_storage.fetch";
+
+/// Install a global tracer exporting spans (via OTLP over HTTP) to
+/// `endpoint`
+#[cfg(feature = "otel")]
+pub fn init(service_name: &'static str, endpoint: &str) -> Result<()> {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace::Config, Resource};
+
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            Config::default()
+                .with_resource(Resource::new([KeyValue::new("service.name", service_name)])),
+        )
+        .install_batch(runtime::TokioCurrentThread)
+        .map_err(|e| ApcErrorKind::GeneralError(format!("Invalid OpenTelemetry setup: {e}")))?;
+    opentelemetry::global::set_tracer_provider(provider);
+    Ok(())
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(_service_name: &'static str, _endpoint: &str) -> Result<()> {
+    Err(ApcErrorKind::GeneralError(
+        "OpenTelemetry requires building with the `otel` feature".to_owned(),
+    )
+    .into())
+}
+
+/// Flush any pending spans and stop exporting
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Run `fut` within a span `name` (a child of the current span), tagged with
+/// the hashed `uaid` and the `message_id`
+pub async fn in_span<F: Future>(
+    name: &'static str,
+    uaid: &Uuid,
+    message_id: Option<&str>,
+    fut: F,
+) -> F::Output {
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::{
+            global,
+            trace::{FutureExt, TraceContextExt, Tracer},
+            Context, KeyValue,
+        };
+
+        let tracer = global::tracer("autopush");
+        let mut attributes = vec![KeyValue::new(
+            "uaid_hash",
+            crate::util::elide(uaid.as_simple().to_string()),
+        )];
+        if let Some(message_id) = message_id {
+            attributes.push(KeyValue::new("message_id", message_id.to_owned()));
+        }
+        let span = tracer
+            .span_builder(name)
+            .with_attributes(attributes)
+            .start(&tracer);
+        fut.with_context(Context::current_with_span(span)).await
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = (name, uaid, message_id);
+        fut.await
+    }
+}
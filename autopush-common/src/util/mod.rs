@@ -1,10 +1,11 @@
 //! Various small utilities accumulated over time for the WebPush server
 use std::collections::HashMap;
+use std::fmt;
 use std::hash::Hash;
 use std::time::Duration;
 
 use base64::Engine;
-use serde::{Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer, Serializer};
 
 pub mod timing;
 pub mod user_agent;
@@ -26,6 +27,15 @@ impl<K: Eq + Hash, V> InsertOpt<K, V> for HashMap<K, V> {
     }
 }
 
+/// Elide a potentially sensitive value (e.g. a UAID or row key) for logging.
+///
+/// Returns a short, stable hash of the value, so log lines can still be
+/// correlated without revealing it.
+pub fn elide(value: impl AsRef<[u8]>) -> String {
+    let digest = openssl::sha::sha256(value.as_ref());
+    format!("<{}>", hex::encode(&digest[..8]))
+}
+
 /// Convenience wrapper for base64 decoding
 /// *note* The `base64` devs are HIGHLY opinionated and the method to encode/decode
 /// changes frequently. This function encapsulates that as much as possible.
@@ -62,3 +72,128 @@ where
     let seconds: Option<u32> = Deserialize::deserialize(deserializer)?;
     Ok(seconds.map(|v| Duration::from_secs(v.into())))
 }
+
+/// Deserialize a [Duration] from either a bare number of seconds (e.g. `90`
+/// or `0.5`, also accepted as strings) or a humantime string (e.g. `"90s"`,
+/// `"5m"`, `"1h30m"`)
+pub fn deserialize_humantime_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct DurationVisitor;
+
+    impl de::Visitor<'_> for DurationVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a number of seconds or a duration string such as \"30s\"")
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Duration, E> {
+            Ok(Duration::from_secs(v))
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Duration, E> {
+            u64::try_from(v)
+                .map(Duration::from_secs)
+                .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<Duration, E> {
+            Duration::try_from_secs_f64(v)
+                .map_err(|_| E::invalid_value(de::Unexpected::Float(v), &self))
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Duration, E> {
+            let v = v.trim();
+            // Settings read from the environment arrive as strings
+            if let Ok(seconds) = v.parse::<f64>() {
+                return self.visit_f64(seconds);
+            }
+            humantime::parse_duration(v).map_err(|e| E::custom(format!("{v:?}: {e}")))
+        }
+    }
+
+    deserializer.deserialize_any(DurationVisitor)
+}
+
+/// Serialize a [Duration] as a humantime string (e.g. `"1m 30s"`), readable
+/// by [deserialize_humantime_duration]
+pub fn serialize_humantime_duration<S>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(&humantime::format_duration(*duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    struct Interval {
+        #[serde(
+            deserialize_with = "super::deserialize_humantime_duration",
+            serialize_with = "super::serialize_humantime_duration"
+        )]
+        interval: Duration,
+    }
+
+    fn parse(json: &str) -> Result<Duration, serde_json::Error> {
+        serde_json::from_str::<Interval>(json).map(|v| v.interval)
+    }
+
+    #[test]
+    fn humantime_duration() {
+        assert_eq!(
+            parse(r#"{"interval": "90s"}"#).unwrap(),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            parse(r#"{"interval": 90}"#).unwrap(),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            parse(r#"{"interval": "1h30m"}"#).unwrap(),
+            Duration::from_secs(90 * 60)
+        );
+        assert_eq!(
+            parse(r#"{"interval": 0.5}"#).unwrap(),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            parse(r#"{"interval": "90"}"#).unwrap(),
+            Duration::from_secs(90)
+        );
+        assert!(parse(r#"{"interval": "soon"}"#).is_err());
+        assert!(parse(r#"{"interval": -1}"#).is_err());
+
+        // Round trips
+        let interval = Duration::from_millis(90_500);
+        let json = serde_json::to_string(&Interval { interval }).unwrap();
+        assert_eq!(json, r#"{"interval":"1m 30s 500ms"}"#);
+        assert_eq!(parse(&json).unwrap(), interval);
+    }
+
+    #[test]
+    fn elide_hides_uaid() {
+        for _ in 0..100 {
+            let uaid = uuid::Uuid::new_v4();
+            let simple = uaid.simple().to_string();
+            let row_key = format!("{simple}#02:{}", uuid::Uuid::new_v4().simple());
+            for value in [&simple, &uaid.hyphenated().to_string(), &row_key] {
+                let elided = super::elide(value);
+                assert!(!elided.contains(&simple), "{elided}");
+                assert!(!elided.contains(&simple[..8]), "{elided}");
+                // but it's stable for correlating log lines
+                assert_eq!(elided, super::elide(value));
+            }
+            assert_ne!(super::elide(&simple), super::elide(&row_key));
+        }
+    }
+}
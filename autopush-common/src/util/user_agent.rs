@@ -109,4 +109,21 @@ mod tests {
         assert_eq!(ua_result.metrics_browser, "Other");
         assert_eq!(ua_result.browser_name, "UNKNOWN");
     }
+
+    #[test]
+    fn test_chrome() {
+        let agent = r#"Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36"#;
+        let ua_result = UserAgentInfo::from(agent);
+        assert_eq!(ua_result.metrics_os, "Windows");
+        assert_eq!(ua_result.metrics_browser, "Chrome");
+    }
+
+    #[test]
+    fn test_unknown() {
+        for agent in ["", "NotARealBrowser/1.0"] {
+            let ua_result = UserAgentInfo::from(agent);
+            assert_eq!(ua_result.metrics_os, "Other");
+            assert_eq!(ua_result.metrics_browser, "Other");
+        }
+    }
 }
@@ -46,6 +46,7 @@ BT_DB_SETTINGS: str | None = None
 ROUTER_TABLE = os.environ.get("ROUTER_TABLE", "router_int_test")
 MESSAGE_TABLE = os.environ.get("MESSAGE_TABLE", "message_int_test")
 MSG_LIMIT = 20
+CHANNEL_RATE_LIMIT_BURST = 50
 
 CRYPTO_KEY = os.environ.get("CRYPTO_KEY") or Fernet.generate_key().decode("utf-8")
 TRACKING_KEY = ecdsa.SigningKey.generate(curve=ecdsa.NIST256p)
@@ -188,6 +189,9 @@ ENDPOINT_CONFIG = dict(
     crypto_keys=f"[{CRYPTO_KEY}]",
     # convert to x692 format
     tracking_keys=f"[{base64.urlsafe_b64encode((b"\4" + TRACKING_PUB_KEY.to_string())).decode()}]",
+    # Generous enough for the other tests' notifications to a channel
+    channel_rate_limit=1,
+    channel_rate_limit_burst=CHANNEL_RATE_LIMIT_BURST,
 )
 
 
@@ -1387,6 +1391,23 @@ async def test_msg_limit(registered_test_client: AsyncPushTestClient):
     assert registered_test_client.uaid != uaid
 
 
+@pytest.mark.skipif(
+    bool(os.getenv("AUTOPUSH_EP_SERVER")),
+    reason="Requires the local endpoint server's channel_rate_limit",
+)
+async def test_channel_rate_limit(registered_test_client: AsyncPushTestClient):
+    """Test that notifications to a channel beyond its burst are rejected."""
+    await registered_test_client.disconnect()
+    endpoint = list(registered_test_client.channels.values())[0]
+    async with httpx.AsyncClient() as httpx_client:
+        for _ in range(CHANNEL_RATE_LIMIT_BURST * 2):
+            resp = await httpx_client.post(endpoint, headers={"TTL": "60"}, timeout=30)
+            if resp.status_code != 201:
+                break
+    assert resp.status_code == 429
+    assert int(resp.headers["Retry-After"]) >= 1
+
+
 async def test_can_moz_ping(registered_test_client) -> None:
     """Test that the client can send a small ping message and get a valid response."""
     result = await registered_test_client.moz_ping()